@@ -0,0 +1,108 @@
+//! Performing operations with and without the tail-resumptive handler optimization.
+//!
+//! Run with `cargo bench --bench handlers`. The `reading` loop reads its step from a handler that
+//! resumes in tail position, once per iteration. The `counting` loop counts through `get` and
+//! `put`: locals can't be assigned, so its handler threads the state through closures, resuming
+//! inside them. Those resumptions aren't in tail position, so the optimization leaves them alone
+//! and the loop measures what capturing a resumption costs either way.
+
+use std::{hint::black_box, time::Instant};
+
+use korou_lang::{Passes, Program, Session, Value};
+
+const SRC: &str = r#"
+effect reader {
+    fn ask() -> Int;
+}
+
+effect state {
+    fn get() -> Int;
+    fn put(v: Int) -> ();
+}
+
+fn read(n: Int, acc: Int) -> Int = {
+    if n == 0 { acc } else { read(n - 1, acc + ask()) }
+}
+
+fn reading(n: Int) -> Int = {
+    do {
+        read(n, 0)
+    } with handle reader {
+        fn ask() -> Int = { return(1) }
+    }
+}
+
+fn count(n: Int) -> Int = {
+    if n == 0 { get() } else {
+        put(get() + 1);
+        count(n - 1)
+    }
+}
+
+fn counting(n: Int) -> Int = {
+    let run: {Int} = do {
+        count(n)
+    } with handle state {
+        fn get() -> Int = {
+            { s: Int ->
+                let k: {Int} = return(s);
+                k(s)
+            }
+        }
+        fn put(v: Int) -> () = {
+            { s: Int ->
+                let k: {Int} = return();
+                k(v)
+            }
+        }
+        return (x: Int) -> {Int} = { { s: Int -> x } }
+    };
+    run(0)
+}
+"#;
+
+const RUNS: u32 = 10;
+
+fn main() {
+    let mut session = Session::new();
+    let compilation = session.compile("counter.ku", SRC);
+    let program = compilation.program.expect("program");
+    let mut optimized = program.clone();
+    let ds = optimized.optimize(Passes {
+        tail: true,
+        ..Passes::default()
+    });
+    assert!(ds.is_empty(), "{ds:?}");
+    for (function, iterations) in [("reading", 200_000), ("counting", 20_000)] {
+        let before = bench(
+            &session,
+            &program,
+            function,
+            iterations,
+            "capturing resumptions",
+        );
+        let after = bench(
+            &session,
+            &optimized,
+            function,
+            iterations,
+            "tail-resumptive",
+        );
+        println!("{function} speedup: {:.2}x", before / after);
+    }
+}
+
+/// Runs a counter loop a few times, returning the best time per iteration in nanoseconds.
+fn bench(session: &Session, program: &Program, function: &str, iterations: i64, name: &str) -> f64 {
+    let mut best = f64::INFINITY;
+    for _ in 0..RUNS {
+        let start = Instant::now();
+        let args = vec![Value::Int(black_box(iterations))];
+        let values = session.run(program, function, args).expect("run");
+        let elapsed = start.elapsed().as_nanos() as f64 / iterations as f64;
+        assert!(matches!(values[..], [Value::Int(n)] if n == iterations));
+        best = best.min(elapsed);
+    }
+    println!("{function}, {name}: {best:.1} ns/iteration");
+    best
+}
@@ -0,0 +1,119 @@
+//! Tokenizing throughput.
+//!
+//! Run with `cargo bench --bench lexer`. The input is generated from a seeded mix of identifiers,
+//! keywords, number and string literals, punctuation and whitespace, at a few sizes, so the time
+//! covers every kind of token and isn't one small file's tokens repeated in a hot cache.
+
+use std::{hint::black_box, time::Instant};
+
+use korou_lang::{Session, TokenKind};
+
+const SEED: u64 = 0x5eed_1e55;
+const SIZES: [usize; 3] = [64 << 10, 1 << 20, 16 << 20];
+const RUNS: u32 = 10;
+
+const KEYWORDS: &[&str] = &[
+    "do", "effect", "else", "finally", "fn", "handle", "if", "import", "let", "return", "Unit",
+    "with",
+];
+const PUNCTUATION: &[&str] = &[
+    "->", "==", "!=", ">=", "<=", "::", ":", ",", "{", "}", "=", ">", "<", ".", "-", "%", "|", "+",
+    "(", ")", ";", "/", "[", "]", "*",
+];
+const IDENT_START: &[u8] = b"abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ_";
+const IDENT_CONTINUE: &[u8] = b"abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ_0123456789";
+
+/// A xorshift generator, so that every run tokenizes the same input.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+
+    fn pick<T: Copy>(&mut self, items: &[T]) -> T {
+        items[self.below(items.len())]
+    }
+}
+
+/// Generates source of about the given length.
+fn generate(len: usize, rng: &mut Rng) -> String {
+    let mut src = String::with_capacity(len + 64);
+    while src.len() < len {
+        match rng.below(100) {
+            // identifiers are the most common tokens, and separated from what follows
+            0..=34 => {
+                src.push(rng.pick(IDENT_START) as char);
+                for _ in 0..rng.below(12) {
+                    src.push(rng.pick(IDENT_CONTINUE) as char);
+                }
+                src.push(' ');
+            }
+            35..=44 => {
+                src.push_str(rng.pick(KEYWORDS));
+                src.push(' ');
+            }
+            45..=74 => src.push_str(rng.pick(PUNCTUATION)),
+            75..=84 => {
+                src.push_str(&(rng.next() % 1_000_000).to_string());
+                src.push(' ');
+            }
+            85..=87 => {
+                let prefix = rng.pick(&["0x", "0b", "0c"]);
+                src.push_str(&format!("{prefix}{:x} ", rng.next() % 0x10000));
+            }
+            88..=91 => {
+                src.push('"');
+                for _ in 0..rng.below(24) {
+                    match rng.below(10) {
+                        0 => src.push_str(rng.pick(&["\\n", "\\\"", "\\\\"])),
+                        _ => src.push(rng.pick(IDENT_CONTINUE) as char),
+                    }
+                }
+                src.push_str("\" ");
+            }
+            92..=95 => {
+                src.push('\n');
+                src.push_str(&" ".repeat(4 * rng.below(4)));
+            }
+            _ => src.push_str(rng.pick(&[" ", "  ", "\t"])),
+        }
+    }
+    src
+}
+
+fn main() {
+    let mut rng = Rng(SEED);
+    let mut session = Session::new();
+    for size in SIZES {
+        let src = generate(size, &mut rng);
+        let mb = src.len() as f64 / 1e6;
+        let mut best = f64::INFINITY;
+        let mut tokens = Vec::new();
+        for _ in 0..RUNS {
+            let start = Instant::now();
+            tokens = session.tokenize("generated.ku", black_box(&src));
+            best = best.min(start.elapsed().as_secs_f64());
+        }
+        let unrecognized = tokens
+            .iter()
+            .filter(|&&(kind, _)| kind == TokenKind::Unrecognized)
+            .count();
+        assert_eq!(
+            0, unrecognized,
+            "the generated source has unrecognized tokens"
+        );
+        println!(
+            "{} tokens, {mb:.1} MB in {best:.4}s ({:.1} MB/s)",
+            tokens.len(),
+            mb / best
+        );
+    }
+}
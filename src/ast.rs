@@ -9,7 +9,7 @@ mod number;
 mod statement;
 mod types;
 
-pub use expr::{Conditional, Expr};
+pub use expr::{Conditional, Expr, Operator};
 pub use item::{Function, FunctionHeader, Item};
 pub use generic::TypedIdent;
 pub use ident::{Ident, QualifiedIdent};
@@ -1,20 +1,26 @@
 use super::{Effect, Ident, Integer, Item, QualifiedIdent, Statement, TypedIdent};
-use crate::{span::Span, token::TokenKind};
+use crate::{
+    cache::StringKey,
+    span::{Span, Spanned},
+    token::TokenKind,
+};
 
 /// A single case in an if-else ladder.
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct Conditional {
-    pub condition: Expr,
-    pub then_body: Vec<Statement>,
+    pub condition: Spanned<Expr>,
+    pub then_body: Vec<Spanned<Statement>>,
 }
 
 /// Expressions.
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub enum Expr {
     /// (Potentially) qualified identifier.
-    Ident(QualifiedIdent),
+    Ident(Spanned<QualifiedIdent>),
     /// Simple integer literal.
     Int(Integer),
+    /// String literal, with escapes replaced.
+    String(StringKey),
     /// The escape continuation for functions.
     Return,
     /// The implicit continuation (return for closures).
@@ -22,46 +28,48 @@ pub enum Expr {
     /// A binary expression of a single operator
     Binary {
         op: Operator,
-        operands: Vec<Expr>,
+        operands: Vec<Spanned<Expr>>,
     },
     /// Member access.
     Member {
-        recv: Box<Expr>,
-        member: Ident,
+        recv: Box<Spanned<Expr>>,
+        member: Spanned<Ident>,
     },
     /// Function call.
     Call {
         /// The function expression.
-        func: Box<Expr>,
+        func: Box<Spanned<Expr>>,
         /// The arguments to the function.
-        args: Vec<Expr>,
+        args: Vec<Spanned<Expr>>,
     },
     /// Block-based function call.
     BlockCall {
-        func: Box<Expr>,
-        args: Vec<Expr>,
+        func: Box<Spanned<Expr>>,
+        args: Vec<Spanned<Expr>>,
     },
     /// Closure block, with or without parameters.
     Closure {
-        params: Vec<TypedIdent>,
-        stmts: Vec<Statement>,
+        params: Vec<Spanned<TypedIdent>>,
+        stmts: Vec<Spanned<Statement>>,
     },
     /// If-else ladder.
     Conditional {
-        cases: Vec<Conditional>,
+        cases: Vec<Spanned<Conditional>>,
         /// may be empty
-        final_else: Vec<Statement>,
+        final_else: Vec<Spanned<Statement>>,
     },
     Handler {
-        impl_effects: Vec<Effect>,
-        items: Vec<Item>,
+        impl_effects: Vec<Spanned<Effect>>,
+        items: Vec<Spanned<Item>>,
     },
     Do {
-        stmts: Vec<Statement>,
+        stmts: Vec<Spanned<Statement>>,
     },
     DoWith {
-        stmts: Vec<Statement>,
-        handler: Box<Expr>,
+        stmts: Vec<Spanned<Statement>>,
+        /// The `with` keyword.
+        with_span: Span,
+        handler: Box<Spanned<Expr>>,
     },
     /// Error node.
     Error {
@@ -69,21 +77,6 @@ pub enum Expr {
     },
 }
 
-impl Expr {
-    /// Determines whether this expression ends with a block.
-    pub fn is_block_expr(&self) -> bool {
-        match self {
-            Self::BlockCall { .. }
-            | Self::Conditional { .. }
-            | Self::Closure { .. }
-            | Self::Handler { .. }
-            | Self::Do { .. } => true,
-            Self::DoWith { handler, .. } => handler.is_block_expr(),
-            _ => false,
-        }
-    }
-}
-
 /// Binary operator.
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum Operator {
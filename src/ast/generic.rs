@@ -1,6 +1,8 @@
 use super::{Ident, Type};
+use crate::span::Spanned;
 
 /// Type or effect parameter.
+#[allow(dead_code)] // we'll use this later
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct TypeParam {
     pub name: Ident,
@@ -9,6 +11,6 @@ pub struct TypeParam {
 /// Function parameter.
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct TypedIdent {
-    pub name: Ident,
-    pub ty: Type,
+    pub name: Spanned<Ident>,
+    pub ty: Spanned<Type>,
 }
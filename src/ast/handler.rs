@@ -11,7 +11,10 @@ use super::{Effect, Function, QualifiedIdent, Statement, Type};
 // }
 // must be polymorphic over e if stored in a variable..
 // todo: monomorphism restriction?
+// stored handlers can't outlive the function that makes them (see `escape`), so every use of one
+// is in that function
 
+#[allow(dead_code)] // we'll use this later
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct EffectHandler {
     pub effect_name: QualifiedIdent,
@@ -13,6 +13,19 @@ pub enum Ident {
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct QualifiedIdent(pub Vec<Ident>);
 
+impl QualifiedIdent {
+    /// Gets the string keys of this path's components, or `None` if any component is an error.
+    pub fn keys(&self) -> Option<Vec<StringKey>> {
+        self.0
+            .iter()
+            .map(|id| match *id {
+                Ident::Ident(key) => Some(key),
+                Ident::Error => None,
+            })
+            .collect()
+    }
+}
+
 impl From<Ident> for QualifiedIdent {
     fn from(v: Ident) -> Self {
         Self(vec![v])
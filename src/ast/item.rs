@@ -1,40 +1,47 @@
-use super::{Effect, Ident, Statement, Type, TypedIdent, QualifiedIdent};
-use crate::span::Span;
+use super::{Effect, Ident, QualifiedIdent, Statement, Type, TypedIdent};
+use crate::span::{Span, Spanned};
 
 /// A concrete function.
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct Function {
-    pub header: FunctionHeader,
-    pub body: Vec<Statement>,
+    pub header: Spanned<FunctionHeader>,
+    pub body: Vec<Spanned<Statement>>,
 }
 
 /// Function header, everything except the body.
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct FunctionHeader {
-    pub name: Ident,
-    pub type_params: Vec<Ident>,
-    pub effect_params: Vec<Ident>,
-    pub params: Vec<TypedIdent>,
-    pub effects: Vec<Effect>,
-    pub ret: Option<Vec<Type>>,
+    pub name: Spanned<Ident>,
+    pub type_params: Vec<Spanned<Ident>>,
+    pub effect_params: Vec<Spanned<Ident>>,
+    pub params: Vec<Spanned<TypedIdent>>,
+    pub effects: Vec<Spanned<Effect>>,
+    pub ret: Option<Vec<Spanned<Type>>>,
 }
 
 /// An item in the global or a namespace scope.
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub enum Item {
     Function(Function),
-    AbstractFunction(FunctionHeader),
+    AbstractFunction(Spanned<FunctionHeader>),
     Finally {
-        stmts: Vec<Statement>,
+        stmts: Vec<Spanned<Statement>>,
+    },
+    /// A handler's return clause, which receives the handled computation's values and produces
+    /// the values of the `do ... with`, possibly of different types.
+    Return {
+        params: Vec<Spanned<TypedIdent>>,
+        ret: Option<Vec<Spanned<Type>>>,
+        body: Vec<Spanned<Statement>>,
     },
     Effect {
-        name: Ident,
-        type_params: Vec<Ident>,
-        effect_params: Vec<Ident>,
-        body: Vec<Item>,
+        name: Spanned<Ident>,
+        type_params: Vec<Spanned<Ident>>,
+        effect_params: Vec<Spanned<Ident>>,
+        body: Vec<Spanned<Item>>,
     },
     Import {
-        module: QualifiedIdent,
+        module: Spanned<QualifiedIdent>,
     },
     Error {
         err_span: Span,
@@ -1,22 +1,23 @@
 use super::{Expr, TypedIdent};
+use crate::span::Spanned;
 
 /// Statements in a closure
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub enum Statement {
     /// An expression statement
-    Expr(Expr),
+    Expr(Spanned<Expr>),
     /// A block expression statement
-    BlockExpr(Expr),
+    BlockExpr(Spanned<Expr>),
     /// An expression that ends a block (i.e. without a trailing semicolon).
-    BlockEndExpr(Expr),
+    BlockEndExpr(Spanned<Expr>),
     /// A let statement.
     Let {
-        bindings: Vec<TypedIdent>,
-        init: Expr,
+        bindings: Vec<Spanned<TypedIdent>>,
+        init: Spanned<Expr>,
     },
     /// An invocation of a continuation.
     Continue {
-        cont: Expr,
-        args: Vec<Expr>,
+        cont: Spanned<Expr>,
+        args: Vec<Spanned<Expr>>,
     },
 }
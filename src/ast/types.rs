@@ -1,4 +1,5 @@
 use super::QualifiedIdent;
+use crate::span::Spanned;
 
 /// A type in the AST.
 #[derive(Clone, Debug, PartialEq, Eq)]
@@ -6,18 +7,18 @@ pub enum Type {
     /// A named (possibly generic) type.
     Simple {
         name: QualifiedIdent,
-        args: Vec<Type>,
+        args: Vec<Spanned<Type>>,
     },
     /// A continuation type.
     Continuation {
-        args: Vec<Type>,
-        ret: Option<Vec<Type>>,
-        effects: Vec<Effect>,
+        args: Vec<Spanned<Type>>,
+        ret: Option<Vec<Spanned<Type>>>,
+        effects: Vec<Spanned<Effect>>,
     },
     /// A closure type.
     Closure {
-        ret: Vec<Type>,
-        effects: Vec<Effect>,
+        ret: Vec<Spanned<Type>>,
+        effects: Vec<Spanned<Effect>>,
     },
 }
 
@@ -25,6 +26,6 @@ pub enum Type {
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct Effect {
     pub name: QualifiedIdent,
-    pub args: Vec<Type>,
-    pub meta_effects: Vec<Effect>,
+    pub args: Vec<Spanned<Type>>,
+    pub meta_effects: Vec<Spanned<Effect>>,
 }
@@ -0,0 +1,55 @@
+//! Built-in effects, which programs can use without declaring them:
+//!
+//! ```text
+//! effect io {
+//!     fn print(s: String) -> ();
+//!     fn read_line() -> String;
+//! }
+//! ```
+//!
+//! The runtime handles them when no handler in the program does, as if `main` were wrapped in a
+//! handler for them. A program's own definitions take precedence over built-in names.
+
+/// A built-in effect operation.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Builtin {
+    /// Writes a line to the console.
+    Print,
+    /// Reads a line from the console, without the line ending. Returns an empty string at the end
+    /// of input.
+    ReadLine,
+}
+
+impl Builtin {
+    pub const ALL: [Self; 2] = [Self::Print, Self::ReadLine];
+
+    /// The name of the effect declaring the operation.
+    pub fn effect(&self) -> &'static str {
+        match *self {
+            Self::Print | Self::ReadLine => "io",
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Self::Print => "print",
+            Self::ReadLine => "read_line",
+        }
+    }
+
+    /// The number of parameters the operation takes.
+    pub fn params(&self) -> usize {
+        match *self {
+            Self::Print => 1,
+            Self::ReadLine => 0,
+        }
+    }
+
+    /// The number of values the operation returns with.
+    pub fn returns(&self) -> usize {
+        match *self {
+            Self::Print => 0,
+            Self::ReadLine => 1,
+        }
+    }
+}
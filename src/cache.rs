@@ -34,12 +34,23 @@ impl StringCache {
         })
     }
 
+    /// Retrieve the key for the given string, if it has been interned.
+    pub fn find(&self, str: &str) -> Option<StringKey> {
+        self.strings.get_by_right(str).copied()
+    }
+
     /// Retrieve the string associated with the given key.
     pub fn get(&self, key: StringKey) -> Option<&str> {
         self.strings.get_by_left(&key).map(String::as_str)
     }
 }
 
+impl Default for StringCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Index<StringKey> for StringCache {
     type Output = str;
 
@@ -0,0 +1,544 @@
+//! Lossless concrete syntax tree.
+//!
+//! The tree is built in two layers. Green nodes are immutable, position-independent, and can be
+//! shared. Red nodes ([`SyntaxNode`]) wrap green nodes with their absolute position, and are created
+//! on demand while traversing.
+//!
+//! The parser records the token range of every syntax node it completes. Those ranges are combined
+//! with the token stream and the whitespace between tokens, so the tree's text is exactly the input.
+//! Leading whitespace is attached outside of the node that follows it.
+//!
+//! The AST is derived from the tree by [`lower`], so the two always agree.
+
+use std::{
+    fmt::{self, Display, Formatter},
+    ops::Range,
+    rc::Rc,
+};
+
+use crate::{
+    ast::Item,
+    cache::{StringCache, StringKey},
+    diagnostic::Diagnostics,
+    parse::Parser,
+    span::{Span, Spanned},
+    token::{Token, TokenKind},
+    tokenizer::Tokenizer,
+};
+
+pub mod lower;
+
+/// Kinds of syntax nodes.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum SyntaxKind {
+    Root,
+    // Items
+    FunctionItem,
+    AbstractFunctionItem,
+    FinallyItem,
+    ReturnItem,
+    EffectItem,
+    ImportItem,
+    ErrorItem,
+    FunctionHeader,
+    GenericParams,
+    Param,
+    // Statements
+    ExprStmt,
+    BlockExprStmt,
+    BlockEndExprStmt,
+    LetStmt,
+    ContinueStmt,
+    // Expressions
+    PathExpr,
+    IntExpr,
+    StringExpr,
+    ReturnExpr,
+    ContinueExpr,
+    ParenExpr,
+    BinaryExpr,
+    MemberExpr,
+    CallExpr,
+    BlockCallExpr,
+    ClosureExpr,
+    ConditionalExpr,
+    HandlerExpr,
+    DoExpr,
+    DoWithExpr,
+    ErrorExpr,
+    // Types
+    SimpleType,
+    ContinuationType,
+    ClosureType,
+    Effect,
+    // Other
+    Path,
+}
+
+impl SyntaxKind {
+    pub fn is_item(self) -> bool {
+        matches!(
+            self,
+            Self::FunctionItem
+                | Self::AbstractFunctionItem
+                | Self::FinallyItem
+                | Self::ReturnItem
+                | Self::EffectItem
+                | Self::ImportItem
+                | Self::ErrorItem
+        )
+    }
+
+    pub fn is_stmt(self) -> bool {
+        matches!(
+            self,
+            Self::ExprStmt
+                | Self::BlockExprStmt
+                | Self::BlockEndExprStmt
+                | Self::LetStmt
+                | Self::ContinueStmt
+        )
+    }
+
+    pub fn is_expr(self) -> bool {
+        matches!(
+            self,
+            Self::PathExpr
+                | Self::IntExpr
+                | Self::StringExpr
+                | Self::ReturnExpr
+                | Self::ContinueExpr
+                | Self::ParenExpr
+                | Self::BinaryExpr
+                | Self::MemberExpr
+                | Self::CallExpr
+                | Self::BlockCallExpr
+                | Self::ClosureExpr
+                | Self::ConditionalExpr
+                | Self::HandlerExpr
+                | Self::DoExpr
+                | Self::DoWithExpr
+                | Self::ErrorExpr
+        )
+    }
+
+    pub fn is_type(self) -> bool {
+        matches!(
+            self,
+            Self::SimpleType | Self::ContinuationType | Self::ClosureType
+        )
+    }
+}
+
+/// A syntax node completed by the parser, covering the tokens in `start..end`.
+#[derive(Copy, Clone, Debug)]
+struct Completed {
+    start: usize,
+    end: usize,
+    kind: SyntaxKind,
+}
+
+/// Records the syntax nodes completed by the parser.
+#[derive(Clone, Debug, Default)]
+pub struct Recorder {
+    nodes: Vec<Completed>,
+}
+
+impl Recorder {
+    /// Records a node covering the tokens with indices `start..end`. Nodes must be recorded in the order
+    /// they are completed, so that a node is recorded after all of its children.
+    pub fn record(&mut self, start: usize, end: usize, kind: SyntaxKind) {
+        self.nodes.push(Completed { start, end, kind });
+    }
+}
+
+/// An immutable token in the green tree. Whitespace is represented by [`TokenKind::Whitespace`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct GreenToken {
+    pub kind: TokenKind,
+    pub text: Rc<str>,
+}
+
+/// An immutable, position-independent syntax node.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct GreenNode {
+    pub kind: SyntaxKind,
+    pub text_len: usize,
+    pub children: Vec<GreenElement>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum GreenElement {
+    Node(Rc<GreenNode>),
+    Token(GreenToken),
+}
+
+impl GreenElement {
+    pub fn text_len(&self) -> usize {
+        match self {
+            Self::Node(node) => node.text_len,
+            Self::Token(token) => token.text.len(),
+        }
+    }
+}
+
+impl Display for GreenNode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        for child in &self.children {
+            match child {
+                GreenElement::Node(node) => write!(f, "{node}")?,
+                GreenElement::Token(token) => f.write_str(&token.text)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A syntax node with its absolute position in the source.
+#[derive(Clone, Debug)]
+pub struct SyntaxNode(Rc<RedData>);
+
+#[derive(Debug)]
+struct RedData {
+    green: Rc<GreenNode>,
+    offset: usize,
+}
+
+/// A child of a syntax node: either a node or a token with its span.
+#[derive(Clone, Debug)]
+pub enum SyntaxElement {
+    Node(SyntaxNode),
+    Token(Spanned<GreenToken>),
+}
+
+impl SyntaxNode {
+    /// Makes the root of a tree whose text starts at the given offset in the source.
+    pub(crate) fn new_root(green: Rc<GreenNode>, offset: usize) -> Self {
+        Self(Rc::new(RedData { green, offset }))
+    }
+
+    pub fn kind(&self) -> SyntaxKind {
+        self.0.green.kind
+    }
+
+    pub fn span(&self) -> Span {
+        Span {
+            pos: self.0.offset,
+            len: self.0.green.text_len,
+        }
+    }
+
+    /// Gets the children of this node, including tokens and whitespace.
+    pub fn children(&self) -> Vec<SyntaxElement> {
+        let mut offset = self.0.offset;
+        self.0
+            .green
+            .children
+            .iter()
+            .map(|child| {
+                let pos = offset;
+                offset += child.text_len();
+                match child {
+                    GreenElement::Node(green) => {
+                        SyntaxElement::Node(SyntaxNode(Rc::new(RedData {
+                            green: green.clone(),
+                            offset: pos,
+                        })))
+                    }
+                    GreenElement::Token(token) => SyntaxElement::Token(Spanned::from_span_value(
+                        Span {
+                            pos,
+                            len: token.text.len(),
+                        },
+                        token.clone(),
+                    )),
+                }
+            })
+            .collect()
+    }
+
+    /// Gets the child nodes of this node.
+    pub fn child_nodes(&self) -> Vec<SyntaxNode> {
+        self.children()
+            .into_iter()
+            .filter_map(|child| match child {
+                SyntaxElement::Node(node) => Some(node),
+                SyntaxElement::Token(_) => None,
+            })
+            .collect()
+    }
+
+    /// Gets this node and every node under it, each before its children.
+    pub fn descendants(&self) -> Vec<SyntaxNode> {
+        let mut nodes = Vec::new();
+        let mut stack = vec![self.clone()];
+        while let Some(node) = stack.pop() {
+            stack.extend(node.child_nodes().into_iter().rev());
+            nodes.push(node);
+        }
+        nodes
+    }
+
+    /// Gets the tokens under this node in source order, including whitespace.
+    pub fn tokens(&self) -> Vec<Spanned<GreenToken>> {
+        let mut tokens = Vec::new();
+        for child in self.children() {
+            match child {
+                SyntaxElement::Node(node) => tokens.extend(node.tokens()),
+                SyntaxElement::Token(token) => tokens.push(token),
+            }
+        }
+        tokens
+    }
+
+    /// Formats the tree with one node or token per line, for debugging.
+    pub fn dump(&self) -> String {
+        fn go(node: &SyntaxNode, depth: usize, out: &mut String) {
+            let span = node.span();
+            let indent = "  ".repeat(depth);
+            out.push_str(&format!(
+                "{indent}{:?}@{}..{}\n",
+                node.kind(),
+                span.pos,
+                span.pos + span.len
+            ));
+            for child in node.children() {
+                match child {
+                    SyntaxElement::Node(node) => go(&node, depth + 1, out),
+                    SyntaxElement::Token(token) => {
+                        out.push_str(&format!("{indent}  {:?} {:?}\n", token.kind, token.text))
+                    }
+                }
+            }
+        }
+        let mut out = String::new();
+        go(self, 0, &mut out);
+        out
+    }
+}
+
+impl Display for SyntaxNode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        self.0.green.fmt(f)
+    }
+}
+
+/// Builds a green tree from a token stream starting at the given offset in the source, and the nodes
+/// recorded while parsing it.
+fn build(src: &str, start: usize, tokens: &[Token], recorder: Recorder) -> Rc<GreenNode> {
+    let mut nodes = recorder.nodes.into_iter().enumerate().collect::<Vec<_>>();
+    // outer nodes first; nodes covering the same tokens are ordered by completion, outermost first
+    nodes.sort_by(|(ia, a), (ib, b)| {
+        a.start
+            .cmp(&b.start)
+            .then(b.end.cmp(&a.end))
+            .then(ib.cmp(ia))
+    });
+
+    struct Open {
+        kind: SyntaxKind,
+        end: usize,
+        children: Vec<GreenElement>,
+    }
+
+    fn close(stack: &mut Vec<Open>) {
+        let open = stack.pop().expect("node stack is non-empty");
+        let text_len = open.children.iter().map(GreenElement::text_len).sum();
+        let node = GreenNode {
+            kind: open.kind,
+            text_len,
+            children: open.children,
+        };
+        let parent = stack.last_mut().expect("root node is never closed here");
+        parent.children.push(GreenElement::Node(Rc::new(node)));
+    }
+
+    let mut stack = vec![Open {
+        kind: SyntaxKind::Root,
+        end: usize::MAX,
+        children: Vec::new(),
+    }];
+    let mut nodes = nodes.into_iter().map(|(_, n)| n).peekable();
+    let mut pos = start;
+    for (idx, token) in tokens.iter().enumerate() {
+        while stack.last().is_some_and(|open| open.end <= idx) {
+            close(&mut stack);
+        }
+        let span = Token::span(token);
+        if span.pos > pos {
+            let trivia = GreenToken {
+                kind: TokenKind::Whitespace,
+                text: src[pos..span.pos].into(),
+            };
+            let parent = stack.last_mut().expect("root node is open");
+            parent.children.push(GreenElement::Token(trivia));
+        }
+        while let Some(node) = nodes.next_if(|node| node.start <= idx) {
+            stack.push(Open {
+                kind: node.kind,
+                end: node.end,
+                children: Vec::new(),
+            });
+            if node.end <= idx {
+                close(&mut stack);
+            }
+        }
+        if **token != TokenKind::Eof {
+            let token = GreenToken {
+                kind: **token,
+                text: src[span.pos..span.pos + span.len].into(),
+            };
+            let parent = stack.last_mut().expect("root node is open");
+            parent.children.push(GreenElement::Token(token));
+        }
+        pos = span.pos + span.len;
+    }
+    while stack.len() > 1 {
+        close(&mut stack);
+    }
+    let root = stack.pop().expect("root node");
+    Rc::new(GreenNode {
+        kind: SyntaxKind::Root,
+        text_len: root.children.iter().map(GreenElement::text_len).sum(),
+        children: root.children,
+    })
+}
+
+/// Parses a file into its concrete syntax tree, and derives its AST from the tree.
+pub fn parse(
+    file: StringKey,
+    src: &str,
+    cache: &mut StringCache,
+    ds: &mut Diagnostics,
+) -> (Vec<Spanned<Item>>, SyntaxNode) {
+    let root = parse_range(file, src, 0..src.len(), ds, |parser| parser.file());
+    let items = lower::items(&root, cache, ds);
+    (items, root)
+}
+
+/// Parses a range of source with the given parser function, and builds the concrete syntax tree of
+/// the range. Tokens the function doesn't consume are left directly under the root.
+pub fn parse_range(
+    file: StringKey,
+    src: &str,
+    range: Range<usize>,
+    ds: &mut Diagnostics,
+    f: impl FnOnce(&mut Parser<'_>),
+) -> SyntaxNode {
+    let mut parser = Parser::new(Tokenizer::from_range(file, src, range.clone()), ds);
+    f(&mut parser);
+    let nodes = parser.into_nodes();
+    let tokens = Tokenizer::from_range(file, src, range.clone()).into_tokens();
+    let green = build(src, range.start, &tokens, nodes);
+    SyntaxNode::new_root(green, range.start)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_src(src: &str) -> SyntaxNode {
+        let mut cache = StringCache::new();
+        let mut ds = Diagnostics::new();
+        parse(StringKey::EMPTY, src, &mut cache, &mut ds).1
+    }
+
+    /// Finds the nodes covering the given byte offset, from the root inwards.
+    fn covering_nodes(root: &SyntaxNode, pos: usize) -> Vec<SyntaxNode> {
+        let mut node = root.clone();
+        let mut nodes = Vec::new();
+        while let Some(child) = node.child_nodes().into_iter().find(|child| {
+            let span = child.span();
+            span.pos <= pos && pos < span.pos + span.len
+        }) {
+            nodes.push(node);
+            node = child;
+        }
+        nodes.push(node);
+        nodes
+    }
+
+    /// Finds the innermost node covering the given byte offset.
+    fn covering_node(root: &SyntaxNode, pos: usize) -> SyntaxNode {
+        covering_nodes(root, pos).pop().unwrap()
+    }
+
+    #[test]
+    fn lossless() {
+        let inputs = [
+            include_str!("../korou-examples/loop.ku"),
+            "",
+            "   ",
+            "fn foo() -> Int = {  1 +   2  }\n\n",
+            "fn foo() -> = { $ ~ é }",
+            "} } fn ( -> = {",
+            "\tfn foo[T | e](x: T)/e -> (T) -> = { let y: T = x.bar(1, 2) { z: T -> z }; :y; }  ",
+        ];
+        for input in inputs {
+            assert_eq!(input, parse_src(input).to_string());
+        }
+    }
+
+    #[test]
+    fn structure() {
+        let src = "fn foo(x: Int) -> Int = { x + 1 }";
+        let root = parse_src(src);
+        let items = root.child_nodes();
+        assert_eq!(1, items.len());
+        assert_eq!(SyntaxKind::FunctionItem, items[0].kind());
+        assert_eq!(
+            Span {
+                pos: 0,
+                len: src.len()
+            },
+            items[0].span()
+        );
+
+        let nodes = covering_nodes(&root, src.find('+').unwrap());
+        let [.., parent, node] = &nodes[..] else {
+            panic!("no nodes")
+        };
+        assert_eq!(SyntaxKind::BinaryExpr, node.kind());
+        assert_eq!("x + 1", node.to_string());
+        assert_eq!(SyntaxKind::BlockEndExprStmt, parent.kind());
+
+        let nodes = covering_nodes(&root, src.find("Int").unwrap());
+        let kinds = nodes.iter().rev().take(3).map(|n| n.kind());
+        assert_eq!(
+            vec![SyntaxKind::Path, SyntaxKind::SimpleType, SyntaxKind::Param],
+            kinds.collect::<Vec<_>>()
+        );
+
+        let node = covering_node(&root, src.find('{').unwrap() + 1);
+        assert_eq!(SyntaxKind::FunctionItem, node.kind());
+        let whitespace = node
+            .children()
+            .into_iter()
+            .filter(
+                |child| matches!(child, SyntaxElement::Token(t) if t.kind == TokenKind::Whitespace),
+            )
+            .count();
+        assert_eq!(4, whitespace);
+    }
+
+    #[test]
+    fn postfix_nesting() {
+        let src = "fn f() -> = { a.b(c).d; }";
+        let root = parse_src(src);
+        let nodes = covering_nodes(&root, src.find('a').unwrap());
+        let kinds = nodes.iter().rev().map(|n| n.kind()).collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                SyntaxKind::Path,
+                SyntaxKind::PathExpr,
+                SyntaxKind::MemberExpr,
+                SyntaxKind::CallExpr,
+                SyntaxKind::MemberExpr,
+                SyntaxKind::ExprStmt,
+                SyntaxKind::FunctionItem,
+                SyntaxKind::Root,
+            ],
+            kinds
+        );
+    }
+}
@@ -0,0 +1,832 @@
+//! Derivation of the AST from the concrete syntax tree.
+//!
+//! Each node is read back in the order the parser consumed it, including the tokens it consumed in
+//! place of an expected one, so the AST has the same shape the parser saw. Parts the parser never
+//! got to, because the input ended, become errors. Literals are checked here, since the parser only
+//! looks at the kinds of their tokens.
+
+use std::num::IntErrorKind;
+
+use crate::{
+    ast::{
+        Conditional, Effect, Expr, Function, FunctionHeader, Ident, Integer, Item, Operator,
+        QualifiedIdent, Statement, Type, TypedIdent,
+    },
+    cache::{StringCache, StringKey},
+    diagnostic::{Code, Diagnostics},
+    span::{Span, Spanned},
+    token::TokenKind,
+};
+
+use super::{GreenToken, SyntaxElement, SyntaxKind, SyntaxNode};
+
+/// Derives the items under a root node.
+pub fn items(
+    root: &SyntaxNode,
+    cache: &mut StringCache,
+    ds: &mut Diagnostics,
+) -> Vec<Spanned<Item>> {
+    let mut lowerer = Lowerer::new(root, cache, ds);
+    root.child_nodes()
+        .iter()
+        .filter(|node| node.kind().is_item())
+        .map(|node| lowerer.item(node))
+        .collect()
+}
+
+/// Derives AST nodes from the syntax nodes of a tree.
+pub struct Lowerer<'a> {
+    cache: &'a mut StringCache,
+    ds: &'a mut Diagnostics,
+    /// The end of the tree's source, where the parts missing from the end of the input would be.
+    eof: usize,
+}
+
+impl<'a> Lowerer<'a> {
+    pub fn new(root: &SyntaxNode, cache: &'a mut StringCache, ds: &'a mut Diagnostics) -> Self {
+        let span = root.span();
+        Self {
+            cache,
+            ds,
+            eof: span.pos + span.len,
+        }
+    }
+
+    /// Derives an item from an item node.
+    pub fn item(&mut self, node: &SyntaxNode) -> Spanned<Item> {
+        let mut c = Cursor::new(node);
+        let item = match node.kind() {
+            kind @ (SyntaxKind::FunctionItem | SyntaxKind::AbstractFunctionItem) => {
+                let header = c
+                    .node(|kind| kind == SyntaxKind::FunctionHeader)
+                    .expect("functions start with a header");
+                let header = self.function_header(&header);
+                if kind == SyntaxKind::AbstractFunctionItem {
+                    Item::AbstractFunction(header)
+                } else {
+                    c.token();
+                    let body = self.block(&mut c);
+                    Item::Function(Function { header, body })
+                }
+            }
+            SyntaxKind::FinallyItem => {
+                c.token();
+                let stmts = self.block(&mut c);
+                Item::Finally { stmts }
+            }
+            SyntaxKind::ReturnItem => {
+                c.token();
+                c.token();
+                let params = self.params(&mut c);
+                c.token();
+                c.token();
+                let ret = self.return_sequence(&mut c);
+                c.token();
+                let body = self.block(&mut c);
+                Item::Return { params, ret, body }
+            }
+            SyntaxKind::EffectItem => {
+                c.token();
+                let name = self.ident(&mut c);
+                let (type_params, effect_params) = c
+                    .node(|kind| kind == SyntaxKind::GenericParams)
+                    .map(|node| self.generic_params(&node))
+                    .unwrap_or_default();
+                c.token();
+                let body = c
+                    .nodes(SyntaxKind::is_item)
+                    .map(|node| self.item(&node))
+                    .collect();
+                Item::Effect {
+                    name,
+                    type_params,
+                    effect_params,
+                    body,
+                }
+            }
+            SyntaxKind::ImportItem => {
+                c.token();
+                let module = self.path(&mut c);
+                Item::Import { module }
+            }
+            SyntaxKind::ErrorItem => Item::Error {
+                err_span: c.token().map_or(node.span(), |token| Spanned::span(&token)),
+            },
+            kind => unreachable!("{kind:?} is not an item"),
+        };
+        Spanned::from_span_value(node.span(), item)
+    }
+
+    fn function_header(&mut self, node: &SyntaxNode) -> Spanned<FunctionHeader> {
+        let mut c = Cursor::new(node);
+        c.token();
+        let name = self.ident(&mut c);
+        let (type_params, effect_params) = c
+            .node(|kind| kind == SyntaxKind::GenericParams)
+            .map(|node| self.generic_params(&node))
+            .unwrap_or_default();
+        c.token();
+        let params = self.params(&mut c);
+        c.token();
+        let effects = if c.eat(TokenKind::Slash).is_some() {
+            self.effects(&mut c)
+        } else {
+            Vec::new()
+        };
+        c.token();
+        let ret = self.return_sequence(&mut c);
+        let header = FunctionHeader {
+            name,
+            type_params,
+            effect_params,
+            params,
+            effects,
+            ret,
+        };
+        Spanned::from_span_value(node.span(), header)
+    }
+
+    fn generic_params(&mut self, node: &SyntaxNode) -> (Vec<Spanned<Ident>>, Vec<Spanned<Ident>>) {
+        let mut c = Cursor::new(node);
+        c.token();
+        let type_params = self.idents(&mut c, &[TokenKind::SquareR, TokenKind::Pipe]);
+        let effect_params = if c.eat(TokenKind::Pipe).is_some() {
+            self.idents(&mut c, &[TokenKind::SquareR])
+        } else {
+            Vec::new()
+        };
+        (type_params, effect_params)
+    }
+
+    /// Derives a comma-separated sequence of identifiers, ending before any of the given tokens.
+    fn idents(&mut self, c: &mut Cursor, until: &[TokenKind]) -> Vec<Spanned<Ident>> {
+        let mut idents = Vec::new();
+        while !c.at_end() && !c.at(until) {
+            idents.push(self.ident(c));
+            if c.eat(TokenKind::Comma).is_none() {
+                break;
+            }
+        }
+        idents
+    }
+
+    /// Derives a comma-separated sequence of parameters. A parameter missing its type leaves the
+    /// whole sequence empty.
+    fn params(&mut self, c: &mut Cursor) -> Vec<Spanned<TypedIdent>> {
+        c.comma_nodes(|kind| kind == SyntaxKind::Param)
+            .iter()
+            .map(|node| self.param(node))
+            .collect::<Option<Vec<_>>>()
+            .unwrap_or_default()
+    }
+
+    fn param(&mut self, node: &SyntaxNode) -> Option<Spanned<TypedIdent>> {
+        let mut c = Cursor::new(node);
+        let name = self.ident(&mut c);
+        c.eat(TokenKind::Colon)?;
+        let ty = self.ty_or_error(&mut c);
+        Some(Spanned::from_span_value(
+            node.span(),
+            TypedIdent { name, ty },
+        ))
+    }
+
+    /// Derives a block of statements between braces.
+    fn block(&mut self, c: &mut Cursor) -> Vec<Spanned<Statement>> {
+        c.token();
+        let stmts = self.stmts(c);
+        c.token();
+        stmts
+    }
+
+    fn stmts(&mut self, c: &mut Cursor) -> Vec<Spanned<Statement>> {
+        c.nodes(SyntaxKind::is_stmt)
+            .map(|node| self.stmt(&node))
+            .collect()
+    }
+
+    /// Derives a statement from a statement node.
+    pub fn stmt(&mut self, node: &SyntaxNode) -> Spanned<Statement> {
+        let mut c = Cursor::new(node);
+        let stmt = match node.kind() {
+            SyntaxKind::ContinueStmt => {
+                c.token();
+                let cont = self.expr_or_error(&mut c);
+                let args = self.exprs(&mut c);
+                Statement::Continue { cont, args }
+            }
+            SyntaxKind::LetStmt => {
+                c.token();
+                let bindings = self.params(&mut c);
+                c.token();
+                let init = self.expr_or_error(&mut c);
+                Statement::Let { bindings, init }
+            }
+            SyntaxKind::ExprStmt => Statement::Expr(self.expr_or_error(&mut c)),
+            SyntaxKind::BlockExprStmt => Statement::BlockExpr(self.expr_or_error(&mut c)),
+            SyntaxKind::BlockEndExprStmt => Statement::BlockEndExpr(self.expr_or_error(&mut c)),
+            kind => unreachable!("{kind:?} is not a statement"),
+        };
+        Spanned::from_span_value(node.span(), stmt)
+    }
+
+    fn expr_or_error(&mut self, c: &mut Cursor) -> Spanned<Expr> {
+        match c.node(SyntaxKind::is_expr) {
+            Some(node) => self.expr(&node),
+            None => Spanned::from_span_value(
+                self.missing(),
+                Expr::Error {
+                    err_span: self.missing(),
+                },
+            ),
+        }
+    }
+
+    /// Derives a comma-separated sequence of expressions.
+    fn exprs(&mut self, c: &mut Cursor) -> Vec<Spanned<Expr>> {
+        c.comma_nodes(SyntaxKind::is_expr)
+            .iter()
+            .map(|node| self.expr(node))
+            .collect()
+    }
+
+    fn expr(&mut self, node: &SyntaxNode) -> Spanned<Expr> {
+        let mut c = Cursor::new(node);
+        let expr = match node.kind() {
+            // the parentheses only group their expression
+            SyntaxKind::ParenExpr => {
+                c.token();
+                return self.expr_or_error(&mut c);
+            }
+            SyntaxKind::PathExpr => Expr::Ident(self.path(&mut c)),
+            SyntaxKind::IntExpr => {
+                let token = c.token().expect("integer literals have a token");
+                Expr::Int(self.integer(&token))
+            }
+            SyntaxKind::StringExpr => {
+                let token = c.token().expect("string literals have a token");
+                Expr::String(self.string(&token))
+            }
+            SyntaxKind::ReturnExpr => Expr::Return,
+            SyntaxKind::ContinueExpr => Expr::Continue,
+            SyntaxKind::ErrorExpr => Expr::Error {
+                err_span: c.token().map_or(node.span(), |token| Spanned::span(&token)),
+            },
+            SyntaxKind::BinaryExpr => {
+                let mut operands = vec![self.expr_or_error(&mut c)];
+                let op = c
+                    .token()
+                    .and_then(|token| Operator::try_from(token.kind).ok())
+                    .expect("binary expressions have an operator");
+                operands.push(self.expr_or_error(&mut c));
+                while c.token().is_some() {
+                    operands.push(self.expr_or_error(&mut c));
+                }
+                Expr::Binary { op, operands }
+            }
+            SyntaxKind::MemberExpr => {
+                let recv = Box::new(self.expr_or_error(&mut c));
+                c.token();
+                let member = self.ident(&mut c);
+                Expr::Member { recv, member }
+            }
+            SyntaxKind::CallExpr => {
+                let func = Box::new(self.expr_or_error(&mut c));
+                c.token();
+                let args = self.exprs(&mut c);
+                Expr::Call { func, args }
+            }
+            SyntaxKind::BlockCallExpr => {
+                let func = self.expr_or_error(&mut c);
+                let block_arg = self.expr_or_error(&mut c);
+                // a block following a call is its last argument
+                match Spanned::into_span_value(func) {
+                    (_, Expr::Call { func, mut args }) => {
+                        args.push(block_arg);
+                        Expr::BlockCall { func, args }
+                    }
+                    (span, func) => Expr::BlockCall {
+                        func: Box::new(Spanned::from_span_value(span, func)),
+                        args: vec![block_arg],
+                    },
+                }
+            }
+            SyntaxKind::ClosureExpr => {
+                c.token();
+                // a closure with parameters has an arrow, even when the parameters are missing
+                let params = if c.at_node(SyntaxKind::Param) || c.at(&[TokenKind::Arrow]) {
+                    let params = self.params(&mut c);
+                    c.token();
+                    params
+                } else {
+                    Vec::new()
+                };
+                let stmts = self.stmts(&mut c);
+                Expr::Closure { params, stmts }
+            }
+            SyntaxKind::ConditionalExpr => {
+                c.token();
+                let mut cases = Vec::new();
+                loop {
+                    let condition = self.expr_or_error(&mut c);
+                    let then_body = self.block(&mut c);
+                    // a case spans from its condition to the end of its body
+                    let mut span = Spanned::span(&condition);
+                    if let Some(end) = c.prev_span() {
+                        Span::expand(&mut span, end);
+                    }
+                    let case = Conditional {
+                        condition,
+                        then_body,
+                    };
+                    cases.push(Spanned::from_span_value(span, case));
+                    if c.eat(TokenKind::Else).is_none() {
+                        break Expr::Conditional {
+                            cases,
+                            final_else: Vec::new(),
+                        };
+                    }
+                    if c.eat(TokenKind::If).is_none() {
+                        let final_else = self.block(&mut c);
+                        break Expr::Conditional { cases, final_else };
+                    }
+                }
+            }
+            SyntaxKind::HandlerExpr => {
+                c.token();
+                let impl_effects = self.effects(&mut c);
+                c.token();
+                let items = c
+                    .nodes(SyntaxKind::is_item)
+                    .map(|node| self.item(&node))
+                    .collect();
+                Expr::Handler {
+                    impl_effects,
+                    items,
+                }
+            }
+            SyntaxKind::DoExpr => {
+                c.token();
+                let stmts = self.block(&mut c);
+                Expr::Do { stmts }
+            }
+            SyntaxKind::DoWithExpr => {
+                c.token();
+                let stmts = self.block(&mut c);
+                let with_span = c
+                    .token()
+                    .map_or(self.missing(), |token| Spanned::span(&token));
+                let handler = Box::new(self.expr_or_error(&mut c));
+                Expr::DoWith {
+                    stmts,
+                    with_span,
+                    handler,
+                }
+            }
+            kind => unreachable!("{kind:?} is not an expression"),
+        };
+        Spanned::from_span_value(node.span(), expr)
+    }
+
+    fn ty_or_error(&mut self, c: &mut Cursor) -> Spanned<Type> {
+        match c.node(SyntaxKind::is_type) {
+            Some(node) => self.ty(&node),
+            None => Spanned::from_span_value(
+                self.missing(),
+                Type::Simple {
+                    name: QualifiedIdent(vec![Ident::Error]),
+                    args: Vec::new(),
+                },
+            ),
+        }
+    }
+
+    /// Derives a comma-separated sequence of types.
+    fn types(&mut self, c: &mut Cursor) -> Vec<Spanned<Type>> {
+        c.comma_nodes(SyntaxKind::is_type)
+            .iter()
+            .map(|node| self.ty(node))
+            .collect()
+    }
+
+    fn ty(&mut self, node: &SyntaxNode) -> Spanned<Type> {
+        let mut c = Cursor::new(node);
+        let ty = match node.kind() {
+            SyntaxKind::SimpleType => {
+                let (_, name) = self.path(&mut c).into_span_value();
+                let args = self.type_args(&mut c);
+                Type::Simple { name, args }
+            }
+            SyntaxKind::ClosureType => {
+                c.token();
+                let ret = self.types(&mut c);
+                c.token();
+                let effects = if c.eat(TokenKind::Slash).is_some() {
+                    self.effects(&mut c)
+                } else {
+                    Vec::new()
+                };
+                Type::Closure { ret, effects }
+            }
+            SyntaxKind::ContinuationType => {
+                c.token();
+                let args = self.types(&mut c);
+                c.token();
+                let effects = if c.eat(TokenKind::Slash).is_some() {
+                    self.effects(&mut c)
+                } else {
+                    Vec::new()
+                };
+                c.token();
+                let ret = self.return_sequence(&mut c);
+                Type::Continuation { args, ret, effects }
+            }
+            kind => unreachable!("{kind:?} is not a type"),
+        };
+        Spanned::from_span_value(node.span(), ty)
+    }
+
+    /// Derives the generic arguments of a type or effect, if there are any.
+    fn type_args(&mut self, c: &mut Cursor) -> Vec<Spanned<Type>> {
+        if c.eat(TokenKind::SquareL).is_none() {
+            return Vec::new();
+        }
+        let args = self.types(c);
+        c.token();
+        args
+    }
+
+    /// Derives the return types following an arrow. A continuation type returned by the arrow is its
+    /// own node, so only a single type or a parenthesized list is left here.
+    fn return_sequence(&mut self, c: &mut Cursor) -> Option<Vec<Spanned<Type>>> {
+        if let Some(node) = c.node(SyntaxKind::is_type) {
+            return Some(vec![self.ty(&node)]);
+        }
+        c.eat(TokenKind::RoundL)?;
+        let ret = self.types(c);
+        c.token();
+        Some(ret)
+    }
+
+    /// Derives a comma-separated sequence of effects.
+    fn effects(&mut self, c: &mut Cursor) -> Vec<Spanned<Effect>> {
+        c.comma_nodes(|kind| kind == SyntaxKind::Effect)
+            .iter()
+            .map(|node| self.effect(node))
+            .collect()
+    }
+
+    fn effect(&mut self, node: &SyntaxNode) -> Spanned<Effect> {
+        let mut c = Cursor::new(node);
+        let mut effects = Vec::new();
+        while c.at_node(SyntaxKind::Path) {
+            let name = self.path(&mut c);
+            let mut span = Spanned::span(&name);
+            let args = self.type_args(&mut c);
+            if let Some(end) = c.prev_span() {
+                Span::expand(&mut span, end);
+            }
+            let effect = Effect {
+                name: Spanned::into_span_value(name).1,
+                args,
+                meta_effects: Vec::new(),
+            };
+            effects.push(Spanned::from_span_value(span, effect));
+        }
+        // the last effect is the one the others apply to, so it spans them all
+        let mut effect = effects.pop().map_or_else(
+            || Effect {
+                name: QualifiedIdent(vec![Ident::Error]),
+                args: Vec::new(),
+                meta_effects: Vec::new(),
+            },
+            |effect| Spanned::into_span_value(effect).1,
+        );
+        effect.meta_effects = effects;
+        Spanned::from_span_value(node.span(), effect)
+    }
+
+    /// Derives a qualified identifier from the next path node.
+    fn path(&mut self, c: &mut Cursor) -> Spanned<QualifiedIdent> {
+        let Some(node) = c.node(|kind| kind == SyntaxKind::Path) else {
+            return Spanned::from_span_value(self.missing(), QualifiedIdent(vec![Ident::Error]));
+        };
+        let mut c = Cursor::new(&node);
+        let mut path = vec![self.ident(&mut c).into_span_value().1];
+        while c.eat(TokenKind::Scope).is_some() {
+            path.push(self.ident(&mut c).into_span_value().1);
+        }
+        Spanned::from_span_value(node.span(), QualifiedIdent(path))
+    }
+
+    /// Derives an identifier from the next token, which is an error if it isn't an identifier.
+    fn ident(&mut self, c: &mut Cursor) -> Spanned<Ident> {
+        match c.token() {
+            Some(token) if token.kind == TokenKind::Ident => {
+                let key = self.cache.intern(&token.text);
+                Spanned::from_span_value(Spanned::span(&token), Ident::Ident(key))
+            }
+            Some(token) => Spanned::from_span_value(Spanned::span(&token), Ident::Error),
+            None => Spanned::from_span_value(self.missing(), Ident::Error),
+        }
+    }
+
+    /// Reads the value of an integer literal.
+    fn integer(&mut self, token: &Spanned<GreenToken>) -> Integer {
+        let span = Spanned::span(token);
+        let (src, radix) = match token.kind {
+            TokenKind::BasePrefixNumber => {
+                let (base, src) = token.text.split_at(2);
+                let radix = match base {
+                    "0x" | "0X" => 16,
+                    "0c" | "0C" => 8,
+                    "0b" | "0B" => 2,
+                    _ => {
+                        self.ds.add(Code::InvalidIntegerBase, span, base);
+                        return Integer::Error;
+                    }
+                };
+                (src, radix)
+            }
+            _ => (&*token.text, 10),
+        };
+        i64::from_str_radix(src, radix)
+            .map_err(|err| match err.kind() {
+                IntErrorKind::PosOverflow => self.ds.add(Code::IntegerTooLarge, span, ""),
+                IntErrorKind::InvalidDigit => self.ds.add(Code::InvalidIntegerDigit, span, ""),
+                _ => unreachable!("Unexpected error: {:?}; on input: {} r {}", err, src, radix),
+            })
+            .map_or(Integer::Error, Integer::Integer)
+    }
+
+    /// Reads the value of a string literal, replacing escapes.
+    fn string(&mut self, token: &Spanned<GreenToken>) -> StringKey {
+        let span = Spanned::span(token);
+        let src = &*token.text;
+        let mut value = String::new();
+        let mut chars = src.char_indices().skip(1);
+        let mut terminated = false;
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => {
+                    terminated = true;
+                    break;
+                }
+                '\\' => {
+                    let escaped = chars.next().map(|(_, c)| c);
+                    match escaped {
+                        Some('n') => value.push('\n'),
+                        Some('t') => value.push('\t'),
+                        Some('r') => value.push('\r'),
+                        Some('0') => value.push('\0'),
+                        Some(c @ ('"' | '\\')) => value.push(c),
+                        _ => {
+                            let len = 1 + escaped.map_or(0, char::len_utf8);
+                            let esc_span = Span {
+                                pos: span.pos + i,
+                                len,
+                            };
+                            self.ds.add(Code::InvalidEscape, esc_span, &src[i..i + len]);
+                        }
+                    }
+                }
+                c => value.push(c),
+            }
+        }
+        if !terminated {
+            self.ds.add(Code::UnterminatedString, span, "");
+        }
+        self.cache.intern(&value)
+    }
+
+    /// The span of a part missing from the end of the input.
+    fn missing(&self) -> Span {
+        Span {
+            pos: self.eof,
+            len: 0,
+        }
+    }
+}
+
+/// Reads the children of a node in order, skipping whitespace.
+struct Cursor {
+    elements: Vec<SyntaxElement>,
+    next: usize,
+}
+
+impl Cursor {
+    fn new(node: &SyntaxNode) -> Self {
+        let elements = node
+            .children()
+            .into_iter()
+            .filter(|element| {
+                !matches!(element, SyntaxElement::Token(token) if token.kind == TokenKind::Whitespace)
+            })
+            .collect();
+        Self { elements, next: 0 }
+    }
+
+    fn at_end(&self) -> bool {
+        self.next == self.elements.len()
+    }
+
+    /// Tests whether the next element is a token of one of the given kinds.
+    fn at(&self, kinds: &[TokenKind]) -> bool {
+        matches!(self.elements.get(self.next), Some(SyntaxElement::Token(token)) if kinds.contains(&token.kind))
+    }
+
+    /// Tests whether the next element is a node of the given kind.
+    fn at_node(&self, kind: SyntaxKind) -> bool {
+        matches!(self.elements.get(self.next), Some(SyntaxElement::Node(node)) if node.kind() == kind)
+    }
+
+    /// Takes the next token, whatever its kind, like the parser does when it expects one. There is
+    /// none if the next element is a node, or if the parser expected a token at the end of the input.
+    fn token(&mut self) -> Option<Spanned<GreenToken>> {
+        match self.elements.get(self.next) {
+            Some(SyntaxElement::Token(token)) => {
+                self.next += 1;
+                Some(token.clone())
+            }
+            _ => None,
+        }
+    }
+
+    /// The span of the last element taken.
+    fn prev_span(&self) -> Option<Span> {
+        match self.elements.get(self.next.checked_sub(1)?)? {
+            SyntaxElement::Node(node) => Some(node.span()),
+            SyntaxElement::Token(token) => Some(Spanned::span(token)),
+        }
+    }
+
+    /// Takes the next token if it has the given kind.
+    fn eat(&mut self, kind: TokenKind) -> Option<Span> {
+        if self.at(&[kind]) {
+            self.token().map(|token| Spanned::span(&token))
+        } else {
+            None
+        }
+    }
+
+    /// Takes the next node if its kind satisfies the predicate.
+    fn node(&mut self, pred: impl Fn(SyntaxKind) -> bool) -> Option<SyntaxNode> {
+        match self.elements.get(self.next) {
+            Some(SyntaxElement::Node(node)) if pred(node.kind()) => {
+                self.next += 1;
+                Some(node.clone())
+            }
+            _ => None,
+        }
+    }
+
+    /// Takes the following nodes whose kinds satisfy the predicate.
+    fn nodes<'c>(
+        &'c mut self,
+        pred: impl 'c + Fn(SyntaxKind) -> bool,
+    ) -> impl 'c + Iterator<Item = SyntaxNode> {
+        std::iter::from_fn(move || self.node(&pred))
+    }
+
+    /// Takes a comma-separated sequence of nodes whose kinds satisfy the predicate.
+    fn comma_nodes(&mut self, pred: impl Fn(SyntaxKind) -> bool) -> Vec<SyntaxNode> {
+        let mut nodes = Vec::new();
+        while let Some(node) = self.node(&pred) {
+            nodes.push(node);
+            if self.eat(TokenKind::Comma).is_none() {
+                break;
+            }
+        }
+        nodes
+    }
+}
+
+#[cfg(test)]
+macro_rules! qident {
+    ($($components:ident)::*) => {
+        $crate::ast::QualifiedIdent(vec![$($components),*])
+    };
+    ($bgn:literal .. $end:literal : $($ts:tt)*) => {
+        $crate::span::Spanned::from_span_value(
+            ($bgn..$end).into(),
+            qident!($($ts)*),
+        )
+    }
+}
+
+#[cfg(test)]
+macro_rules! spanned {
+    ($bgn:literal .. $end:literal : $value:expr) => {
+        $crate::span::Spanned::from_span_value(($bgn..$end).into(), $value)
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{cst, parse::declare_idents};
+
+    use super::*;
+
+    /// Parses a statement and derives the expression it holds.
+    fn expr(src: &str, cache: &mut StringCache, ds: &mut Diagnostics) -> Spanned<Expr> {
+        let root = cst::parse_range(StringKey::EMPTY, src, 0..src.len(), ds, |p| p.stmt());
+        let node = root
+            .child_nodes()
+            .first()
+            .cloned()
+            .expect("a statement was parsed");
+        match Spanned::into_span_value(Lowerer::new(&root, cache, ds).stmt(&node)).1 {
+            Statement::Expr(expr) | Statement::BlockEndExpr(expr) => expr,
+            stmt => panic!("not an expression statement: {stmt:?}"),
+        }
+    }
+
+    #[test]
+    fn atoms() {
+        let mut cache = StringCache::new();
+        let mut ds = Diagnostics::new();
+        declare_idents!(cache; f hello);
+
+        let call = Expr::Call {
+            func: Box::new(spanned!(0..1: Expr::Ident(qident!(0..1: f)))),
+            args: vec![
+                spanned!(2..7: Expr::Ident(qident!(2..7: hello))),
+                spanned!(9..11: Expr::Int(Integer::Integer(17))),
+                spanned!(13..18: Expr::Int(Integer::Integer(0xc3f))),
+                spanned!(20..24: Expr::Int(Integer::Error)),
+            ],
+        };
+        let expected = spanned!(0..25: call);
+        let actual = expr("f(hello, 17, 0xc3f, 0c19);", &mut cache, &mut ds);
+        assert_eq!(expected, actual);
+
+        let codes = ds.iter().map(|d| (d.code, d.span)).collect::<Vec<_>>();
+        assert_eq!(
+            vec![(Code::InvalidIntegerDigit, Span { pos: 20, len: 4 })],
+            codes
+        );
+    }
+
+    #[test]
+    fn qualified_ident() {
+        let mut cache = StringCache::new();
+        let mut ds = Diagnostics::new();
+        declare_idents!(cache; foo bar baz);
+
+        let expected = spanned!(0..13: Expr::Ident(qident!(0..13: foo::bar::baz)));
+        assert_eq!(expected, expr("foo::bar::baz;", &mut cache, &mut ds));
+        assert!(!ds.has_errors());
+    }
+
+    #[test]
+    fn strings() {
+        let mut cache = StringCache::new();
+        let mut ds = Diagnostics::new();
+        let src = r#"f("a\tb\"c", "\q", "open"#;
+
+        let Expr::Call { args, .. } = Spanned::into_span_value(expr(src, &mut cache, &mut ds)).1
+        else {
+            panic!("not a call");
+        };
+        let values = args
+            .iter()
+            .map(|arg| match &**arg {
+                Expr::String(key) => &cache[*key],
+                arg => panic!("not a string: {arg:?}"),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(vec!["a\tb\"c", "", "open"], values);
+
+        let codes = ds
+            .iter()
+            .map(|d| (d.code, d.span))
+            .filter(|(code, _)| matches!(code, Code::InvalidEscape | Code::UnterminatedString))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                (Code::InvalidEscape, Span { pos: 14, len: 2 }),
+                (Code::UnterminatedString, Span { pos: 19, len: 5 }),
+            ],
+            codes
+        );
+    }
+
+    #[test]
+    fn missing_parts_are_errors() {
+        let mut cache = StringCache::new();
+        let mut ds = Diagnostics::new();
+
+        let binary = Expr::Binary {
+            op: Operator::Add,
+            operands: vec![
+                spanned!(0..1: Expr::Int(Integer::Integer(1))),
+                spanned!(4..4: Expr::Error {
+                    err_span: Span { pos: 4, len: 0 },
+                }),
+            ],
+        };
+        let expected = spanned!(0..4: binary);
+        assert_eq!(expected, expr("1 + ", &mut cache, &mut ds));
+        assert!(ds.has_errors());
+    }
+}
@@ -0,0 +1,337 @@
+//! Step debugger over a line-based command protocol.
+//!
+//! The debugger reads one command per line and writes plain-text responses, so sessions can be
+//! scripted:
+//!
+//! - `break LINE`, `delete LINE`: sets or removes a breakpoint on a line.
+//! - `step`: runs to the next statement, entering calls.
+//! - `next`: runs to the next statement in the current function or a caller.
+//! - `continue`: runs to the next breakpoint.
+//! - `locals`: prints the named locals of the current closure and those it's nested in.
+//! - `backtrace`: prints the continuations waiting for results and the handler stack.
+//! - `handlers`: prints the handler stack.
+//! - `resume`: prints where resuming the innermost operation being handled continues.
+//! - `quit`
+//!
+//! Execution stops on entering a statement on a breakpoint's line, when the program halts, and when
+//! it fails.
+
+use std::{
+    collections::BTreeSet,
+    io::{self, BufRead, Write},
+};
+
+use crate::{
+    cache::{StringCache, StringKey},
+    mir::Program,
+    runtime::{Backtrace, HandlerTrace, Machine, TraceFrame, Value},
+    span::Span,
+};
+
+/// How far to run.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Mode {
+    Into,
+    Over,
+    Continue,
+}
+
+/// Debugs a machine running a program whose source is `src`.
+pub struct Debugger<'a> {
+    machine: Machine<'a>,
+    program: &'a Program,
+    cache: &'a StringCache,
+    src: &'a str,
+    /// The offsets at which lines start.
+    lines: Vec<usize>,
+    breakpoints: BTreeSet<usize>,
+    finished: bool,
+}
+
+impl<'a> Debugger<'a> {
+    /// Creates a debugger for a machine about to run. The cache is the one the program was compiled
+    /// with.
+    pub(crate) fn new(
+        machine: Machine<'a>,
+        program: &'a Program,
+        cache: &'a StringCache,
+        src: &'a str,
+    ) -> Self {
+        let lines = std::iter::once(0)
+            .chain(src.match_indices('\n').map(|(i, _)| i + 1))
+            .collect();
+        Debugger {
+            machine,
+            program,
+            cache,
+            src,
+            lines,
+            breakpoints: BTreeSet::new(),
+            finished: false,
+        }
+    }
+
+    /// Runs commands until `quit` or the end of input.
+    pub fn serve(&mut self, input: impl BufRead, mut out: impl Write) -> io::Result<()> {
+        self.stopped(&mut out)?;
+        for line in input.lines() {
+            let line = line?;
+            let mut words = line.split_whitespace();
+            let Some(command) = words.next() else {
+                continue;
+            };
+            let arg = words.next();
+            match command {
+                "break" | "b" => match arg.and_then(|arg| arg.parse().ok()) {
+                    Some(line) => {
+                        self.breakpoints.insert(line);
+                        writeln!(out, "breakpoint at line {line}")?;
+                    }
+                    None => writeln!(out, "expected a line number")?,
+                },
+                "delete" | "d" => match arg.and_then(|arg| arg.parse().ok()) {
+                    Some(line) if self.breakpoints.remove(&line) => {
+                        writeln!(out, "deleted breakpoint at line {line}")?
+                    }
+                    _ => writeln!(out, "no such breakpoint")?,
+                },
+                "step" | "s" => self.resume(Mode::Into, &mut out)?,
+                "next" | "n" => self.resume(Mode::Over, &mut out)?,
+                "continue" | "c" => self.resume(Mode::Continue, &mut out)?,
+                "locals" => self.locals(&mut out)?,
+                "backtrace" | "bt" => write!(out, "{}", self.machine.backtrace().render(self.src))?,
+                "handlers" => {
+                    let handlers = Backtrace {
+                        frames: Vec::new(),
+                        handlers: self.machine.backtrace().handlers,
+                    };
+                    match handlers.handlers.is_empty() {
+                        true => writeln!(out, "no handlers")?,
+                        false => write!(out, "{}", handlers.render(self.src))?,
+                    }
+                }
+                "resume" => self.resumption(&mut out)?,
+                "quit" | "q" => break,
+                other => writeln!(out, "unknown command: {other}")?,
+            }
+            out.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Runs until the mode says to stop, a breakpoint is hit, or the program ends.
+    fn resume(&mut self, mode: Mode, out: &mut impl Write) -> io::Result<()> {
+        if self.finished {
+            return writeln!(out, "the program has finished");
+        }
+        let start = self.machine.span();
+        let depth = self.machine.backtrace().frames.len();
+        let mut prev = start;
+        loop {
+            match self.machine.step() {
+                Ok(None) => {}
+                Ok(Some(values)) => {
+                    self.finished = true;
+                    let values = values.iter().map(show).collect::<Vec<_>>();
+                    return writeln!(out, "finished with ({})", values.join(", "));
+                }
+                Err(err) => {
+                    self.finished = true;
+                    writeln!(out, "error: {err}")?;
+                    return write!(out, "{}", self.machine.backtrace().render(self.src));
+                }
+            }
+            let span = self.machine.span();
+            if span.is_none() || span == prev {
+                continue;
+            }
+            let line = span.map(|span| self.line(span.pos));
+            let new_line = line != prev.map(|span| self.line(span.pos));
+            prev = span;
+            let stop = match mode {
+                Mode::Into => true,
+                Mode::Over => span != start && self.machine.backtrace().frames.len() <= depth,
+                Mode::Continue => false,
+            };
+            if stop || (new_line && line.is_some_and(|line| self.breakpoints.contains(&line))) {
+                return self.stopped(out);
+            }
+        }
+    }
+
+    /// Describes where execution stopped.
+    fn stopped(&self, out: &mut impl Write) -> io::Result<()> {
+        let function = match self.machine.backtrace().frames.into_iter().next() {
+            Some(TraceFrame::Code { function, .. }) => function,
+            _ => "<unknown>".to_owned(),
+        };
+        let Some(span) = self.machine.span() else {
+            return writeln!(out, "stopped in {function}");
+        };
+        let line = self.line(span.pos);
+        let column = span.pos - self.lines[line - 1] + 1;
+        writeln!(out, "stopped in {function} at {line}:{column}")?;
+        let text = self.src[self.lines[line - 1]..]
+            .lines()
+            .next()
+            .unwrap_or_default();
+        writeln!(out, "{line} | {text}")
+    }
+
+    fn locals(&self, out: &mut impl Write) -> io::Result<()> {
+        let mut any = false;
+        for (key, values) in self.machine.locals() {
+            let Some(closure) = self.program.closures.get(&key) else {
+                continue;
+            };
+            for (local, value) in closure.locals.iter().zip(&values) {
+                if local.name != StringKey::EMPTY {
+                    any = true;
+                    writeln!(out, "{} = {}", &self.cache[local.name], show(value))?;
+                }
+            }
+        }
+        if !any {
+            writeln!(out, "no locals")?;
+        }
+        Ok(())
+    }
+
+    /// Describes the continuation that resuming the innermost operation being handled would run.
+    fn resumption(&self, out: &mut impl Write) -> io::Result<()> {
+        let handling = self
+            .machine
+            .backtrace()
+            .handlers
+            .into_iter()
+            .find(|handler| matches!(handler, HandlerTrace::Handling { .. }));
+        let Some(HandlerTrace::Handling { op, resume, .. }) = handling else {
+            return writeln!(out, "no operation is being handled");
+        };
+        match resume {
+            Some(TraceFrame::Code { function, span }) => {
+                let (line, column) = self.position(span);
+                writeln!(
+                    out,
+                    "resuming {op} continues in {function} at {line}:{column}"
+                )
+            }
+            Some(TraceFrame::Exit { span }) => {
+                let (line, column) = self.position(span);
+                writeln!(
+                    out,
+                    "resuming {op} leaves the do ... with at {line}:{column}"
+                )
+            }
+            None => writeln!(out, "{op} never resumes"),
+        }
+    }
+
+    /// The 1-based line containing an offset.
+    fn line(&self, pos: usize) -> usize {
+        self.lines.partition_point(|&start| start <= pos)
+    }
+
+    fn position(&self, span: Span) -> (usize, usize) {
+        let line = self.line(span.pos);
+        (line, span.pos - self.lines[line - 1] + 1)
+    }
+}
+
+fn show(value: &Value) -> String {
+    match value {
+        Value::String(s) => format!("{s:?}"),
+        other => other.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{runtime::Captured, Session};
+
+    use super::*;
+
+    const SRC: &str = r#"effect choice {
+    fn choose() -> Int;
+}
+
+fn add(a: Int, b: Int) -> Int = {
+    let c: Int = a + b;
+    c
+}
+
+fn main() -> Int = {
+    let x: Int = add(1, 2);
+    let y: Int = do {
+        let a: Int = choose();
+        a + x
+    } with handle choice {
+        fn choose() -> Int = {
+            let r: Int = return(10);
+            r * 2
+        }
+    };
+    print("done");
+    y
+}
+"#;
+
+    fn debug(script: &str) -> String {
+        let mut session = Session::new();
+        let compilation = session.compile("main.ku", SRC);
+        let program = compilation.program.expect("program");
+        let mut console = Captured::default();
+        let machine = session
+            .machine(&program, "main", Vec::new())
+            .expect("machine")
+            .with_console(&mut console);
+        let mut out = Vec::new();
+        let mut debugger = Debugger::new(machine, &program, session.cache(), SRC);
+        debugger.serve(script.as_bytes(), &mut out).expect("serve");
+        String::from_utf8(out).expect("utf-8")
+    }
+
+    #[test]
+    fn steps_through_calls() {
+        let out = debug("step\nstep\nlocals\nnext\nnext\nlocals\n");
+        let expected = "\
+stopped in main at 11:5
+11 |     let x: Int = add(1, 2);
+stopped in add at 6:5
+6 |     let c: Int = a + b;
+stopped in add at 7:5
+7 |     c
+a = 1
+b = 2
+c = 3
+stopped in main at 12:5
+12 |     let y: Int = do {
+stopped in main at 21:5
+21 |     print(\"done\");
+y = 26
+x = 3
+";
+        assert_eq!(expected, out);
+    }
+
+    #[test]
+    fn stops_at_breakpoints_in_handlers() {
+        let out = debug("break 18\ncontinue\nlocals\nresume\nhandlers\ncontinue\ncontinue\nstep\n");
+        let expected = "\
+stopped in main at 11:5
+11 |     let x: Int = add(1, 2);
+breakpoint at line 18
+stopped in handler of choice::choose in main at 18:13
+18 |             r * 2
+r = 13
+x = 3
+resuming choice::choose continues in main at 13:22
+handlers:
+  handling choice::choose by do ... with (15:7), resumes at main (13:22)
+finished with (26)
+the program has finished
+the program has finished
+";
+        assert_eq!(expected, out);
+    }
+}
@@ -15,6 +15,17 @@ pub enum Code {
     IntegerTooLarge,
     InvalidIntegerDigit,
     InvalidIntegerBase,
+    UnterminatedString,
+    InvalidEscape,
+    UnresolvedName,
+    DuplicateDefinition,
+    Unsupported,
+    MissingContinuation,
+    ValueCount,
+    TypeMismatch,
+    Escape,
+    /// The compiler produced invalid code, which is a bug in the compiler rather than the program.
+    Internal,
 }
 
 impl Code {
@@ -25,6 +36,36 @@ impl Code {
             Code::IntegerTooLarge => K::Error,
             Code::InvalidIntegerDigit => K::Error,
             Code::InvalidIntegerBase => K::Error,
+            Code::UnterminatedString => K::Error,
+            Code::InvalidEscape => K::Error,
+            Code::UnresolvedName => K::Error,
+            Code::DuplicateDefinition => K::Error,
+            Code::Unsupported => K::Error,
+            Code::MissingContinuation => K::Error,
+            Code::ValueCount => K::Error,
+            Code::TypeMismatch => K::Error,
+            Code::Escape => K::Error,
+            Code::Internal => K::Error,
+        }
+    }
+
+    /// The stable name of this code.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Code::Unexpected => "Unexpected",
+            Code::IntegerTooLarge => "IntegerTooLarge",
+            Code::InvalidIntegerDigit => "InvalidIntegerDigit",
+            Code::InvalidIntegerBase => "InvalidIntegerBase",
+            Code::UnterminatedString => "UnterminatedString",
+            Code::InvalidEscape => "InvalidEscape",
+            Code::UnresolvedName => "UnresolvedName",
+            Code::DuplicateDefinition => "DuplicateDefinition",
+            Code::Unsupported => "Unsupported",
+            Code::MissingContinuation => "MissingContinuation",
+            Code::ValueCount => "ValueCount",
+            Code::TypeMismatch => "TypeMismatch",
+            Code::Escape => "Escape",
+            Code::Internal => "Internal",
         }
     }
 }
@@ -70,6 +111,29 @@ impl Diagnostics {
     pub fn clear(&mut self) {
         self.diagnostics.clear();
     }
+
+    pub fn len(&self) -> usize {
+        self.diagnostics.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.diagnostics.is_empty()
+    }
+
+    /// Removes and returns the diagnostics added after the first `at`.
+    pub fn split_off(&mut self, at: usize) -> Self {
+        Self {
+            diagnostics: self.diagnostics.split_off(at),
+        }
+    }
+
+    pub fn iter_mut(&mut self) -> std::slice::IterMut<'_, Diagnostic> {
+        self.diagnostics.iter_mut()
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, Diagnostic> {
+        self.diagnostics.iter()
+    }
 }
 
 impl IntoIterator for Diagnostics {
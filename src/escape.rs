@@ -0,0 +1,482 @@
+//! Escape analysis.
+//!
+//! Closures, handlers, and `return` and `continue` used as values are scoped to the function that
+//! makes them: they can be called, bound, captured by nested closures and handlers, and passed down
+//! to other functions, but nothing outside the function may get hold of one.
+//!
+//! Besides reporting the values that do escape, the analysis finds the [`Scoped`] ones: closures
+//! and handlers made in functions nothing escapes from, and the continuations of calls to such
+//! functions, since those are their `return`. Lowering marks what they lower to, and the backends
+//! allocate those values in the frame making them instead of on their own. A resumption can keep
+//! the frame in use after the function has returned, so they're freed along with the frame rather
+//! than when the function returns.
+//!
+//! They escape by being returned from the function, by being passed to an effect operation, whose
+//! handler may be outside the function, or by being passed to a continuation or closure the
+//! function received as a parameter. Parameters and locals declared with closure or continuation
+//! types are scoped too, as are the results of functions declared to return them. Values of generic
+//! types aren't followed.
+
+use std::collections::HashSet;
+
+use crate::{
+    ast::{Expr, Function, Ident, Item, Statement, Type, TypedIdent},
+    cache::StringCache,
+    diagnostic::{Code, Diagnostics},
+    resolve::{Resolution, SymbolKind},
+    span::{Span, Spanned},
+    symbol::SymbolKey,
+};
+
+/// The values found not to escape the function making them.
+#[derive(Debug, Default)]
+pub struct Scoped {
+    /// The spans of closure and handler expressions, and of the callees of calls whose
+    /// continuation is scoped.
+    spans: HashSet<Span>,
+}
+
+impl Scoped {
+    /// Whether the closure or handler written at a span is scoped, or the continuation of a call
+    /// to the callee written there.
+    pub fn contains(&self, span: Span) -> bool {
+        self.spans.contains(&span)
+    }
+}
+
+/// Reports the scoped values that escape the functions making them, and finds the ones that don't.
+pub fn check(
+    items: &[Spanned<Item>],
+    res: &Resolution,
+    cache: &StringCache,
+    ds: &mut Diagnostics,
+) -> Scoped {
+    let returning = items
+        .iter()
+        .filter_map(|item| match &**item {
+            Item::Function(function) => Some(&function.header),
+            Item::AbstractFunction(header) => Some(header),
+            _ => None,
+        })
+        .filter(|header| header.ret.iter().flatten().any(|ty| scoped_type(ty)))
+        .filter_map(|header| res.defined_at(Spanned::span(&header.name)))
+        .collect();
+    let mut checker = Checker {
+        res,
+        cache,
+        ds,
+        returning,
+        scoped: HashSet::new(),
+        returns: HashSet::new(),
+        params: HashSet::new(),
+        made: Vec::new(),
+        calls: Vec::new(),
+    };
+    let mut found = Scoped::default();
+    let mut clean = HashSet::new();
+    for item in items {
+        if let Item::Function(function) = &**item {
+            let errors = checker.ds.len();
+            checker.function(function);
+            let made = std::mem::take(&mut checker.made);
+            if checker.ds.len() == errors {
+                found.spans.extend(made);
+                clean.extend(res.defined_at(Spanned::span(&function.header.name)));
+            }
+        }
+    }
+    for (span, callee) in checker.calls {
+        if clean.contains(&callee) {
+            found.spans.insert(span);
+        }
+    }
+    found
+}
+
+/// Whether values of a type are scoped.
+fn scoped_type(ty: &Type) -> bool {
+    matches!(ty, Type::Closure { .. } | Type::Continuation { .. })
+}
+
+/// What `return` and `continue` lead to in the code being checked.
+#[derive(Copy, Clone, Debug)]
+struct Scope {
+    /// Whether `return` is the function's return continuation, rather than a resumption or a
+    /// handler's `return` clause continuing after its `do ... with`.
+    ret: bool,
+    /// Whether `continue` leaves the function, as it does at the end of a function's body.
+    cont: bool,
+}
+
+/// Where a call sends its arguments.
+enum Sink {
+    /// Values passed down to a function or a closure made in the function.
+    Down,
+    /// Values leaving the function, with what they're passed to.
+    Out(&'static str),
+}
+
+struct Checker<'a> {
+    res: &'a Resolution,
+    cache: &'a StringCache,
+    ds: &'a mut Diagnostics,
+    /// The functions declared to return scoped values.
+    returning: HashSet<SymbolKey>,
+    /// The parameters and locals holding scoped values.
+    scoped: HashSet<SymbolKey>,
+    /// The locals holding the function's return continuation.
+    returns: HashSet<SymbolKey>,
+    /// The function's parameters.
+    params: HashSet<SymbolKey>,
+    /// The closures and handlers made in the function.
+    made: Vec<Span>,
+    /// The callees of calls to top-level functions, with the functions they call.
+    calls: Vec<(Span, SymbolKey)>,
+}
+
+impl Checker<'_> {
+    fn function(&mut self, function: &Function) {
+        self.scoped.clear();
+        self.returns.clear();
+        self.params.clear();
+        for param in &function.header.params {
+            if let Some(key) = self.res.defined_at(Spanned::span(&param.name)) {
+                self.params.insert(key);
+            }
+        }
+        self.params(&function.header.params);
+        let scope = Scope {
+            ret: true,
+            cont: true,
+        };
+        self.block(&function.body, scope, true);
+    }
+
+    /// Marks the parameters declared with scoped types.
+    fn params(&mut self, params: &[Spanned<TypedIdent>]) {
+        for param in params {
+            if scoped_type(&param.ty) {
+                self.scoped
+                    .extend(self.res.defined_at(Spanned::span(&param.name)));
+            }
+        }
+    }
+
+    /// Checks a block, whose last value leaves the function if `tail` is set.
+    fn block(&mut self, stmts: &[Spanned<Statement>], scope: Scope, tail: bool) {
+        let last = stmts.len().checked_sub(1);
+        for (i, stmt) in stmts.iter().enumerate() {
+            match &**stmt {
+                Statement::BlockExpr(expr) | Statement::BlockEndExpr(expr) if Some(i) == last => {
+                    self.expr(expr, scope, tail);
+                }
+                Statement::Expr(expr)
+                | Statement::BlockExpr(expr)
+                | Statement::BlockEndExpr(expr) => {
+                    self.expr(expr, scope, false);
+                }
+                Statement::Let { bindings, init } => {
+                    let scoped = self.expr(init, scope, false).is_some();
+                    let returns = match **init {
+                        Expr::Return => scope.ret,
+                        Expr::Continue => scope.cont,
+                        _ => false,
+                    };
+                    for binding in bindings {
+                        let Some(key) = self.res.defined_at(Spanned::span(&binding.name)) else {
+                            continue;
+                        };
+                        if scoped || scoped_type(&binding.ty) {
+                            self.scoped.insert(key);
+                        }
+                        if returns {
+                            self.returns.insert(key);
+                        }
+                    }
+                }
+                Statement::Continue { cont, args } => self.call(cont, args, scope),
+            }
+        }
+    }
+
+    /// Checks an expression whose value leaves the function if `tail` is set. Returns what the value
+    /// is if it's scoped.
+    fn expr(&mut self, expr: &Spanned<Expr>, scope: Scope, tail: bool) -> Option<String> {
+        let value = match &**expr {
+            Expr::Ident(path) => {
+                let key = self.res.reference(Spanned::span(path))?;
+                let name = path.0.last().and_then(|ident| match *ident {
+                    Ident::Ident(name) => Some(&self.cache[name]),
+                    Ident::Error => None,
+                });
+                self.scoped
+                    .contains(&key)
+                    .then(|| format!("`{}`", name.unwrap_or_default()))
+            }
+            Expr::Return => Some("`return`".to_owned()),
+            Expr::Continue => Some("`continue`".to_owned()),
+            Expr::Int(_) | Expr::String(_) | Expr::Error { .. } => None,
+            Expr::Binary { operands, .. } => {
+                for operand in operands {
+                    self.expr(operand, scope, false);
+                }
+                None
+            }
+            Expr::Member { recv, .. } => {
+                self.expr(recv, scope, false);
+                None
+            }
+            Expr::Call { func, args } | Expr::BlockCall { func, args } => {
+                self.call(func, args, scope);
+                let key = match &***func {
+                    Expr::Ident(path) => self.res.reference(Spanned::span(path)),
+                    _ => None,
+                };
+                key.filter(|key| self.returning.contains(key))
+                    .map(|_| "a value returned from a function".to_owned())
+            }
+            Expr::Closure { params, stmts } => {
+                self.params(params);
+                let scope = Scope {
+                    cont: false,
+                    ..scope
+                };
+                self.block(stmts, scope, false);
+                self.made.push(Spanned::span(expr));
+                Some("a closure".to_owned())
+            }
+            Expr::Conditional { cases, final_else } => {
+                let scope = Scope {
+                    cont: tail,
+                    ..scope
+                };
+                for case in cases {
+                    self.expr(&case.condition, scope, false);
+                    self.block(&case.then_body, scope, tail);
+                }
+                self.block(final_else, scope, tail);
+                return None;
+            }
+            Expr::Handler { items, .. } => {
+                self.handler(items, false);
+                self.made.push(Spanned::span(expr));
+                Some("a handler".to_owned())
+            }
+            Expr::Do { stmts } => {
+                let scope = Scope {
+                    cont: tail,
+                    ..scope
+                };
+                self.block(stmts, scope, tail);
+                return None;
+            }
+            Expr::DoWith { stmts, handler, .. } => {
+                // a handler written in place can produce the values of the `do ... with`
+                match &***handler {
+                    Expr::Handler { items, .. } => {
+                        self.handler(items, tail);
+                        self.made.push(Spanned::span(handler));
+                    }
+                    _ => {
+                        self.expr(handler, scope, false);
+                    }
+                }
+                let scope = Scope {
+                    cont: tail,
+                    ..scope
+                };
+                self.block(stmts, scope, tail);
+                return None;
+            }
+        };
+        if tail {
+            if let Some(value) = &value {
+                self.escape(value, expr, "returned from the function");
+            }
+        }
+        value
+    }
+
+    /// Checks a call or an invocation of a continuation.
+    fn call(&mut self, func: &Spanned<Expr>, args: &[Spanned<Expr>], scope: Scope) {
+        let sink = match &**func {
+            Expr::Return if scope.ret => Sink::Out("returned from the function"),
+            Expr::Continue if scope.cont => Sink::Out("returned from the function"),
+            Expr::Ident(path) => match self.res.reference(Spanned::span(path)) {
+                Some(key) if self.returns.contains(&key) => Sink::Out("returned from the function"),
+                Some(key) if self.params.contains(&key) => {
+                    Sink::Out("passed to a parameter of the function")
+                }
+                Some(key) if self.res.kind(key) == Some(SymbolKind::Operation) => {
+                    Sink::Out("passed to an effect operation")
+                }
+                Some(key) => {
+                    if self.res.kind(key) == Some(SymbolKind::Function) {
+                        self.calls.push((Spanned::span(path), key));
+                    }
+                    Sink::Down
+                }
+                None => Sink::Down,
+            },
+            _ => Sink::Down,
+        };
+        self.expr(func, scope, false);
+        for arg in args {
+            let value = self.expr(arg, scope, false);
+            if let (Some(value), Sink::Out(how)) = (value, &sink) {
+                self.escape(&value, arg, how);
+            }
+        }
+    }
+
+    /// Checks a handler's clauses. Their values are those of its `do ... with` if `tail` is set.
+    fn handler(&mut self, items: &[Spanned<Item>], tail: bool) {
+        for item in items {
+            // `return` resumes in operations, and continues after the `do ... with` in `return`
+            // clauses
+            let (params, body, scope) = match &**item {
+                Item::Function(function) => {
+                    let scope = Scope {
+                        ret: false,
+                        cont: tail,
+                    };
+                    (&function.header.params[..], &function.body, scope)
+                }
+                Item::Return { params, body, .. } => {
+                    let scope = Scope {
+                        ret: tail,
+                        cont: tail,
+                    };
+                    (&params[..], body, scope)
+                }
+                Item::Finally { stmts } => {
+                    let scope = Scope {
+                        ret: false,
+                        cont: false,
+                    };
+                    (&[][..], stmts, scope)
+                }
+                _ => continue,
+            };
+            self.params(params);
+            self.block(body, scope, scope.cont);
+        }
+    }
+
+    fn escape(&mut self, value: &str, expr: &Spanned<Expr>, how: &str) {
+        let span = Spanned::span(expr);
+        self.ds.add(Code::Escape, span, format!("{value} is {how}"));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{diagnostic::Diagnostic, Session};
+
+    use super::*;
+
+    fn escapes(src: &str) -> Vec<String> {
+        let mut session = Session::new();
+        let compilation = session.compile("main.ku", src);
+        let ds = compilation.diagnostics.into_iter();
+        ds.map(
+            |Diagnostic {
+                 code,
+                 span,
+                 context,
+             }| {
+                assert_eq!(Code::Escape, code);
+                format!("{}: {context}", &src[span.pos..span.pos + span.len])
+            },
+        )
+        .collect()
+    }
+
+    #[test]
+    fn allows_scoped_uses() {
+        let loop_ku = include_str!("../korou-examples/loop.ku");
+        assert_eq!(Vec::<String>::new(), escapes(loop_ku));
+        let src = r#"
+fn twice(f: {Int}) -> Int = {
+    let a: Int = f();
+    a + f()
+}
+
+fn main() -> Int = {
+    let n: Int = 20;
+    let g: {Int} = { n + 1 };
+    let leave: (Int) -> = return;
+    if n > 10 { :leave twice(g); } else { twice({ n }) }
+}
+"#;
+        assert_eq!(Vec::<String>::new(), escapes(src));
+    }
+
+    #[test]
+    fn reports_escapes() {
+        let src = r#"
+effect keep {
+    fn keep(f: {Int}) -> Int;
+}
+
+fn closure() -> {Int} = {
+    let n: Int = 1;
+    { n }
+}
+
+fn cont() -> (Int) -> = {
+    let ret: (Int) -> = return;
+    let n: Int = 1;
+    if n > 0 { ret } else { return(return) }
+}
+
+fn passed(k: (Int) ->) -> = {
+    let f: {Int} = { 2 };
+    let n: Int = keep(f);
+    :k f;
+}
+
+fn forwarded() -> {Int} = {
+    closure()
+}
+"#;
+        let expected = [
+            "{ n }: a closure is returned from the function",
+            "ret: `ret` is returned from the function",
+            "return: `return` is returned from the function",
+            "f: `f` is passed to an effect operation",
+            "f: `f` is passed to a parameter of the function",
+            "closure(): a value returned from a function is returned from the function",
+        ];
+        assert_eq!(Vec::from(expected), escapes(src));
+    }
+
+    #[test]
+    fn finds_scoped_values() {
+        let src = r#"
+fn twice(f: {Int}) -> Int = {
+    let a: Int = f();
+    a + f()
+}
+
+fn main() -> Int = {
+    let n: Int = twice({ 20 });
+    twice({ n + 1 })
+}
+"#;
+        let mut session = Session::new();
+        let program = session.compile("main.ku", src).program.expect("program");
+        let text = session.disassemble(&program);
+        let scoped = text
+            .lines()
+            .filter(|line| line.starts_with("closure") && line.ends_with(" scoped"))
+            .collect::<Vec<_>>();
+        // the closures, and the continuation of the call to `twice`, but not of the calls to `f`
+        let expected = [
+            "closure main#0 in main params 1 cont 0 scoped",
+            "closure main#1 in main params 1 scoped",
+            "closure main#2 in main#1 params 1 cont 0 scoped",
+        ];
+        assert_eq!(Vec::from(expected), scoped);
+    }
+}
@@ -0,0 +1,591 @@
+//! Machine-readable JSON export of compiler data.
+//!
+//! The exported document has the following shape:
+//!
+//! ```text
+//! {
+//!   "schema_version": 1,
+//!   "file": "<file name>",
+//!   "tokens": [ { "kind": "<TokenKind>", "span": <span>, "text": "<source>" }, ... ],
+//!   "items": [ <item>, ... ],
+//!   "diagnostics": [ { "code": "<Code>", "severity": "error" | "warn", "span": <span>, "context": "<text>" }, ... ]
+//! }
+//! ```
+//!
+//! Spans are `{ "pos": <byte offset>, "len": <byte length> }`. AST nodes are objects with a `"kind"`
+//! tag naming the variant, followed by that variant's fields. Other nodes, like parameters and
+//! effects, are objects of their fields. Every node object ends with the node's `"span"`. Interned
+//! strings are resolved to their text, and erroneous identifiers and integers are `null`. Token
+//! kinds and diagnostic codes use their Rust variant names.
+//!
+//! The schema version is bumped whenever a field is removed or changes meaning. Adding fields or
+//! node kinds does not bump the version, so consumers should ignore fields they don't recognize.
+
+use std::fmt::{self, Display, Formatter, Write};
+
+use crate::{
+    ast::Item,
+    cache::StringCache,
+    diagnostic::{Diagnostic, DiagnosticKind, Diagnostics},
+    span::{Span, Spanned},
+    token::Token,
+};
+
+mod ast;
+
+/// The current version of the export schema.
+pub const SCHEMA_VERSION: i64 = 1;
+
+/// A JSON value. Object fields keep their insertion order.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Int(i64),
+    /// A number that isn't an integer, or doesn't fit in one.
+    Float(f64),
+    Str(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    /// Constructs an object from its fields.
+    pub fn object<const N: usize>(fields: [(&str, Json); N]) -> Self {
+        Self::Object(fields.into_iter().map(|(k, v)| (k.to_owned(), v)).collect())
+    }
+
+    /// Constructs an object tagged with the given kind.
+    pub fn tagged<const N: usize>(kind: &str, fields: [(&str, Json); N]) -> Self {
+        let mut obj = vec![("kind".to_owned(), Json::from(kind))];
+        obj.extend(fields.into_iter().map(|(k, v)| (k.to_owned(), v)));
+        Self::Object(obj)
+    }
+}
+
+impl Json {
+    /// Parses a JSON document. Numbers are integers if they are written as ones and fit.
+    pub fn parse(src: &str) -> Result<Self, ParseError> {
+        let mut reader = Reader { src, pos: 0 };
+        let value = reader.value()?;
+        reader.skip_ws();
+        if reader.pos != src.len() {
+            return Err(reader.error());
+        }
+        Ok(value)
+    }
+
+    /// Gets an object field.
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Self::Object(fields) => fields.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Self::Str(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_i64(&self) -> Option<i64> {
+        match *self {
+            Self::Int(n) => Some(n),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Json]> {
+        match self {
+            Self::Array(values) => Some(values),
+            _ => None,
+        }
+    }
+}
+
+/// An error parsing a JSON document, with the byte offset it occurred at.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct ParseError {
+    pub pos: usize,
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid JSON at byte {}", self.pos)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Recursive descent JSON reader.
+struct Reader<'a> {
+    src: &'a str,
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn error(&self) -> ParseError {
+        ParseError { pos: self.pos }
+    }
+
+    fn rest(&self) -> &str {
+        &self.src[self.pos..]
+    }
+
+    fn skip_ws(&mut self) {
+        let rest = self.rest();
+        self.pos += rest.len() - rest.trim_start_matches([' ', '\t', '\n', '\r']).len();
+    }
+
+    /// Takes the digits at the current position, returning how many there were.
+    fn digits(&mut self) -> usize {
+        let len = self.rest().bytes().take_while(u8::is_ascii_digit).count();
+        self.pos += len;
+        len
+    }
+
+    fn eat(&mut self, s: &str) -> bool {
+        self.skip_ws();
+        if self.rest().starts_with(s) {
+            self.pos += s.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, s: &str) -> Result<(), ParseError> {
+        if self.eat(s) {
+            Ok(())
+        } else {
+            Err(self.error())
+        }
+    }
+
+    fn value(&mut self) -> Result<Json, ParseError> {
+        self.skip_ws();
+        match self.rest().bytes().next() {
+            Some(b'n') => self.expect("null").map(|_| Json::Null),
+            Some(b't') => self.expect("true").map(|_| Json::Bool(true)),
+            Some(b'f') => self.expect("false").map(|_| Json::Bool(false)),
+            Some(b'"') => self.string().map(Json::Str),
+            Some(b'[') => {
+                self.pos += 1;
+                let mut values = Vec::new();
+                if !self.eat("]") {
+                    loop {
+                        values.push(self.value()?);
+                        if self.eat("]") {
+                            break;
+                        }
+                        self.expect(",")?;
+                    }
+                }
+                Ok(Json::Array(values))
+            }
+            Some(b'{') => {
+                self.pos += 1;
+                let mut fields = Vec::new();
+                if !self.eat("}") {
+                    loop {
+                        self.skip_ws();
+                        let key = self.string()?;
+                        self.expect(":")?;
+                        fields.push((key, self.value()?));
+                        if self.eat("}") {
+                            break;
+                        }
+                        self.expect(",")?;
+                    }
+                }
+                Ok(Json::Object(fields))
+            }
+            Some(b'-' | b'0'..=b'9') => self.number(),
+            _ => Err(self.error()),
+        }
+    }
+
+    fn number(&mut self) -> Result<Json, ParseError> {
+        let start = self.pos;
+        if self.rest().starts_with('-') {
+            self.pos += 1;
+        }
+        let int_start = self.pos;
+        match self.digits() {
+            0 => return Err(self.error()),
+            // no leading zeros
+            len if len > 1 && self.src.as_bytes()[int_start] == b'0' => {
+                return Err(ParseError { pos: int_start })
+            }
+            _ => {}
+        }
+        let mut integer = true;
+        if self.rest().starts_with('.') {
+            self.pos += 1;
+            if self.digits() == 0 {
+                return Err(self.error());
+            }
+            integer = false;
+        }
+        if self.rest().starts_with(['e', 'E']) {
+            self.pos += 1;
+            if self.rest().starts_with(['+', '-']) {
+                self.pos += 1;
+            }
+            if self.digits() == 0 {
+                return Err(self.error());
+            }
+            integer = false;
+        }
+        let text = &self.src[start..self.pos];
+        match text.parse() {
+            Ok(n) if integer => Ok(Json::Int(n)),
+            _ => text
+                .parse()
+                .map(Json::Float)
+                .map_err(|_| ParseError { pos: start }),
+        }
+    }
+
+    /// Reads the four hex digits of a `\u` escape.
+    fn hex4(&mut self) -> Result<u32, ParseError> {
+        let src = self.src;
+        let hex = src[self.pos..].get(..4).ok_or_else(|| self.error())?;
+        if !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(self.error());
+        }
+        self.pos += 4;
+        Ok(u32::from_str_radix(hex, 16).expect("checked hex digits"))
+    }
+
+    fn string(&mut self) -> Result<String, ParseError> {
+        if !self.rest().starts_with('"') {
+            return Err(self.error());
+        }
+        self.pos += 1;
+        let mut out = String::new();
+        loop {
+            let c = self.rest().chars().next().ok_or_else(|| self.error())?;
+            if c < ' ' {
+                // control characters must be escaped
+                return Err(self.error());
+            }
+            self.pos += c.len_utf8();
+            match c {
+                '"' => return Ok(out),
+                '\\' => {
+                    let esc = self.rest().bytes().next().ok_or_else(|| self.error())?;
+                    let c = match esc {
+                        b'"' => '"',
+                        b'\\' => '\\',
+                        b'/' => '/',
+                        b'n' => '\n',
+                        b'r' => '\r',
+                        b't' => '\t',
+                        b'b' => '\u{8}',
+                        b'f' => '\u{c}',
+                        b'u' => {
+                            let start = self.pos - 1;
+                            self.pos += 1;
+                            let mut code = self.hex4()?;
+                            if (0xd800..0xdc00).contains(&code) {
+                                // a high surrogate must be followed by an escaped low surrogate
+                                if !self.rest().starts_with("\\u") {
+                                    return Err(ParseError { pos: start });
+                                }
+                                self.pos += 2;
+                                let low = self.hex4()?;
+                                if !(0xdc00..0xe000).contains(&low) {
+                                    return Err(ParseError { pos: start });
+                                }
+                                code = 0x10000 + ((code - 0xd800) << 10) + (low - 0xdc00);
+                            }
+                            // lone low surrogates aren't characters
+                            out.push(char::from_u32(code).ok_or(ParseError { pos: start })?);
+                            continue;
+                        }
+                        _ => return Err(self.error()),
+                    };
+                    self.pos += 1;
+                    out.push(c);
+                }
+                c => out.push(c),
+            }
+        }
+    }
+}
+
+impl From<&str> for Json {
+    fn from(v: &str) -> Self {
+        Self::Str(v.to_owned())
+    }
+}
+
+impl From<String> for Json {
+    fn from(v: String) -> Self {
+        Self::Str(v)
+    }
+}
+
+impl From<i64> for Json {
+    fn from(v: i64) -> Self {
+        Self::Int(v)
+    }
+}
+
+impl From<usize> for Json {
+    fn from(v: usize) -> Self {
+        Self::Int(v as i64)
+    }
+}
+
+impl From<bool> for Json {
+    fn from(v: bool) -> Self {
+        Self::Bool(v)
+    }
+}
+
+impl<T: Into<Json>> From<Option<T>> for Json {
+    fn from(v: Option<T>) -> Self {
+        v.map(Into::into).unwrap_or(Self::Null)
+    }
+}
+
+/// Writes a string literal with JSON escapes.
+fn write_str(f: &mut Formatter<'_>, s: &str) -> fmt::Result {
+    f.write_char('"')?;
+    for c in s.chars() {
+        match c {
+            '"' => f.write_str("\\\"")?,
+            '\\' => f.write_str("\\\\")?,
+            '\n' => f.write_str("\\n")?,
+            '\r' => f.write_str("\\r")?,
+            '\t' => f.write_str("\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => f.write_char(c)?,
+        }
+    }
+    f.write_char('"')
+}
+
+impl Display for Json {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Null => f.write_str("null"),
+            Self::Bool(b) => write!(f, "{b}"),
+            Self::Int(n) => write!(f, "{n}"),
+            Self::Float(n) if n.is_finite() => write!(f, "{n}"),
+            // JSON has no infinities or NaN
+            Self::Float(_) => f.write_str("null"),
+            Self::Str(s) => write_str(f, s),
+            Self::Array(values) => {
+                f.write_char('[')?;
+                for (i, v) in values.iter().enumerate() {
+                    if i > 0 {
+                        f.write_char(',')?;
+                    }
+                    write!(f, "{v}")?;
+                }
+                f.write_char(']')
+            }
+            Self::Object(fields) => {
+                f.write_char('{')?;
+                for (i, (k, v)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        f.write_char(',')?;
+                    }
+                    write_str(f, k)?;
+                    write!(f, ":{v}")?;
+                }
+                f.write_char('}')
+            }
+        }
+    }
+}
+
+/// Trait for things that can be exported as JSON with the context of a string cache.
+pub trait ToJson {
+    fn to_json(&self, cache: &StringCache) -> Json;
+}
+
+impl<T: ToJson> ToJson for [T] {
+    fn to_json(&self, cache: &StringCache) -> Json {
+        Json::Array(self.iter().map(|v| v.to_json(cache)).collect())
+    }
+}
+
+impl<T: ToJson> ToJson for Vec<T> {
+    fn to_json(&self, cache: &StringCache) -> Json {
+        self.as_slice().to_json(cache)
+    }
+}
+
+impl<T: ToJson> ToJson for Box<T> {
+    fn to_json(&self, cache: &StringCache) -> Json {
+        (**self).to_json(cache)
+    }
+}
+
+impl<T: ToJson> ToJson for Option<T> {
+    fn to_json(&self, cache: &StringCache) -> Json {
+        self.as_ref()
+            .map(|v| v.to_json(cache))
+            .unwrap_or(Json::Null)
+    }
+}
+
+/// Node objects get a `"span"` field. Other values, like identifiers, are exported as they are.
+impl<T: ToJson> ToJson for Spanned<T> {
+    fn to_json(&self, cache: &StringCache) -> Json {
+        match (**self).to_json(cache) {
+            Json::Object(mut fields) => {
+                fields.push(("span".to_owned(), Spanned::span(self).to_json(cache)));
+                Json::Object(fields)
+            }
+            json => json,
+        }
+    }
+}
+
+impl ToJson for Span {
+    fn to_json(&self, _: &StringCache) -> Json {
+        Json::object([("pos", self.pos.into()), ("len", self.len.into())])
+    }
+}
+
+impl ToJson for Diagnostic {
+    fn to_json(&self, cache: &StringCache) -> Json {
+        let severity = match self.code.kind() {
+            DiagnosticKind::Error => "error",
+            DiagnosticKind::Warn => "warn",
+        };
+        Json::object([
+            ("code", self.code.name().into()),
+            ("severity", severity.into()),
+            ("span", self.span.to_json(cache)),
+            ("context", self.context.as_str().into()),
+        ])
+    }
+}
+
+/// Exports the tokens, items, and diagnostics of a single source file.
+pub fn export(
+    file: &str,
+    src: &str,
+    tokens: &[Token],
+    items: &[Spanned<Item>],
+    ds: &Diagnostics,
+    cache: &StringCache,
+) -> Json {
+    let tokens = tokens
+        .iter()
+        .map(|tkn| {
+            let span = Token::span(tkn);
+            Json::object([
+                ("kind", format!("{:?}", **tkn).into()),
+                ("span", span.to_json(cache)),
+                ("text", src[span.pos..span.pos + span.len].into()),
+            ])
+        })
+        .collect();
+    Json::object([
+        ("schema_version", SCHEMA_VERSION.into()),
+        ("file", file.into()),
+        ("tokens", Json::Array(tokens)),
+        ("items", items.to_json(cache)),
+        (
+            "diagnostics",
+            Json::Array(ds.iter().map(|d| d.to_json(cache)).collect()),
+        ),
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{cache::StringKey, cst, tokenizer::Tokenizer};
+
+    use super::*;
+
+    #[test]
+    fn escapes_strings() {
+        let value = Json::object([
+            ("a\"b", "line\nbreak\\".into()),
+            (
+                "c",
+                Json::Array(vec![Json::Null, true.into(), (-3i64).into()]),
+            ),
+            ("d", "\u{1}".into()),
+        ]);
+        assert_eq!(
+            r#"{"a\"b":"line\nbreak\\","c":[null,true,-3],"d":"\u0001"}"#,
+            value.to_string()
+        );
+    }
+
+    #[test]
+    fn parses() {
+        let src = r#" { "a": [1, -20, null, true, false], "b\n\u00e9\ud83d\ude00": {}, "c": [] } "#;
+        let expected = Json::object([
+            (
+                "a",
+                Json::Array(vec![
+                    1i64.into(),
+                    (-20i64).into(),
+                    Json::Null,
+                    true.into(),
+                    false.into(),
+                ]),
+            ),
+            ("b\n\u{e9}\u{1f600}", Json::object([])),
+            ("c", Json::Array(vec![])),
+        ]);
+        assert_eq!(Ok(expected), Json::parse(src));
+        assert_eq!(Err(ParseError { pos: 4 }), Json::parse("[1, }"));
+        assert!(Json::parse("[1] 2").is_err());
+    }
+
+    #[test]
+    fn parses_numbers() {
+        assert_eq!(Ok(Json::Int(0)), Json::parse("-0"));
+        assert_eq!(Ok(Json::Float(1.5)), Json::parse("1.5"));
+        assert_eq!(Ok(Json::Float(-2.5e-3)), Json::parse("-25E-4"));
+        assert_eq!(Ok(Json::Float(1e2)), Json::parse("1e+2"));
+        assert_eq!(Ok(Json::Float(1e19)), Json::parse("10000000000000000000"));
+        for src in ["01", "-01", "-", "1.", ".5", "1e", "+1", "0x10", "1.e3"] {
+            assert!(Json::parse(src).is_err(), "{src}");
+        }
+    }
+
+    #[test]
+    fn parses_escapes() {
+        assert_eq!(
+            Ok(Json::from("/\u{1f600}")),
+            Json::parse(r#""\/\ud83d\ude00""#)
+        );
+        assert_eq!(Err(ParseError { pos: 1 }), Json::parse(r#""\ud83d""#));
+        assert_eq!(Err(ParseError { pos: 1 }), Json::parse(r#""\ud83d\u0041""#));
+        assert_eq!(Err(ParseError { pos: 1 }), Json::parse(r#""\ude00""#));
+        assert_eq!(Err(ParseError { pos: 2 }), Json::parse(r#""\q""#));
+        assert_eq!(Err(ParseError { pos: 3 }), Json::parse(r#""\u+123""#));
+        assert_eq!(Err(ParseError { pos: 2 }), Json::parse("\"a\tb\""));
+        assert_eq!(Err(ParseError { pos: 0 }), Json::parse("\u{a0}1"));
+        assert_eq!(Ok(Json::Int(1)), Json::parse(" \t\r\n1\n"));
+    }
+
+    #[test]
+    fn exports_file() {
+        let src = "fn foo(x: Int) -> Int = { :return x + 1; }";
+        let mut cache = StringCache::new();
+        let mut ds = Diagnostics::new();
+        let tokens = Tokenizer::from_parts(StringKey::EMPTY, src).into_tokens();
+        let (items, _) = cst::parse(StringKey::EMPTY, src, &mut cache, &mut ds);
+        let json = export("foo.ku", src, &tokens, &items, &ds, &cache).to_string();
+        assert!(json.starts_with(r#"{"schema_version":1,"file":"foo.ku","tokens":[{"kind":"Fn","span":{"pos":0,"len":2},"text":"fn"}"#));
+        assert!(json.contains(r#""name":"foo""#));
+        assert!(json.contains(r#"{"kind":"Binary","op":"Add","operands":[{"kind":"Ident","path":["x"],"span":{"pos":34,"len":1}},{"kind":"Int","value":1,"span":{"pos":38,"len":1}}],"span":{"pos":34,"len":5}}"#));
+        assert!(json.contains(r#""params":[{"name":"x","ty":{"kind":"Simple","name":["Int"],"args":[],"span":{"pos":10,"len":3}},"span":{"pos":7,"len":6}}]"#));
+        assert!(json.ends_with(r#""diagnostics":[]}"#));
+    }
+}
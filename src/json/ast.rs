@@ -0,0 +1,251 @@
+//! JSON export of the AST.
+
+use crate::{
+    ast::{
+        Conditional, Effect, Expr, FunctionHeader, Ident, Integer, Item, Operator, QualifiedIdent,
+        Statement, Type, TypedIdent,
+    },
+    cache::StringCache,
+};
+
+use super::{Json, ToJson};
+
+impl ToJson for Ident {
+    fn to_json(&self, cache: &StringCache) -> Json {
+        match *self {
+            Ident::Ident(key) => cache[key].into(),
+            Ident::Error => Json::Null,
+        }
+    }
+}
+
+impl ToJson for QualifiedIdent {
+    fn to_json(&self, cache: &StringCache) -> Json {
+        self.0.to_json(cache)
+    }
+}
+
+impl ToJson for Integer {
+    fn to_json(&self, _: &StringCache) -> Json {
+        match *self {
+            Integer::Integer(n) => n.into(),
+            Integer::Error => Json::Null,
+        }
+    }
+}
+
+impl ToJson for Operator {
+    fn to_json(&self, _: &StringCache) -> Json {
+        format!("{self:?}").into()
+    }
+}
+
+impl ToJson for Type {
+    fn to_json(&self, cache: &StringCache) -> Json {
+        match self {
+            Type::Simple { name, args } => Json::tagged(
+                "Simple",
+                [("name", name.to_json(cache)), ("args", args.to_json(cache))],
+            ),
+            Type::Continuation { args, ret, effects } => Json::tagged(
+                "Continuation",
+                [
+                    ("args", args.to_json(cache)),
+                    ("ret", ret.to_json(cache)),
+                    ("effects", effects.to_json(cache)),
+                ],
+            ),
+            Type::Closure { ret, effects } => Json::tagged(
+                "Closure",
+                [
+                    ("ret", ret.to_json(cache)),
+                    ("effects", effects.to_json(cache)),
+                ],
+            ),
+        }
+    }
+}
+
+impl ToJson for Effect {
+    fn to_json(&self, cache: &StringCache) -> Json {
+        Json::object([
+            ("name", self.name.to_json(cache)),
+            ("args", self.args.to_json(cache)),
+            ("meta_effects", self.meta_effects.to_json(cache)),
+        ])
+    }
+}
+
+impl ToJson for TypedIdent {
+    fn to_json(&self, cache: &StringCache) -> Json {
+        Json::object([
+            ("name", self.name.to_json(cache)),
+            ("ty", self.ty.to_json(cache)),
+        ])
+    }
+}
+
+impl ToJson for Conditional {
+    fn to_json(&self, cache: &StringCache) -> Json {
+        Json::object([
+            ("condition", self.condition.to_json(cache)),
+            ("then_body", self.then_body.to_json(cache)),
+        ])
+    }
+}
+
+impl ToJson for Expr {
+    fn to_json(&self, cache: &StringCache) -> Json {
+        match self {
+            Expr::Ident(path) => Json::tagged("Ident", [("path", path.to_json(cache))]),
+            Expr::Int(value) => Json::tagged("Int", [("value", value.to_json(cache))]),
+            Expr::String(value) => Json::tagged("String", [("value", cache[*value].into())]),
+            Expr::Return => Json::tagged("Return", []),
+            Expr::Continue => Json::tagged("Continue", []),
+            Expr::Binary { op, operands } => Json::tagged(
+                "Binary",
+                [
+                    ("op", op.to_json(cache)),
+                    ("operands", operands.to_json(cache)),
+                ],
+            ),
+            Expr::Member { recv, member } => Json::tagged(
+                "Member",
+                [
+                    ("recv", recv.to_json(cache)),
+                    ("member", member.to_json(cache)),
+                ],
+            ),
+            Expr::Call { func, args } => Json::tagged(
+                "Call",
+                [("func", func.to_json(cache)), ("args", args.to_json(cache))],
+            ),
+            Expr::BlockCall { func, args } => Json::tagged(
+                "BlockCall",
+                [("func", func.to_json(cache)), ("args", args.to_json(cache))],
+            ),
+            Expr::Closure { params, stmts } => Json::tagged(
+                "Closure",
+                [
+                    ("params", params.to_json(cache)),
+                    ("stmts", stmts.to_json(cache)),
+                ],
+            ),
+            Expr::Conditional { cases, final_else } => Json::tagged(
+                "Conditional",
+                [
+                    ("cases", cases.to_json(cache)),
+                    ("final_else", final_else.to_json(cache)),
+                ],
+            ),
+            Expr::Handler {
+                impl_effects,
+                items,
+            } => Json::tagged(
+                "Handler",
+                [
+                    ("impl_effects", impl_effects.to_json(cache)),
+                    ("items", items.to_json(cache)),
+                ],
+            ),
+            Expr::Do { stmts } => Json::tagged("Do", [("stmts", stmts.to_json(cache))]),
+            Expr::DoWith {
+                stmts,
+                with_span,
+                handler,
+            } => Json::tagged(
+                "DoWith",
+                [
+                    ("stmts", stmts.to_json(cache)),
+                    ("with_span", with_span.to_json(cache)),
+                    ("handler", handler.to_json(cache)),
+                ],
+            ),
+            Expr::Error { err_span } => {
+                Json::tagged("Error", [("err_span", err_span.to_json(cache))])
+            }
+        }
+    }
+}
+
+impl ToJson for Statement {
+    fn to_json(&self, cache: &StringCache) -> Json {
+        match self {
+            Statement::Expr(expr) => Json::tagged("Expr", [("expr", expr.to_json(cache))]),
+            Statement::BlockExpr(expr) => {
+                Json::tagged("BlockExpr", [("expr", expr.to_json(cache))])
+            }
+            Statement::BlockEndExpr(expr) => {
+                Json::tagged("BlockEndExpr", [("expr", expr.to_json(cache))])
+            }
+            Statement::Let { bindings, init } => Json::tagged(
+                "Let",
+                [
+                    ("bindings", bindings.to_json(cache)),
+                    ("init", init.to_json(cache)),
+                ],
+            ),
+            Statement::Continue { cont, args } => Json::tagged(
+                "Continue",
+                [("cont", cont.to_json(cache)), ("args", args.to_json(cache))],
+            ),
+        }
+    }
+}
+
+impl ToJson for FunctionHeader {
+    fn to_json(&self, cache: &StringCache) -> Json {
+        Json::object([
+            ("name", self.name.to_json(cache)),
+            ("type_params", self.type_params.to_json(cache)),
+            ("effect_params", self.effect_params.to_json(cache)),
+            ("params", self.params.to_json(cache)),
+            ("effects", self.effects.to_json(cache)),
+            ("ret", self.ret.to_json(cache)),
+        ])
+    }
+}
+
+impl ToJson for Item {
+    fn to_json(&self, cache: &StringCache) -> Json {
+        match self {
+            Item::Function(function) => Json::tagged(
+                "Function",
+                [
+                    ("header", function.header.to_json(cache)),
+                    ("body", function.body.to_json(cache)),
+                ],
+            ),
+            Item::AbstractFunction(header) => {
+                Json::tagged("AbstractFunction", [("header", header.to_json(cache))])
+            }
+            Item::Finally { stmts } => Json::tagged("Finally", [("stmts", stmts.to_json(cache))]),
+            Item::Return { params, ret, body } => Json::tagged(
+                "Return",
+                [
+                    ("params", params.to_json(cache)),
+                    ("ret", ret.to_json(cache)),
+                    ("body", body.to_json(cache)),
+                ],
+            ),
+            Item::Effect {
+                name,
+                type_params,
+                effect_params,
+                body,
+            } => Json::tagged(
+                "Effect",
+                [
+                    ("name", name.to_json(cache)),
+                    ("type_params", type_params.to_json(cache)),
+                    ("effect_params", effect_params.to_json(cache)),
+                    ("body", body.to_json(cache)),
+                ],
+            ),
+            Item::Import { module } => Json::tagged("Import", [("module", module.to_json(cache))]),
+            Item::Error { err_span } => {
+                Json::tagged("Error", [("err_span", err_span.to_json(cache))])
+            }
+        }
+    }
+}
@@ -0,0 +1,62 @@
+//! The Korou language: parser, name resolution, lowering to MIR, and an interpreter.
+//!
+//! [`Session`] covers the common path from source to a running program:
+//!
+//! ```
+//! use korou_lang::Session;
+//!
+//! let mut session = Session::new();
+//! let compilation = session.compile("add.ku", "fn main() -> Int = { 1 + 2 }");
+//! assert!(!compilation.diagnostics.has_errors());
+//! let program = compilation.program.expect("program");
+//! let values = session.run(&program, "main", Vec::new()).expect("main");
+//! assert_eq!(values[0].to_string(), "3");
+//! ```
+//!
+//! The parser, resolver, lowering and optimizer are internal to the crate; everything outside it
+//! goes through the session, the diagnostics it reports, and the [`Machine`] running a program.
+//! Tools that need the syntax itself can get a file's tokens from [`Session::tokenize`], and walk
+//! its concrete syntax tree from [`Session::syntax_tree`]:
+//!
+//! ```
+//! use korou_lang::{Session, SyntaxKind};
+//!
+//! let mut session = Session::new();
+//! let tree = session.syntax_tree("add.ku", "fn add(a: Int, b: Int) -> Int = { a + b }");
+//! let nodes = tree.root.descendants();
+//! let params = nodes.iter().filter(|node| node.kind() == SyntaxKind::Param);
+//! assert_eq!(params.count(), 2);
+//! assert_eq!(tree.root.to_string(), "fn add(a: Int, b: Int) -> Int = { a + b }");
+//! ```
+
+mod ast;
+mod builtin;
+mod cache;
+mod cst;
+mod debug;
+mod diagnostic;
+mod escape;
+mod json;
+mod lsp;
+mod mir;
+mod parse;
+mod resolve;
+mod runtime;
+mod session;
+mod span;
+mod symbol;
+mod token;
+mod tokenizer;
+
+pub use cst::{GreenToken, SyntaxElement, SyntaxKind, SyntaxNode};
+pub use debug::Debugger;
+pub use diagnostic::{Code, Diagnostic, DiagnosticKind, Diagnostics};
+pub use lsp::serve as serve_lsp;
+pub use mir::{bytecode::DecodeError, opt::Passes, Program};
+pub use runtime::{
+    Backtrace, Captured, Console, Continuation, Control, FromValue, HeapStats, Host, HostFunction,
+    IntoValue, IntoValues, Limit, Limits, Machine, RuntimeError, Stdio, Value,
+};
+pub use session::{Compilation, Session, Syntax, SyntaxTree, Target};
+pub use span::{Span, Spanned};
+pub use token::TokenKind;
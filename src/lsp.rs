@@ -0,0 +1,680 @@
+//! Language server over stdio.
+//!
+//! Supports full document synchronization, diagnostics, go-to-definition, and hover.
+
+use std::{
+    collections::HashMap,
+    io::{self, BufRead, Read, Write},
+};
+
+use crate::{
+    cache::StringCache,
+    diagnostic::{DiagnosticKind, Diagnostics},
+    escape,
+    json::Json,
+    parse::incremental::ParsedFile,
+    resolve::{self, Resolution},
+    span::Span,
+};
+
+/// JSON-RPC error code for messages that aren't valid JSON.
+const PARSE_ERROR: i64 = -32700;
+/// JSON-RPC error code for unknown methods.
+const METHOD_NOT_FOUND: i64 = -32601;
+
+/// Runs the language server until the client sends `exit`.
+pub fn serve(mut input: impl BufRead, output: impl Write) -> io::Result<()> {
+    let mut server = Server {
+        out: output,
+        documents: HashMap::new(),
+    };
+    while let Some(msg) = read_message(&mut input)? {
+        let keep_running = match msg {
+            Ok(msg) => server.handle(&msg)?,
+            Err(err) => {
+                // the message can't be answered by its id, so the error goes to no id
+                let response = error_response(Json::Null, PARSE_ERROR, err);
+                write_message(&mut server.out, &response)?;
+                true
+            }
+        };
+        if !keep_running {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Reads a single `Content-Length` framed message. Returns `None` at end of input, and a
+/// description of the problem if the message is malformed.
+fn read_message(input: &mut impl BufRead) -> io::Result<Option<Result<Json, String>>> {
+    let mut len = None;
+    loop {
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                len = value.trim().parse::<usize>().ok();
+            }
+        }
+    }
+    let Some(len) = len else {
+        return Ok(Some(Err("missing or invalid Content-Length".to_owned())));
+    };
+    // the length comes from the client, so the buffer only grows as the body arrives
+    let mut body = Vec::new();
+    input.take(len as u64).read_to_end(&mut body)?;
+    if body.len() < len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    let msg = String::from_utf8(body)
+        .map_err(|err| err.to_string())
+        .and_then(|body| Json::parse(&body).map_err(|err| err.to_string()));
+    Ok(Some(msg))
+}
+
+/// Makes an error response to the request with the given id.
+fn error_response(id: Json, code: i64, message: impl Into<Json>) -> Json {
+    Json::object([
+        ("jsonrpc", "2.0".into()),
+        ("id", id),
+        (
+            "error",
+            Json::object([("code", code.into()), ("message", message.into())]),
+        ),
+    ])
+}
+
+/// Writes a single `Content-Length` framed message.
+fn write_message(out: &mut impl Write, msg: &Json) -> io::Result<()> {
+    let body = msg.to_string();
+    write!(out, "Content-Length: {}\r\n\r\n{body}", body.len())?;
+    out.flush()
+}
+
+/// Converts between byte offsets and LSP positions (lines and UTF-16 columns).
+struct LineIndex<'a> {
+    src: &'a str,
+    line_starts: Vec<usize>,
+}
+
+impl<'a> LineIndex<'a> {
+    fn new(src: &'a str) -> Self {
+        let line_starts = std::iter::once(0)
+            .chain(src.match_indices('\n').map(|(i, _)| i + 1))
+            .collect();
+        Self { src, line_starts }
+    }
+
+    fn position(&self, offset: usize) -> Json {
+        let line = self.line_starts.partition_point(|&s| s <= offset) - 1;
+        let start = self.line_starts[line];
+        let character = self.src[start..offset].encode_utf16().count();
+        Json::object([("line", line.into()), ("character", character.into())])
+    }
+
+    fn range(&self, span: Span) -> Json {
+        Json::object([
+            ("start", self.position(span.pos)),
+            ("end", self.position(span.pos + span.len)),
+        ])
+    }
+
+    fn offset(&self, position: &Json) -> Option<usize> {
+        let line = usize::try_from(position.get("line")?.as_i64()?).ok()?;
+        let character = usize::try_from(position.get("character")?.as_i64()?).ok()?;
+        let start = *self.line_starts.get(line)?;
+        let end = self
+            .line_starts
+            .get(line + 1)
+            .copied()
+            .unwrap_or(self.src.len());
+        let mut units = 0;
+        for (i, c) in self.src[start..end].char_indices() {
+            if units >= character {
+                return Some(start + i);
+            }
+            units += c.len_utf16();
+        }
+        Some(end)
+    }
+
+    /// Gets the full text of the line containing the given offset.
+    fn line_text(&self, offset: usize) -> &'a str {
+        let line = self.line_starts.partition_point(|&s| s <= offset) - 1;
+        let start = self.line_starts[line];
+        let end = self
+            .line_starts
+            .get(line + 1)
+            .copied()
+            .unwrap_or(self.src.len());
+        self.src[start..end].trim()
+    }
+}
+
+/// An open document, which is reparsed incrementally as it changes.
+struct Document {
+    cache: StringCache,
+    parsed: ParsedFile,
+}
+
+impl Document {
+    fn new(uri: &str, text: &str) -> Self {
+        let mut cache = StringCache::new();
+        let file = cache.intern(uri);
+        let parsed = ParsedFile::parse(file, text.to_owned(), &mut cache);
+        Self { cache, parsed }
+    }
+
+    fn src(&self) -> &str {
+        self.parsed.src()
+    }
+
+    /// Applies a change from `textDocument/didChange`. The whole document is replaced only if the
+    /// change has no range; a change whose range isn't in the document is rejected.
+    fn apply(&mut self, uri: &str, change: &Json) -> Result<(), String> {
+        let text = change
+            .get("text")
+            .and_then(Json::as_str)
+            .ok_or("change without text")?;
+        let Some(range) = change.get("range") else {
+            *self = Self::new(uri, text);
+            return Ok(());
+        };
+        let lines = LineIndex::new(self.src());
+        let start = range.get("start").and_then(|pos| lines.offset(pos));
+        let end = range.get("end").and_then(|pos| lines.offset(pos));
+        match (start, end) {
+            (Some(start), Some(end)) if start <= end => {
+                self.parsed.edit(start..end, text, &mut self.cache);
+                Ok(())
+            }
+            _ => Err(format!("invalid range in change to {uri}: {range}")),
+        }
+    }
+
+    fn analyze(&self) -> Analysis {
+        let mut ds = self.parsed.diagnostics();
+        let items = self
+            .parsed
+            .items()
+            .iter()
+            .map(|parsed| parsed.item.clone())
+            .collect::<Vec<_>>();
+        let res = resolve::resolve(&items, &self.cache, &mut ds);
+        escape::check(&items, &res, &self.cache, &mut ds);
+        Analysis { ds, res }
+    }
+}
+
+/// The results of analyzing a document.
+struct Analysis {
+    ds: Diagnostics,
+    res: Resolution,
+}
+
+struct Server<W> {
+    out: W,
+    /// Open documents by URI.
+    documents: HashMap<String, Document>,
+}
+
+impl<W: Write> Server<W> {
+    /// Handles a single message. Returns whether the server should keep running.
+    fn handle(&mut self, msg: &Json) -> io::Result<bool> {
+        let method = msg.get("method").and_then(Json::as_str).unwrap_or_default();
+        let params = msg.get("params").unwrap_or(&Json::Null);
+        let result = match method {
+            "initialize" => Some(Json::object([
+                (
+                    "capabilities",
+                    Json::object([
+                        ("textDocumentSync", 2i64.into()),
+                        ("definitionProvider", true.into()),
+                        ("hoverProvider", true.into()),
+                    ]),
+                ),
+                ("serverInfo", Json::object([("name", "korou".into())])),
+            ])),
+            "shutdown" => Some(Json::Null),
+            "exit" => return Ok(false),
+            "textDocument/didOpen" => {
+                let doc = params.get("textDocument").unwrap_or(&Json::Null);
+                if let (Some(uri), Some(text)) = (
+                    doc.get("uri").and_then(Json::as_str),
+                    doc.get("text").and_then(Json::as_str),
+                ) {
+                    self.documents
+                        .insert(uri.to_owned(), Document::new(uri, text));
+                    self.publish_diagnostics(uri)?;
+                }
+                None
+            }
+            "textDocument/didChange" => {
+                let uri = params
+                    .get("textDocument")
+                    .and_then(|d| d.get("uri"))
+                    .and_then(Json::as_str);
+                let changes = params.get("contentChanges").and_then(Json::as_array);
+                if let (Some(uri), Some(changes)) = (uri, changes) {
+                    if let Some(doc) = self.documents.get_mut(uri) {
+                        // later changes are relative to the ones before, so they stop at the first
+                        // that can't be applied
+                        let result = changes.iter().try_for_each(|change| doc.apply(uri, change));
+                        if let Err(err) = result {
+                            let params =
+                                Json::object([("type", 1i64.into()), ("message", err.into())]);
+                            self.notify("window/logMessage", params)?;
+                        }
+                        self.publish_diagnostics(uri)?;
+                    }
+                }
+                None
+            }
+            "textDocument/didClose" => {
+                if let Some(uri) = params
+                    .get("textDocument")
+                    .and_then(|d| d.get("uri"))
+                    .and_then(Json::as_str)
+                {
+                    self.documents.remove(uri);
+                    self.notify(
+                        "textDocument/publishDiagnostics",
+                        Json::object([("uri", uri.into()), ("diagnostics", Json::Array(vec![]))]),
+                    )?;
+                }
+                None
+            }
+            "textDocument/definition" => Some(self.definition(params).unwrap_or(Json::Null)),
+            "textDocument/hover" => Some(self.hover(params).unwrap_or(Json::Null)),
+            _ => None,
+        };
+        if let Some(id) = msg.get("id") {
+            let response = match result {
+                Some(result) => Json::object([
+                    ("jsonrpc", "2.0".into()),
+                    ("id", id.clone()),
+                    ("result", result),
+                ]),
+                None => error_response(
+                    id.clone(),
+                    METHOD_NOT_FOUND,
+                    format!("unknown method: {method}"),
+                ),
+            };
+            write_message(&mut self.out, &response)?;
+        }
+        Ok(true)
+    }
+
+    fn notify(&mut self, method: &str, params: Json) -> io::Result<()> {
+        let msg = Json::object([
+            ("jsonrpc", "2.0".into()),
+            ("method", method.into()),
+            ("params", params),
+        ]);
+        write_message(&mut self.out, &msg)
+    }
+
+    fn publish_diagnostics(&mut self, uri: &str) -> io::Result<()> {
+        let doc = &self.documents[uri];
+        let analysis = doc.analyze();
+        let lines = LineIndex::new(doc.src());
+        let diagnostics = analysis
+            .ds
+            .iter()
+            .map(|d| {
+                let severity: i64 = match d.code.kind() {
+                    DiagnosticKind::Error => 1,
+                    DiagnosticKind::Warn => 2,
+                };
+                let message = if d.context.is_empty() {
+                    d.code.name().to_owned()
+                } else {
+                    format!("{}: {}", d.code.name(), d.context)
+                };
+                Json::object([
+                    ("range", lines.range(d.span)),
+                    ("severity", severity.into()),
+                    ("code", d.code.name().into()),
+                    ("source", "korou".into()),
+                    ("message", message.into()),
+                ])
+            })
+            .collect();
+        let params = Json::object([
+            ("uri", uri.into()),
+            ("diagnostics", Json::Array(diagnostics)),
+        ]);
+        self.notify("textDocument/publishDiagnostics", params)
+    }
+
+    /// Finds the document, analysis, and byte offset of a text document position.
+    fn locate(&self, params: &Json) -> Option<(&str, &Document, Analysis, usize)> {
+        let uri = params.get("textDocument")?.get("uri")?.as_str()?;
+        let (uri, doc) = self.documents.get_key_value(uri)?;
+        let offset = LineIndex::new(doc.src()).offset(params.get("position")?)?;
+        Some((uri, doc, doc.analyze(), offset))
+    }
+
+    fn definition(&self, params: &Json) -> Option<Json> {
+        let (uri, doc, analysis, offset) = self.locate(params)?;
+        let src = doc.src();
+        let def = analysis.res.definition(analysis.res.symbol_at(offset)?)?;
+        Some(Json::object([
+            ("uri", uri.into()),
+            ("range", LineIndex::new(src).range(def.span)),
+        ]))
+    }
+
+    fn hover(&self, params: &Json) -> Option<Json> {
+        let (_, doc, analysis, offset) = self.locate(params)?;
+        let src = doc.src();
+        let key = analysis.res.symbol_at(offset)?;
+        let def = analysis.res.definition(key)?;
+        let lines = LineIndex::new(src);
+        let name = match def.kind {
+            resolve::SymbolKind::Parameter | resolve::SymbolKind::Local => {
+                doc.cache[analysis.res.table.string_key(key)].to_owned()
+            }
+            _ => analysis
+                .res
+                .table
+                .path(key)
+                .into_iter()
+                .map(|k| &doc.cache[k])
+                .collect::<Vec<_>>()
+                .join("::"),
+        };
+        let value = format!(
+            "{} `{name}`\n```korou\n{}\n```",
+            def.kind.as_str(),
+            lines.line_text(def.span.pos)
+        );
+        Some(Json::object([(
+            "contents",
+            Json::object([("kind", "markdown".into()), ("value", value.into())]),
+        )]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A scripted client session.
+    struct Client {
+        input: Vec<u8>,
+        next_id: i64,
+    }
+
+    impl Client {
+        fn new() -> Self {
+            Self {
+                input: Vec::new(),
+                next_id: 0,
+            }
+        }
+
+        fn request(&mut self, method: &str, params: Json) {
+            self.next_id += 1;
+            let msg = Json::object([
+                ("jsonrpc", "2.0".into()),
+                ("id", self.next_id.into()),
+                ("method", method.into()),
+                ("params", params),
+            ]);
+            write_message(&mut self.input, &msg).unwrap();
+        }
+
+        fn notify(&mut self, method: &str, params: Json) {
+            let msg = Json::object([
+                ("jsonrpc", "2.0".into()),
+                ("method", method.into()),
+                ("params", params),
+            ]);
+            write_message(&mut self.input, &msg).unwrap();
+        }
+
+        /// Runs the server over the scripted input and returns every message it sent.
+        fn run(self) -> Vec<Json> {
+            let mut output = Vec::new();
+            serve(self.input.as_slice(), &mut output).unwrap();
+            let mut output = output.as_slice();
+            let mut messages = Vec::new();
+            while let Some(msg) = read_message(&mut output).unwrap() {
+                messages.push(msg.unwrap());
+            }
+            messages
+        }
+    }
+
+    fn position(line: i64, character: i64) -> Json {
+        Json::object([("line", line.into()), ("character", character.into())])
+    }
+
+    fn text_position(uri: &str, line: i64, character: i64) -> Json {
+        Json::object([
+            ("textDocument", Json::object([("uri", uri.into())])),
+            ("position", position(line, character)),
+        ])
+    }
+
+    #[test]
+    fn session() {
+        let uri = "file:///loop.ku";
+        let src = include_str!("../korou-examples/loop.ku");
+        let mut client = Client::new();
+        client.request("initialize", Json::object([]));
+        client.notify("initialized", Json::object([]));
+        client.notify(
+            "textDocument/didOpen",
+            Json::object([(
+                "textDocument",
+                Json::object([
+                    ("uri", uri.into()),
+                    ("languageId", "korou".into()),
+                    ("version", 1i64.into()),
+                    ("text", src.into()),
+                ]),
+            )]),
+        );
+        // `:infinite code;` in `loop`
+        client.request("textDocument/definition", text_position(uri, 13, 10));
+        client.request("textDocument/hover", text_position(uri, 13, 10));
+        client.notify(
+            "textDocument/didChange",
+            Json::object([
+                (
+                    "textDocument",
+                    Json::object([("uri", uri.into()), ("version", 2i64.into())]),
+                ),
+                (
+                    "contentChanges",
+                    Json::Array(vec![Json::object([(
+                        "text",
+                        "fn foo() -> = { :bar; }".into(),
+                    )])]),
+                ),
+            ]),
+        );
+        // rename `bar` to `foo`
+        client.notify(
+            "textDocument/didChange",
+            Json::object([
+                (
+                    "textDocument",
+                    Json::object([("uri", uri.into()), ("version", 3i64.into())]),
+                ),
+                (
+                    "contentChanges",
+                    Json::Array(vec![Json::object([
+                        (
+                            "range",
+                            Json::object([("start", position(0, 17)), ("end", position(0, 20))]),
+                        ),
+                        ("text", "foo".into()),
+                    ])]),
+                ),
+            ]),
+        );
+        client.request("textDocument/unknown", Json::object([]));
+        client.request("shutdown", Json::Null);
+        client.notify("exit", Json::Null);
+
+        let messages = client.run();
+        assert_eq!(8, messages.len(), "{messages:?}");
+
+        let caps = messages[0]
+            .get("result")
+            .unwrap()
+            .get("capabilities")
+            .unwrap();
+        assert_eq!(Some(&Json::Bool(true)), caps.get("definitionProvider"));
+
+        let diagnostics = messages[1]
+            .get("params")
+            .unwrap()
+            .get("diagnostics")
+            .unwrap();
+        assert_eq!(Some(&[][..]), diagnostics.as_array());
+
+        let expected = Json::object([
+            ("uri", uri.into()),
+            (
+                "range",
+                Json::object([("start", position(5, 3)), ("end", position(5, 11))]),
+            ),
+        ]);
+        assert_eq!(Some(&expected), messages[2].get("result"));
+
+        let hover = messages[3].get("result").unwrap().get("contents").unwrap();
+        assert_eq!(
+            Some("function `infinite`\n```korou\nfn infinite(code: {}) -> = {\n```"),
+            hover.get("value").and_then(Json::as_str)
+        );
+
+        let diagnostics = messages[4]
+            .get("params")
+            .unwrap()
+            .get("diagnostics")
+            .unwrap();
+        let diagnostic = &diagnostics.as_array().unwrap()[0];
+        assert_eq!(
+            Some("UnresolvedName: bar"),
+            diagnostic.get("message").and_then(Json::as_str)
+        );
+        let expected = Json::object([("start", position(0, 17)), ("end", position(0, 20))]);
+        assert_eq!(Some(&expected), diagnostic.get("range"));
+
+        let diagnostics = messages[5]
+            .get("params")
+            .unwrap()
+            .get("diagnostics")
+            .unwrap();
+        assert_eq!(Some(&[][..]), diagnostics.as_array());
+
+        let error = messages[6].get("error").unwrap();
+        assert_eq!(
+            Some(METHOD_NOT_FOUND),
+            error.get("code").and_then(Json::as_i64)
+        );
+    }
+
+    #[test]
+    fn recovers_from_malformed_messages() {
+        let mut client = Client::new();
+        client
+            .input
+            .extend_from_slice(b"Content-Length: 5\r\n\r\n{\"a\":");
+        client
+            .input
+            .extend_from_slice(b"Content-Type: text\r\n\r\n");
+        client
+            .input
+            .extend_from_slice(b"Content-Length: 2\r\n\r\n\xff\xfe");
+        client.request("shutdown", Json::Null);
+        client.notify("exit", Json::Null);
+        let messages = client.run();
+
+        assert_eq!(4, messages.len());
+        for msg in &messages[..3] {
+            assert_eq!(Some(&Json::Null), msg.get("id"));
+            let error = msg.get("error").unwrap();
+            assert_eq!(Some(PARSE_ERROR), error.get("code").and_then(Json::as_i64));
+        }
+        assert_eq!(Some(&Json::Null), messages[3].get("result"));
+    }
+
+    #[test]
+    fn rejects_invalid_changes() {
+        let uri = "file:///main.ku";
+        let src = "fn foo() -> = { :foo; }";
+        let mut client = Client::new();
+        client.notify(
+            "textDocument/didOpen",
+            Json::object([(
+                "textDocument",
+                Json::object([("uri", uri.into()), ("text", src.into())]),
+            )]),
+        );
+        let ranges = [
+            (position(0, 20), position(0, 17)),
+            (position(0, 17), position(3, 0)),
+        ];
+        for (start, end) in ranges {
+            client.notify(
+                "textDocument/didChange",
+                Json::object([
+                    ("textDocument", Json::object([("uri", uri.into())])),
+                    (
+                        "contentChanges",
+                        Json::Array(vec![Json::object([
+                            ("range", Json::object([("start", start), ("end", end)])),
+                            ("text", "bar".into()),
+                        ])]),
+                    ),
+                ]),
+            );
+        }
+        client.request("textDocument/hover", text_position(uri, 0, 18));
+        client.notify("exit", Json::Null);
+        let messages = client.run();
+
+        assert_eq!(6, messages.len(), "{messages:?}");
+        for msg in [&messages[1], &messages[3]] {
+            assert_eq!(
+                Some("window/logMessage"),
+                msg.get("method").and_then(Json::as_str)
+            );
+        }
+        // the document is unchanged, so `foo` still refers to the function
+        let hover = messages[5].get("result").unwrap().get("contents").unwrap();
+        assert_eq!(
+            Some("function `foo`\n```korou\nfn foo() -> = { :foo; }\n```"),
+            hover.get("value").and_then(Json::as_str)
+        );
+    }
+
+    #[test]
+    fn reads_bodies_as_they_arrive() {
+        let mut input = &b"Content-Length: 1000000000000\r\n\r\n{}"[..];
+        let err = read_message(&mut input).unwrap_err();
+        assert_eq!(io::ErrorKind::UnexpectedEof, err.kind());
+    }
+
+    #[test]
+    fn utf16_positions() {
+        let src = "é😀x\ny";
+        let lines = LineIndex::new(src);
+        assert_eq!(position(0, 3), lines.position(src.find('x').unwrap()));
+        assert_eq!(Some(src.find('x').unwrap()), lines.offset(&position(0, 3)));
+        assert_eq!(Some(src.find('y').unwrap()), lines.offset(&position(1, 0)));
+    }
+}
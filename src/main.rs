@@ -1,67 +1,295 @@
 use std::{
     error::Error,
+    ffi::OsString,
     io::{stdin, stdout, Write},
+    path::Path,
 };
 
-use crate::{cache::StringCache, diagnostic::Diagnostics, parse::Parser, tokenizer::Tokenizer};
+use korou_lang::{serve_lsp, Passes, Program, Session, Syntax, Target};
 
-mod ast;
-mod cache;
-mod diagnostic;
-mod mir;
-mod parse;
-mod span;
-mod symbol;
-mod token;
-mod tokenizer;
+/// What to do with the file.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+enum Command {
+    /// Parse the file and print what was parsed, or read statements if there's no file.
+    #[default]
+    Parse,
+    Run,
+    Debug,
+    /// Compile the file, which only checks it unless asked to emit something.
+    Build,
+    /// Serve the language server over stdio, ignoring any other arguments.
+    Lsp,
+}
 
-fn main() -> Result<(), Box<dyn Error>> {
-    let args = std::env::args_os().collect::<Vec<_>>();
-    if let Some(filename) = args.get(1) {
-        let file = std::fs::File::open(filename)?;
-        let src = std::io::read_to_string(&file)?;
-        let mut cache = StringCache::new();
-        let filename = cache.intern(&filename.to_string_lossy());
-        let tz = Tokenizer::from_parts(filename, &src);
-        let mut ds = Diagnostics::new();
-        let mut parser = Parser {
-            tz,
-            cache: &mut cache,
-            ds: &mut ds,
+/// What to print instead of the default output.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Emit {
+    Mir,
+    Syntax(Syntax),
+}
+
+#[derive(Debug, Default)]
+struct Options {
+    command: Command,
+    filename: Option<OsString>,
+    emit: Option<Emit>,
+    output: Option<OsString>,
+    target: Option<Target>,
+    gc_stats: bool,
+    /// Collect garbage at every allocation.
+    gc_stress: bool,
+    passes: Passes,
+}
+
+/// How an option takes its value.
+#[derive(Copy, Clone)]
+enum Arity {
+    /// No value: `--gc-stats`.
+    Flag,
+    /// The next argument, or the rest after `=`: `--emit mir` or `--emit=mir`.
+    Value,
+    /// The rest of the argument: `-O2`.
+    Attached,
+}
+
+type Apply = fn(&mut Options, OsString) -> Result<(), String>;
+
+const COMMANDS: &[(&str, Command)] = &[
+    ("run", Command::Run),
+    ("debug", Command::Debug),
+    ("build", Command::Build),
+    ("lsp", Command::Lsp),
+];
+
+const OPTIONS: &[(&str, Arity, Apply)] = &[
+    ("--emit", Arity::Value, |options, kind| {
+        let emit = match kind.to_str() {
+            Some("mir") => Emit::Mir,
+            Some("json") => Emit::Syntax(Syntax::Json),
+            Some("cst") => Emit::Syntax(Syntax::Cst),
+            _ => return Err(format!("unknown emit kind: {}", kind.to_string_lossy())),
         };
-        let output = parser.file();
-        for item in output {
-            println!("Output: {item:?}\n");
-        }
-        println!("Diagnostics: {ds:?}");
-    } else {
-        // repl
-        loop {
-            print!("> ");
-            stdout().flush()?;
-            let mut input = String::new();
-            stdin().read_line(&mut input)?;
-
-            if input.trim() == ":quit" {
-                break;
+        options.emit = Some(emit);
+        Ok(())
+    }),
+    ("-o", Arity::Value, |options, output| {
+        options.output = Some(output);
+        Ok(())
+    }),
+    ("--target", Arity::Value, |options, target| {
+        let target = match target.to_str() {
+            Some("c") => Target::C,
+            Some("wat") => Target::Wat,
+            _ => return Err(format!("unknown target: {}", target.to_string_lossy())),
+        };
+        options.target = Some(target);
+        Ok(())
+    }),
+    ("--gc-stats", Arity::Flag, |options, _| {
+        options.gc_stats = true;
+        Ok(())
+    }),
+    ("--gc-stress", Arity::Flag, |options, _| {
+        options.gc_stress = true;
+        Ok(())
+    }),
+    ("-O", Arity::Attached, |options, level| {
+        let level = level.to_string_lossy();
+        let level = level
+            .parse()
+            .map_err(|_| format!("unknown optimization level: {level}"))?;
+        options.passes = Passes::level(level);
+        Ok(())
+    }),
+];
+
+/// Parses the arguments after the program name: an optional command, then options from
+/// [`OPTIONS`] and the filename in any order.
+fn parse_args(args: impl IntoIterator<Item = OsString>) -> Result<Options, String> {
+    let mut args = args.into_iter().peekable();
+    let mut options = Options::default();
+    if let Some(&(_, command)) = args
+        .peek()
+        .and_then(|arg| COMMANDS.iter().find(|(name, _)| arg == name))
+    {
+        options.command = command;
+        args.next();
+    }
+    while let Some(arg) = args.next() {
+        let text = arg.to_str().unwrap_or_default();
+        let option = OPTIONS.iter().find_map(|&(name, arity, apply)| {
+            let rest = text.strip_prefix(name)?;
+            match arity {
+                Arity::Flag | Arity::Value if rest.is_empty() => Some((name, arity, apply, None)),
+                Arity::Value => rest
+                    .strip_prefix('=')
+                    .map(|value| (name, arity, apply, Some(value.into()))),
+                Arity::Flag => None,
+                Arity::Attached => Some((name, arity, apply, Some(rest.into()))),
             }
+        });
+        let Some((name, arity, apply, value)) = option else {
+            options.filename = Some(arg);
+            continue;
+        };
+        let value = match (arity, value) {
+            (Arity::Value, None) => args
+                .next()
+                .ok_or_else(|| format!("missing value for {name}"))?,
+            (_, value) => value.unwrap_or_default(),
+        };
+        apply(&mut options, value)?;
+    }
+    Ok(options)
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let options = parse_args(std::env::args_os().skip(1))?;
+    if options.command == Command::Lsp {
+        return Ok(serve_lsp(stdin().lock(), stdout().lock())?);
+    }
+    let Some(filename) = &options.filename else {
+        return repl();
+    };
+    let bytes = std::fs::read(filename)?;
+    let mut session = Session::new();
+    if options.command == Command::Run && Program::is_bytecode(&bytes) {
+        let program = session.decode(&bytes)?;
+        return run(&session, &program, None, &options);
+    }
+    let src = String::from_utf8(bytes)?;
+    let filename = filename.to_string_lossy();
+    if options.command == Command::Debug {
+        let program = compile(&mut session, &filename, &src, Passes::default())?;
+        let machine = session
+            .machine(&program, "main", Vec::new())?
+            .with_gc_stress(options.gc_stress);
+        let mut debugger = session.debugger(machine, &program, &src);
+        return Ok(debugger.serve(stdin().lock(), stdout().lock())?);
+    }
+    if let Some(target) = options.target {
+        let program = compile(&mut session, &filename, &src, options.passes)?;
+        let code = session
+            .emit(&program, target, "main")
+            .map_err(|err| err.to_string())?;
+        let output = options.output.unwrap_or_else(|| {
+            Path::new(&*filename)
+                .with_extension(target.extension())
+                .into()
+        });
+        std::fs::write(output, code)?;
+        return Ok(());
+    }
+    let mir = options.emit == Some(Emit::Mir);
+    if mir || (options.command == Command::Build && options.emit.is_none()) {
+        let program = compile(&mut session, &filename, &src, options.passes)?;
+        if mir {
+            print!("{}", session.disassemble(&program));
+        }
+        if let Some(output) = options.output {
+            std::fs::write(output, session.encode(&program))?;
+        }
+        return Ok(());
+    }
+    if options.command == Command::Run {
+        let program = compile(&mut session, &filename, &src, options.passes)?;
+        return run(&session, &program, Some(&src), &options);
+    }
+    let syntax = match options.emit {
+        Some(Emit::Syntax(syntax)) => syntax,
+        _ => Syntax::Debug,
+    };
+    print!("{}", session.dump(&filename, &src, syntax));
+    Ok(())
+}
+
+/// Compiles and optimizes a file, failing with its diagnostics if it has errors.
+fn compile(
+    session: &mut Session,
+    filename: &str,
+    src: &str,
+    passes: Passes,
+) -> Result<Program, Box<dyn Error>> {
+    let compilation = session.compile(filename, src);
+    let mut program = compilation
+        .program
+        .ok_or_else(|| format!("{:?}", compilation.diagnostics))?;
+    let diagnostics = program.optimize(passes);
+    if diagnostics.has_errors() {
+        return Err(format!("{diagnostics:?}").into());
+    }
+    Ok(program)
+}
 
-            let mut cache = StringCache::new();
-            let file = cache.intern("repl.ku");
-            let tz = Tokenizer::from_parts(file, &input);
-            let mut ds = Diagnostics::new();
-            let mut parser = Parser {
-                tz,
-                cache: &mut cache,
-                ds: &mut ds,
-            };
-            let output = parser.stmt();
-            println!("Output: {:?}", output);
-            println!("Diagnostics: {:?}", ds);
+/// Runs `main` and prints the values it returns with. Errors are shown with a backtrace, pointing
+/// into the source if there is any.
+fn run(
+    session: &Session,
+    program: &Program,
+    src: Option<&str>,
+    options: &Options,
+) -> Result<(), Box<dyn Error>> {
+    let mut machine = session
+        .machine(program, "main", Vec::new())?
+        .with_gc_stress(options.gc_stress);
+    let values = machine.run().inspect_err(|err| match src {
+        Some(src) => eprintln!("error: {err}\n{}", machine.backtrace().render(src)),
+        None => eprintln!("error: {err}\n{}", machine.backtrace()),
+    });
+    if options.gc_stats {
+        eprintln!("{}", machine.heap_stats());
+    }
+    for value in values? {
+        println!("{value}");
+    }
+    Ok(())
+}
+
+/// Reads statements and prints what they parse to, until `:quit` or the end of input.
+fn repl() -> Result<(), Box<dyn Error>> {
+    let mut session = Session::new();
+    loop {
+        print!("> ");
+        stdout().flush()?;
+        let mut input = String::new();
+        if stdin().read_line(&mut input)? == 0 || input.trim() == ":quit" {
+            break;
         }
+        print!("{}", session.dump_statement(&input));
     }
     Ok(())
 }
 
 #[cfg(test)]
-mod tests {}
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Options, String> {
+        parse_args(args.iter().map(OsString::from))
+    }
+
+    #[test]
+    fn parses_arguments() {
+        let options = parse(&["run", "-O2", "--emit=mir", "main.ku", "-o", "out.kuc"]).unwrap();
+        assert_eq!(Command::Run, options.command);
+        assert_eq!(Some("main.ku".into()), options.filename);
+        assert_eq!(Some(Emit::Mir), options.emit);
+        assert_eq!(Some("out.kuc".into()), options.output);
+        assert_eq!(Passes::level(2), options.passes);
+
+        let options = parse(&["main.ku", "--target", "wat", "--gc-stats"]).unwrap();
+        assert_eq!(Command::Parse, options.command);
+        assert_eq!(Some(Target::Wat), options.target);
+        assert!(options.gc_stats);
+        assert!(!options.gc_stress);
+
+        let options = parse(&["run", "--gc-stress", "main.ku"]).unwrap();
+        assert!(options.gc_stress);
+
+        let err = |args: &[&str]| parse(args).unwrap_err();
+        assert_eq!("unknown emit kind: asm", err(&["--emit", "asm"]));
+        assert_eq!("unknown target: js", err(&["--target=js"]));
+        assert_eq!("unknown optimization level: x", err(&["-Ox"]));
+        assert_eq!("missing value for -o", err(&["main.ku", "-o"]));
+    }
+}
@@ -3,48 +3,126 @@
 // - operations are typed
 // - all symbols are already resolved
 // - lists of operations are associated with a function/closure definition
-//   - definitions have desugared signatures
-//
-// Literal format (for effect handlers and complex types):
-// - I have no idea :(
+//   - definitions have desugared signatures: a function that returns takes its continuation as
+//     its last parameter, and a closure always does
 //
 // Closure format:
 // - Closures can be suspended and resumed via their effect handlers
 // - Note that `let x = foo();` actually creates a *new* closure
+// - A closure's environment is the frame of the closure it is nested in (`parent`), so nested
+//   closures reach enclosing locals with `LoadOuter`
+// - Closure conversion can replace that environment with an explicit record of the values the
+//   closure and those nested in it read (`captures`), and lambda lifting passes them as parameters
+//   to closures that are only ever called by the closure that made them
+// - Bodies are in continuation-passing style: the operand stack is empty between statements, and
+//   every path ends in `Continue`, `Perform`, or `Handle`, so a frame is never resumed midway
+//
+// Continuation format:
+// - Continuations are closures made with `MakeCont`. They also remember the innermost handler
+//   frame they were made under, and invoking one unwinds the handler stack back to that frame
+// - A handler operation receives the captured handler frames and continuation as a resumption,
+//   which may be invoked any number of times; each invocation copies the frames
 //
 // Type format:
 // - TBD
 #![allow(dead_code)]
 
-use crate::symbol::SymbolKey;
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::{
+    builtin::Builtin,
+    cache::StringKey,
+    diagnostic::Diagnostics,
+    span::Span,
+    symbol::{SymbolKey, SymbolTable},
+};
+
+pub mod bytecode;
+pub mod c;
+pub mod convert;
+pub mod lower;
+pub mod opt;
+pub mod text;
+pub mod verify;
+pub mod wat;
 
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub enum Opcode {
     // -> Any
     LoadValue(Value),
     // -> Any
     LoadLocal(usize),
+    // -> Any
+    /// Loads a local from the frame `depth` closures out, where 0 is the current frame.
+    LoadOuter(usize, usize),
+    // Any ->
+    StoreLocal(usize),
     // Int, Int -> Int
     Add,
     // Int, Int -> Int
     Sub,
     // Int, Int -> Int
     Mul,
+    // Int, Int -> Int
+    Div,
+    // Int, Int -> Int
+    Rem,
+    // Int, Int -> Int
+    Eq,
+    // Int, Int -> Int
+    NotEq,
+    // Int, Int -> Int
+    Gt,
+    // Int, Int -> Int
+    Ge,
+    // Int, Int -> Int
+    Lt,
+    // Int, Int -> Int
+    Le,
     // Record -> Record.x
     /// Member access.
     Access(usize),
-    /// If-else branch with relative jumps
+    // Int ->
+    /// If-else branch with relative jumps, taking the first offset if the condition is nonzero.
     Branch(i32, i32),
+    // -> Closure
+    /// Creates a closure whose environment is the current frame.
+    MakeClosure(SymbolKey),
+    // -> Cont
+    /// Creates a continuation whose environment is the current frame.
+    MakeCont(SymbolKey),
+    // -> Handler
+    /// Creates an effect handler whose operations' environment is the current frame.
+    MakeHandler(SymbolKey),
+    // Handler, Body, Cont ->
+    /// Calls the body under the handler. The body receives a continuation that exits the handler
+    /// and continues with `Cont`.
+    Handle,
+    // Args..., [Cont] ->
+    /// Performs an effect operation with the given number of arguments. The continuation is absent
+    /// if the operation never returns.
+    Perform(SymbolKey, usize),
     // Args..., Cont ->
     Continue,
 }
 
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum Value {
+    /// The absence of a value.
+    Unit,
     /// Integer.
     Int(i64),
     /// Continuation/function/closure (they're all the same at this point).
     Cont(SymbolKey),
-    /// Effect handler.
-    Handler(/* oh lawdy */),
+    /// String, as an index into the program's strings.
+    String(usize),
+}
+
+/// A local variable slot.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Local {
+    /// The variable's name, or empty for temporaries.
+    pub name: StringKey,
 }
 
 // |x, y| {
@@ -57,33 +135,187 @@ pub enum Value {
 //   let z = x + y;   -- simple ops, no continuation desugaring
 //   foo(z, |w| { w + 1 -> k; });
 // }
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct Closure {
+    /// The closure whose frame is this closure's environment, if any.
+    pub parent: Option<SymbolKey>,
+    /// The number of parameters, which are the first locals.
+    pub params: usize,
+    /// The parameter holding the closure's continuation. `None` for continuations, which continue
+    /// with their parent's, and for functions that never return.
+    pub cont: Option<usize>,
+    /// Where the closure comes from: a function's name, the call a continuation receives the
+    /// results of, or the `with` of a `do ... with` body.
+    pub span: Span,
+    /// For closures whose environment is a record rather than their parent's frame, the values
+    /// copied into the record when the closure is made, as the depth and slot they're loaded from
+    /// there. `LoadOuter(1, i)` reads the `i`th. A closure that's never made, but called by name
+    /// with what it needs as arguments, has an empty record.
+    pub captures: Option<Vec<(usize, usize)>>,
     // includes parameters and simple `let`-bindings
-    locals: Vec</* typed value */ ()>,
-    code: Vec<Opcode>,
-}
-
-pub struct BoundClosure {
-    /// The closure's bound effect handler. (make this an index)
-    handler: Handler,
-    closure: Closure,
+    pub locals: Vec<Local>,
+    pub code: Vec<Opcode>,
+    /// For debuggers, the span of the statement each instruction was lowered from. Empty if
+    /// unknown.
+    pub spans: Vec<Span>,
 }
 
 // - Stores the effect handlers
-// - Stores the CC (return)
-// - Needs to store the finally
-// - Needs to know about the finallies of the other bound handlers
-// Handler stack? Handlers can reference the stack to get the finally...
-// Closure lifetimes ensure that nothing important is reachable outside of its home stack frame.
+// - Stores the CC (return), and the return clause that processes the values passed to it
+// - Stores the finally, which runs once when the handled scope is left: normally, by a continuation
+//   escaping past it, or by the handler not resuming
+// - The finallies of the other bound handlers live on the handler stack, which runs them innermost
+//   first when several scopes are left at once
+// What a closure reaches outside its own frame is explicit: loads from enclosing frames, or from its
+// record once converted. The verifier checks every such load resolves to a local of an enclosing
+// closure (`Program::origin`), and conversion only copies a local into a record when nothing can
+// store to it afterwards. Escape analysis keeps closures, continuations and handlers from outliving
+// the function that makes them.
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct Handler {
-    // Takes the next finally and continues with it
-    finally: Box<Closure>,
-    /// The return continuation, which processes the return value before the handler drops.
-    cc: Box<Closure>,
-    /// Handles declared effect operations.
-    actions: Vec<Closure>,
+    /// Handles declared effect operations, as pairs of the operation and the closure handling it.
+    /// The closure takes the operation's arguments, then the resumption (`return`), then the
+    /// handler's return continuation (`continue`).
+    pub actions: Vec<(SymbolKey, SymbolKey)>,
+    /// The operations whose actions are tail-resumptive: every path through them ends by resuming
+    /// with the operation's results alone, and nothing else they do can observe the handler stack.
+    /// Performing one calls the action with the operation's continuation as `return` rather than
+    /// capturing a resumption.
+    pub tail: Vec<SymbolKey>,
+    /// The return clause, which processes the handled computation's values before they reach the
+    /// `do ... with`. It takes the values, then the continuation.
+    pub ret: Option<SymbolKey>,
+    /// The closure run when the handled scope is left, taking only its continuation.
+    pub finally: Option<SymbolKey>,
 }
 
 impl Handler {
-    // need an empty "total" handler?
+    /// Gets the closure handling the given operation.
+    pub fn action(&self, op: SymbolKey) -> Option<SymbolKey> {
+        self.actions
+            .iter()
+            .find(|&&(key, _)| key == op)
+            .map(|&(_, action)| action)
+    }
+}
+
+/// A lowered program.
+#[derive(Clone, Debug)]
+pub struct Program {
+    pub(crate) table: SymbolTable,
+    pub(crate) closures: BTreeMap<SymbolKey, Closure>,
+    pub(crate) handlers: BTreeMap<SymbolKey, Handler>,
+    /// The contents of string literals.
+    pub(crate) strings: Vec<String>,
+    /// The built-in operations, which the runtime handles if the program doesn't.
+    pub(crate) builtins: BTreeMap<SymbolKey, Builtin>,
+    /// The signatures of operations, and of top-level functions declared without a body, which the
+    /// host may provide.
+    pub(crate) signatures: BTreeMap<SymbolKey, Signature>,
+    /// The qualified names of functions and operations, such as `choice::choose`.
+    pub(crate) names: BTreeMap<SymbolKey, String>,
+    /// The closures and handlers whose values don't escape the function making them, which the
+    /// backends allocate in the frame making them.
+    pub(crate) scoped: BTreeSet<SymbolKey>,
+}
+
+/// The signature of a function declared without a body.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Signature {
+    pub params: usize,
+    /// The number of values it returns with, or `None` if it never does.
+    pub returns: Option<usize>,
+}
+
+impl Program {
+    /// Whether a file holds a program encoded as bytecode rather than source.
+    pub fn is_bytecode(bytes: &[u8]) -> bool {
+        bytes.starts_with(&bytecode::MAGIC)
+    }
+
+    /// Runs optimization passes over the program. If they fail, the program is left as it was, and
+    /// the failure is reported in the diagnostics.
+    pub fn optimize(&mut self, passes: opt::Passes) -> Diagnostics {
+        let mut ds = Diagnostics::new();
+        opt::optimize(self, passes, &mut ds);
+        ds
+    }
+
+    /// Finds a top-level function by name.
+    pub(crate) fn function(&self, name: StringKey) -> Option<SymbolKey> {
+        self.table
+            .resolve(&[name], SymbolKey::ROOT)
+            .filter(|key| self.closures.contains_key(key))
+    }
+
+    /// Numbers the instructions of a closure that make scoped values, by their place in the space
+    /// the closure's frame reserves for them. If a branch leads back, an instruction could run more
+    /// than once in a frame, so nothing is numbered and the values are made on the heap.
+    pub(crate) fn frame_values(&self, closure: &Closure) -> BTreeMap<usize, usize> {
+        let loops = closure.code.iter().any(|op| match *op {
+            Opcode::Branch(then, other) => then <= 0 || other <= 0,
+            _ => false,
+        });
+        if loops {
+            return BTreeMap::new();
+        }
+        let scoped = closure.code.iter().enumerate().filter(|(_, op)| match op {
+            Opcode::MakeClosure(key) | Opcode::MakeCont(key) | Opcode::MakeHandler(key) => {
+                self.scoped.contains(key)
+            }
+            _ => false,
+        });
+        scoped.enumerate().map(|(idx, (pc, _))| (pc, idx)).collect()
+    }
+
+    /// Finds the local that a load from `depth` frames out of a closure's frame reads, as the
+    /// closure it belongs to and its slot.
+    pub(crate) fn origin(
+        &self,
+        key: SymbolKey,
+        depth: usize,
+        slot: usize,
+    ) -> Option<(SymbolKey, usize)> {
+        if depth == 0 {
+            return Some((key, slot));
+        }
+        let closure = self.closures.get(&key)?;
+        match &closure.captures {
+            None => self.origin(closure.parent?, depth - 1, slot),
+            Some(captures) if depth == 1 => {
+                let &(depth, slot) = captures.get(slot)?;
+                self.origin(closure.parent?, depth, slot)
+            }
+            Some(_) => None,
+        }
+    }
+
+    /// Finds where a closure's code can load a local of a closure it's nested in from, as the
+    /// depth and slot, if it can at all.
+    pub(crate) fn locate(
+        &self,
+        key: SymbolKey,
+        owner: SymbolKey,
+        slot: usize,
+    ) -> Option<(usize, usize)> {
+        if key == owner {
+            return Some((0, slot));
+        }
+        let closure = self.closures.get(&key)?;
+        let (depth, slot) = self.locate(closure.parent?, owner, slot)?;
+        match &closure.captures {
+            None => Some((depth + 1, slot)),
+            Some(captures) => captures
+                .iter()
+                .position(|&capture| capture == (depth, slot))
+                .map(|idx| (1, idx)),
+        }
+    }
+
+    /// Finds a function or operation by its qualified name.
+    pub(crate) fn symbol(&self, name: &str) -> Option<SymbolKey> {
+        self.names
+            .iter()
+            .find_map(|(&key, n)| (n == name).then_some(key))
+    }
 }
@@ -0,0 +1,830 @@
+//! The `.kuc` file format, for shipping compiled programs without their source.
+//!
+//! A file is a header followed by a body:
+//!
+//! - The magic bytes `KUC\0`, the format version as a little-endian `u16`, then the length of the
+//!   body and its FNV-1a hash as little-endian `u64`s.
+//! - The strings that name symbols and locals.
+//! - The symbol table: each symbol's context and name, in the order they were defined.
+//! - The program's string literals, built-in operations, signatures, and the symbols with names.
+//! - Closures with the records they capture, and handlers with their tail-resumptive operations.
+//! - The closures and handlers that are scoped to the frame making them.
+//!
+//! Numbers in the body are LEB128 varints, and signed ones are zigzag-encoded. Symbols are written
+//! as their index in the symbol table, where 0 is the root and stands for no symbol. An optional
+//! count such as a closure's continuation slot is written as 0 for none, or as the count plus 1.
+//! Spans aren't stored, since the source isn't shipped.
+//!
+//! Loading rejects anything that doesn't decode to exactly a well-formed program: a wrong hash,
+//! truncation, trailing bytes, handlers referring to closures or operations that don't exist, and
+//! code the [verifier](super::verify) rejects.
+
+use std::{
+    cmp::Ordering,
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fmt::{self, Display, Formatter},
+};
+
+use crate::{
+    builtin::Builtin,
+    cache::{StringCache, StringKey},
+    span::Span,
+    symbol::{SymbolKey, SymbolTable},
+};
+
+use super::{
+    verify::{verify, VerifyError},
+    Closure, Handler, Local, Opcode, Program, Signature, Value,
+};
+
+pub const MAGIC: [u8; 4] = *b"KUC\0";
+
+/// The version of the format this crate writes and reads.
+pub const VERSION: u16 = 1;
+
+const HEADER: usize = MAGIC.len() + 2 + 8 + 8;
+
+/// Why a file couldn't be loaded.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The file doesn't start with the magic bytes.
+    NotBytecode,
+    /// The file was written by an unsupported version of the format.
+    Version(u16),
+    /// The file ends early.
+    Truncated,
+    /// The body doesn't match its hash.
+    Checksum,
+    /// The body is malformed.
+    Invalid(&'static str),
+    /// The code is malformed.
+    Verify(VerifyError),
+}
+
+impl Display for DecodeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::NotBytecode => f.write_str("not a compiled program"),
+            DecodeError::Version(v) => write!(f, "unsupported bytecode version {v}"),
+            DecodeError::Truncated => f.write_str("truncated compiled program"),
+            DecodeError::Checksum => f.write_str("compiled program is corrupt"),
+            DecodeError::Invalid(why) => write!(f, "invalid compiled program: {why}"),
+            DecodeError::Verify(err) => write!(f, "invalid compiled program: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// Encodes a program compiled with the given cache.
+pub fn encode(program: &Program, cache: &StringCache) -> Vec<u8> {
+    let mut enc = Encoder {
+        out: Vec::new(),
+        symbols: HashMap::from([(SymbolKey::ROOT, 0)]),
+        strings: Vec::new(),
+        string_idx: HashMap::new(),
+    };
+    let table = &program.table;
+    for (idx, key) in table.keys().enumerate() {
+        enc.symbols.insert(key, idx + 1);
+    }
+
+    // names are gathered first so the string table can come before everything that uses it
+    let symbols = table
+        .keys()
+        .map(|key| (table.context(key), enc.string(table.string_key(key))))
+        .collect::<Vec<_>>();
+    let locals = program
+        .closures
+        .values()
+        .flat_map(|closure| &closure.locals)
+        .map(|local| enc.string(local.name))
+        .collect::<Vec<_>>();
+    let strings = std::mem::take(&mut enc.strings);
+    enc.len(strings.len());
+    for key in strings {
+        enc.str(&cache[key]);
+    }
+    enc.len(symbols.len());
+    for (context, name) in symbols {
+        enc.key(context);
+        enc.uint(name as u64);
+    }
+    let mut locals = locals.into_iter();
+
+    enc.len(program.strings.len());
+    for s in &program.strings {
+        enc.str(s);
+    }
+    enc.len(program.builtins.len());
+    for (&key, builtin) in &program.builtins {
+        enc.key(key);
+        let idx = Builtin::ALL.iter().position(|b| b == builtin);
+        enc.uint(idx.expect("listed built-in") as u64);
+    }
+    enc.len(program.signatures.len());
+    for (&key, signature) in &program.signatures {
+        enc.key(key);
+        enc.len(signature.params);
+        enc.option(signature.returns);
+    }
+    enc.len(program.names.len());
+    for &key in program.names.keys() {
+        enc.key(key);
+    }
+
+    enc.len(program.closures.len());
+    for (&key, closure) in &program.closures {
+        enc.key(key);
+        enc.key(closure.parent.unwrap_or(SymbolKey::ROOT));
+        enc.len(closure.params);
+        enc.option(closure.cont);
+        enc.option(closure.captures.as_ref().map(Vec::len));
+        for &(depth, slot) in closure.captures.iter().flatten() {
+            enc.len(depth);
+            enc.len(slot);
+        }
+        enc.len(closure.locals.len());
+        for name in locals.by_ref().take(closure.locals.len()) {
+            enc.uint(name as u64);
+        }
+        enc.len(closure.code.len());
+        for op in &closure.code {
+            enc.opcode(op);
+        }
+    }
+    enc.len(program.handlers.len());
+    for (&key, handler) in &program.handlers {
+        enc.key(key);
+        enc.len(handler.actions.len());
+        for &(op, action) in &handler.actions {
+            enc.key(op);
+            enc.key(action);
+        }
+        enc.len(handler.tail.len());
+        for &op in &handler.tail {
+            enc.key(op);
+        }
+        enc.key(handler.ret.unwrap_or(SymbolKey::ROOT));
+        enc.key(handler.finally.unwrap_or(SymbolKey::ROOT));
+    }
+    enc.len(program.scoped.len());
+    for &key in &program.scoped {
+        enc.key(key);
+    }
+
+    let mut out = Vec::with_capacity(HEADER + enc.out.len());
+    out.extend_from_slice(&MAGIC);
+    out.extend_from_slice(&VERSION.to_le_bytes());
+    out.extend_from_slice(&(enc.out.len() as u64).to_le_bytes());
+    out.extend_from_slice(&fnv1a(&enc.out).to_le_bytes());
+    out.extend_from_slice(&enc.out);
+    out
+}
+
+/// Decodes a program, interning its names in the cache.
+pub fn decode(bytes: &[u8], cache: &mut StringCache) -> Result<Program, DecodeError> {
+    if bytes.len() < MAGIC.len() || bytes[..MAGIC.len()] != MAGIC {
+        return Err(DecodeError::NotBytecode);
+    }
+    if bytes.len() < HEADER {
+        return Err(DecodeError::Truncated);
+    }
+    let version = u16::from_le_bytes([bytes[4], bytes[5]]);
+    if version != VERSION {
+        return Err(DecodeError::Version(version));
+    }
+    let len = u64::from_le_bytes(bytes[6..14].try_into().expect("8 bytes"));
+    let hash = u64::from_le_bytes(bytes[14..HEADER].try_into().expect("8 bytes"));
+    let body = &bytes[HEADER..];
+    match (body.len() as u64).cmp(&len) {
+        Ordering::Less => return Err(DecodeError::Truncated),
+        Ordering::Greater => return Err(DecodeError::Invalid("trailing bytes")),
+        Ordering::Equal if fnv1a(body) != hash => return Err(DecodeError::Checksum),
+        Ordering::Equal => {}
+    }
+    let mut dec = Decoder {
+        bytes: body,
+        pos: 0,
+        keys: vec![SymbolKey::ROOT],
+    };
+    let program = dec.program(cache)?;
+    if dec.pos != body.len() {
+        return Err(DecodeError::Invalid("trailing bytes"));
+    }
+    validate(&program)?;
+    verify(&program).map_err(DecodeError::Verify)?;
+    Ok(program)
+}
+
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &b| {
+        (hash ^ b as u64).wrapping_mul(0x100_0000_01b3)
+    })
+}
+
+struct Encoder {
+    out: Vec<u8>,
+    symbols: HashMap<SymbolKey, usize>,
+    /// The string table, and the index plus 1 of each string in it.
+    strings: Vec<StringKey>,
+    string_idx: HashMap<StringKey, usize>,
+}
+
+impl Encoder {
+    /// Adds a string to the string table, returning 0 for the empty string or its index plus 1.
+    fn string(&mut self, key: StringKey) -> usize {
+        if key == StringKey::EMPTY {
+            return 0;
+        }
+        *self.string_idx.entry(key).or_insert_with(|| {
+            self.strings.push(key);
+            self.strings.len()
+        })
+    }
+
+    fn uint(&mut self, mut v: u64) {
+        loop {
+            let byte = (v & 0x7f) as u8;
+            v >>= 7;
+            if v == 0 {
+                self.out.push(byte);
+                return;
+            }
+            self.out.push(byte | 0x80);
+        }
+    }
+
+    fn int(&mut self, v: i64) {
+        self.uint(((v << 1) ^ (v >> 63)) as u64);
+    }
+
+    fn len(&mut self, len: usize) {
+        self.uint(len as u64);
+    }
+
+    fn option(&mut self, v: Option<usize>) {
+        self.uint(v.map_or(0, |v| v as u64 + 1));
+    }
+
+    fn key(&mut self, key: SymbolKey) {
+        self.uint(self.symbols[&key] as u64);
+    }
+
+    fn str(&mut self, s: &str) {
+        self.len(s.len());
+        self.out.extend_from_slice(s.as_bytes());
+    }
+
+    fn opcode(&mut self, op: &Opcode) {
+        match *op {
+            Opcode::LoadValue(value) => {
+                self.out.push(0);
+                match value {
+                    Value::Unit => self.out.push(0),
+                    Value::Int(v) => {
+                        self.out.push(1);
+                        self.int(v);
+                    }
+                    Value::Cont(key) => {
+                        self.out.push(2);
+                        self.key(key);
+                    }
+                    Value::String(idx) => {
+                        self.out.push(3);
+                        self.len(idx);
+                    }
+                }
+            }
+            Opcode::LoadLocal(slot) => {
+                self.out.push(1);
+                self.len(slot);
+            }
+            Opcode::LoadOuter(depth, slot) => {
+                self.out.push(2);
+                self.len(depth);
+                self.len(slot);
+            }
+            Opcode::StoreLocal(slot) => {
+                self.out.push(3);
+                self.len(slot);
+            }
+            Opcode::Add => self.out.push(4),
+            Opcode::Sub => self.out.push(5),
+            Opcode::Mul => self.out.push(6),
+            Opcode::Div => self.out.push(7),
+            Opcode::Rem => self.out.push(8),
+            Opcode::Eq => self.out.push(9),
+            Opcode::NotEq => self.out.push(10),
+            Opcode::Gt => self.out.push(11),
+            Opcode::Ge => self.out.push(12),
+            Opcode::Lt => self.out.push(13),
+            Opcode::Le => self.out.push(14),
+            Opcode::Access(idx) => {
+                self.out.push(15);
+                self.len(idx);
+            }
+            Opcode::Branch(then, other) => {
+                self.out.push(16);
+                self.int(then as i64);
+                self.int(other as i64);
+            }
+            Opcode::MakeClosure(key) => {
+                self.out.push(17);
+                self.key(key);
+            }
+            Opcode::MakeCont(key) => {
+                self.out.push(18);
+                self.key(key);
+            }
+            Opcode::MakeHandler(key) => {
+                self.out.push(19);
+                self.key(key);
+            }
+            Opcode::Handle => self.out.push(20),
+            Opcode::Perform(op, args) => {
+                self.out.push(21);
+                self.key(op);
+                self.len(args);
+            }
+            Opcode::Continue => self.out.push(22),
+        }
+    }
+}
+
+/// A body that ends early despite matching its length and hash was written that way.
+const END: DecodeError = DecodeError::Invalid("unexpected end of data");
+
+struct Decoder<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    /// The symbols defined so far, by index.
+    keys: Vec<SymbolKey>,
+}
+
+impl Decoder<'_> {
+    fn byte(&mut self) -> Result<u8, DecodeError> {
+        let byte = *self.bytes.get(self.pos).ok_or(END)?;
+        self.pos += 1;
+        Ok(byte)
+    }
+
+    fn uint(&mut self) -> Result<u64, DecodeError> {
+        let mut v = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            let bits = (byte & 0x7f) as u64;
+            if shift == 63 && bits > 1 {
+                return Err(DecodeError::Invalid("number out of range"));
+            }
+            v |= bits << shift;
+            if byte & 0x80 == 0 {
+                return Ok(v);
+            }
+        }
+        Err(DecodeError::Invalid("number out of range"))
+    }
+
+    fn int(&mut self) -> Result<i64, DecodeError> {
+        let v = self.uint()?;
+        Ok((v >> 1) as i64 ^ -((v & 1) as i64))
+    }
+
+    fn usize(&mut self) -> Result<usize, DecodeError> {
+        usize::try_from(self.uint()?).map_err(|_| DecodeError::Invalid("number out of range"))
+    }
+
+    /// Reads the length of a list whose elements each take at least one byte, so that a bogus
+    /// length fails before anything is allocated for it.
+    fn len(&mut self) -> Result<usize, DecodeError> {
+        let len = self.usize()?;
+        if len > self.bytes.len() - self.pos {
+            return Err(END);
+        }
+        Ok(len)
+    }
+
+    fn option(&mut self) -> Result<Option<usize>, DecodeError> {
+        Ok(self.usize()?.checked_sub(1))
+    }
+
+    /// Reads a symbol, which may be the root.
+    fn symbol(&mut self) -> Result<SymbolKey, DecodeError> {
+        let idx = self.usize()?;
+        self.keys
+            .get(idx)
+            .copied()
+            .ok_or(DecodeError::Invalid("undefined symbol"))
+    }
+
+    fn key(&mut self) -> Result<SymbolKey, DecodeError> {
+        match self.symbol()? {
+            SymbolKey::ROOT => Err(DecodeError::Invalid("missing symbol")),
+            key => Ok(key),
+        }
+    }
+
+    fn optional_key(&mut self) -> Result<Option<SymbolKey>, DecodeError> {
+        Ok(Some(self.symbol()?).filter(|&key| key != SymbolKey::ROOT))
+    }
+
+    fn str(&mut self) -> Result<String, DecodeError> {
+        let len = self.len()?;
+        let bytes = &self.bytes[self.pos..self.pos + len];
+        self.pos += len;
+        String::from_utf8(bytes.to_vec()).map_err(|_| DecodeError::Invalid("invalid UTF-8"))
+    }
+
+    fn program(&mut self, cache: &mut StringCache) -> Result<Program, DecodeError> {
+        let count = self.len()?;
+        let mut strings = vec![StringKey::EMPTY];
+        for _ in 0..count {
+            let s = self.str()?;
+            strings.push(cache.intern(&s));
+        }
+        let string = |dec: &mut Self| {
+            let idx = dec.usize()?;
+            strings
+                .get(idx)
+                .copied()
+                .ok_or(DecodeError::Invalid("undefined string"))
+        };
+
+        let mut table = SymbolTable::new();
+        for _ in 0..self.len()? {
+            let context = self.symbol()?;
+            let key = match string(self)? {
+                StringKey::EMPTY => table.define_anonymous(context),
+                name => table
+                    .define(name, context)
+                    .ok_or(DecodeError::Invalid("duplicate symbol"))?,
+            };
+            self.keys.push(key);
+        }
+
+        let mut literals = Vec::new();
+        for _ in 0..self.len()? {
+            literals.push(self.str()?);
+        }
+        let mut builtins = BTreeMap::new();
+        for _ in 0..self.len()? {
+            let key = self.key()?;
+            let builtin = *Builtin::ALL
+                .get(self.usize()?)
+                .ok_or(DecodeError::Invalid("unknown built-in"))?;
+            builtins.insert(key, builtin);
+        }
+        let mut signatures = BTreeMap::new();
+        for _ in 0..self.len()? {
+            let key = self.key()?;
+            let params = self.usize()?;
+            let returns = self.option()?;
+            signatures.insert(key, Signature { params, returns });
+        }
+        let mut names = BTreeMap::new();
+        for _ in 0..self.len()? {
+            let key = self.key()?;
+            let path = table.path(key);
+            let path = path.iter().map(|&s| &cache[s]).collect::<Vec<_>>();
+            names.insert(key, path.join("::"));
+        }
+
+        let mut closures = BTreeMap::new();
+        for _ in 0..self.len()? {
+            let key = self.key()?;
+            let parent = self.optional_key()?;
+            let params = self.usize()?;
+            let cont = self.option()?;
+            let captures = match self.option()? {
+                Some(count) => {
+                    let mut captures = Vec::new();
+                    for _ in 0..count {
+                        captures.push((self.usize()?, self.usize()?));
+                    }
+                    Some(captures)
+                }
+                None => None,
+            };
+            let mut locals = Vec::new();
+            for _ in 0..self.len()? {
+                locals.push(Local {
+                    name: string(self)?,
+                });
+            }
+            let mut code = Vec::new();
+            for _ in 0..self.len()? {
+                code.push(self.opcode()?);
+            }
+            let closure = Closure {
+                parent,
+                params,
+                cont,
+                span: Span::default(),
+                captures,
+                locals,
+                code,
+                spans: Vec::new(),
+            };
+            if closures.insert(key, closure).is_some() {
+                return Err(DecodeError::Invalid("duplicate closure"));
+            }
+        }
+        let mut handlers = BTreeMap::new();
+        for _ in 0..self.len()? {
+            let key = self.key()?;
+            let mut actions = Vec::new();
+            for _ in 0..self.len()? {
+                actions.push((self.key()?, self.key()?));
+            }
+            let mut tail = Vec::new();
+            for _ in 0..self.len()? {
+                tail.push(self.key()?);
+            }
+            let handler = Handler {
+                actions,
+                tail,
+                ret: self.optional_key()?,
+                finally: self.optional_key()?,
+            };
+            if handlers.insert(key, handler).is_some() {
+                return Err(DecodeError::Invalid("duplicate handler"));
+            }
+        }
+        let mut scoped = BTreeSet::new();
+        for _ in 0..self.len()? {
+            scoped.insert(self.key()?);
+        }
+        Ok(Program {
+            table,
+            closures,
+            handlers,
+            strings: literals,
+            builtins,
+            signatures,
+            names,
+            scoped,
+        })
+    }
+
+    fn opcode(&mut self) -> Result<Opcode, DecodeError> {
+        let op = match self.byte()? {
+            0 => Opcode::LoadValue(match self.byte()? {
+                0 => Value::Unit,
+                1 => Value::Int(self.int()?),
+                2 => Value::Cont(self.key()?),
+                3 => Value::String(self.usize()?),
+                _ => return Err(DecodeError::Invalid("unknown value")),
+            }),
+            1 => Opcode::LoadLocal(self.usize()?),
+            2 => Opcode::LoadOuter(self.usize()?, self.usize()?),
+            3 => Opcode::StoreLocal(self.usize()?),
+            4 => Opcode::Add,
+            5 => Opcode::Sub,
+            6 => Opcode::Mul,
+            7 => Opcode::Div,
+            8 => Opcode::Rem,
+            9 => Opcode::Eq,
+            10 => Opcode::NotEq,
+            11 => Opcode::Gt,
+            12 => Opcode::Ge,
+            13 => Opcode::Lt,
+            14 => Opcode::Le,
+            15 => Opcode::Access(self.usize()?),
+            16 => {
+                let offset = |dec: &mut Self| {
+                    i32::try_from(dec.int()?)
+                        .map_err(|_| DecodeError::Invalid("branch out of range"))
+                };
+                Opcode::Branch(offset(self)?, offset(self)?)
+            }
+            17 => Opcode::MakeClosure(self.key()?),
+            18 => Opcode::MakeCont(self.key()?),
+            19 => Opcode::MakeHandler(self.key()?),
+            20 => Opcode::Handle,
+            21 => Opcode::Perform(self.key()?, self.usize()?),
+            22 => Opcode::Continue,
+            _ => return Err(DecodeError::Invalid("unknown instruction")),
+        };
+        Ok(op)
+    }
+}
+
+/// Checks that the handlers and built-ins a program refers to exist. The verifier checks the code.
+fn validate(program: &Program) -> Result<(), DecodeError> {
+    let closure = |key: SymbolKey| {
+        program
+            .closures
+            .contains_key(&key)
+            .then_some(())
+            .ok_or(DecodeError::Invalid("undefined closure"))
+    };
+    let signature = |key: SymbolKey| {
+        program
+            .signatures
+            .contains_key(&key)
+            .then_some(())
+            .ok_or(DecodeError::Invalid("undefined signature"))
+    };
+    for key in program.builtins.keys() {
+        signature(*key)?;
+    }
+    for handler in program.handlers.values() {
+        for &(op, action) in &handler.actions {
+            signature(op)?;
+            closure(action)?;
+        }
+        for key in handler.ret.into_iter().chain(handler.finally) {
+            closure(key)?;
+        }
+    }
+    for key in &program.scoped {
+        if !program.closures.contains_key(key) && !program.handlers.contains_key(key) {
+            return Err(DecodeError::Invalid("undefined scoped closure or handler"));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        mir::{
+            text::{assemble, disassemble},
+            verify::Problem,
+        },
+        runtime::Captured,
+        Session,
+    };
+
+    use super::*;
+
+    const SRC: &str = r#"
+effect choice {
+    fn choose() -> Int;
+}
+
+fn pick(n: Int) -> Int = {
+    let k: Int = if n > 0 { n } else { 0 - n };
+    k * 2
+}
+
+fn main() -> Int = {
+    print("héllo");
+    let x: Int = pick(3);
+    do {
+        let a: Int = choose();
+        a + x
+    } with handle choice {
+        fn choose() -> Int = {
+            let r: Int = return(10);
+            r + 1
+        }
+        finally { print("bye"); }
+    }
+}
+"#;
+
+    fn compile() -> (Session, Program) {
+        let mut session = Session::new();
+        let compilation = session.compile("main.ku", SRC);
+        let program = compilation.program.expect("program");
+        (session, program)
+    }
+
+    #[test]
+    fn round_trips() {
+        let (session, program) = compile();
+        let bytes = encode(&program, session.cache());
+
+        // a fresh session has none of the names, as when running a shipped file
+        let mut fresh = Session::new();
+        let loaded = decode(&bytes, fresh.cache_mut()).expect("decode");
+        assert_eq!(
+            disassemble(&program, session.cache()),
+            disassemble(&loaded, fresh.cache())
+        );
+        let mut console = Captured::default();
+        let machine = fresh.machine(&loaded, "main", Vec::new()).expect("main");
+        let values = machine.with_console(&mut console).run().expect("run");
+        assert_eq!(
+            vec!["17"],
+            values.iter().map(|v| v.to_string()).collect::<Vec<_>>()
+        );
+        assert_eq!(vec!["héllo", "bye"], console.output);
+    }
+
+    #[test]
+    fn rejects_truncated() {
+        let (session, program) = compile();
+        let bytes = encode(&program, session.cache());
+        for len in 0..bytes.len() {
+            let result = decode(&bytes[..len], &mut StringCache::new());
+            let err = result.expect_err("truncated");
+            assert!(
+                matches!(err, DecodeError::Truncated | DecodeError::NotBytecode),
+                "{len}: {err}"
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_tampered() {
+        let (session, program) = compile();
+        let bytes = encode(&program, session.cache());
+        for idx in HEADER..bytes.len() {
+            let mut tampered = bytes.clone();
+            tampered[idx] ^= 0x10;
+            let result = decode(&tampered, &mut StringCache::new());
+            assert_eq!(Some(DecodeError::Checksum), result.err(), "{idx}");
+        }
+        let mut longer = bytes.clone();
+        longer.push(0);
+        assert!(decode(&longer, &mut StringCache::new()).is_err());
+
+        let mut version = bytes.clone();
+        version[4] = 9;
+        let result = decode(&version, &mut StringCache::new());
+        assert_eq!(Some(DecodeError::Version(9)), result.err());
+        assert_eq!(
+            Some(DecodeError::NotBytecode),
+            decode(SRC.as_bytes(), &mut StringCache::new()).err()
+        );
+    }
+
+    #[test]
+    fn validates_references() {
+        let (session, mut program) = compile();
+        let handler = *program.handlers.keys().next().expect("handler");
+        let action = program.handlers[&handler].actions[0].1;
+        program.closures.remove(&action);
+        let bytes = encode(&program, session.cache());
+        let result = decode(&bytes, &mut StringCache::new());
+        assert_eq!(
+            Some(DecodeError::Invalid("undefined closure")),
+            result.err()
+        );
+    }
+
+    #[test]
+    fn verifies_code() {
+        let (session, mut program) = compile();
+        let main = program.function(session.cache().find("main").expect("main"));
+        let code = &mut program
+            .closures
+            .get_mut(&main.expect("main"))
+            .expect("main")
+            .code;
+        code.insert(0, Opcode::Add);
+        let bytes = encode(&program, session.cache());
+        let result = decode(&bytes, &mut StringCache::new());
+        assert!(
+            matches!(
+                result,
+                Err(DecodeError::Verify(VerifyError {
+                    pc: 0,
+                    problem: Problem::Underflow,
+                    ..
+                }))
+            ),
+            "{result:?}"
+        );
+    }
+
+    #[test]
+    fn verifies_nesting() {
+        // `main` makes a closure nested in `other`, which would read `main`'s frame as `other`'s
+        let text = r#"
+closure main params 1 cont 0
+  locals k
+  make_closure other#0
+  load_local 0
+  continue
+
+closure other params 1 cont 0
+  locals k x
+  load_local 0
+  continue
+
+closure other#0 in other params 1 cont 0
+  locals r
+  load_outer 1 1
+  load_local 0
+  continue
+"#;
+        let mut cache = StringCache::new();
+        let program = assemble(text, &mut cache).expect("assemble");
+        let bytes = encode(&program, &cache);
+        let result = decode(&bytes, &mut StringCache::new());
+        assert!(
+            matches!(
+                result,
+                Err(DecodeError::Verify(VerifyError {
+                    pc: 0,
+                    problem: Problem::Nesting,
+                    ..
+                }))
+            ),
+            "{result:?}"
+        );
+    }
+}
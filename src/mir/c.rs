@@ -0,0 +1,405 @@
+//! C code generation, for compiling programs ahead of time.
+//!
+//! The output is a single portable C99 file: the runtime in `c/runtime.c`, then the program. Each
+//! closure becomes a C function that runs its body on a fixed-size operand array and ends by
+//! handing the runtime the value to call next, so calls, continuations and resumptions all go
+//! through the runtime's trampoline and never grow the C stack. Frames are heap-allocated, as in the
+//! interpreter, and the runtime collects them along with closures, handlers and resumptions. Scoped
+//! closures and handlers, which don't escape the function making them, are allocated in the frame
+//! of the body making them rather than on their own.
+//!
+//! The runtime follows the interpreter's semantics, except that there's no host: functions declared
+//! without a body fail when called, and built-in operations use stdio. Runtime errors are printed
+//! to stderr, naming symbols as the [text form](super::text) does, and exit with status 1.
+
+use std::{collections::HashMap, fmt::Write};
+
+use crate::{builtin::Builtin, symbol::SymbolKey};
+
+use super::{opt::depths, text::Names, Closure, Opcode, Program, Value};
+
+const RUNTIME: &str = include_str!("c/runtime.c");
+
+/// Emits a C program that runs `entry` and prints the values it finishes with.
+pub fn emit(program: &Program, entry: SymbolKey) -> String {
+    let emitter = Emitter::new(program);
+    let mut out = String::from(RUNTIME);
+    out += "\n/* The program */\n\n";
+    emitter.declarations(&mut out);
+    for (&key, closure) in &program.closures {
+        emitter.body(&mut out, key, closure);
+    }
+    let strings = program.strings.len();
+    out += "static const KuProgram ku_compiled = {\n";
+    out += &format!("    ku_closures, ku_handlers, ku_ops, ku_strings, {strings},\n}};\n\n");
+    out += "int main(void) {\n";
+    out += &format!(
+        "    return ku_run(&ku_compiled, {});\n}}\n",
+        emitter.closures[&entry]
+    );
+    out
+}
+
+struct Emitter<'a> {
+    program: &'a Program,
+    names: Names<'a>,
+    /// The index of each value that can be called, in the closure table. Closures come first,
+    /// then functions without a body.
+    closures: HashMap<SymbolKey, usize>,
+    callable: Vec<SymbolKey>,
+    handlers: HashMap<SymbolKey, usize>,
+    /// The index of each operation in the operation table.
+    ops: HashMap<SymbolKey, usize>,
+    op_keys: Vec<SymbolKey>,
+}
+
+impl<'a> Emitter<'a> {
+    fn new(program: &'a Program) -> Self {
+        let mut callable = program.closures.keys().copied().collect::<Vec<_>>();
+        let mut op_keys = program.signatures.keys().copied().collect::<Vec<_>>();
+        let code = program.closures.values().flat_map(|closure| &closure.code);
+        for op in code {
+            match *op {
+                Opcode::LoadValue(Value::Cont(key)) if !callable.contains(&key) => {
+                    callable.push(key);
+                }
+                Opcode::Perform(op, _) if !op_keys.contains(&op) => op_keys.push(op),
+                _ => {}
+            }
+        }
+        for &(op, _) in program.handlers.values().flat_map(|h| &h.actions) {
+            if !op_keys.contains(&op) {
+                op_keys.push(op);
+            }
+        }
+        let index = |keys: &[SymbolKey]| {
+            keys.iter()
+                .enumerate()
+                .map(|(idx, &key)| (key, idx))
+                .collect()
+        };
+        Emitter {
+            program,
+            names: Names::new(program),
+            closures: index(&callable),
+            callable,
+            handlers: index(&program.handlers.keys().copied().collect::<Vec<_>>()),
+            ops: index(&op_keys),
+            op_keys,
+        }
+    }
+
+    /// Writes the prototypes of the bodies and the program's tables.
+    fn declarations(&self, out: &mut String) {
+        for (idx, &key) in self.callable.iter().enumerate() {
+            if self.program.closures.contains_key(&key) {
+                out.push_str(&format!("static void ku_c{idx}(KuEnv *env);\n"));
+            }
+        }
+        out.push('\n');
+
+        let mut rows = Vec::new();
+        for (idx, &key) in self.callable.iter().enumerate() {
+            let name = literal(self.names.label(key).as_bytes());
+            rows.push(match self.program.closures.get(&key) {
+                Some(closure) => format!(
+                    "{{{name}, ku_c{idx}, {}, {}, {}}}",
+                    closure.params,
+                    closure.locals.len(),
+                    self.program.frame_values(closure).len()
+                ),
+                None => format!("{{{name}, NULL, 0, 0, 0}}"),
+            });
+        }
+        table(out, "KuClosureInfo", "ku_closures", &rows);
+
+        let mut rows = Vec::new();
+        for (idx, handler) in self.program.handlers.values().enumerate() {
+            let actions = handler
+                .actions
+                .iter()
+                .map(|&(op, action)| {
+                    let tail = handler.tail.contains(&op) as u8;
+                    format!("{{{}, {}, {tail}}}", self.ops[&op], self.closures[&action])
+                })
+                .collect::<Vec<_>>();
+            let array = format!("ku_actions{idx}");
+            table(out, "KuAction", &array, &actions);
+            let clause = |clause: Option<SymbolKey>| {
+                clause.map_or("-1".to_owned(), |key| self.closures[&key].to_string())
+            };
+            rows.push(format!(
+                "{{{array}, {}, {}, {}}}",
+                actions.len(),
+                clause(handler.ret),
+                clause(handler.finally)
+            ));
+        }
+        table(out, "KuHandlerInfo", "ku_handlers", &rows);
+
+        let mut rows = Vec::new();
+        for &op in &self.op_keys {
+            let name = literal(self.names.label(op).as_bytes());
+            let returns = self.program.signatures.get(&op).and_then(|s| s.returns);
+            let builtin = match self.program.builtins.get(&op) {
+                None => "KU_BUILTIN_NONE",
+                Some(Builtin::Print) => "KU_BUILTIN_PRINT",
+                Some(Builtin::ReadLine) => "KU_BUILTIN_READ_LINE",
+            };
+            rows.push(format!("{{{name}, {}, {builtin}}}", returns.unwrap_or(0)));
+        }
+        table(out, "KuOpInfo", "ku_ops", &rows);
+
+        let rows = self
+            .program
+            .strings
+            .iter()
+            .map(|s| format!("{{{}, {}}}", literal(s.as_bytes()), s.len()))
+            .collect::<Vec<_>>();
+        table(out, "KuLiteral", "ku_strings", &rows);
+    }
+
+    fn body(&self, out: &mut String, key: SymbolKey, closure: &Closure) {
+        let idx = self.closures[&key];
+        let code = &closure.code;
+        let depths = depths(code);
+        let mut targets = vec![false; code.len() + 1];
+        for (pc, op) in code.iter().enumerate() {
+            if let (Opcode::Branch(then, other), Some(_)) = (op, depths[pc]) {
+                for offset in [then, other] {
+                    targets[target(pc, *offset)] = true;
+                }
+            }
+        }
+        let size = depths.iter().flatten().max().map_or(0, |depth| depth + 1);
+        let stack = if size > 0 { "s" } else { "NULL" };
+        let scoped = self.program.frame_values(closure);
+
+        let _ = writeln!(out, "/* {} */", self.names.label(key));
+        let _ = writeln!(out, "static void ku_c{idx}(KuEnv *env) {{");
+        if size > 0 {
+            let _ = writeln!(out, "    KuValue s[{size}];");
+        }
+        out.push_str("    (void)env;\n");
+        for (pc, op) in code.iter().enumerate() {
+            let Some(d) = depths[pc] else {
+                continue;
+            };
+            if targets[pc] {
+                let _ = writeln!(out, "L{pc}:");
+            }
+            let binary = |f: &str| format!("s[{}] = {f}(s[{}], s[{}]);", d - 2, d - 2, d - 1);
+            let arith =
+                |op: &str| format!("s[{}] = ku_arith({op}, s[{}], s[{}]);", d - 2, d - 2, d - 1);
+            let equal =
+                |eq: u8| format!("s[{}] = ku_equal(s[{}], s[{}], {eq});", d - 2, d - 2, d - 1);
+            let line = match *op {
+                Opcode::LoadValue(value) => format!("s[{d}] = {};", self.value(value)),
+                Opcode::LoadLocal(slot) => format!("s[{d}] = env->slots[{slot}];"),
+                Opcode::LoadOuter(depth, slot) => {
+                    format!("s[{d}] = ku_outer(env, {depth}, {slot});")
+                }
+                Opcode::StoreLocal(slot) => format!("env->slots[{slot}] = s[{}];", d - 1),
+                Opcode::Add => binary("ku_add"),
+                Opcode::Sub => arith("KU_SUB"),
+                Opcode::Mul => arith("KU_MUL"),
+                Opcode::Div => arith("KU_DIV"),
+                Opcode::Rem => arith("KU_REM"),
+                Opcode::Eq => equal(1),
+                Opcode::NotEq => equal(0),
+                Opcode::Gt => arith("KU_GT"),
+                Opcode::Ge => arith("KU_GE"),
+                Opcode::Lt => arith("KU_LT"),
+                Opcode::Le => arith("KU_LE"),
+                Opcode::Access(_) => "ku_unsupported(\"member access\");".to_owned(),
+                Opcode::Branch(then, other) => format!(
+                    "if (ku_truth(s[{}])) goto L{}; else goto L{};",
+                    d - 1,
+                    target(pc, then),
+                    target(pc, other)
+                ),
+                Opcode::MakeClosure(key) | Opcode::MakeCont(key) => {
+                    let (idx, env) = (self.closures[&key], self.environment(key));
+                    let cont = matches!(op, Opcode::MakeCont(_)) as u8;
+                    match scoped.get(&pc) {
+                        Some(n) => {
+                            format!("s[{d}] = ku_scoped_closure(env, {n}, {idx}, {env}, {cont});")
+                        }
+                        None => format!("s[{d}] = ku_closure({idx}, {env}, {cont});"),
+                    }
+                }
+                Opcode::MakeHandler(key) => match scoped.get(&pc) {
+                    Some(n) => format!(
+                        "s[{d}] = ku_scoped_handler(env, {n}, {});",
+                        self.handlers[&key]
+                    ),
+                    None => format!("s[{d}] = ku_handler({}, env);", self.handlers[&key]),
+                },
+                Opcode::Handle => format!(
+                    "ku_handle(s[{}], s[{}], s[{}]);\n    return;",
+                    d - 3,
+                    d - 2,
+                    d - 1
+                ),
+                Opcode::Perform(op, arity) => format!(
+                    "ku_perform({}, {arity}, {stack}, {d}); /* {} */\n    return;",
+                    self.ops[&op],
+                    self.names.label(op)
+                ),
+                Opcode::Continue => format!("ku_continue({stack}, {d});\n    return;"),
+            };
+            let _ = writeln!(out, "    {line}");
+        }
+        if targets[code.len()] {
+            let _ = writeln!(out, "L{}:", code.len());
+        }
+        let _ = writeln!(out, "    ku_fell_off({idx});\n}}\n");
+    }
+
+    fn value(&self, value: Value) -> String {
+        match value {
+            Value::Unit => "ku_unit".to_owned(),
+            Value::Int(i64::MIN) => "ku_int_value(INT64_MIN)".to_owned(),
+            Value::Int(v) => format!("ku_int_value(INT64_C({v}))"),
+            Value::Cont(key) => format!(
+                "ku_function({}) /* {} */",
+                self.closures[&key],
+                self.names.label(key)
+            ),
+            Value::String(idx) => format!("ku_literal({idx})"),
+        }
+    }
+
+    /// The environment a closure made in the current frame gets.
+    fn environment(&self, key: SymbolKey) -> String {
+        let captures = self
+            .program
+            .closures
+            .get(&key)
+            .and_then(|c| c.captures.as_ref());
+        match captures {
+            None => "env".to_owned(),
+            Some(captures) if captures.is_empty() => "NULL".to_owned(),
+            Some(captures) => {
+                let pairs = captures
+                    .iter()
+                    .map(|(depth, slot)| format!("{depth}, {slot}"))
+                    .collect::<Vec<_>>();
+                format!(
+                    "ku_record(env, {}, (const size_t[]){{{}}})",
+                    captures.len(),
+                    pairs.join(", ")
+                )
+            }
+        }
+    }
+}
+
+fn target(pc: usize, offset: i32) -> usize {
+    pc.wrapping_add_signed(offset as isize)
+}
+
+/// Writes a constant array. C has no empty arrays, so an empty one gets a zeroed element.
+fn table(out: &mut String, ty: &str, name: &str, rows: &[String]) {
+    let _ = writeln!(out, "static const {ty} {name}[] = {{");
+    if rows.is_empty() {
+        out.push_str("    {0},\n");
+    }
+    for row in rows {
+        let _ = writeln!(out, "    {row},");
+    }
+    out.push_str("};\n\n");
+}
+
+/// Writes bytes as a C string literal.
+fn literal(bytes: &[u8]) -> String {
+    let mut out = String::from("\"");
+    for &byte in bytes {
+        match byte {
+            b'"' | b'\\' => {
+                out.push('\\');
+                out.push(byte as char);
+            }
+            // `?` could start a trigraph
+            b' '..=b'~' if byte != b'?' => out.push(byte as char),
+            _ => out += &format!("\\{byte:03o}"),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::Path, process::Command};
+
+    use crate::{mir::opt::Passes, runtime::Captured, Session};
+
+    use super::*;
+
+    /// Runs a program's `main` in the interpreter and as a compiled C program, returning what each
+    /// printed. `None` if the program has no `main`.
+    fn outputs(src: &str, dir: &Path, name: &str) -> Option<(Vec<String>, Vec<String>)> {
+        let mut session = Session::new();
+        let compilation = session.compile(name, src);
+        let mut program = compilation.program.expect("program");
+        let main = session
+            .cache()
+            .find("main")
+            .and_then(|main| program.function(main))?;
+        let mut console = Captured::default();
+        let machine = session.machine(&program, "main", Vec::new()).expect("main");
+        let values = machine.with_console(&mut console).run().expect("run");
+        let mut interpreted = console.output;
+        interpreted.extend(values.iter().map(|v| v.to_string()));
+
+        let ds = program.optimize(Passes::level(2));
+        assert!(ds.is_empty(), "{ds:?}");
+        let source = dir.join(format!("{name}.c"));
+        let binary = dir.join(name);
+        std::fs::write(&source, emit(&program, main)).expect("write C");
+        let status = Command::new("cc")
+            .arg("-std=c99")
+            .arg(&source)
+            .arg("-o")
+            .arg(&binary)
+            .status()
+            .expect("run cc");
+        assert!(status.success(), "{name} didn't compile");
+        let output = Command::new(&binary).output().expect("run binary");
+        assert!(output.status.success(), "{name} failed");
+        let compiled = String::from_utf8(output.stdout).expect("UTF-8 output");
+        let compiled = compiled.lines().map(str::to_owned).collect();
+        Some((interpreted, compiled))
+    }
+
+    #[test]
+    #[ignore = "needs a C compiler named `cc`"]
+    fn matches_interpreter() {
+        let dir = std::env::temp_dir().join(format!("korou-c-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).expect("temp dir");
+        let examples = Path::new(env!("CARGO_MANIFEST_DIR")).join("korou-examples");
+        let mut entries = std::fs::read_dir(examples)
+            .expect("examples")
+            .map(|entry| entry.expect("example").path())
+            .collect::<Vec<_>>();
+        entries.sort();
+        let mut compiled = 0;
+        for path in entries {
+            let name = path.file_stem().expect("file name").to_string_lossy();
+            let src = std::fs::read_to_string(&path).expect("read example");
+            if let Some((expected, found)) = outputs(&src, &dir, &name) {
+                assert_eq!(expected, found, "{name}");
+                compiled += 1;
+            }
+        }
+        std::fs::remove_dir_all(&dir).expect("remove temp dir");
+        assert!(compiled >= 3);
+    }
+
+    #[test]
+    fn escapes_literals() {
+        assert_eq!(r#""a\"b\\c""#, literal(b"a\"b\\c"));
+        assert_eq!(r#""\077\077=\012\303\251""#, literal("??=\né".as_bytes()));
+    }
+}
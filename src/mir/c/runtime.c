@@ -0,0 +1,923 @@
+/*
+ * The Korou C runtime, which compiled programs are emitted after.
+ *
+ * It follows the MIR interpreter: bodies run on a trampoline, each ending by handing the runtime
+ * the value to call next, and the only other state is the handler stack. Frames, closures,
+ * handlers, resumptions and strings live on a heap that is collected by mark and sweep between
+ * bodies, when the only roots are the frame about to run and the handler stack.
+ *
+ * Closures and handlers that don't escape the function making them are allocated in the frame of
+ * the body making them instead, after its slots. They have no size of their own, and their `next`
+ * is that frame, which is marked in their place and traces them.
+ */
+
+#include <inttypes.h>
+#include <stdarg.h>
+#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+typedef enum {
+    KU_UNIT,
+    KU_INT,
+    KU_STRING,
+    KU_FUNCTION,
+    KU_CLOSURE,
+    KU_HANDLER,
+    KU_EXIT,
+    KU_RESUME,
+    KU_THEN,
+    KU_HALT
+} KuKind;
+
+typedef enum {
+    KU_OBJ_STRING,
+    KU_OBJ_ENV,
+    KU_OBJ_CLOSURE,
+    KU_OBJ_HANDLER,
+    KU_OBJ_RESUMPTION,
+    KU_OBJ_PENDING,
+    KU_OBJ_FLAG
+} KuObjType;
+
+typedef struct KuObj {
+    struct KuObj *next;
+    size_t size;
+    unsigned char type;
+    unsigned char marked;
+} KuObj;
+
+typedef struct {
+    KuKind kind;
+    union {
+        int64_t i;
+        KuObj *obj;
+    } as;
+} KuValue;
+
+typedef struct {
+    KuObj obj;
+    size_t len;
+    char data[];
+} KuString;
+
+/* The locals of a closure invocation, or a converted closure's record. */
+typedef struct KuEnv {
+    KuObj obj;
+    struct KuEnv *parent;
+    size_t len;
+    /* The number of scoped values allocated after the slots. */
+    size_t scoped;
+    KuValue slots[];
+} KuEnv;
+
+typedef struct {
+    KuObj obj;
+    size_t key;
+    KuEnv *env;
+    /* For continuations, the ID of the handler frame that was innermost when it was made. */
+    int cont;
+    uint64_t prompt;
+} KuClosureValue;
+
+typedef struct {
+    KuObj obj;
+    size_t key;
+    KuEnv *env;
+} KuHandlerValue;
+
+/* The space a frame reserves for a scoped value. */
+typedef union {
+    KuObj obj;
+    KuClosureValue closure;
+    KuHandlerValue handler;
+} KuScoped;
+
+/* Whether a handler's finally block has run, shared between the copies of its frame. */
+typedef struct {
+    KuObj obj;
+    int set;
+} KuFlag;
+
+typedef struct KuResumption KuResumption;
+
+typedef struct {
+    uint64_t id;
+    /* NULL for the outermost frame and frames standing in for a resumption. */
+    KuHandlerValue *handler;
+    KuValue cc;
+    KuFlag *finalized;
+    int resumed;
+    KuResumption *suspended;
+} KuFrame;
+
+struct KuResumption {
+    KuObj obj;
+    KuValue k;
+    size_t results;
+    size_t len;
+    KuFrame frames[];
+};
+
+typedef struct {
+    size_t key;
+    KuEnv *env;
+} KuFinally;
+
+/* finally blocks to run, last first, before calling a value. */
+typedef struct {
+    KuObj obj;
+    KuValue callee;
+    size_t finallies;
+    size_t args;
+    KuFinally *finally;
+    KuValue *arg;
+} KuPending;
+
+typedef void (*KuCode)(KuEnv *env);
+
+typedef struct {
+    const char *name;
+    /* NULL for functions without a body. */
+    KuCode code;
+    size_t params;
+    size_t locals;
+    /* The number of scoped values its frame makes. */
+    size_t scoped;
+} KuClosureInfo;
+
+typedef struct {
+    size_t op;
+    size_t action;
+    int tail;
+} KuAction;
+
+typedef struct {
+    const KuAction *actions;
+    size_t len;
+    /* The return clause and finally block, or -1. */
+    long ret;
+    long finally;
+} KuHandlerInfo;
+
+enum { KU_BUILTIN_NONE, KU_BUILTIN_PRINT, KU_BUILTIN_READ_LINE };
+
+typedef struct {
+    const char *name;
+    size_t returns;
+    int builtin;
+} KuOpInfo;
+
+typedef struct {
+    const char *data;
+    size_t len;
+} KuLiteral;
+
+typedef struct {
+    const KuClosureInfo *closures;
+    const KuHandlerInfo *handlers;
+    const KuOpInfo *ops;
+    const KuLiteral *strings;
+    size_t nstrings;
+} KuProgram;
+
+static const KuProgram *ku_program;
+
+static KuFrame *ku_frames;
+static size_t ku_depth, ku_frames_cap;
+static uint64_t ku_next_id = 1;
+
+/* The value to call next and its arguments, unless a body has been entered. */
+static KuValue ku_callee;
+static KuValue *ku_args;
+static size_t ku_argc, ku_args_cap;
+static int ku_entered;
+static KuCode ku_code;
+static KuEnv *ku_env;
+
+static KuString **ku_literals;
+
+static KuObj *ku_heap;
+static size_t ku_bytes, ku_threshold = 1 << 20;
+static KuObj **ku_gray;
+static size_t ku_gray_len, ku_gray_cap;
+
+static const KuValue ku_unit = {KU_UNIT, {0}};
+static const KuValue ku_halt = {KU_HALT, {0}};
+
+static void ku_write(FILE *out, KuValue v);
+
+static void ku_fail(const char *fmt, ...) {
+    va_list args;
+    va_start(args, fmt);
+    fputs("error: ", stderr);
+    vfprintf(stderr, fmt, args);
+    fputc('\n', stderr);
+    va_end(args);
+    exit(1);
+}
+
+static void ku_type_error(const char *expected, KuValue found) {
+    fprintf(stderr, "error: expected %s, found ", expected);
+    ku_write(stderr, found);
+    fputc('\n', stderr);
+    exit(1);
+}
+
+static void *ku_grow(void *ptr, size_t *cap, size_t len, size_t size) {
+    if (len < *cap) {
+        return ptr;
+    }
+    *cap = *cap ? *cap * 2 : 16;
+    ptr = realloc(ptr, *cap * size);
+    if (!ptr) {
+        ku_fail("out of memory");
+    }
+    return ptr;
+}
+
+static void *ku_alloc(KuObjType type, size_t size) {
+    KuObj *obj = malloc(size);
+    if (!obj) {
+        ku_fail("out of memory");
+    }
+    obj->next = ku_heap;
+    obj->size = size;
+    obj->type = (unsigned char)type;
+    obj->marked = 0;
+    ku_heap = obj;
+    ku_bytes += size;
+    return obj;
+}
+
+static KuValue ku_int_value(int64_t i) {
+    KuValue v;
+    v.kind = KU_INT;
+    v.as.i = i;
+    return v;
+}
+
+static KuValue ku_obj_value(KuKind kind, void *obj) {
+    KuValue v;
+    v.kind = kind;
+    v.as.obj = obj;
+    return v;
+}
+
+static KuValue ku_function(size_t key) {
+    KuValue v;
+    v.kind = KU_FUNCTION;
+    v.as.i = (int64_t)key;
+    return v;
+}
+
+static KuString *ku_string(const char *data, size_t len) {
+    KuString *s = ku_alloc(KU_OBJ_STRING, sizeof(KuString) + len);
+    s->len = len;
+    memcpy(s->data, data, len);
+    return s;
+}
+
+static KuValue ku_literal(size_t idx) {
+    return ku_obj_value(KU_STRING, ku_literals[idx]);
+}
+
+static KuScoped *ku_scoped(KuEnv *env) {
+    return (KuScoped *)(env->slots + env->len);
+}
+
+static KuEnv *ku_env_new(KuEnv *parent, size_t len, size_t scoped) {
+    size_t size = sizeof(KuEnv) + len * sizeof(KuValue) + scoped * sizeof(KuScoped);
+    KuEnv *env = ku_alloc(KU_OBJ_ENV, size);
+    env->parent = parent;
+    env->len = len;
+    env->scoped = scoped;
+    for (size_t i = 0; i < len; i++) {
+        env->slots[i] = ku_unit;
+    }
+    /* until a value is made in it, the space traces nothing */
+    for (size_t i = 0; i < scoped; i++) {
+        KuObj *obj = &ku_scoped(env)[i].obj;
+        obj->next = &env->obj;
+        obj->size = 0;
+        obj->type = KU_OBJ_FLAG;
+        obj->marked = 0;
+    }
+    return env;
+}
+
+/* Reads a local of the frame `depth` frames out from `env`. */
+static KuValue ku_outer(KuEnv *env, size_t depth, size_t slot) {
+    while (depth--) {
+        env = env->parent;
+    }
+    return env->slots[slot];
+}
+
+/* Copies the values a converted closure captures, as depth and slot pairs, into a record. */
+static KuEnv *ku_record(KuEnv *env, size_t len, const size_t *captures) {
+    KuEnv *record = ku_env_new(NULL, len, 0);
+    for (size_t i = 0; i < len; i++) {
+        record->slots[i] = ku_outer(env, captures[2 * i], captures[2 * i + 1]);
+    }
+    return record;
+}
+
+static KuValue ku_closure_in(KuClosureValue *closure, size_t key, KuEnv *env, int cont) {
+    closure->key = key;
+    closure->env = env;
+    closure->cont = cont;
+    closure->prompt = cont ? ku_frames[ku_depth - 1].id : 0;
+    return ku_obj_value(KU_CLOSURE, closure);
+}
+
+static KuValue ku_closure(size_t key, KuEnv *env, int cont) {
+    KuClosureValue *closure = ku_alloc(KU_OBJ_CLOSURE, sizeof(KuClosureValue));
+    return ku_closure_in(closure, key, env, cont);
+}
+
+/* Makes a scoped closure in the space `frame` reserves for the `idx`th scoped value. */
+static KuValue ku_scoped_closure(KuEnv *frame, size_t idx, size_t key, KuEnv *env, int cont) {
+    KuClosureValue *closure = &ku_scoped(frame)[idx].closure;
+    closure->obj.type = KU_OBJ_CLOSURE;
+    return ku_closure_in(closure, key, env, cont);
+}
+
+static KuValue ku_handler_in(KuHandlerValue *handler, size_t key, KuEnv *env) {
+    handler->key = key;
+    handler->env = env;
+    return ku_obj_value(KU_HANDLER, handler);
+}
+
+static KuValue ku_handler(size_t key, KuEnv *env) {
+    KuHandlerValue *handler = ku_alloc(KU_OBJ_HANDLER, sizeof(KuHandlerValue));
+    return ku_handler_in(handler, key, env);
+}
+
+/* Makes a scoped handler in the space its frame reserves for the `idx`th scoped value. */
+static KuValue ku_scoped_handler(KuEnv *env, size_t idx, size_t key) {
+    KuHandlerValue *handler = &ku_scoped(env)[idx].handler;
+    handler->obj.type = KU_OBJ_HANDLER;
+    return ku_handler_in(handler, key, env);
+}
+
+static void ku_write(FILE *out, KuValue v) {
+    switch (v.kind) {
+    case KU_UNIT:
+        fputs("()", out);
+        break;
+    case KU_INT:
+        fprintf(out, "%" PRId64, v.as.i);
+        break;
+    case KU_STRING: {
+        KuString *s = (KuString *)v.as.obj;
+        fwrite(s->data, 1, s->len, out);
+        break;
+    }
+    case KU_FUNCTION:
+        fputs("<function>", out);
+        break;
+    case KU_CLOSURE:
+        fputs(((KuClosureValue *)v.as.obj)->cont ? "<continuation>" : "<closure>", out);
+        break;
+    case KU_HANDLER:
+        fputs("<handler>", out);
+        break;
+    default:
+        fputs("<continuation>", out);
+        break;
+    }
+}
+
+/* Garbage collection */
+
+static void ku_mark(KuObj *obj) {
+    if (obj && !obj->size) {
+        /* a scoped value, which its frame traces */
+        obj = obj->next;
+    }
+    if (!obj || obj->marked) {
+        return;
+    }
+    obj->marked = 1;
+    ku_gray = ku_grow(ku_gray, &ku_gray_cap, ku_gray_len, sizeof(KuObj *));
+    ku_gray[ku_gray_len++] = obj;
+}
+
+static void ku_mark_value(KuValue v) {
+    switch (v.kind) {
+    case KU_STRING:
+    case KU_CLOSURE:
+    case KU_HANDLER:
+    case KU_RESUME:
+    case KU_THEN:
+        ku_mark(v.as.obj);
+        break;
+    default:
+        break;
+    }
+}
+
+static void ku_mark_frame(const KuFrame *frame) {
+    ku_mark((KuObj *)frame->handler);
+    ku_mark_value(frame->cc);
+    ku_mark((KuObj *)frame->finalized);
+    ku_mark((KuObj *)frame->suspended);
+}
+
+static void ku_trace(KuObj *obj) {
+    switch ((KuObjType)obj->type) {
+    case KU_OBJ_ENV: {
+        KuEnv *env = (KuEnv *)obj;
+        ku_mark((KuObj *)env->parent);
+        for (size_t i = 0; i < env->len; i++) {
+            ku_mark_value(env->slots[i]);
+        }
+        for (size_t i = 0; i < env->scoped; i++) {
+            ku_trace(&ku_scoped(env)[i].obj);
+        }
+        break;
+    }
+    case KU_OBJ_CLOSURE:
+        ku_mark((KuObj *)((KuClosureValue *)obj)->env);
+        break;
+    case KU_OBJ_HANDLER:
+        ku_mark((KuObj *)((KuHandlerValue *)obj)->env);
+        break;
+    case KU_OBJ_RESUMPTION: {
+        KuResumption *r = (KuResumption *)obj;
+        ku_mark_value(r->k);
+        for (size_t i = 0; i < r->len; i++) {
+            ku_mark_frame(&r->frames[i]);
+        }
+        break;
+    }
+    case KU_OBJ_PENDING: {
+        KuPending *p = (KuPending *)obj;
+        ku_mark_value(p->callee);
+        for (size_t i = 0; i < p->finallies; i++) {
+            ku_mark((KuObj *)p->finally[i].env);
+        }
+        for (size_t i = 0; i < p->args; i++) {
+            ku_mark_value(p->arg[i]);
+        }
+        break;
+    }
+    case KU_OBJ_STRING:
+    case KU_OBJ_FLAG:
+        break;
+    }
+}
+
+static void ku_collect(void) {
+    for (size_t i = 0; i < ku_program->nstrings; i++) {
+        ku_mark((KuObj *)ku_literals[i]);
+    }
+    for (size_t i = 0; i < ku_depth; i++) {
+        ku_mark_frame(&ku_frames[i]);
+    }
+    ku_mark_value(ku_callee);
+    for (size_t i = 0; i < ku_argc; i++) {
+        ku_mark_value(ku_args[i]);
+    }
+    ku_mark((KuObj *)ku_env);
+    while (ku_gray_len) {
+        ku_trace(ku_gray[--ku_gray_len]);
+    }
+    KuObj **link = &ku_heap;
+    while (*link) {
+        KuObj *obj = *link;
+        if (obj->marked) {
+            obj->marked = 0;
+            link = &obj->next;
+        } else {
+            *link = obj->next;
+            ku_bytes -= obj->size;
+            free(obj);
+        }
+    }
+    if (ku_threshold < ku_bytes * 2) {
+        ku_threshold = ku_bytes * 2;
+    }
+}
+
+/* Operands */
+
+static int64_t ku_int(KuValue v) {
+    if (v.kind != KU_INT) {
+        ku_type_error("an integer", v);
+    }
+    return v.as.i;
+}
+
+static int ku_truth(KuValue v) {
+    return ku_int(v) != 0;
+}
+
+static KuValue ku_add(KuValue a, KuValue b) {
+    if (a.kind == KU_INT && b.kind == KU_INT) {
+        return ku_int_value((int64_t)((uint64_t)a.as.i + (uint64_t)b.as.i));
+    }
+    if (a.kind == KU_STRING && b.kind == KU_STRING) {
+        KuString *x = (KuString *)a.as.obj, *y = (KuString *)b.as.obj;
+        KuString *s = ku_alloc(KU_OBJ_STRING, sizeof(KuString) + x->len + y->len);
+        s->len = x->len + y->len;
+        memcpy(s->data, x->data, x->len);
+        memcpy(s->data + x->len, y->data, y->len);
+        return ku_obj_value(KU_STRING, s);
+    }
+    if (a.kind == KU_STRING) {
+        ku_type_error("a string", b);
+    }
+    ku_type_error("an integer", a.kind == KU_INT ? b : a);
+    return ku_unit;
+}
+
+typedef enum { KU_SUB, KU_MUL, KU_DIV, KU_REM, KU_GT, KU_GE, KU_LT, KU_LE } KuArith;
+
+static KuValue ku_arith(KuArith op, KuValue a, KuValue b) {
+    int64_t y = ku_int(b), x = ku_int(a);
+    switch (op) {
+    case KU_SUB:
+        return ku_int_value((int64_t)((uint64_t)x - (uint64_t)y));
+    case KU_MUL:
+        return ku_int_value((int64_t)((uint64_t)x * (uint64_t)y));
+    case KU_DIV:
+    case KU_REM:
+        if (y == 0 || (x == INT64_MIN && y == -1)) {
+            ku_fail("division by zero");
+        }
+        return ku_int_value(op == KU_DIV ? x / y : x % y);
+    case KU_GT:
+        return ku_int_value(x > y);
+    case KU_GE:
+        return ku_int_value(x >= y);
+    case KU_LT:
+        return ku_int_value(x < y);
+    case KU_LE:
+        return ku_int_value(x <= y);
+    }
+    return ku_unit;
+}
+
+/* Compares two integers or two strings, producing 1 if their equality is `eq`. */
+static KuValue ku_equal(KuValue a, KuValue b, int eq) {
+    int equal;
+    if (a.kind == KU_INT && b.kind == KU_INT) {
+        equal = a.as.i == b.as.i;
+    } else if (a.kind == KU_STRING && b.kind == KU_STRING) {
+        KuString *x = (KuString *)a.as.obj, *y = (KuString *)b.as.obj;
+        equal = x->len == y->len && memcmp(x->data, y->data, x->len) == 0;
+    } else if (a.kind == KU_STRING) {
+        ku_type_error("a string", b);
+        return ku_unit;
+    } else {
+        ku_type_error("an integer", a.kind == KU_INT ? b : a);
+        return ku_unit;
+    }
+    return ku_int_value(equal == eq);
+}
+
+/* Control */
+
+static void ku_push_arg(KuValue v) {
+    ku_args = ku_grow(ku_args, &ku_args_cap, ku_argc, sizeof(KuValue));
+    ku_args[ku_argc++] = v;
+}
+
+static void ku_set_args(const KuValue *args, size_t len) {
+    ku_argc = 0;
+    for (size_t i = 0; i < len; i++) {
+        ku_push_arg(args[i]);
+    }
+}
+
+static void ku_push_frame(KuFrame frame) {
+    ku_frames = ku_grow(ku_frames, &ku_frames_cap, ku_depth, sizeof(KuFrame));
+    ku_frames[ku_depth++] = frame;
+}
+
+/* Starts executing a closure with the given environment, taking its arguments. */
+static void ku_enter(size_t key, KuEnv *parent) {
+    const KuClosureInfo *info = &ku_program->closures[key];
+    if (!info->code) {
+        ku_fail("function %s has no body", info->name);
+    }
+    if (ku_argc < info->params) {
+        ku_fail("expected %zu arguments, found %zu", info->params, ku_argc);
+    }
+    KuEnv *env = ku_env_new(parent, info->locals, info->scoped);
+    for (size_t i = 0; i < info->params; i++) {
+        env->slots[i] = ku_args[i];
+    }
+    ku_argc = 0;
+    ku_code = info->code;
+    ku_env = env;
+    ku_entered = 1;
+}
+
+/* Finds the index of the handler frame with the given ID. */
+static size_t ku_frame(uint64_t id) {
+    for (size_t i = ku_depth; i-- > 0;) {
+        if (ku_frames[i].id == id) {
+            return i;
+        }
+    }
+    ku_fail("continuation invoked after its handler exited");
+    return 0;
+}
+
+typedef struct {
+    KuFinally *items;
+    size_t len, cap;
+} KuFinallies;
+
+/* Collects the finally blocks to run for a frame leaving the handler stack, innermost first. */
+static void ku_finalize(const KuFrame *frame, KuFinallies *finallies) {
+    if (frame->suspended) {
+        for (size_t i = frame->suspended->len; i-- > 0;) {
+            ku_finalize(&frame->suspended->frames[i], finallies);
+        }
+    }
+    if (!frame->handler || !frame->finalized) {
+        return;
+    }
+    /* a resumed frame returns to its handler, whose own frame runs the block when it's left */
+    if (!frame->resumed && !frame->finalized->set) {
+        frame->finalized->set = 1;
+        finallies->items =
+            ku_grow(finallies->items, &finallies->cap, finallies->len, sizeof(KuFinally));
+        KuFinally finally = {
+            (size_t)ku_program->handlers[frame->handler->key].finally,
+            frame->handler->env,
+        };
+        finallies->items[finallies->len++] = finally;
+    }
+}
+
+/* Removes the handler frames from `idx` up, then calls `callee` once their finally blocks have run. */
+static void ku_leave(size_t idx, KuValue callee) {
+    KuFinallies finallies = {NULL, 0, 0};
+    while (ku_depth > idx) {
+        ku_finalize(&ku_frames[--ku_depth], &finallies);
+    }
+    if (!finallies.len) {
+        ku_callee = callee;
+        return;
+    }
+    size_t size = sizeof(KuPending) + finallies.len * sizeof(KuFinally) + ku_argc * sizeof(KuValue);
+    KuPending *p = ku_alloc(KU_OBJ_PENDING, size);
+    p->callee = callee;
+    p->finallies = finallies.len;
+    p->args = ku_argc;
+    p->finally = (KuFinally *)(p + 1);
+    p->arg = (KuValue *)(p->finally + finallies.len);
+    /* the innermost block runs first, so it goes last */
+    for (size_t i = 0; i < finallies.len; i++) {
+        p->finally[i] = finallies.items[finallies.len - 1 - i];
+    }
+    memcpy(p->arg, ku_args, ku_argc * sizeof(KuValue));
+    free(finallies.items);
+    ku_callee = ku_obj_value(KU_THEN, p);
+    ku_argc = 0;
+}
+
+/* Calls `ku_callee` with `ku_args` until a body is entered. Returns 1 if the program halted. */
+static int ku_apply(void) {
+    for (;;) {
+        KuValue callee = ku_callee;
+        switch (callee.kind) {
+        case KU_FUNCTION:
+            ku_enter((size_t)callee.as.i, NULL);
+            return 0;
+        case KU_CLOSURE: {
+            KuClosureValue *closure = (KuClosureValue *)callee.as.obj;
+            if (closure->cont) {
+                /* leave the frames made since the continuation was */
+                size_t idx = ku_frame(closure->prompt) + 1;
+                if (idx < ku_depth) {
+                    ku_leave(idx, callee);
+                    continue;
+                }
+            }
+            ku_enter(closure->key, closure->env);
+            return 0;
+        }
+        case KU_EXIT: {
+            size_t idx = ku_frame((uint64_t)callee.as.i);
+            KuValue cc = ku_frames[idx].cc;
+            KuHandlerValue *handler = ku_frames[idx].handler;
+            long ret = ku_program->handlers[handler->key].ret;
+            if (ret >= 0) {
+                /* the return clause runs outside the handler, after any finally blocks */
+                size_t params = ku_program->closures[ret].params;
+                if (params && ku_argc > params - 1) {
+                    ku_argc = params - 1;
+                }
+                ku_push_arg(cc);
+                cc = ku_closure((size_t)ret, handler->env, 0);
+            }
+            ku_leave(idx, cc);
+            break;
+        }
+        case KU_RESUME: {
+            KuResumption *r = (KuResumption *)callee.as.obj;
+            if (ku_argc == r->results + 1) {
+                size_t base = ku_depth;
+                for (size_t i = 0; i < r->len; i++) {
+                    ku_push_frame(r->frames[i]);
+                }
+                ku_frames[base].cc = ku_args[--ku_argc];
+                ku_frames[base].resumed = 1;
+            } else {
+                /* the handler is done, and the resumed frames take the place of its frame */
+                if (ku_depth && ku_frames[ku_depth - 1].suspended == r) {
+                    ku_depth--;
+                }
+                for (size_t i = 0; i < r->len; i++) {
+                    ku_push_frame(r->frames[i]);
+                }
+            }
+            ku_callee = r->k;
+            break;
+        }
+        case KU_THEN: {
+            KuPending *p = (KuPending *)callee.as.obj;
+            if (p->finallies) {
+                size_t size = sizeof(KuPending) + (p->finallies - 1) * sizeof(KuFinally) +
+                              p->args * sizeof(KuValue);
+                KuPending *rest = ku_alloc(KU_OBJ_PENDING, size);
+                rest->callee = p->callee;
+                rest->finallies = p->finallies - 1;
+                rest->args = p->args;
+                rest->finally = (KuFinally *)(rest + 1);
+                rest->arg = (KuValue *)(rest->finally + rest->finallies);
+                memcpy(rest->finally, p->finally, rest->finallies * sizeof(KuFinally));
+                memcpy(rest->arg, p->arg, p->args * sizeof(KuValue));
+                KuFinally finally = p->finally[p->finallies - 1];
+                ku_argc = 0;
+                ku_push_arg(ku_obj_value(KU_THEN, rest));
+                ku_enter(finally.key, finally.env);
+                return 0;
+            }
+            ku_callee = p->callee;
+            ku_set_args(p->arg, p->args);
+            break;
+        }
+        case KU_HALT:
+            if (ku_depth > 1) {
+                ku_leave(1, callee);
+                break;
+            }
+            return 1;
+        default:
+            fputs("error: ", stderr);
+            ku_write(stderr, callee);
+            fputs(" is not callable\n", stderr);
+            exit(1);
+        }
+    }
+}
+
+/* Ends a body by calling the value on top of its operand stack with the values below it. */
+static void ku_continue(const KuValue *stack, size_t len) {
+    ku_callee = stack[len - 1];
+    ku_set_args(stack, len - 1);
+}
+
+/* Ends a body by calling `body` under a handler, continuing with `cc`. */
+static void ku_handle(KuValue handler, KuValue body, KuValue cc) {
+    if (handler.kind != KU_HANDLER) {
+        ku_type_error("a handler", handler);
+    }
+    KuHandlerValue *h = (KuHandlerValue *)handler.as.obj;
+    KuFrame frame = {ku_next_id++, h, cc, NULL, 0, NULL};
+    if (ku_program->handlers[h->key].finally >= 0) {
+        frame.finalized = ku_alloc(KU_OBJ_FLAG, sizeof(KuFlag));
+        frame.finalized->set = 0;
+    }
+    ku_push_frame(frame);
+    ku_callee = body;
+    ku_argc = 0;
+    ku_push_arg((KuValue){KU_EXIT, {.i = (int64_t)frame.id}});
+}
+
+/* Handles a built-in operation with stdio, leaving its results in `ku_args`. */
+static void ku_builtin(int builtin) {
+    if (builtin == KU_BUILTIN_PRINT) {
+        if (ku_argc < 1) {
+            ku_fail("expected 1 arguments, found 0");
+        }
+        ku_write(stdout, ku_args[0]);
+        fputc('\n', stdout);
+        ku_argc = 0;
+        return;
+    }
+    size_t len = 0, cap = 0;
+    char *line = NULL;
+    int c;
+    while ((c = getchar()) != EOF && c != '\n') {
+        line = ku_grow(line, &cap, len, 1);
+        line[len++] = (char)c;
+    }
+    while (len && (line[len - 1] == '\r' || line[len - 1] == '\n')) {
+        len--;
+    }
+    KuString *s = ku_string(line ? line : "", len);
+    free(line);
+    ku_argc = 0;
+    ku_push_arg(ku_obj_value(KU_STRING, s));
+}
+
+/* Ends a body by performing an operation with `arity` arguments and maybe a continuation. */
+static void ku_perform(size_t op, size_t arity, const KuValue *stack, size_t len) {
+    int resumes = len > arity;
+    KuValue k = resumes ? stack[--len] : ku_unit;
+    ku_set_args(stack, len);
+    const KuOpInfo *info = &ku_program->ops[op];
+    size_t idx = ku_depth;
+    const KuAction *action = NULL;
+    while (!action && idx-- > 0) {
+        if (!ku_frames[idx].handler) {
+            continue;
+        }
+        const KuHandlerInfo *h = &ku_program->handlers[ku_frames[idx].handler->key];
+        for (size_t i = 0; i < h->len; i++) {
+            if (h->actions[i].op == op) {
+                action = &h->actions[i];
+                break;
+            }
+        }
+    }
+    if (!action) {
+        if (info->builtin == KU_BUILTIN_NONE) {
+            ku_fail("unhandled operation %s", info->name);
+        }
+        ku_builtin(info->builtin);
+        if (!resumes) {
+            ku_fail("unhandled operation %s", info->name);
+        }
+        ku_callee = k;
+        return;
+    }
+    KuHandlerValue *handler = ku_frames[idx].handler;
+    if (resumes && action->tail) {
+        /* the action runs on the current stack and resumes by calling `k` directly */
+        ku_push_arg(k);
+        ku_push_arg(ku_unit);
+        ku_enter(action->action, handler->env);
+        return;
+    }
+    size_t frames = ku_depth - idx;
+    KuResumption *r =
+        ku_alloc(KU_OBJ_RESUMPTION, sizeof(KuResumption) + frames * sizeof(KuFrame));
+    r->k = k;
+    r->results = info->returns;
+    r->len = frames;
+    memcpy(r->frames, &ku_frames[idx], frames * sizeof(KuFrame));
+    ku_depth = idx;
+    KuFrame frame = {ku_next_id++, NULL, ku_unit, NULL, 0, r};
+    ku_push_frame(frame);
+    ku_push_arg(resumes ? ku_obj_value(KU_RESUME, r) : ku_unit);
+    ku_push_arg(r->frames[0].cc);
+    ku_enter(action->action, handler->env);
+}
+
+static void ku_fell_off(size_t key) {
+    ku_fail("execution fell off the end of %s", ku_program->closures[key].name);
+}
+
+static void ku_unsupported(const char *what) {
+    ku_fail("%s is not supported", what);
+}
+
+/* Runs a program from the given function, printing the values it finishes with. */
+static int ku_run(const KuProgram *program, size_t entry) {
+    ku_program = program;
+    ku_literals = malloc((program->nstrings + 1) * sizeof(KuString *));
+    for (size_t i = 0; i < program->nstrings; i++) {
+        ku_literals[i] = ku_string(program->strings[i].data, program->strings[i].len);
+    }
+    KuFrame outermost = {0, NULL, ku_halt, NULL, 0, NULL};
+    ku_push_frame(outermost);
+    if (program->closures[entry].params) {
+        ku_push_arg(ku_halt);
+    }
+    ku_enter(entry, NULL);
+    for (;;) {
+        if (!ku_entered && ku_apply()) {
+            break;
+        }
+        ku_entered = 0;
+        if (ku_bytes > ku_threshold) {
+            ku_collect();
+        }
+        ku_code(ku_env);
+    }
+    for (size_t i = 0; i < ku_argc; i++) {
+        ku_write(stdout, ku_args[i]);
+        fputc('\n', stdout);
+    }
+    return fflush(stdout) == 0 ? 0 : 1;
+}
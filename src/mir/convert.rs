@@ -0,0 +1,502 @@
+//! Closure conversion and lambda lifting.
+//!
+//! Lowered closures reach the locals of the closures they're nested in through the chain of frames
+//! they were made in, which keeps every one of those frames alive as long as the closure is.
+//! Conversion gives a closure a record of just the values it and the closures nested in it read
+//! from outside, copied when it's made. That's only sound when those values can't change
+//! afterwards. The frames further out have finished running by the time anything in them is made,
+//! so it's enough that no path in the maker stores to a copied local after making the closure.
+//!
+//! Lifting goes further for closures only ever called by the closure that made them, right where
+//! they're loaded, such as the code after an `if`: what they read from outside becomes extra
+//! parameters, passed at each call, and they're called by name instead of being made at all.
+
+use std::collections::{HashMap, HashSet};
+
+use crate::{cache::StringKey, symbol::SymbolKey};
+
+use super::{
+    opt::{depths, outer_reads, reachable_from, rewrite, targets},
+    Local, Opcode, Program, Value,
+};
+
+/// Where a closure, or one nested in it, refers to a local.
+#[derive(Copy, Clone, Debug)]
+struct Reference {
+    key: SymbolKey,
+    site: Site,
+    /// How far the closure it's made from is nested in the one the references were collected for.
+    level: usize,
+    depth: usize,
+    slot: usize,
+}
+
+#[derive(Copy, Clone, Debug)]
+enum Site {
+    /// An instruction of the closure.
+    Code(usize),
+    /// An entry of the closure's record, read from its parent's frame.
+    Capture(usize),
+}
+
+/// Runs lambda lifting.
+pub fn lift(program: &mut Program) {
+    while let Some((key, pc, slot)) = liftable(program) {
+        lift_closure(program, key, pc, slot);
+    }
+}
+
+/// Finds a closure to lift: one made once, by its parent, into a local that's only ever loaded to
+/// call it with all its arguments. Returns it, where it's made, and the local.
+fn liftable(program: &Program) -> Option<(SymbolKey, usize, usize)> {
+    let (made, pinned) = uses(program);
+    let outer = outer_reads(program);
+    program.closures.iter().find_map(|(&key, closure)| {
+        let parent = closure.parent?;
+        if closure.captures.is_some() || pinned.contains(&key) {
+            return None;
+        }
+        let &[(maker, pc)] = made.get(&key)?.as_slice() else {
+            return None;
+        };
+        let maker = program.closures.get(&maker).filter(|_| maker == parent)?;
+        let code = &maker.code;
+        let Some(&Opcode::StoreLocal(slot)) = code.get(pc + 1) else {
+            return None;
+        };
+        let targets = targets(code);
+        let depths = depths(code);
+        let stores = code
+            .iter()
+            .filter(|&op| *op == Opcode::StoreLocal(slot))
+            .count();
+        if targets[pc + 1] || slot < maker.params || stores != 1 || outer.contains(&(parent, slot))
+        {
+            return None;
+        }
+        let calls = code.iter().enumerate().all(|(at, op)| match *op {
+            Opcode::LoadLocal(s) if s == slot => {
+                code.get(at + 1) == Some(&Opcode::Continue)
+                    && !targets[at + 1]
+                    && depths[at] == Some(closure.params)
+            }
+            Opcode::LoadOuter(0, s) if s == slot => false,
+            _ => true,
+        });
+        calls.then_some((key, pc, slot))
+    })
+}
+
+/// Lifts a closure made at `pc` of its parent into `slot`.
+fn lift_closure(program: &mut Program, key: SymbolKey, pc: usize, slot: usize) {
+    let closure = &program.closures[&key];
+    let parent = closure.parent.expect("lifted closures are nested");
+    let base = closure.params;
+    let references = references(program, key);
+    let needs = needs(&references);
+    let count = needs.len();
+    // a continuation shared with an enclosing closure becomes its own if it's passed
+    let cont = closure.cont.or_else(|| {
+        let mut owner = parent;
+        let slot = loop {
+            let closure = program.closures.get(&owner)?;
+            if let Some(slot) = closure.cont {
+                break slot;
+            }
+            owner = closure.parent?;
+        };
+        let location = program.locate(parent, owner, slot)?;
+        let idx = needs.iter().position(|&need| need == location)?;
+        Some(base + idx)
+    });
+    let locals = needs
+        .iter()
+        .map(|&(depth, slot)| {
+            let origin = program.origin(parent, depth, slot);
+            let local =
+                origin.and_then(|(owner, slot)| program.closures.get(&owner)?.locals.get(slot));
+            local.cloned().unwrap_or(Local {
+                name: StringKey::EMPTY,
+            })
+        })
+        .collect::<Vec<_>>();
+
+    // what it read from outside is now in its parameters, which come before its other locals
+    relocate(program, &references, |r| {
+        if r.depth > r.level {
+            let need = (r.depth - r.level - 1, r.slot);
+            let idx = needs.iter().position(|&n| n == need)?;
+            Some((r.level, base + idx))
+        } else if r.depth == r.level && r.slot >= base {
+            Some((r.depth, r.slot + count))
+        } else {
+            None
+        }
+    });
+    let closure = program.closures.get_mut(&key).expect("lifted closure");
+    closure.locals.splice(base..base, locals);
+    closure.params += count;
+    closure.cont = cont;
+    closure.captures = Some(Vec::new());
+
+    let maker = program.closures.get_mut(&parent).expect("parent");
+    let code = maker.code.clone();
+    rewrite(maker, |at| {
+        if at == pc || at == pc + 1 {
+            Some(Vec::new())
+        } else if code[at] == Opcode::LoadLocal(slot) {
+            let mut call = needs
+                .iter()
+                .map(|&(depth, slot)| match depth {
+                    0 => Opcode::LoadLocal(slot),
+                    _ => Opcode::LoadOuter(depth, slot),
+                })
+                .collect::<Vec<_>>();
+            call.push(Opcode::LoadValue(Value::Cont(key)));
+            Some(call)
+        } else {
+            None
+        }
+    });
+}
+
+/// Runs closure conversion.
+pub fn convert(program: &mut Program) {
+    let (made, pinned) = uses(program);
+    // outer closures first, so the records of those nested in them copy from records
+    let mut order = program
+        .closures
+        .keys()
+        .map(|&key| {
+            let nesting = std::iter::successors(Some(key), |key| program.closures[key].parent);
+            (nesting.count(), key)
+        })
+        .collect::<Vec<_>>();
+    order.sort();
+    for (_, key) in order {
+        let closure = &program.closures[&key];
+        let Some(parent) = closure.parent else {
+            continue;
+        };
+        if closure.captures.is_some() || pinned.contains(&key) {
+            continue;
+        }
+        let Some(sites) = made.get(&key) else {
+            continue;
+        };
+        if sites.iter().any(|&(maker, _)| maker != parent) {
+            continue;
+        }
+        let references = references(program, key);
+        let needs = needs(&references);
+        // whenever the closure runs, the maker's locals must still be what was copied
+        let code = &program.closures[&parent].code;
+        let stored = sites.iter().any(|&(_, pc)| {
+            let after = reachable_from(code, pc + 1);
+            code.iter().zip(after).any(|(op, after)| {
+                after && matches!(*op, Opcode::StoreLocal(slot) if needs.contains(&(0, slot)))
+            })
+        });
+        if stored {
+            continue;
+        }
+        relocate(program, &references, |r| {
+            let need = (r.depth.checked_sub(r.level + 1)?, r.slot);
+            let idx = needs.iter().position(|&n| n == need)?;
+            Some((r.level + 1, idx))
+        });
+        program.closures.get_mut(&key).expect("closure").captures = Some(needs);
+    }
+}
+
+/// Where each closure is made, and the closures whose environment can't change because they're
+/// called by name or handle operations.
+#[allow(clippy::type_complexity)]
+fn uses(
+    program: &Program,
+) -> (
+    HashMap<SymbolKey, Vec<(SymbolKey, usize)>>,
+    HashSet<SymbolKey>,
+) {
+    let mut made: HashMap<_, Vec<_>> = HashMap::new();
+    let mut pinned = HashSet::new();
+    for (&key, closure) in &program.closures {
+        for (pc, op) in closure.code.iter().enumerate() {
+            match *op {
+                Opcode::MakeClosure(k) | Opcode::MakeCont(k) => {
+                    made.entry(k).or_default().push((key, pc))
+                }
+                Opcode::LoadValue(Value::Cont(k)) => {
+                    pinned.insert(k);
+                }
+                _ => {}
+            }
+        }
+    }
+    for handler in program.handlers.values() {
+        let actions = handler.actions.iter().map(|&(_, action)| action);
+        pinned.extend(actions.chain(handler.ret).chain(handler.finally));
+    }
+    (made, pinned)
+}
+
+/// The references to locals in a closure and the closures nested in it, down to those with records
+/// of their own, whose records are references from their parents instead.
+fn references(program: &Program, root: SymbolKey) -> Vec<Reference> {
+    let mut children: HashMap<SymbolKey, Vec<SymbolKey>> = HashMap::new();
+    for (&key, closure) in &program.closures {
+        if let Some(parent) = closure.parent {
+            children.entry(parent).or_default().push(key);
+        }
+    }
+    let mut references = Vec::new();
+    let mut pending = vec![(root, 0)];
+    while let Some((key, level)) = pending.pop() {
+        for (pc, op) in program.closures[&key].code.iter().enumerate() {
+            let (depth, slot) = match *op {
+                Opcode::LoadLocal(slot) | Opcode::StoreLocal(slot) => (0, slot),
+                Opcode::LoadOuter(depth, slot) => (depth, slot),
+                _ => continue,
+            };
+            let site = Site::Code(pc);
+            references.push(Reference {
+                key,
+                site,
+                level,
+                depth,
+                slot,
+            });
+        }
+        for &child in children.get(&key).into_iter().flatten() {
+            match &program.closures[&child].captures {
+                Some(captures) => {
+                    let captures = captures.iter().enumerate();
+                    references.extend(captures.map(|(idx, &(depth, slot))| Reference {
+                        key: child,
+                        site: Site::Capture(idx),
+                        level,
+                        depth,
+                        slot,
+                    }))
+                }
+                None => pending.push((child, level + 1)),
+            }
+        }
+    }
+    references
+}
+
+/// The locals from outside a closure that references read, as the depth and slot they're loaded
+/// from in its parent's frame.
+fn needs(references: &[Reference]) -> Vec<(usize, usize)> {
+    let mut needs = Vec::new();
+    for r in references {
+        if r.depth > r.level {
+            let need = (r.depth - r.level - 1, r.slot);
+            if !needs.contains(&need) {
+                needs.push(need);
+            }
+        }
+    }
+    needs
+}
+
+/// Points the references that `map` gives a new depth and slot there.
+fn relocate(
+    program: &mut Program,
+    references: &[Reference],
+    mut map: impl FnMut(&Reference) -> Option<(usize, usize)>,
+) {
+    for r in references {
+        let Some((depth, slot)) = map(r) else {
+            continue;
+        };
+        let closure = program
+            .closures
+            .get_mut(&r.key)
+            .expect("referenced closure");
+        match r.site {
+            Site::Code(pc) => {
+                closure.code[pc] = match closure.code[pc] {
+                    Opcode::StoreLocal(_) => {
+                        debug_assert_eq!(0, depth, "stores are to the current frame");
+                        Opcode::StoreLocal(slot)
+                    }
+                    _ if depth == 0 => Opcode::LoadLocal(slot),
+                    _ => Opcode::LoadOuter(depth, slot),
+                }
+            }
+            Site::Capture(idx) => {
+                if let Some(captures) = &mut closure.captures {
+                    captures[idx] = (depth, slot);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        cache::StringCache,
+        mir::{
+            bytecode,
+            text::{assemble, disassemble},
+            verify::verify,
+        },
+        runtime::Captured,
+        Session,
+    };
+
+    use super::*;
+
+    const SRC: &str = r#"
+fn twice(f: {}) -> Int = {
+    let a: Int = f();
+    let b: Int = f();
+    a + b
+}
+
+fn pick(c: Int, n: Int) -> Int = {
+    let d: Int = if c > 50 { c - n } else { n };
+    d * 2
+}
+
+fn main() -> Int = {
+    let n: Int = 20;
+    let m: Int = n + 1;
+    let c: Int = twice({ m * 2 });
+    if c > 50 { print("big"); } else { print("small"); }
+    pick(c, n) + pick(1, m)
+}
+"#;
+
+    fn compile(passes: fn(&mut Program)) -> (Session, Program) {
+        let mut session = Session::new();
+        let compilation = session.compile("main.ku", SRC);
+        let mut program = compilation.program.expect("program");
+        passes(&mut program);
+        verify(&program).expect("verified");
+        (session, program)
+    }
+
+    fn run(session: &Session, program: &Program) -> (Vec<String>, Vec<String>) {
+        let mut console = Captured::default();
+        let machine = session.machine(program, "main", Vec::new()).expect("main");
+        let values = machine.with_console(&mut console).run().expect("run");
+        let values = values.iter().map(|v| v.to_string()).collect();
+        (values, console.output)
+    }
+
+    #[test]
+    fn keeps_output() {
+        let (session, program) = compile(|_| {});
+        let expected = run(&session, &program);
+        assert_eq!((vec!["170".to_owned()], vec!["big".to_owned()]), expected);
+        for passes in [lift, convert, |program: &mut Program| {
+            lift(program);
+            convert(program);
+        }] {
+            let (session, program) = compile(passes);
+            assert_eq!(expected, run(&session, &program));
+        }
+    }
+
+    #[test]
+    fn converts_closures() {
+        let (session, program) = compile(convert);
+        // nothing stores to a local after making a closure that reads it
+        let nested = program.closures.values().filter(|c| c.parent.is_some());
+        for closure in nested {
+            let captures = closure.captures.as_ref();
+            assert!(
+                captures.is_some(),
+                "{}",
+                disassemble(&program, session.cache())
+            );
+            // reads reach no further than the record
+            let reach = closure.code.iter().all(|op| match *op {
+                Opcode::LoadOuter(depth, slot) => depth <= 1 && slot < captures.unwrap().len(),
+                _ => true,
+            });
+            assert!(reach, "{:?}", closure.code);
+        }
+    }
+
+    #[test]
+    fn lifts_join_points() {
+        let (session, program) = compile(lift);
+        let pick = program.symbol("pick").expect("pick");
+        let code = &program.closures[&pick].code;
+        let made = |op: &Opcode| matches!(op, Opcode::MakeCont(_) | Opcode::MakeClosure(_));
+        assert!(!code.iter().any(made), "{code:?}");
+        let (&join, closure) = program
+            .closures
+            .iter()
+            .find(|(_, closure)| closure.parent == Some(pick))
+            .expect("join point");
+        assert!(code.contains(&Opcode::LoadValue(Value::Cont(join))));
+        // `d`, then `pick`'s continuation, which is now the join point's own
+        assert_eq!(Some(&Vec::new()), closure.captures.as_ref());
+        assert_eq!((2, Some(1)), (closure.params, closure.cont));
+        let cache = session.cache();
+        assert_eq!(Some("d"), cache.get(closure.locals[0].name));
+
+        // the closure passed to `twice` isn't only called where it's made
+        let main = program.symbol("main").expect("main");
+        let code = &program.closures[&main].code;
+        assert!(code.iter().any(made), "{code:?}");
+    }
+
+    #[test]
+    fn keeps_frames_of_changing_locals() {
+        let text = |store: &str| {
+            format!(
+                "\
+closure main params 1 cont 0
+  locals k x
+  load_value 1
+  store_local 1
+  make_closure main#0
+{store}  load_local 0
+  continue
+
+closure main#0 in main params 1 cont 0
+  locals r
+  load_outer 1 1
+  load_local 0
+  continue
+"
+            )
+        };
+        let mut cache = StringCache::new();
+        let converted = |text: String, cache: &mut StringCache| {
+            let mut program = assemble(&text, cache).expect("assemble");
+            convert(&mut program);
+            verify(&program).expect("verified");
+            let main = program.symbol("main").expect("main");
+            let mut nested = program.closures.values().filter(|c| c.parent == Some(main));
+            nested.next().expect("closure").captures.clone()
+        };
+        assert_eq!(Some(vec![(0, 1)]), converted(text(""), &mut cache));
+        // the closure sees 2, which a copy made before the store wouldn't
+        let store = "  load_value 2\n  store_local 1\n";
+        assert_eq!(None, converted(text(store), &mut cache));
+    }
+
+    #[test]
+    fn round_trips_records() {
+        let (mut session, program) = compile(|program| {
+            lift(program);
+            convert(program);
+        });
+        let text = disassemble(&program, session.cache());
+        assert!(text.contains("  captures 0:"), "{text}");
+        let assembled = assemble(&text, session.cache_mut()).expect("assemble");
+        assert_eq!(text, disassemble(&assembled, session.cache()));
+        let bytes = bytecode::encode(&program, session.cache());
+        let decoded = bytecode::decode(&bytes, session.cache_mut()).expect("decode");
+        assert_eq!(text, disassemble(&decoded, session.cache()));
+        assert_eq!(run(&session, &program), run(&session, &decoded));
+    }
+}
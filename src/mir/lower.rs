@@ -0,0 +1,953 @@
+//! Lowering from the AST to MIR.
+//!
+//! Function bodies are converted to continuation-passing style. A call in expression position ends
+//! the current closure by passing a new continuation closure, which receives the call's results
+//! and holds the rest of the body. Conditionals and `do` blocks whose values are used continue to a
+//! join closure in the same way. Values are kept in locals rather than on the operand stack, so
+//! the stack only holds the operands of the instruction being built.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use crate::{
+    ast::{
+        Expr, Function, FunctionHeader, Ident, Integer, Item, Operator, QualifiedIdent, Statement,
+        TypedIdent,
+    },
+    builtin::Builtin,
+    cache::{StringCache, StringKey},
+    diagnostic::{Code, Diagnostics},
+    escape::Scoped,
+    resolve::{Resolution, SymbolKind},
+    span::{Span, Spanned},
+    symbol::{fmt::SymbolFormatted, SymbolKey},
+};
+
+use super::{
+    text::Names, verify::verify, Closure, Handler, Local, Opcode, Program, Signature, Value,
+};
+
+/// Lowers resolved items to a program, marking what the escape analysis found scoped. Items should
+/// be free of errors.
+pub fn lower(
+    items: &[Spanned<Item>],
+    res: Resolution,
+    escapes: &Scoped,
+    cache: &StringCache,
+    ds: &mut Diagnostics,
+) -> Program {
+    let builtins = res.builtins().collect::<BTreeMap<_, _>>();
+    let returns = builtins
+        .iter()
+        .map(|(&key, builtin)| (key, Some(builtin.returns())))
+        .collect();
+    let signatures = builtins
+        .iter()
+        .map(|(&key, builtin)| {
+            let signature = Signature {
+                params: builtin.params(),
+                returns: Some(builtin.returns()),
+            };
+            (key, signature)
+        })
+        .collect();
+    let mut lowerer = Lowerer {
+        res,
+        escapes,
+        scoped: BTreeSet::new(),
+        cache,
+        ds,
+        builders: Vec::new(),
+        current: 0,
+        terminated: false,
+        vars: HashMap::new(),
+        returns,
+        handlers: BTreeMap::new(),
+        signatures,
+        strings: Vec::new(),
+        scope: Scope {
+            function: SymbolKey::ROOT,
+            span: Span::default(),
+            ret: None,
+            cont: None,
+        },
+        span: Span::default(),
+        stmt: Span::default(),
+    };
+    for item in items {
+        match &**item {
+            Item::Function(function) => lowerer.declare(&function.header),
+            Item::AbstractFunction(header) => lowerer.declare_abstract(header),
+            Item::Effect { body, .. } => {
+                for item in body {
+                    match &**item {
+                        Item::Function(function) => lowerer.declare(&function.header),
+                        Item::AbstractFunction(header) => lowerer.declare_abstract(header),
+                        _ => {}
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    for item in items {
+        if let Item::Function(function) = &**item {
+            lowerer.function(function);
+        }
+    }
+    let program = lowerer.finish(builtins);
+    if !ds.has_errors() {
+        // nothing checks types before lowering, so the verifier is the first to see mismatched
+        // constants; anything else it finds is a bug here
+        match verify(&program) {
+            Err(err) if err.problem.is_type_error() => {
+                ds.add(Code::TypeMismatch, err.span(&program), err.problem);
+            }
+            Err(err) => {
+                let names = Names::new(&program);
+                let context = format!("lowered invalid MIR {}", SymbolFormatted(err, &names));
+                ds.add(Code::Internal, err.span(&program), context);
+            }
+            Ok(()) => {}
+        }
+    }
+    program
+}
+
+/// A value available to the code being lowered.
+#[derive(Copy, Clone, Debug)]
+enum Operand {
+    Const(Value),
+    /// A local in the frame of the given closure builder.
+    Var {
+        closure: usize,
+        slot: usize,
+    },
+}
+
+/// Where an expression's values go.
+#[derive(Copy, Clone, Debug)]
+enum Target {
+    /// Continue with the values.
+    Tail(Operand),
+    /// Produce the given number of values for the code that follows.
+    Values(usize),
+}
+
+/// What a call invokes.
+enum Callee {
+    Op(SymbolKey),
+    Value(Operand),
+}
+
+/// A closure under construction.
+struct Builder {
+    key: SymbolKey,
+    parent: Option<usize>,
+    params: usize,
+    cont: Option<usize>,
+    span: Span,
+    locals: Vec<Local>,
+    code: Vec<Opcode>,
+    spans: Vec<Span>,
+}
+
+/// The continuations that `return` and `continue` refer to.
+#[derive(Copy, Clone)]
+struct Scope {
+    /// The function being lowered, which new closures are defined under.
+    function: SymbolKey,
+    /// The function's name, for diagnostics.
+    span: Span,
+    ret: Option<Operand>,
+    cont: Option<Operand>,
+}
+
+/// The state to restore after lowering a nested closure.
+struct Saved {
+    current: usize,
+    terminated: bool,
+    scope: Scope,
+}
+
+struct Lowerer<'a> {
+    res: Resolution,
+    escapes: &'a Scoped,
+    /// The closures and handlers made from scoped values.
+    scoped: BTreeSet<SymbolKey>,
+    cache: &'a StringCache,
+    ds: &'a mut Diagnostics,
+    builders: Vec<Builder>,
+    /// The builder code is emitted into.
+    current: usize,
+    /// Whether the current code path has ended, so that anything emitted would be unreachable.
+    terminated: bool,
+    vars: HashMap<SymbolKey, Operand>,
+    /// The number of values each function and operation returns with, or `None` if it never does.
+    returns: HashMap<SymbolKey, Option<usize>>,
+    handlers: BTreeMap<SymbolKey, Handler>,
+    signatures: BTreeMap<SymbolKey, Signature>,
+    /// The contents of string literals, indexed by `Value::String`.
+    strings: Vec<StringKey>,
+    scope: Scope,
+    /// The span given to new closures: that of the last call or `do ... with` lowered.
+    span: Span,
+    /// The statement being lowered, recorded for each instruction.
+    stmt: Span,
+}
+
+impl Lowerer<'_> {
+    fn declare(&mut self, header: &FunctionHeader) {
+        if let Some(key) = self.res.defined_at(Spanned::span(&header.name)) {
+            self.returns.insert(key, header.ret.as_ref().map(Vec::len));
+        }
+    }
+
+    fn declare_abstract(&mut self, header: &FunctionHeader) {
+        self.declare(header);
+        if let Some(key) = self.res.defined_at(Spanned::span(&header.name)) {
+            let signature = Signature {
+                params: header.params.len(),
+                returns: header.ret.as_ref().map(Vec::len),
+            };
+            self.signatures.insert(key, signature);
+        }
+    }
+
+    fn finish(self, builtins: BTreeMap<SymbolKey, Builtin>) -> Program {
+        let keys = self.builders.iter().map(|b| b.key).collect::<Vec<_>>();
+        let names = self
+            .returns
+            .keys()
+            .map(|&key| {
+                let path = self.res.table.path(key);
+                let path = path.iter().map(|&s| &self.cache[s]).collect::<Vec<_>>();
+                (key, path.join("::"))
+            })
+            .collect();
+        let closures = self
+            .builders
+            .into_iter()
+            .map(|b| {
+                let closure = Closure {
+                    parent: b.parent.map(|p| keys[p]),
+                    params: b.params,
+                    cont: b.cont,
+                    span: b.span,
+                    captures: None,
+                    locals: b.locals,
+                    code: b.code,
+                    spans: b.spans,
+                };
+                (b.key, closure)
+            })
+            .collect();
+        Program {
+            table: self.res.table,
+            closures,
+            handlers: self.handlers,
+            strings: self
+                .strings
+                .iter()
+                .map(|&s| self.cache[s].to_owned())
+                .collect(),
+            builtins,
+            signatures: self.signatures,
+            names,
+            scoped: self.scoped,
+        }
+    }
+
+    fn function(&mut self, function: &Function) {
+        let header = &function.header;
+        let span = Spanned::span(&header.name);
+        let Some(key) = self.res.defined_at(span) else {
+            return;
+        };
+        self.builders.push(Builder {
+            key,
+            parent: None,
+            params: 0,
+            cont: None,
+            span,
+            locals: Vec::new(),
+            code: Vec::new(),
+            spans: Vec::new(),
+        });
+        self.current = self.builders.len() - 1;
+        self.terminated = false;
+        self.params(&header.params);
+        let k = header.ret.is_some().then(|| self.cont_param());
+        self.scope = Scope {
+            function: key,
+            span,
+            ret: k,
+            cont: k,
+        };
+        self.block(&function.body, k);
+    }
+
+    /// Starts lowering a closure nested in the current one.
+    fn enter(&mut self) -> (SymbolKey, Saved) {
+        let key = self.res.table.define_anonymous(self.scope.function);
+        self.builders.push(Builder {
+            key,
+            parent: Some(self.current),
+            params: 0,
+            cont: None,
+            span: self.span,
+            locals: Vec::new(),
+            code: Vec::new(),
+            spans: Vec::new(),
+        });
+        let saved = Saved {
+            current: self.current,
+            terminated: self.terminated,
+            scope: self.scope,
+        };
+        self.current = self.builders.len() - 1;
+        self.terminated = false;
+        (key, saved)
+    }
+
+    fn leave(&mut self, saved: Saved) {
+        self.current = saved.current;
+        self.terminated = saved.terminated;
+        self.scope = saved.scope;
+    }
+
+    fn emit(&mut self, op: Opcode) {
+        if !self.terminated {
+            let builder = &mut self.builders[self.current];
+            builder.code.push(op);
+            builder.spans.push(self.stmt);
+        }
+    }
+
+    /// Emits an instruction that ends the current code path.
+    fn emit_end(&mut self, op: Opcode) {
+        self.emit(op);
+        self.terminated = true;
+    }
+
+    fn local(&mut self, name: StringKey) -> usize {
+        let locals = &mut self.builders[self.current].locals;
+        locals.push(Local { name });
+        locals.len() - 1
+    }
+
+    /// Adds a parameter to the current closure. Parameters must be added before any other locals.
+    fn param(&mut self, name: StringKey) -> Operand {
+        let slot = self.local(name);
+        self.builders[self.current].params += 1;
+        Operand::Var {
+            closure: self.current,
+            slot,
+        }
+    }
+
+    /// Adds the parameter holding the current closure's continuation.
+    fn cont_param(&mut self) -> Operand {
+        let k = self.param(StringKey::EMPTY);
+        self.builders[self.current].cont = Some(self.builders[self.current].params - 1);
+        k
+    }
+
+    fn params(&mut self, params: &[Spanned<TypedIdent>]) {
+        for param in params {
+            let name = match *param.name {
+                Ident::Ident(name) => name,
+                Ident::Error => StringKey::EMPTY,
+            };
+            let op = self.param(name);
+            if let Some(key) = self.res.defined_at(Spanned::span(&param.name)) {
+                self.vars.insert(key, op);
+            }
+        }
+    }
+
+    /// Stores the top of the operand stack in a new temporary.
+    fn store_temp(&mut self) -> Operand {
+        let slot = self.local(StringKey::EMPTY);
+        self.emit(Opcode::StoreLocal(slot));
+        Operand::Var {
+            closure: self.current,
+            slot,
+        }
+    }
+
+    fn load(&mut self, op: Operand) {
+        match op {
+            Operand::Const(value) => self.emit(Opcode::LoadValue(value)),
+            Operand::Var { closure, slot } => {
+                let mut depth = 0;
+                let mut builder = self.current;
+                while builder != closure {
+                    depth += 1;
+                    builder = self.builders[builder]
+                        .parent
+                        .expect("variables are used within their closure");
+                }
+                if depth == 0 {
+                    self.emit(Opcode::LoadLocal(slot))
+                } else {
+                    self.emit(Opcode::LoadOuter(depth, slot))
+                }
+            }
+        }
+    }
+
+    /// Binds a `let` name to a value, giving it a named local.
+    fn bind(&mut self, name: &Spanned<Ident>, op: Operand) {
+        let Ident::Ident(name_key) = **name else {
+            return;
+        };
+        let op = match op {
+            Operand::Var { closure, slot }
+                if closure == self.current
+                    && self.builders[closure].locals[slot].name == StringKey::EMPTY =>
+            {
+                self.builders[closure].locals[slot].name = name_key;
+                op
+            }
+            _ => {
+                self.load(op);
+                let slot = self.local(name_key);
+                self.emit(Opcode::StoreLocal(slot));
+                Operand::Var {
+                    closure: self.current,
+                    slot,
+                }
+            }
+        };
+        if let Some(key) = self.res.defined_at(Spanned::span(name)) {
+            self.vars.insert(key, op);
+        }
+    }
+
+    /// Lowers a block of statements, continuing with `k` at the end.
+    fn block(&mut self, stmts: &[Spanned<Statement>], k: Option<Operand>) {
+        let last = stmts.len().checked_sub(1);
+        for (i, stmt) in stmts.iter().enumerate() {
+            if self.terminated {
+                break;
+            }
+            let span = Spanned::span(stmt);
+            self.stmt = span;
+            match &**stmt {
+                Statement::BlockExpr(expr) | Statement::BlockEndExpr(expr) if Some(i) == last => {
+                    match k {
+                        Some(k) => {
+                            self.expr(expr, Target::Tail(k));
+                        }
+                        None => {
+                            self.expr(expr, Target::Values(0));
+                        }
+                    }
+                }
+                Statement::Expr(expr)
+                | Statement::BlockExpr(expr)
+                | Statement::BlockEndExpr(expr) => {
+                    self.expr(expr, Target::Values(0));
+                }
+                Statement::Let { bindings, init } => {
+                    let ops = self.expr(init, Target::Values(bindings.len()));
+                    for (binding, op) in bindings.iter().zip(ops) {
+                        self.bind(&binding.name, op);
+                    }
+                }
+                Statement::Continue { cont, args } => {
+                    let callee = self.callee(cont).0;
+                    let args = args.iter().map(|arg| self.operand(arg)).collect::<Vec<_>>();
+                    self.invoke(callee, &args, None);
+                }
+            }
+            // nested blocks leave their own statements' spans behind
+            self.stmt = span;
+        }
+        if !self.terminated {
+            match k {
+                Some(k) => {
+                    self.load(k);
+                    self.emit_end(Opcode::Continue);
+                }
+                None => {
+                    self.ds.add(
+                        Code::MissingContinuation,
+                        self.scope.span,
+                        "the end of the block",
+                    );
+                    self.terminated = true;
+                }
+            }
+        }
+    }
+
+    /// Lowers a block whose `continue` is `k`.
+    fn inner_block(&mut self, stmts: &[Spanned<Statement>], k: Operand) {
+        let cont = self.scope.cont.replace(k);
+        self.block(stmts, Some(k));
+        self.scope.cont = cont;
+    }
+
+    /// Lowers an expression with a single value.
+    fn operand(&mut self, expr: &Spanned<Expr>) -> Operand {
+        self.expr(expr, Target::Values(1))[0]
+    }
+
+    /// Lowers an expression. For a `Values` target, returns the requested number of values.
+    fn expr(&mut self, expr: &Spanned<Expr>, target: Target) -> Vec<Operand> {
+        if self.terminated {
+            return self.deliver(Vec::new(), target);
+        }
+        match &**expr {
+            Expr::Ident(path) => {
+                let op = self.ident(path);
+                self.deliver(vec![op], target)
+            }
+            Expr::Int(Integer::Integer(value)) => {
+                self.deliver(vec![Operand::Const(Value::Int(*value))], target)
+            }
+            Expr::String(value) => {
+                let idx = match self.strings.iter().position(|s| s == value) {
+                    Some(idx) => idx,
+                    None => {
+                        self.strings.push(*value);
+                        self.strings.len() - 1
+                    }
+                };
+                self.deliver(vec![Operand::Const(Value::String(idx))], target)
+            }
+            Expr::Int(Integer::Error) | Expr::Error { .. } => {
+                self.deliver(vec![Operand::Const(Value::Unit)], target)
+            }
+            Expr::Return => {
+                let op = self.scope.ret;
+                let op = self.special(op, "return");
+                self.deliver(vec![op], target)
+            }
+            Expr::Continue => {
+                let op = self.scope.cont;
+                let op = self.special(op, "continue");
+                self.deliver(vec![op], target)
+            }
+            Expr::Binary { op, operands } => {
+                let operands = operands.iter().map(|o| self.operand(o)).collect::<Vec<_>>();
+                let opcode = match op {
+                    Operator::Add => Opcode::Add,
+                    Operator::Sub => Opcode::Sub,
+                    Operator::Mul => Opcode::Mul,
+                    Operator::Div => Opcode::Div,
+                    Operator::Rem => Opcode::Rem,
+                    Operator::Eq => Opcode::Eq,
+                    Operator::NotEq => Opcode::NotEq,
+                    Operator::Gt => Opcode::Gt,
+                    Operator::Ge => Opcode::Ge,
+                    Operator::Lt => Opcode::Lt,
+                    Operator::Le => Opcode::Le,
+                };
+                self.load(operands[0]);
+                for &operand in &operands[1..] {
+                    self.load(operand);
+                    self.emit(opcode.clone());
+                }
+                let result = self.store_temp();
+                self.deliver(vec![result], target)
+            }
+            Expr::Member { .. } => {
+                self.ds
+                    .add(Code::Unsupported, Spanned::span(expr), "member access");
+                self.deliver(vec![Operand::Const(Value::Unit)], target)
+            }
+            Expr::Call { func, args } | Expr::BlockCall { func, args } => {
+                if let Expr::Ident(path) = &***func {
+                    self.span = Spanned::span(path);
+                }
+                let scoped = match &***func {
+                    Expr::Ident(path) => self.escapes.contains(Spanned::span(path)),
+                    _ => false,
+                };
+                let (callee, returns) = self.callee(func);
+                let args = args.iter().map(|arg| self.operand(arg)).collect::<Vec<_>>();
+                match returns {
+                    // a call that never returns ends the code path
+                    Some(None) => {
+                        self.invoke(callee, &args, None);
+                        self.deliver(Vec::new(), target)
+                    }
+                    Some(Some(count)) => self.with_cont(target, Some(count), scoped, |this, k| {
+                        this.invoke(callee, &args, Some(k))
+                    }),
+                    None => self.with_cont(target, None, scoped, |this, k| {
+                        this.invoke(callee, &args, Some(k))
+                    }),
+                }
+            }
+            Expr::Closure { params, stmts } => {
+                let (key, saved) = self.enter();
+                self.params(params);
+                let k = self.cont_param();
+                self.scope.cont = Some(k);
+                self.block(stmts, Some(k));
+                self.leave(saved);
+                if self.escapes.contains(Spanned::span(expr)) {
+                    self.scoped.insert(key);
+                }
+                self.emit(Opcode::MakeClosure(key));
+                let closure = self.store_temp();
+                self.deliver(vec![closure], target)
+            }
+            Expr::Conditional { cases, final_else } => {
+                self.with_cont(target, None, false, |this, k| {
+                    for case in cases {
+                        let condition = this.operand(&case.condition);
+                        this.load(condition);
+                        let here = this.current;
+                        let pos = this.builders[here].code.len();
+                        let terminated = this.terminated;
+                        this.emit(Opcode::Branch(1, 1));
+                        this.inner_block(&case.then_body, k);
+                        this.current = here;
+                        this.terminated = terminated;
+                        if !terminated {
+                            let offset = (this.builders[here].code.len() - pos) as i32;
+                            this.builders[here].code[pos] = Opcode::Branch(1, offset);
+                        }
+                    }
+                    this.inner_block(final_else, k);
+                })
+            }
+            Expr::Handler { items, .. } => {
+                let handler = self.handler(items, self.escapes.contains(Spanned::span(expr)));
+                self.deliver(vec![handler], target)
+            }
+            Expr::Do { stmts } => {
+                self.with_cont(target, None, false, |this, k| this.inner_block(stmts, k))
+            }
+            Expr::DoWith {
+                stmts,
+                with_span,
+                handler,
+            } => {
+                // a return clause declares the values of the `do ... with`
+                let returns = match &***handler {
+                    Expr::Handler { items, .. } => items.iter().find_map(|item| match &**item {
+                        Item::Return { ret, .. } => ret.as_ref().map(Vec::len),
+                        _ => None,
+                    }),
+                    _ => None,
+                };
+                let handler = self.operand(handler);
+                self.span = *with_span;
+                self.with_cont(target, returns, false, |this, cc| {
+                    let stmt = this.stmt;
+                    let (key, saved) = this.enter();
+                    let exit = this.cont_param();
+                    this.inner_block(stmts, exit);
+                    this.leave(saved);
+                    this.stmt = stmt;
+                    this.load(handler);
+                    this.emit(Opcode::MakeClosure(key));
+                    this.load(cc);
+                    this.emit_end(Opcode::Handle);
+                })
+            }
+        }
+    }
+
+    /// Passes values to a target.
+    fn deliver(&mut self, values: Vec<Operand>, target: Target) -> Vec<Operand> {
+        match target {
+            Target::Tail(k) => {
+                for &value in &values {
+                    self.load(value);
+                }
+                self.load(k);
+                self.emit_end(Opcode::Continue);
+                Vec::new()
+            }
+            Target::Values(count) => {
+                if !self.terminated && values.len() < count {
+                    let context = format!("expected {count} values, found {}", values.len());
+                    self.ds.add(Code::ValueCount, self.scope.span, context);
+                }
+                let unit = Operand::Const(Value::Unit);
+                values
+                    .into_iter()
+                    .chain(std::iter::repeat(unit))
+                    .take(count)
+                    .collect()
+            }
+        }
+    }
+
+    /// Lowers code that ends by continuing with its values. For a `Values` target, the continuation
+    /// is a new join closure taking `count` values (or as many as requested), and code that follows
+    /// is emitted into it. The join closure is marked scoped if `scoped` is set.
+    fn with_cont(
+        &mut self,
+        target: Target,
+        count: Option<usize>,
+        scoped: bool,
+        lower: impl FnOnce(&mut Self, Operand),
+    ) -> Vec<Operand> {
+        match target {
+            Target::Tail(k) => {
+                lower(self, k);
+                Vec::new()
+            }
+            Target::Values(wanted) => {
+                let count = count.unwrap_or(wanted);
+                let (key, saved) = self.enter();
+                let values = (0..count)
+                    .map(|_| self.param(StringKey::EMPTY))
+                    .collect::<Vec<_>>();
+                let join = self.current;
+                self.leave(saved);
+                if scoped {
+                    self.scoped.insert(key);
+                }
+                self.emit(Opcode::MakeCont(key));
+                let k = self.store_temp();
+                lower(self, k);
+                self.current = join;
+                self.terminated = false;
+                self.deliver(values, target)
+            }
+        }
+    }
+
+    /// Emits a call, passing `k` as its continuation if present.
+    fn invoke(&mut self, callee: Callee, args: &[Operand], k: Option<Operand>) {
+        for &arg in args {
+            self.load(arg);
+        }
+        if let Some(k) = k {
+            self.load(k);
+        }
+        match callee {
+            Callee::Op(op) => self.emit_end(Opcode::Perform(op, args.len())),
+            Callee::Value(func) => {
+                self.load(func);
+                self.emit_end(Opcode::Continue);
+            }
+        }
+    }
+
+    /// Lowers the target of a call. Also returns the number of values a known function or operation
+    /// returns with.
+    fn callee(&mut self, func: &Spanned<Expr>) -> (Callee, Option<Option<usize>>) {
+        if let Expr::Ident(path) = &**func {
+            let key = self.res.reference(Spanned::span(path));
+            let kind = key.and_then(|key| self.res.kind(key));
+            match (key, kind) {
+                (Some(key), Some(SymbolKind::Operation)) => {
+                    return (Callee::Op(key), self.returns.get(&key).copied());
+                }
+                (Some(key), Some(SymbolKind::Function)) => {
+                    let returns = self.returns.get(&key).copied();
+                    return (Callee::Value(Operand::Const(Value::Cont(key))), returns);
+                }
+                _ => {}
+            }
+        }
+        (Callee::Value(self.operand(func)), None)
+    }
+
+    fn ident(&mut self, path: &Spanned<QualifiedIdent>) -> Operand {
+        let span = Spanned::span(path);
+        let Some(key) = self.res.reference(span) else {
+            // already reported by name resolution
+            return Operand::Const(Value::Unit);
+        };
+        match self.res.kind(key) {
+            Some(SymbolKind::Function) => Operand::Const(Value::Cont(key)),
+            Some(SymbolKind::Parameter | SymbolKind::Local) => self
+                .vars
+                .get(&key)
+                .copied()
+                .unwrap_or(Operand::Const(Value::Unit)),
+            Some(SymbolKind::Operation | SymbolKind::Effect) | None => {
+                self.ds.add(
+                    Code::Unsupported,
+                    span,
+                    "an effect operation used as a value",
+                );
+                Operand::Const(Value::Unit)
+            }
+        }
+    }
+
+    /// Gets `return` or `continue`, reporting their absence.
+    fn special(&mut self, op: Option<Operand>, name: &str) -> Operand {
+        op.unwrap_or_else(|| {
+            self.ds
+                .add(Code::MissingContinuation, self.scope.span, name);
+            Operand::Const(Value::Unit)
+        })
+    }
+
+    /// Lowers a handler expression.
+    fn handler(&mut self, items: &[Spanned<Item>], scoped: bool) -> Operand {
+        let key = self.res.table.define_anonymous(self.scope.function);
+        if scoped {
+            self.scoped.insert(key);
+        }
+        let stmt = self.stmt;
+        let mut actions = Vec::new();
+        let mut ret = None;
+        let mut finally = None;
+        for item in items {
+            match &**item {
+                Item::Function(function) => {
+                    let header = &function.header;
+                    let Some(op) = self.res.reference(Spanned::span(&header.name)) else {
+                        continue;
+                    };
+                    self.span = Spanned::span(&header.name);
+                    let (action, saved) = self.enter();
+                    self.params(&header.params);
+                    let resume = self.param(StringKey::EMPTY);
+                    let cc = self.cont_param();
+                    let resumes = self.returns.get(&op).copied().flatten().is_some();
+                    self.scope.ret = resumes.then_some(resume);
+                    self.scope.cont = Some(cc);
+                    self.block(&function.body, Some(cc));
+                    self.leave(saved);
+                    actions.push((op, action));
+                }
+                Item::Return { params, body, .. } => {
+                    if ret.is_some() {
+                        self.ds.add(
+                            Code::Unsupported,
+                            self.scope.span,
+                            "multiple return clauses",
+                        );
+                        continue;
+                    }
+                    let (clause, saved) = self.enter();
+                    self.params(params);
+                    let k = self.cont_param();
+                    self.scope.ret = Some(k);
+                    self.scope.cont = Some(k);
+                    self.block(body, Some(k));
+                    self.leave(saved);
+                    ret = Some(clause);
+                }
+                Item::Finally { stmts } => {
+                    if finally.is_some() {
+                        self.ds.add(
+                            Code::Unsupported,
+                            self.scope.span,
+                            "multiple finally blocks",
+                        );
+                        continue;
+                    }
+                    let (block, saved) = self.enter();
+                    let k = self.cont_param();
+                    self.scope.ret = None;
+                    self.scope.cont = Some(k);
+                    self.block(stmts, Some(k));
+                    self.leave(saved);
+                    finally = Some(block);
+                }
+                _ => {}
+            }
+        }
+        self.handlers.insert(
+            key,
+            Handler {
+                actions,
+                tail: Vec::new(),
+                ret,
+                finally,
+            },
+        );
+        self.stmt = stmt;
+        self.emit(Opcode::MakeHandler(key));
+        self.store_temp()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{cst, resolve::resolve};
+
+    use super::*;
+
+    /// Lowers a program, returning its diagnostics.
+    fn lower_src(src: &str) -> Diagnostics {
+        let mut cache = StringCache::new();
+        let mut ds = Diagnostics::new();
+        let (items, _) = cst::parse(StringKey::EMPTY, src, &mut cache, &mut ds);
+        let res = resolve(&items, &cache, &mut ds);
+        lower(&items, res, &Scoped::default(), &cache, &mut ds);
+        ds
+    }
+
+    #[test]
+    fn return_clause_declares_values() {
+        // the clause turns the computation's one value into two
+        let ds = lower_src(
+            r#"
+effect choice { fn choose() -> Int; }
+
+fn pair() -> Int = {
+    let a: Int, b: Int = do { 1 } with handle choice {
+        return(v: Int) -> (Int, Int) = { :continue v, v + 1; }
+    };
+    a + b
+}
+"#,
+        );
+        assert!(ds.is_empty(), "{ds:?}");
+
+        let ds = lower_src(
+            r#"
+effect choice { fn choose() -> Int; }
+
+fn pair() -> Int = {
+    let a: Int, b: Int = do { 1 } with handle choice {
+        return(v: Int) -> Int = { v }
+    };
+    a + b
+}
+"#,
+        );
+        let [diagnostic] = ds.iter().as_slice() else {
+            panic!("{ds:?}");
+        };
+        assert_eq!(Code::ValueCount, diagnostic.code);
+        assert_eq!("expected 2 values, found 1", diagnostic.context);
+    }
+
+    #[test]
+    fn reports_mismatched_constants() {
+        let cases = [
+            (
+                "fn main() -> Int = { \"a\" + 1 }",
+                "\"a\" + 1",
+                "expected a string, found an integer",
+            ),
+            (
+                "fn main() -> Int = { 3(4) }",
+                "3(4)",
+                "expected a function, found an integer",
+            ),
+            (
+                "effect e { fn op() -> Int; }\nfn main() -> Int = { do { op() } with 5 }",
+                "do { op() }",
+                "expected a handler, found an integer",
+            ),
+        ];
+        for (src, expr, context) in cases {
+            let ds = lower_src(src);
+            let [diagnostic] = ds.iter().as_slice() else {
+                panic!("{src}: {ds:?}");
+            };
+            assert_eq!(Code::TypeMismatch, diagnostic.code, "{src}");
+            assert_eq!(context, diagnostic.context, "{src}");
+            let pos = src.find(expr).unwrap();
+            assert_eq!(pos, diagnostic.span.pos, "{src}");
+        }
+    }
+}
@@ -0,0 +1,830 @@
+//! Optimizations of lowered programs.
+//!
+//! - Constant folding evaluates arithmetic and comparisons of constants, and turns branches on
+//!   constants into unconditional ones. Lowering keeps every intermediate value in a local, so it
+//!   also forwards a value stored to a local and loaded right back when nothing else reads the local.
+//! - Dead code elimination removes instructions no path reaches, such as the arm of a branch that's
+//!   never taken, and anonymous closures and handlers nothing refers to.
+//! - Inlining replaces a call of a small function by its body, when the function doesn't create
+//!   closures that would see the caller's frame as their environment.
+//! - Lambda lifting and closure conversion cut closures off from the frames they're nested in, as
+//!   described in [`convert`](super::convert).
+//! - Tail resumption marks the handler actions that only ever end by resuming, so performing their
+//!   operations calls them directly instead of capturing the handler stack. Actions pass their own
+//!   `continue` when resuming in tail position, which changes nothing, so that's dropped first.
+//!
+//! The passes expect a verified program and keep it verifiable.
+
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+use crate::{
+    diagnostic::{Code, Diagnostics},
+    symbol::{fmt::SymbolFormatted, SymbolKey},
+};
+
+use super::{
+    convert::{convert, lift},
+    text::Names,
+    verify::verify,
+    Closure, Opcode, Program, Value,
+};
+
+/// The number of instructions a function may have to be inlined.
+const INLINE_SIZE: usize = 16;
+
+/// The optimizations to run.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Passes {
+    pub fold: bool,
+    pub dce: bool,
+    pub inline: bool,
+    pub lift: bool,
+    pub convert: bool,
+    pub tail: bool,
+}
+
+impl Passes {
+    /// The passes of an optimization level: none at 0, all but inlining and the closure passes at
+    /// 1, and those as well from 2.
+    pub fn level(level: u8) -> Self {
+        Passes {
+            fold: level >= 1,
+            dce: level >= 1,
+            inline: level >= 2,
+            lift: level >= 2,
+            convert: level >= 2,
+            tail: level >= 1,
+        }
+    }
+}
+
+/// Optimizes a program. If the passes produce invalid code, the program is left as it was and the
+/// failure is reported.
+pub fn optimize(program: &mut Program, passes: Passes, ds: &mut Diagnostics) {
+    let original = program.clone();
+    if passes.inline {
+        inline(program);
+    }
+    if passes.fold {
+        fold(program);
+    }
+    if passes.dce {
+        dce(program);
+    }
+    if passes.lift {
+        lift(program);
+    }
+    if passes.convert {
+        convert(program);
+    }
+    if passes.tail {
+        tail(program);
+    }
+    // inlining can turn an ill-typed argument into a mismatched constant, which the program would
+    // also have failed on when run unoptimized
+    match verify(program) {
+        Err(err) if !err.problem.is_type_error() => {
+            let names = Names::new(program);
+            let context = format!(
+                "optimized into invalid MIR {}",
+                SymbolFormatted(err, &names)
+            );
+            ds.add(Code::Internal, err.span(program), context);
+            *program = original;
+        }
+        _ => {}
+    }
+}
+
+/// Runs constant folding.
+pub fn fold(program: &mut Program) {
+    loop {
+        let mut changed = propagate(program);
+        let outer = outer_reads(program);
+        for (&key, closure) in &mut program.closures {
+            while fold_closure(key, closure, &outer) {
+                changed = true;
+            }
+        }
+        if !changed {
+            break;
+        }
+    }
+}
+
+/// Replaces the loads of locals that are set once to an integer at the start of a closure, before
+/// anything can read them, returning whether anything changed.
+fn propagate(program: &mut Program) -> bool {
+    let mut constants = HashMap::new();
+    let mut stores = HashSet::new();
+    for (&key, closure) in &program.closures {
+        let code = &closure.code;
+        // the instructions every path runs first, before any closure that could read them exists
+        let targets = targets(code);
+        let prefix = code
+            .iter()
+            .zip(&targets)
+            .position(|(op, &target)| {
+                target
+                    || matches!(
+                        op,
+                        Opcode::Branch(..)
+                            | Opcode::MakeClosure(_)
+                            | Opcode::MakeCont(_)
+                            | Opcode::MakeHandler(_)
+                    )
+            })
+            .unwrap_or(code.len());
+        for pc in 1..prefix {
+            let (&Opcode::LoadValue(Value::Int(value)), &Opcode::StoreLocal(slot)) =
+                (&code[pc - 1], &code[pc])
+            else {
+                continue;
+            };
+            let stored = code
+                .iter()
+                .filter(|&op| *op == Opcode::StoreLocal(slot))
+                .count();
+            let read_before = code[..pc].iter().any(
+                |op| matches!(*op, Opcode::LoadLocal(s) | Opcode::LoadOuter(0, s) if s == slot),
+            );
+            if slot >= closure.params && stored == 1 && !read_before {
+                constants.insert((key, slot), value);
+                stores.insert((key, pc));
+            }
+        }
+    }
+    if constants.is_empty() {
+        return false;
+    }
+
+    let mut loads = HashMap::new();
+    for (&key, closure) in &program.closures {
+        for (pc, op) in closure.code.iter().enumerate() {
+            let (depth, slot) = match *op {
+                Opcode::LoadLocal(slot) => (0, slot),
+                Opcode::LoadOuter(depth, slot) => (depth, slot),
+                _ => continue,
+            };
+            let value = program
+                .origin(key, depth, slot)
+                .and_then(|origin| constants.get(&origin));
+            if let Some(&value) = value {
+                loads.insert((key, pc), value);
+            }
+        }
+    }
+    for (&key, closure) in &mut program.closures {
+        for (pc, op) in closure.code.iter_mut().enumerate() {
+            if let Some(&value) = loads.get(&(key, pc)) {
+                *op = Opcode::LoadValue(Value::Int(value));
+            }
+        }
+        rewrite(closure, |pc| {
+            let store = stores.contains(&(key, pc)) || stores.contains(&(key, pc + 1));
+            store.then(Vec::new)
+        });
+    }
+    true
+}
+
+/// Folds what it can in one pass over a closure, returning whether anything changed.
+fn fold_closure(
+    key: SymbolKey,
+    closure: &mut Closure,
+    outer: &HashSet<(SymbolKey, usize)>,
+) -> bool {
+    let code = &mut closure.code;
+    let targets = targets(code);
+    let mut loads = vec![0; closure.locals.len()];
+    for op in code.iter() {
+        if let Opcode::LoadLocal(slot) | Opcode::LoadOuter(0, slot) = *op {
+            loads[slot] += 1;
+        }
+    }
+    let mut edits = vec![None; code.len()];
+    let mut changed = false;
+    let mut pc = 0;
+    while pc < code.len() {
+        let next = |n: usize| code.get(pc + n).filter(|_| !targets[pc + n]);
+        let int = |op: Option<&Opcode>| match op {
+            Some(&Opcode::LoadValue(Value::Int(v))) => Some(v),
+            _ => None,
+        };
+        match (&code[pc], next(1), next(2)) {
+            // a value only ever read right after it's stored stays on the stack instead
+            (&Opcode::StoreLocal(slot), Some(&Opcode::LoadLocal(load)), _)
+                if slot == load
+                    && slot >= closure.params
+                    && loads[slot] == 1
+                    && !outer.contains(&(key, slot)) =>
+            {
+                edits[pc] = Some(Vec::new());
+                edits[pc + 1] = Some(Vec::new());
+                pc += 2;
+            }
+            (&Opcode::LoadValue(Value::Int(a)), b, Some(op)) if int(b).is_some() => {
+                let Some(value) = evaluate(op, a, int(b).expect("integer")) else {
+                    pc += 1;
+                    continue;
+                };
+                edits[pc] = Some(vec![Opcode::LoadValue(Value::Int(value))]);
+                edits[pc + 1] = Some(Vec::new());
+                edits[pc + 2] = Some(Vec::new());
+                pc += 3;
+            }
+            (&Opcode::LoadValue(Value::Int(c)), Some(&Opcode::Branch(then, other)), _)
+                if then != other =>
+            {
+                let taken = if c != 0 { then } else { other };
+                if taken == 1 {
+                    // branching to the next instruction does nothing
+                    edits[pc] = Some(Vec::new());
+                    edits[pc + 1] = Some(Vec::new());
+                } else {
+                    code[pc + 1] = Opcode::Branch(taken, taken);
+                }
+                pc += 2;
+            }
+            _ => {
+                pc += 1;
+                continue;
+            }
+        }
+        changed = true;
+    }
+    if changed {
+        rewrite(closure, |pc| edits[pc].take());
+    }
+    changed
+}
+
+/// Evaluates an instruction that folds on two integers.
+fn evaluate(op: &Opcode, a: i64, b: i64) -> Option<i64> {
+    let value = match op {
+        Opcode::Add => a.wrapping_add(b),
+        Opcode::Sub => a.wrapping_sub(b),
+        Opcode::Mul => a.wrapping_mul(b),
+        Opcode::Eq => (a == b) as i64,
+        Opcode::NotEq => (a != b) as i64,
+        Opcode::Gt => (a > b) as i64,
+        Opcode::Ge => (a >= b) as i64,
+        Opcode::Lt => (a < b) as i64,
+        Opcode::Le => (a <= b) as i64,
+        _ => return None,
+    };
+    Some(value)
+}
+
+/// The locals that closures read from the frames they're nested in or copy into their records, by
+/// the closure they belong to.
+pub(crate) fn outer_reads(program: &Program) -> HashSet<(SymbolKey, usize)> {
+    let mut reads = HashSet::new();
+    for (&key, closure) in &program.closures {
+        for op in &closure.code {
+            if let Opcode::LoadOuter(depth @ 1.., slot) = *op {
+                reads.extend(program.origin(key, depth, slot));
+            }
+        }
+        if let (Some(parent), Some(captures)) = (closure.parent, &closure.captures) {
+            for &(depth, slot) in captures {
+                reads.extend(program.origin(parent, depth, slot));
+            }
+        }
+    }
+    reads
+}
+
+/// Runs dead code elimination.
+pub fn dce(program: &mut Program) {
+    for closure in program.closures.values_mut() {
+        let reachable = reachable(&closure.code);
+        if reachable.iter().any(|&r| !r) {
+            rewrite(closure, |pc| (!reachable[pc]).then(Vec::new));
+        }
+
+        // once the code between them is gone, branches on constants can go to the next instruction
+        let code = &closure.code;
+        let targets = targets(code);
+        let jumps = (1..code.len())
+            .filter(|&pc| {
+                code[pc] == Opcode::Branch(1, 1)
+                    && matches!(code[pc - 1], Opcode::LoadValue(_))
+                    && !targets[pc]
+            })
+            .collect::<HashSet<_>>();
+        if !jumps.is_empty() {
+            rewrite(closure, |pc| {
+                (jumps.contains(&pc) || jumps.contains(&(pc + 1))).then(Vec::new)
+            });
+        }
+    }
+
+    // named functions are where programs start; everything else must be referred to
+    let mut live = BTreeSet::new();
+    let mut pending = program
+        .closures
+        .keys()
+        .filter(|key| program.names.contains_key(key))
+        .copied()
+        .collect::<Vec<_>>();
+    while let Some(key) = pending.pop() {
+        if !live.insert(key) {
+            continue;
+        }
+        if let Some(closure) = program.closures.get(&key) {
+            pending.extend(closure.parent);
+            for op in &closure.code {
+                match *op {
+                    Opcode::LoadValue(Value::Cont(key))
+                    | Opcode::MakeClosure(key)
+                    | Opcode::MakeCont(key)
+                    | Opcode::MakeHandler(key) => pending.push(key),
+                    _ => {}
+                }
+            }
+        }
+        if let Some(handler) = program.handlers.get(&key) {
+            let actions = handler.actions.iter().map(|&(_, action)| action);
+            pending.extend(actions.chain(handler.ret).chain(handler.finally));
+        }
+    }
+    program.closures.retain(|key, _| live.contains(key));
+    program.handlers.retain(|key, _| live.contains(key));
+    program.scoped.retain(|key| live.contains(key));
+}
+
+/// Which instructions some path from the start reaches.
+fn reachable(code: &[Opcode]) -> Vec<bool> {
+    reachable_from(code, 0)
+}
+
+/// Which instructions some path from the given instruction reaches.
+pub(super) fn reachable_from(code: &[Opcode], start: usize) -> Vec<bool> {
+    let mut reachable = vec![false; code.len()];
+    let mut pending = vec![start];
+    while let Some(pc) = pending.pop() {
+        if pc >= code.len() || reachable[pc] {
+            continue;
+        }
+        reachable[pc] = true;
+        match code[pc] {
+            Opcode::Branch(then, other) => {
+                for offset in [then, other] {
+                    pending.extend(pc.checked_add_signed(offset as isize));
+                }
+            }
+            Opcode::Continue | Opcode::Perform(..) | Opcode::Handle => {}
+            _ => pending.push(pc + 1),
+        }
+    }
+    reachable
+}
+
+/// Runs inlining.
+pub fn inline(program: &mut Program) {
+    let inlinable = program
+        .closures
+        .iter()
+        .filter(|(_, closure)| {
+            closure.parent.is_none()
+                && closure.code.len() <= INLINE_SIZE
+                && closure.code.iter().all(|op| {
+                    !matches!(
+                        op,
+                        Opcode::MakeClosure(_)
+                            | Opcode::MakeCont(_)
+                            | Opcode::MakeHandler(_)
+                            | Opcode::LoadOuter(1.., _)
+                    )
+                })
+        })
+        .map(|(&key, closure)| (key, closure.clone()))
+        .collect::<Vec<_>>();
+    let callee = |key: SymbolKey| {
+        inlinable
+            .iter()
+            .find(|&&(k, _)| k == key)
+            .map(|(_, closure)| closure)
+    };
+    for (&key, closure) in &mut program.closures {
+        let targets = targets(&closure.code);
+        let depths = depths(&closure.code);
+        let mut edits = vec![None; closure.code.len()];
+        let mut changed = false;
+        for pc in 0..closure.code.len().saturating_sub(1) {
+            let (Opcode::LoadValue(Value::Cont(f)), Opcode::Continue) =
+                (&closure.code[pc], &closure.code[pc + 1])
+            else {
+                continue;
+            };
+            let Some(callee) = callee(*f).filter(|_| *f != key && !targets[pc + 1]) else {
+                continue;
+            };
+            if depths[pc] != Some(callee.params) {
+                continue;
+            }
+            // the callee's locals follow the caller's, and the arguments go into its parameters
+            let offset = closure.locals.len();
+            closure.locals.extend(callee.locals.iter().cloned());
+            let mut body = (0..callee.params)
+                .rev()
+                .map(|slot| Opcode::StoreLocal(offset + slot))
+                .collect::<Vec<_>>();
+            body.extend(callee.code.iter().map(|op| match *op {
+                Opcode::LoadLocal(slot) => Opcode::LoadLocal(offset + slot),
+                Opcode::LoadOuter(0, slot) => Opcode::LoadOuter(0, offset + slot),
+                Opcode::StoreLocal(slot) => Opcode::StoreLocal(offset + slot),
+                ref op => op.clone(),
+            }));
+            edits[pc] = Some(body);
+            edits[pc + 1] = Some(Vec::new());
+            changed = true;
+        }
+        if changed {
+            rewrite(closure, |pc| edits[pc].take());
+        }
+    }
+}
+
+/// Runs tail resumption.
+pub fn tail(program: &mut Program) {
+    for handler in program.handlers.values_mut() {
+        for &(op, action) in &handler.actions {
+            let Some(results) = program.signatures.get(&op).and_then(|s| s.returns) else {
+                continue;
+            };
+            let Some(closure) = program.closures.get_mut(&action) else {
+                continue;
+            };
+            if closure.params < 2 || handler.tail.contains(&op) {
+                continue;
+            }
+            let (resume, cc) = (closure.params - 2, closure.params - 1);
+            let code = &closure.code;
+            let targets = targets(code);
+            let passed = (0..code.len().saturating_sub(2))
+                .filter(|&pc| {
+                    code[pc] == Opcode::LoadLocal(cc)
+                        && code[pc + 1] == Opcode::LoadLocal(resume)
+                        && code[pc + 2] == Opcode::Continue
+                        && !targets[pc + 1]
+                        && !targets[pc + 2]
+                })
+                .collect::<HashSet<_>>();
+            let mut candidate = closure.clone();
+            rewrite(&mut candidate, |pc| passed.contains(&pc).then(Vec::new));
+            if tail_resumptive(&candidate, results) {
+                *closure = candidate;
+                handler.tail.push(op);
+            }
+        }
+    }
+}
+
+/// Whether a handler action for an operation with the given number of results is tail-resumptive.
+/// Every path must end by calling `return` with just the results, and the action must not read
+/// `return` otherwise, read `continue` at all, or make closures, perform operations or install
+/// handlers, which would see the handler stack.
+pub(crate) fn tail_resumptive(action: &Closure, results: usize) -> bool {
+    let Some(resume) = action.params.checked_sub(2) else {
+        return false;
+    };
+    let cc = resume + 1;
+    let code = &action.code;
+    let targets = targets(code);
+    let depths = depths(code);
+    code.iter().enumerate().all(|(pc, op)| match *op {
+        Opcode::MakeClosure(_)
+        | Opcode::MakeCont(_)
+        | Opcode::MakeHandler(_)
+        | Opcode::Perform(..)
+        | Opcode::Handle => false,
+        Opcode::LoadLocal(slot) | Opcode::LoadOuter(0, slot) | Opcode::StoreLocal(slot)
+            if slot == cc =>
+        {
+            false
+        }
+        Opcode::LoadLocal(slot) if slot == resume => {
+            code.get(pc + 1) == Some(&Opcode::Continue) && !targets[pc + 1]
+        }
+        Opcode::LoadOuter(0, slot) | Opcode::StoreLocal(slot) if slot == resume => false,
+        Opcode::Continue => {
+            pc > 0
+                && code[pc - 1] == Opcode::LoadLocal(resume)
+                && depths[pc].is_none_or(|depth| depth == results + 1)
+        }
+        _ => true,
+    })
+}
+
+/// The number of values on the operand stack before each instruction, if any path reaches it.
+pub(super) fn depths(code: &[Opcode]) -> Vec<Option<usize>> {
+    let mut depths = vec![None; code.len()];
+    let mut pending = vec![(0, 0usize)];
+    while let Some((pc, depth)) = pending.pop() {
+        if pc >= code.len() || depths[pc].is_some() {
+            continue;
+        }
+        depths[pc] = Some(depth);
+        let after = match code[pc] {
+            Opcode::LoadValue(_)
+            | Opcode::LoadLocal(_)
+            | Opcode::LoadOuter(..)
+            | Opcode::MakeClosure(_)
+            | Opcode::MakeCont(_)
+            | Opcode::MakeHandler(_) => depth + 1,
+            Opcode::Access(_) => depth,
+            Opcode::Branch(then, other) => {
+                for offset in [then, other] {
+                    if let Some(target) = pc.checked_add_signed(offset as isize) {
+                        pending.push((target, depth.saturating_sub(1)));
+                    }
+                }
+                continue;
+            }
+            Opcode::Continue | Opcode::Perform(..) | Opcode::Handle => continue,
+            _ => depth.saturating_sub(1),
+        };
+        pending.push((pc + 1, after));
+    }
+    depths
+}
+
+/// Which instructions are branch targets.
+pub(super) fn targets(code: &[Opcode]) -> Vec<bool> {
+    let mut targets = vec![false; code.len()];
+    for (pc, op) in code.iter().enumerate() {
+        if let Opcode::Branch(then, other) = *op {
+            for offset in [then, other] {
+                if let Some(target) = pc.checked_add_signed(offset as isize) {
+                    if let Some(target) = targets.get_mut(target) {
+                        *target = true;
+                    }
+                }
+            }
+        }
+    }
+    targets
+}
+
+/// Rebuilds a closure's code, replacing the instructions for which `edit` returns replacements.
+/// Branches that are kept are fixed up to reach the same instructions, or the ones after them if
+/// they were removed. Replacements keep the span of what they replace.
+pub(super) fn rewrite(closure: &mut Closure, mut edit: impl FnMut(usize) -> Option<Vec<Opcode>>) {
+    let old = std::mem::take(&mut closure.code);
+    let old_spans = std::mem::take(&mut closure.spans);
+    let mut code = Vec::with_capacity(old.len());
+    let mut spans = Vec::with_capacity(old_spans.len());
+    // the new index of each old instruction, and of the end
+    let mut map = Vec::with_capacity(old.len() + 1);
+    let mut branches = Vec::new();
+    for (pc, op) in old.into_iter().enumerate() {
+        map.push(code.len());
+        let span = old_spans.get(pc).copied();
+        let ops = match edit(pc) {
+            Some(ops) => ops,
+            None => {
+                if let Opcode::Branch(..) = op {
+                    branches.push((code.len(), pc));
+                }
+                vec![op]
+            }
+        };
+        if let Some(span) = span {
+            spans.extend(std::iter::repeat_n(span, ops.len()));
+        }
+        code.extend(ops);
+    }
+    map.push(code.len());
+    for (new, old) in branches {
+        if let Opcode::Branch(then, other) = &mut code[new] {
+            let fix = |offset: &mut i32| {
+                let target = old.wrapping_add_signed(*offset as isize);
+                *offset = (map[target] as i64 - new as i64) as i32;
+            };
+            fix(then);
+            fix(other);
+        }
+    }
+    closure.code = code;
+    closure.spans = spans;
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        cache::StringCache,
+        mir::text::{assemble, disassemble},
+        runtime::Captured,
+        Session,
+    };
+
+    use super::*;
+
+    const SRC: &str = r#"
+effect choice {
+    fn choose() -> Int;
+}
+
+effect reader {
+    fn ask() -> Int;
+}
+
+fn add(a: Int, b: Int) -> Int = {
+    a + b
+}
+
+fn constant() -> Int = {
+    let x: Int = (1 + 2) * 3;
+    if x > 100 { print("big"); } else { print("small"); }
+    x - 1
+}
+
+fn chosen() -> Int = {
+    let n: Int = add(constant(), 4);
+    do {
+        let a: Int = choose();
+        add(a, n)
+    } with handle choice {
+        fn choose() -> Int = {
+            let r: Int = return(add(10, 5));
+            r * 2
+        }
+    }
+}
+
+fn main() -> Int = {
+    print("start");
+    let c: Int = chosen();
+    if c == 54 { print("right"); } else { print("wrong"); }
+    do {
+        c + ask()
+    } with handle reader {
+        fn ask() -> Int = { if c > 0 { return(1) } else { return(0 - 1) } }
+    }
+}
+"#;
+
+    fn compile(passes: Passes) -> (Session, Program) {
+        let mut session = Session::new();
+        let compilation = session.compile("main.ku", SRC);
+        let mut program = compilation.program.expect("program");
+        let ds = program.optimize(passes);
+        assert!(ds.is_empty(), "{ds:?}");
+        verify(&program).expect("verified");
+        (session, program)
+    }
+
+    fn run(session: &Session, program: &Program) -> (Vec<String>, Vec<String>) {
+        let mut console = Captured::default();
+        let machine = session.machine(program, "main", Vec::new()).expect("main");
+        let values = machine.with_console(&mut console).run().expect("run");
+        let values = values.iter().map(|v| v.to_string()).collect();
+        (values, console.output)
+    }
+
+    fn code(session: &Session, program: &Program, function: &str) -> Vec<Opcode> {
+        let key = program.function(session.cache().find(function).expect("name"));
+        program.closures[&key.expect("function")].code.clone()
+    }
+
+    #[test]
+    fn keeps_output() {
+        let (session, program) = compile(Passes::level(0));
+        let expected = run(&session, &program);
+        assert_eq!(vec!["55"], expected.0);
+        assert_eq!(vec!["start", "small", "right"], expected.1);
+        for passes in [
+            Passes::level(1),
+            Passes::level(2),
+            Passes {
+                inline: true,
+                ..Passes::default()
+            },
+            Passes {
+                fold: true,
+                ..Passes::default()
+            },
+            Passes {
+                dce: true,
+                ..Passes::default()
+            },
+            Passes {
+                lift: true,
+                convert: true,
+                ..Passes::default()
+            },
+            Passes {
+                tail: true,
+                ..Passes::default()
+            },
+        ] {
+            let (session, program) = compile(passes);
+            assert_eq!(expected, run(&session, &program), "{passes:?}");
+        }
+    }
+
+    #[test]
+    fn folds_constants() {
+        let (session, program) = compile(Passes {
+            fold: true,
+            ..Passes::default()
+        });
+        let code = code(&session, &program, "constant");
+        assert!(!code.contains(&Opcode::Mul), "{code:?}");
+        assert!(!code.contains(&Opcode::Gt), "{code:?}");
+        // `x > 100` is known to be false
+        let unconditional =
+            |op: &Opcode| matches!(*op, Opcode::Branch(then, other) if then == other);
+        assert!(code.iter().any(unconditional), "{code:?}");
+        // the continuation computing `x - 1` sees `x` is 9
+        let rest = program
+            .closures
+            .values()
+            .find(|closure| closure.code.first() == Some(&Opcode::LoadValue(Value::Int(8))));
+        assert!(rest.is_some(), "{}", disassemble(&program, session.cache()));
+    }
+
+    #[test]
+    fn eliminates_dead_code() {
+        let (session, folded) = compile(Passes {
+            fold: true,
+            ..Passes::default()
+        });
+        let (_, program) = compile(Passes::level(1));
+        let before = code(&session, &folded, "constant");
+        let after = code(&session, &program, "constant");
+        assert!(after.len() < before.len(), "{after:?}");
+        assert!(
+            !after.iter().any(|op| matches!(op, Opcode::Branch(..))),
+            "{after:?}"
+        );
+        // the continuation after `print("big")` is gone with it
+        assert_eq!(folded.closures.len() - 1, program.closures.len());
+    }
+
+    #[test]
+    fn inlines_small_functions() {
+        let (session, program) = compile(Passes::level(2));
+        let add = program.symbol("add").expect("add");
+        let calls = program
+            .closures
+            .values()
+            .flat_map(|closure| &closure.code)
+            .filter(|&op| *op == Opcode::LoadValue(Value::Cont(add)))
+            .count();
+        assert_eq!(0, calls, "{}", disassemble(&program, session.cache()));
+    }
+
+    #[test]
+    fn marks_tail_resumptive_actions() {
+        let (session, program) = compile(Passes {
+            tail: true,
+            ..Passes::default()
+        });
+        let ask = program.symbol("reader::ask").expect("ask");
+        let handler = program
+            .handlers
+            .values()
+            .find(|handler| handler.action(ask).is_some())
+            .expect("handler");
+        // `choose` does more after resuming, and `ask` resumes on both paths
+        assert_eq!(vec![ask], handler.tail);
+        let tails = program.handlers.values().flat_map(|handler| &handler.tail);
+        assert_eq!(
+            1,
+            tails.count(),
+            "{}",
+            disassemble(&program, session.cache())
+        );
+        let action = &program.closures[&handler.action(ask).expect("action")];
+        let cc = Opcode::LoadLocal(action.params - 1);
+        assert!(!action.code.contains(&cc), "{:?}", action.code);
+    }
+
+    #[test]
+    fn keeps_type_errors_for_runtime() {
+        // inlining `f` makes `"a" + 1` a sum of constants
+        let src = "fn f(x: Int) -> Int = { x + 1 }\nfn main() -> Int = { f(\"a\") }";
+        let mut session = Session::new();
+        let compilation = session.compile("main.ku", src);
+        let mut program = compilation.program.expect("program");
+        let ds = program.optimize(Passes::level(2));
+        assert!(ds.is_empty(), "{ds:?}");
+        assert!(session.run(&program, "main", Vec::new()).is_err());
+    }
+
+    #[test]
+    fn reports_invalid_output() {
+        let text = "closure main params 1 cont 0\n  locals k\n  add\n  load_local 0\n  continue\n";
+        let mut cache = StringCache::new();
+        let mut program = assemble(text, &mut cache).expect("assemble");
+        let before = disassemble(&program, &cache);
+        let mut ds = Diagnostics::new();
+        optimize(&mut program, Passes::level(2), &mut ds);
+        let codes = ds.iter().map(|d| d.code).collect::<Vec<_>>();
+        assert_eq!(vec![Code::Internal], codes);
+        assert_eq!(before, disassemble(&program, &cache));
+    }
+}
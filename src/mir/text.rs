@@ -0,0 +1,911 @@
+//! The text form of MIR, for reviewing what lowering produces and for writing MIR by hand.
+//!
+//! ```text
+//! builtin print
+//! signature print 1 -> 0
+//! signature forever 0 -> never
+//!
+//! closure main params 1 cont 0
+//!   locals - x
+//!   0: load_value 3
+//!   1: store_local 1  ; x
+//!   2: load_local 1  ; x
+//!   3: make_cont main#0
+//!   4: perform choice::choose 1
+//!
+//! closure main#0 in main params 1 scoped
+//!   captures 0:1
+//!   ...
+//!
+//! handler main#1 scoped
+//!   choice::choose main#2
+//!   state::get main#3 tail
+//!   return main#4
+//! ```
+//!
+//! Functions and operations are written as their qualified names. Anonymous closures and handlers
+//! are named after the function they belong to and numbered in the order they're defined, so the
+//! numbers are only labels. Branch targets are instruction indices; relative offsets such as `+1`
+//! are accepted too. Instruction indices and comments, which start with `;`, are optional. Spans
+//! aren't part of the text form. A converted closure lists its record as the depth and slot each
+//! value is copied from, relative to its parent's frame. Closures and handlers whose values don't
+//! escape the function making them are marked `scoped`.
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fmt::{self, Display, Formatter},
+};
+
+use crate::{
+    builtin::Builtin,
+    cache::{StringCache, StringKey},
+    symbol::{
+        fmt::{SymbolDisplay, SymbolFormatted},
+        SymbolKey, SymbolTable,
+    },
+};
+
+use super::{Closure, Handler, Local, Opcode, Program, Signature, Value};
+
+/// The names MIR is displayed with.
+pub struct Names<'a> {
+    labels: BTreeMap<SymbolKey, String>,
+    strings: &'a [String],
+}
+
+impl<'a> Names<'a> {
+    /// Names the symbols of a program.
+    pub fn new(program: &'a Program) -> Self {
+        let mut labels = program.names.clone();
+        let anonymous = program
+            .closures
+            .keys()
+            .chain(program.handlers.keys())
+            .filter(|key| !program.names.contains_key(key))
+            .collect::<BTreeSet<_>>();
+        let mut counts = HashMap::new();
+        for &key in anonymous {
+            let function = program.table.context(key);
+            let count = counts.entry(function).or_insert(0);
+            let name = program.names.get(&function).map_or("", String::as_str);
+            labels.insert(key, format!("{name}#{count}"));
+            *count += 1;
+        }
+        Names {
+            labels,
+            strings: &program.strings,
+        }
+    }
+
+    pub(crate) fn label(&self, key: SymbolKey) -> &str {
+        self.labels.get(&key).map_or("<unknown>", String::as_str)
+    }
+}
+
+impl SymbolDisplay<Names<'_>> for Value {
+    fn fmt(&self, f: &mut Formatter<'_>, names: &Names<'_>) -> fmt::Result {
+        match *self {
+            Value::Unit => f.write_str("()"),
+            Value::Int(v) => write!(f, "{v}"),
+            Value::Cont(key) => f.write_str(names.label(key)),
+            Value::String(idx) => match names.strings.get(idx) {
+                Some(s) => write!(f, "{s:?}"),
+                None => write!(f, "<string {idx}>"),
+            },
+        }
+    }
+}
+
+impl SymbolDisplay<Names<'_>> for Opcode {
+    fn fmt(&self, f: &mut Formatter<'_>, names: &Names<'_>) -> fmt::Result {
+        f.write_str(mnemonic(self))?;
+        match *self {
+            Opcode::LoadValue(value) => write!(f, " {}", SymbolFormatted(value, names)),
+            Opcode::LoadLocal(slot) | Opcode::StoreLocal(slot) => write!(f, " {slot}"),
+            Opcode::LoadOuter(depth, slot) => write!(f, " {depth} {slot}"),
+            Opcode::Access(idx) => write!(f, " {idx}"),
+            Opcode::Branch(then, other) => write!(f, " {then:+} {other:+}"),
+            Opcode::MakeClosure(key) | Opcode::MakeCont(key) | Opcode::MakeHandler(key) => {
+                write!(f, " {}", names.label(key))
+            }
+            Opcode::Perform(op, args) => write!(f, " {} {args}", names.label(op)),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn mnemonic(op: &Opcode) -> &'static str {
+    match op {
+        Opcode::LoadValue(_) => "load_value",
+        Opcode::LoadLocal(_) => "load_local",
+        Opcode::LoadOuter(..) => "load_outer",
+        Opcode::StoreLocal(_) => "store_local",
+        Opcode::Add => "add",
+        Opcode::Sub => "sub",
+        Opcode::Mul => "mul",
+        Opcode::Div => "div",
+        Opcode::Rem => "rem",
+        Opcode::Eq => "eq",
+        Opcode::NotEq => "not_eq",
+        Opcode::Gt => "gt",
+        Opcode::Ge => "ge",
+        Opcode::Lt => "lt",
+        Opcode::Le => "le",
+        Opcode::Access(_) => "access",
+        Opcode::Branch(..) => "branch",
+        Opcode::MakeClosure(_) => "make_closure",
+        Opcode::MakeCont(_) => "make_cont",
+        Opcode::MakeHandler(_) => "make_handler",
+        Opcode::Handle => "handle",
+        Opcode::Perform(..) => "perform",
+        Opcode::Continue => "continue",
+    }
+}
+
+/// Lists a program. Local names are looked up in the cache it was compiled with.
+pub fn disassemble(program: &Program, cache: &StringCache) -> String {
+    let names = Names::new(program);
+    let mut out = String::new();
+    for builtin in program.builtins.keys() {
+        out += &format!("builtin {}\n", names.label(*builtin));
+    }
+    for (&key, signature) in &program.signatures {
+        let returns = match signature.returns {
+            Some(n) => n.to_string(),
+            None => "never".to_owned(),
+        };
+        out += &format!(
+            "signature {} {} -> {returns}\n",
+            names.label(key),
+            signature.params
+        );
+    }
+    let keys = program
+        .closures
+        .keys()
+        .chain(program.handlers.keys())
+        .collect::<BTreeSet<_>>();
+    for &key in keys {
+        if !out.is_empty() {
+            out.push('\n');
+        }
+        if let Some(closure) = program.closures.get(&key) {
+            list_closure(&mut out, program, &names, cache, key, closure);
+        } else if let Some(handler) = program.handlers.get(&key) {
+            out += &format!("handler {}", names.label(key));
+            out += if program.scoped.contains(&key) {
+                " scoped\n"
+            } else {
+                "\n"
+            };
+            for &(op, action) in &handler.actions {
+                out += &format!("  {} {}", names.label(op), names.label(action));
+                out += if handler.tail.contains(&op) {
+                    " tail\n"
+                } else {
+                    "\n"
+                };
+            }
+            if let Some(ret) = handler.ret {
+                out += &format!("  return {}\n", names.label(ret));
+            }
+            if let Some(finally) = handler.finally {
+                out += &format!("  finally {}\n", names.label(finally));
+            }
+        }
+    }
+    out
+}
+
+fn list_closure(
+    out: &mut String,
+    program: &Program,
+    names: &Names<'_>,
+    cache: &StringCache,
+    key: SymbolKey,
+    closure: &Closure,
+) {
+    *out += &format!("closure {}", names.label(key));
+    if let Some(parent) = closure.parent {
+        *out += &format!(" in {}", names.label(parent));
+    }
+    *out += &format!(" params {}", closure.params);
+    if let Some(cont) = closure.cont {
+        *out += &format!(" cont {cont}");
+    }
+    if program.scoped.contains(&key) {
+        *out += " scoped";
+    }
+    out.push('\n');
+    if let Some(captures) = &closure.captures {
+        *out += "  captures";
+        for (depth, slot) in captures {
+            *out += &format!(" {depth}:{slot}");
+        }
+        out.push('\n');
+    }
+    let name = |local: &Local| match local.name {
+        StringKey::EMPTY => "-",
+        name => &cache[name],
+    };
+    if !closure.locals.is_empty() {
+        let locals = closure.locals.iter().map(name).collect::<Vec<_>>();
+        *out += &format!("  locals {}\n", locals.join(" "));
+    }
+    for (pc, op) in closure.code.iter().enumerate() {
+        *out += &format!("  {pc}: ");
+        let local = match *op {
+            Opcode::Branch(then, other) => {
+                let target = |offset: i32| pc as i64 + offset as i64;
+                *out += &format!("branch {} {}\n", target(then), target(other));
+                continue;
+            }
+            Opcode::LoadLocal(slot) | Opcode::StoreLocal(slot) => closure.locals.get(slot),
+            Opcode::LoadOuter(depth, slot) => program
+                .origin(key, depth, slot)
+                .and_then(|(owner, slot)| program.closures.get(&owner)?.locals.get(slot)),
+            _ => None,
+        };
+        *out += &SymbolFormatted(op.clone(), names).to_string();
+        match local.map(name) {
+            Some(name) if name != "-" => *out += &format!("  ; {name}\n"),
+            _ => out.push('\n'),
+        }
+    }
+}
+
+/// An error in the text form of MIR.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ParseError {
+    /// The 1-based line it occurred on.
+    pub line: usize,
+    pub message: String,
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Reads a program from its text form, interning names in the cache.
+pub fn assemble(text: &str, cache: &mut StringCache) -> Result<Program, ParseError> {
+    let mut lines = Vec::new();
+    for (idx, line) in text.lines().enumerate() {
+        let tokens = tokens(line).map_err(|message| ParseError {
+            line: idx + 1,
+            message,
+        })?;
+        if !tokens.is_empty() {
+            lines.push(Line {
+                line: idx + 1,
+                tokens,
+                pos: 0,
+            });
+        }
+    }
+    let mut asm = Assembler {
+        cache,
+        table: SymbolTable::new(),
+        keys: HashMap::new(),
+        parents: BTreeMap::new(),
+        closures: BTreeMap::new(),
+        handlers: BTreeMap::new(),
+        strings: Vec::new(),
+        builtins: BTreeMap::new(),
+        signatures: BTreeMap::new(),
+        names: BTreeMap::new(),
+        scoped: BTreeSet::new(),
+    };
+    // symbols are allocated in the order they're defined, named before anonymous like the lowering
+    // does, so a listing of the result is in the same order and numbers anonymous symbols the same
+    for line in &lines {
+        if let [Token::Word("signature" | "closure" | "handler"), Token::Word(label), ..] =
+            line.tokens[..]
+        {
+            if !label.contains('#') {
+                asm.symbol(line, label)?;
+            }
+        }
+    }
+    for line in &lines {
+        if let [Token::Word("closure" | "handler"), Token::Word(label), ..] = line.tokens[..] {
+            if label.contains('#') {
+                asm.anonymous(line, label)?;
+            }
+        }
+    }
+    let mut entry = Entry::None;
+    for mut line in lines {
+        match line.word()? {
+            "builtin" => {
+                asm.finish(std::mem::take(&mut entry));
+                let label = line.word()?;
+                let key = asm.symbol(&line, label)?;
+                let builtin = Builtin::ALL
+                    .into_iter()
+                    .find(|b| b.name() == label || format!("{}::{}", b.effect(), b.name()) == label)
+                    .ok_or_else(|| line.error(format!("no built-in operation {label}")))?;
+                asm.builtins.insert(key, builtin);
+            }
+            "signature" => {
+                asm.finish(std::mem::take(&mut entry));
+                let label = line.word()?;
+                let key = asm.symbol(&line, label)?;
+                let params = line.number()?;
+                line.expect("->")?;
+                let returns = match line.word()? {
+                    "never" => None,
+                    n => Some(n.parse().map_err(|_| line.error("expected a number"))?),
+                };
+                asm.signatures.insert(key, Signature { params, returns });
+            }
+            "closure" => {
+                asm.finish(std::mem::take(&mut entry));
+                let label = line.word()?;
+                let key = asm.definition(&line, label)?;
+                let mut parent = None;
+                let mut keyword = line.word()?;
+                if keyword == "in" {
+                    parent = Some(asm.label(&mut line)?);
+                    keyword = line.word()?;
+                }
+                if keyword != "params" {
+                    return Err(line.error("expected `params`"));
+                }
+                let params = line.number()?;
+                let cont = match line.peek_word() {
+                    Some("cont") => {
+                        line.pos += 1;
+                        Some(line.number()?)
+                    }
+                    _ => None,
+                };
+                asm.scope(&mut line, key);
+                let closure = Closure {
+                    parent,
+                    params,
+                    cont,
+                    span: Default::default(),
+                    captures: None,
+                    locals: Vec::new(),
+                    code: Vec::new(),
+                    spans: Vec::new(),
+                };
+                entry = Entry::Closure(key, closure, line.line);
+            }
+            "handler" => {
+                asm.finish(std::mem::take(&mut entry));
+                let label = line.word()?;
+                let key = asm.definition(&line, label)?;
+                asm.scope(&mut line, key);
+                let handler = Handler {
+                    actions: Vec::new(),
+                    tail: Vec::new(),
+                    ret: None,
+                    finally: None,
+                };
+                entry = Entry::Handler(key, handler);
+            }
+            word => match &mut entry {
+                Entry::None => return Err(line.error(format!("unexpected `{word}`"))),
+                Entry::Closure(_, closure, _) => {
+                    if word == "captures" {
+                        let mut captures = Vec::new();
+                        while let Some(capture) = line.peek_word() {
+                            let capture = capture
+                                .split_once(':')
+                                .and_then(|(depth, slot)| {
+                                    Some((depth.parse().ok()?, slot.parse().ok()?))
+                                })
+                                .ok_or_else(|| {
+                                    line.error(format!("expected a capture, found {capture}"))
+                                })?;
+                            line.pos += 1;
+                            captures.push(capture);
+                        }
+                        closure.captures = Some(captures);
+                    } else if word == "locals" {
+                        while let Some(name) = line.peek_word() {
+                            line.pos += 1;
+                            let name = match name {
+                                "-" => StringKey::EMPTY,
+                                name => asm.cache.intern(name),
+                            };
+                            closure.locals.push(Local { name });
+                        }
+                    } else {
+                        line.pos -= 1;
+                        let op = asm.instruction(&mut line, closure.code.len())?;
+                        closure.code.push(op);
+                    }
+                }
+                Entry::Handler(_, handler) => match word {
+                    "return" => handler.ret = Some(asm.label(&mut line)?),
+                    "finally" => handler.finally = Some(asm.label(&mut line)?),
+                    op => {
+                        let op = asm.symbol(&line, op)?;
+                        let action = asm.label(&mut line)?;
+                        handler.actions.push((op, action));
+                        if line.peek_word() == Some("tail") {
+                            line.pos += 1;
+                            handler.tail.push(op);
+                        }
+                    }
+                },
+            },
+        }
+        line.end()?;
+    }
+    asm.finish(entry);
+    for (parent, line) in asm.parents.values() {
+        if let Some(parent) = parent {
+            if !asm.closures.contains_key(parent) {
+                return Err(ParseError {
+                    line: *line,
+                    message: "the parent isn't a closure".to_owned(),
+                });
+            }
+        }
+    }
+    Ok(Program {
+        table: asm.table,
+        closures: asm.closures,
+        handlers: asm.handlers,
+        strings: asm.strings,
+        builtins: asm.builtins,
+        signatures: asm.signatures,
+        names: asm.names,
+        scoped: asm.scoped,
+    })
+}
+
+#[derive(Default)]
+enum Entry {
+    #[default]
+    None,
+    /// A closure, and the line it's defined on.
+    Closure(SymbolKey, Closure, usize),
+    Handler(SymbolKey, Handler),
+}
+
+struct Assembler<'a> {
+    cache: &'a mut StringCache,
+    table: SymbolTable,
+    /// The symbols of labels.
+    keys: HashMap<String, SymbolKey>,
+    /// The parent of each closure and the line it's defined on, for checking once all are known.
+    parents: BTreeMap<SymbolKey, (Option<SymbolKey>, usize)>,
+    closures: BTreeMap<SymbolKey, Closure>,
+    handlers: BTreeMap<SymbolKey, Handler>,
+    strings: Vec<String>,
+    builtins: BTreeMap<SymbolKey, Builtin>,
+    signatures: BTreeMap<SymbolKey, Signature>,
+    names: BTreeMap<SymbolKey, String>,
+    scoped: BTreeSet<SymbolKey>,
+}
+
+impl Assembler<'_> {
+    /// Allocates an anonymous symbol under the function its label names.
+    fn anonymous(&mut self, line: &Line<'_>, label: &str) -> Result<(), ParseError> {
+        if self.keys.contains_key(label) {
+            return Err(line.error(format!("{label} is defined twice")));
+        }
+        let (function, _) = label.split_once('#').expect("anonymous label");
+        let context = match function {
+            "" => SymbolKey::ROOT,
+            function => self.symbol(line, function)?,
+        };
+        let key = self.table.define_anonymous(context);
+        self.keys.insert(label.to_owned(), key);
+        Ok(())
+    }
+
+    /// Gets the symbol of a label, defining it if it's a qualified name.
+    fn symbol(&mut self, line: &Line<'_>, label: &str) -> Result<SymbolKey, ParseError> {
+        if let Some(&key) = self.keys.get(label) {
+            return Ok(key);
+        }
+        if label.contains('#') {
+            return Err(line.error(format!("{label} is never defined")));
+        }
+        let mut path = Vec::new();
+        let mut key = SymbolKey::ROOT;
+        for part in label.split("::") {
+            if !is_name(part) {
+                return Err(line.error(format!("invalid name {label}")));
+            }
+            let part = self.cache.intern(part);
+            path.push(part);
+            key = match self.table.resolve(&path, SymbolKey::ROOT) {
+                Some(key) => key,
+                None => self.table.define(part, key).expect("undefined"),
+            };
+        }
+        self.keys.insert(label.to_owned(), key);
+        self.names.insert(key, label.to_owned());
+        Ok(key)
+    }
+
+    /// Reads a label and gets its symbol.
+    fn label(&mut self, line: &mut Line<'_>) -> Result<SymbolKey, ParseError> {
+        let label = line.word()?;
+        self.symbol(line, label)
+    }
+
+    /// Gets the symbol of a closure or handler being defined.
+    fn definition(&mut self, line: &Line<'_>, label: &str) -> Result<SymbolKey, ParseError> {
+        let key = self.symbol(line, label)?;
+        if self.closures.contains_key(&key)
+            || self.handlers.contains_key(&key)
+            || self.parents.contains_key(&key)
+        {
+            return Err(line.error(format!("{label} is defined twice")));
+        }
+        self.parents.insert(key, (None, line.line));
+        Ok(key)
+    }
+
+    /// Reads the `scoped` marker ending the header of a closure or handler, if it's there.
+    fn scope(&mut self, line: &mut Line<'_>, key: SymbolKey) {
+        if line.peek_word() == Some("scoped") {
+            line.pos += 1;
+            self.scoped.insert(key);
+        }
+    }
+
+    fn finish(&mut self, entry: Entry) {
+        match entry {
+            Entry::None => {}
+            Entry::Closure(key, closure, line) => {
+                self.parents.insert(key, (closure.parent, line));
+                self.closures.insert(key, closure);
+            }
+            Entry::Handler(key, handler) => {
+                self.parents.remove(&key);
+                self.handlers.insert(key, handler);
+            }
+        }
+    }
+
+    /// Reads the instruction at index `pc` of a closure.
+    fn instruction(&mut self, line: &mut Line<'_>, pc: usize) -> Result<Opcode, ParseError> {
+        let mut mnemonic = line.word()?;
+        if let Some(idx) = mnemonic.strip_suffix(':') {
+            if idx.parse() != Ok(pc) {
+                return Err(line.error(format!("expected instruction {pc}")));
+            }
+            mnemonic = line.word()?;
+        }
+        let op = match mnemonic {
+            "load_value" => Opcode::LoadValue(self.value(line)?),
+            "load_local" => Opcode::LoadLocal(line.number()?),
+            "load_outer" => Opcode::LoadOuter(line.number()?, line.number()?),
+            "store_local" => Opcode::StoreLocal(line.number()?),
+            "add" => Opcode::Add,
+            "sub" => Opcode::Sub,
+            "mul" => Opcode::Mul,
+            "div" => Opcode::Div,
+            "rem" => Opcode::Rem,
+            "eq" => Opcode::Eq,
+            "not_eq" => Opcode::NotEq,
+            "gt" => Opcode::Gt,
+            "ge" => Opcode::Ge,
+            "lt" => Opcode::Lt,
+            "le" => Opcode::Le,
+            "access" => Opcode::Access(line.number()?),
+            "branch" => Opcode::Branch(line.target(pc)?, line.target(pc)?),
+            "make_closure" => Opcode::MakeClosure(self.label(line)?),
+            "make_cont" => Opcode::MakeCont(self.label(line)?),
+            "make_handler" => Opcode::MakeHandler(self.label(line)?),
+            "handle" => Opcode::Handle,
+            "perform" => Opcode::Perform(self.label(line)?, line.number()?),
+            "continue" => Opcode::Continue,
+            other => return Err(line.error(format!("unknown instruction `{other}`"))),
+        };
+        Ok(op)
+    }
+
+    fn value(&mut self, line: &mut Line<'_>) -> Result<Value, ParseError> {
+        match line.tokens.get(line.pos) {
+            Some(Token::Str(s)) => {
+                line.pos += 1;
+                let idx = match self.strings.iter().position(|t| t == s) {
+                    Some(idx) => idx,
+                    None => {
+                        self.strings.push(s.clone());
+                        self.strings.len() - 1
+                    }
+                };
+                Ok(Value::String(idx))
+            }
+            _ => match line.word()? {
+                "()" => Ok(Value::Unit),
+                word if word.starts_with(|c: char| c == '-' || c.is_ascii_digit()) => word
+                    .parse()
+                    .map(Value::Int)
+                    .map_err(|_| line.error(format!("invalid integer {word}"))),
+                label => Ok(Value::Cont(self.symbol(line, label)?)),
+            },
+        }
+    }
+}
+
+fn is_name(s: &str) -> bool {
+    s.starts_with(|c: char| c.is_alphabetic() || c == '_')
+        && s.chars().all(|c| c.is_alphanumeric() || c == '_')
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Token<'a> {
+    Word(&'a str),
+    Str(String),
+}
+
+/// The tokens of a line, and how many have been read.
+struct Line<'a> {
+    line: usize,
+    tokens: Vec<Token<'a>>,
+    pos: usize,
+}
+
+impl<'a> Line<'a> {
+    fn error(&self, message: impl Into<String>) -> ParseError {
+        ParseError {
+            line: self.line,
+            message: message.into(),
+        }
+    }
+
+    fn peek(&self) -> Option<&Token<'a>> {
+        self.tokens.get(self.pos)
+    }
+
+    fn peek_word(&self) -> Option<&'a str> {
+        match self.peek() {
+            Some(&Token::Word(word)) => Some(word),
+            _ => None,
+        }
+    }
+
+    fn word(&mut self) -> Result<&'a str, ParseError> {
+        let word = self
+            .peek_word()
+            .ok_or_else(|| self.error("expected a word"))?;
+        self.pos += 1;
+        Ok(word)
+    }
+
+    fn expect(&mut self, expected: &str) -> Result<(), ParseError> {
+        match self.word()? {
+            word if word == expected => Ok(()),
+            _ => Err(self.error(format!("expected `{expected}`"))),
+        }
+    }
+
+    fn number(&mut self) -> Result<usize, ParseError> {
+        let word = self.word()?;
+        word.parse()
+            .map_err(|_| self.error(format!("expected a number, found {word}")))
+    }
+
+    /// Reads a branch target, as an index or an offset, as the offset from `pc`.
+    fn target(&mut self, pc: usize) -> Result<i32, ParseError> {
+        let word = self.word()?;
+        let target = match word.strip_prefix('+') {
+            Some(offset) => offset.parse().ok(),
+            None if word.starts_with('-') => word.parse().ok(),
+            None => word
+                .parse::<i64>()
+                .ok()
+                .and_then(|idx| i32::try_from(idx - pc as i64).ok()),
+        };
+        target.ok_or_else(|| self.error(format!("invalid branch target {word}")))
+    }
+
+    fn end(&self) -> Result<(), ParseError> {
+        match self.peek() {
+            None => Ok(()),
+            Some(_) => Err(self.error("unexpected text at the end of the line")),
+        }
+    }
+}
+
+/// Splits a line into words and string literals, up to a comment.
+fn tokens(line: &str) -> Result<Vec<Token<'_>>, String> {
+    let mut tokens = Vec::new();
+    let mut rest = line.trim_start();
+    while !rest.is_empty() && !rest.starts_with(';') {
+        if let Some(s) = rest.strip_prefix('"') {
+            let (value, len) = unescape(s)?;
+            tokens.push(Token::Str(value));
+            rest = &s[len..];
+        } else {
+            let end = rest
+                .find(|c: char| c.is_whitespace() || c == ';' || c == '"')
+                .unwrap_or(rest.len());
+            tokens.push(Token::Word(&rest[..end]));
+            rest = &rest[end..];
+        }
+        rest = rest.trim_start();
+    }
+    Ok(tokens)
+}
+
+/// Reads a string literal written as Rust's `Debug` writes strings, starting after the opening
+/// quote. Returns the string and the length of the literal's remainder, including the closing quote.
+fn unescape(s: &str) -> Result<(String, usize), String> {
+    let mut value = String::new();
+    let mut chars = s.char_indices();
+    while let Some((idx, c)) = chars.next() {
+        match c {
+            '"' => return Ok((value, idx + 1)),
+            '\\' => {
+                let escaped = match chars.next().map(|(_, c)| c) {
+                    Some('n') => '\n',
+                    Some('t') => '\t',
+                    Some('r') => '\r',
+                    Some('0') => '\0',
+                    Some(c @ ('\\' | '"' | '\'')) => c,
+                    Some('u') => {
+                        let rest = &s[idx + 2..];
+                        let code = rest
+                            .strip_prefix('{')
+                            .and_then(|rest| rest.split_once('}'))
+                            .and_then(|(hex, _)| u32::from_str_radix(hex, 16).ok())
+                            .and_then(char::from_u32)
+                            .ok_or("invalid unicode escape")?;
+                        let len = rest.find('}').expect("closing brace");
+                        for _ in 0..len {
+                            chars.next();
+                        }
+                        code
+                    }
+                    _ => return Err("invalid escape".to_owned()),
+                };
+                value.push(escaped);
+            }
+            c => value.push(c),
+        }
+    }
+    Err("unterminated string".to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        runtime::{Captured, Control, Host, IntoValue, Machine},
+        Session,
+    };
+
+    use super::*;
+
+    const SRC: &str = r#"
+effect choice {
+    fn choose() -> Int;
+}
+
+fn pick(n: Int) -> Int = {
+    let k: Int = if n > 0 { n } else { 0 - n };
+    k * 2
+}
+
+fn main() -> Int = {
+    print("a \"quoted\"\tline");
+    let x: Int = pick(3);
+    do {
+        let a: Int = choose();
+        a + x
+    } with handle choice {
+        fn choose() -> Int = {
+            let r: Int = return(10);
+            r + 1
+        }
+    }
+}
+"#;
+
+    fn run(program: &Program, cache: &StringCache) -> (Vec<String>, Vec<String>) {
+        let main = program.function(cache.find("main").expect("main"));
+        let mut console = Captured::default();
+        let mut machine = Machine::new(program, main.expect("main"), Vec::new())
+            .expect("machine")
+            .with_console(&mut console);
+        let values = machine.run().expect("run");
+        drop(machine);
+        let values = values.iter().map(|v| v.to_string()).collect();
+        (values, console.output)
+    }
+
+    #[test]
+    fn round_trips() {
+        let mut session = Session::new();
+        let compilation = session.compile("main.ku", SRC);
+        let program = compilation.program.expect("program");
+        let text = disassemble(&program, session.cache());
+        let assembled = assemble(&text, session.cache_mut()).expect("assemble");
+        assert_eq!(text, disassemble(&assembled, session.cache()));
+        assert_eq!(
+            run(&program, session.cache()),
+            run(&assembled, session.cache())
+        );
+    }
+
+    #[test]
+    fn lists_closures() {
+        let mut session = Session::new();
+        let compilation =
+            session.compile("main.ku", "fn main() -> Int = { let x: Int = 3; x + 1 }");
+        let program = compilation.program.expect("program");
+        let expected = "\
+closure main params 1 cont 0
+  locals - x -
+  0: load_value 3
+  1: store_local 1  ; x
+  2: load_local 1  ; x
+  3: load_value 1
+  4: add
+  5: store_local 2
+  6: load_local 2
+  7: load_local 0
+  8: continue
+";
+        assert_eq!(expected, disassemble(&program, session.cache()));
+    }
+
+    #[test]
+    fn assembles_handwritten() {
+        let text = r#"
+signature ask 0 -> 1
+
+; main(k) = ask(|n| if n < 0 { k(0) } else { k(n * 2) })
+closure main params 1 cont 0
+  locals k
+  make_cont main#0
+  perform ask 0
+
+closure main#0 in main params 1
+  locals n
+  0: load_local 0  ; n
+  1: load_value 0
+  2: lt
+  3: branch +1 7
+  4: load_value 0
+  5: load_outer 1 0
+  6: continue
+  7: load_local 0
+  8: load_value 2
+  9: mul
+  10: load_outer 1 0
+  11: continue
+"#;
+        let mut cache = StringCache::new();
+        let program = assemble(text, &mut cache).expect("assemble");
+        let main = program.function(cache.find("main").expect("main"));
+        let mut host = Host::new();
+        host.handler("ask", |_, _| Ok(Control::Resume(vec![21.into_value()])));
+        let mut machine = Machine::new(&program, main.expect("main"), Vec::new())
+            .expect("machine")
+            .with_host(host);
+        let values = machine.run().expect("run");
+        assert_eq!(
+            vec!["42"],
+            values.iter().map(|v| v.to_string()).collect::<Vec<_>>()
+        );
+        let branch = &program.closures.values().nth(1).expect("continuation").code[3];
+        assert_eq!(&Opcode::Branch(1, 4), branch);
+    }
+
+    #[test]
+    fn reports_errors() {
+        let mut cache = StringCache::new();
+        let err = assemble("closure main params 0\n  frobnicate\n", &mut cache).unwrap_err();
+        assert_eq!("line 2: unknown instruction `frobnicate`", err.to_string());
+        let err = assemble("closure main params 0\n  make_cont main#3\n", &mut cache).unwrap_err();
+        assert_eq!(2, err.line);
+        let err = assemble("closure main params 0\n  1: continue\n", &mut cache).unwrap_err();
+        assert_eq!("line 2: expected instruction 0", err.to_string());
+    }
+}
@@ -0,0 +1,690 @@
+//! Checks that MIR keeps the stack discipline described on [`Opcode`].
+//!
+//! Each closure's code is interpreted abstractly, tracking the type of each value on the operand
+//! stack and in the closure's locals along every path. The verifier checks that instructions have
+//! the operands they need with the right types, that locals and branch targets exist, that
+//! everything referred to is defined, and that every path ends by calling something: with
+//! `Continue`, `Perform` or `Handle`.
+
+use std::fmt::{self, Display, Formatter};
+
+use crate::{
+    span::Span,
+    symbol::{fmt::SymbolDisplay, SymbolKey},
+};
+
+use super::{opt::tail_resumptive, text::Names, Closure, Opcode, Program, Value};
+
+/// What the verifier knows about a value.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Type {
+    Unit,
+    Int,
+    String,
+    /// A closure, continuation, or function.
+    Function,
+    Handler,
+    /// Any value, such as a parameter.
+    Any,
+}
+
+impl Type {
+    fn join(self, other: Type) -> Type {
+        if self == other {
+            self
+        } else {
+            Type::Any
+        }
+    }
+}
+
+impl Display for Type {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Type::Unit => "unit",
+            Type::Int => "an integer",
+            Type::String => "a string",
+            Type::Function => "a function",
+            Type::Handler => "a handler",
+            Type::Any => "any value",
+        })
+    }
+}
+
+/// A reason MIR is invalid.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Problem {
+    /// An instruction needs more operands than are on the stack.
+    Underflow,
+    Type {
+        expected: Type,
+        found: Type,
+    },
+    /// A local slot the closure doesn't have.
+    Local(usize),
+    /// A local of an enclosing closure, `depth` closures out, that doesn't exist.
+    Outer {
+        depth: usize,
+        slot: usize,
+    },
+    /// A branch to the given instruction index, which is out of bounds.
+    Target(i64),
+    /// Paths reaching an instruction with different numbers of operands on the stack.
+    Depth {
+        expected: usize,
+        found: usize,
+    },
+    /// A path ends without calling anything.
+    FallsOffEnd,
+    /// A closure, handler or operation that isn't defined.
+    Undefined(SymbolKey),
+    /// A string literal that isn't defined.
+    String(usize),
+    /// An operation performed with the wrong number of arguments.
+    Arity {
+        expected: usize,
+        found: usize,
+    },
+    /// More parameters than locals, or a continuation that isn't a parameter.
+    Params,
+    /// A handler action marked tail-resumptive that isn't.
+    Tail,
+    /// A record of captures that reads locals that don't exist, or a closure with one that's a
+    /// handler clause or called by name.
+    Captures,
+    /// A closure made, or called by name, anywhere but in its parent, or one called by name that
+    /// reads its parent's frame.
+    Nesting,
+}
+
+impl Problem {
+    /// Whether the problem can come from an ill-typed source program, such as one adding a string
+    /// to an integer, rather than from a bug in whatever produced the MIR.
+    pub fn is_type_error(&self) -> bool {
+        matches!(self, Problem::Type { .. } | Problem::Arity { .. })
+    }
+}
+
+impl Display for Problem {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Problem::Underflow => f.write_str("operand stack underflow"),
+            Problem::Type { expected, found } => write!(f, "expected {expected}, found {found}"),
+            Problem::Local(slot) => write!(f, "no local {slot}"),
+            Problem::Outer { depth, slot } => write!(f, "no local {slot} {depth} closures out"),
+            Problem::Target(target) => write!(f, "branch target {target} is out of bounds"),
+            Problem::Depth { expected, found } => write!(
+                f,
+                "paths join with {expected} and {found} values on the stack"
+            ),
+            Problem::FallsOffEnd => f.write_str("falls off the end of the code"),
+            Problem::Undefined(key) => write!(f, "{key:?} isn't defined"),
+            Problem::String(idx) => write!(f, "string {idx} isn't defined"),
+            Problem::Arity { expected, found } => {
+                write!(f, "expected {expected} arguments, found {found}")
+            }
+            Problem::Params => f.write_str("invalid parameters"),
+            Problem::Tail => f.write_str("the action isn't tail-resumptive"),
+            Problem::Captures => f.write_str("invalid captures"),
+            Problem::Nesting => f.write_str("a closure used outside its parent"),
+        }
+    }
+}
+
+/// Invalid MIR, with the closure and instruction index it's at.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct VerifyError {
+    pub closure: SymbolKey,
+    pub pc: usize,
+    pub problem: Problem,
+}
+
+impl Display for VerifyError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "in {:?} at {}: {}", self.closure, self.pc, self.problem)
+    }
+}
+
+impl std::error::Error for VerifyError {}
+
+impl VerifyError {
+    /// The span of the source that the invalid instruction was lowered from.
+    pub fn span(&self, program: &Program) -> Span {
+        program.closures[&self.closure]
+            .spans
+            .get(self.pc)
+            .copied()
+            .unwrap_or_default()
+    }
+}
+
+impl SymbolDisplay<Names<'_>> for VerifyError {
+    fn fmt(&self, f: &mut Formatter<'_>, names: &Names<'_>) -> fmt::Result {
+        write!(f, "in {} at {}: ", names.label(self.closure), self.pc)?;
+        match self.problem {
+            Problem::Undefined(key) => write!(f, "{} isn't defined", names.label(key)),
+            problem => write!(f, "{problem}"),
+        }
+    }
+}
+
+/// Verifies every closure of a program.
+pub fn verify(program: &Program) -> Result<(), VerifyError> {
+    check(program, true)
+}
+
+/// Verifies everything the interpreter relies on to run a program without panicking, which is all
+/// [`verify`] checks but the types of values: the interpreter checks those as it goes.
+pub fn verify_structure(program: &Program) -> Result<(), VerifyError> {
+    check(program, false)
+}
+
+fn check(program: &Program, types: bool) -> Result<(), VerifyError> {
+    for (&key, closure) in &program.closures {
+        let error = |pc, problem| VerifyError {
+            closure: key,
+            pc,
+            problem,
+        };
+        if closure.params > closure.locals.len()
+            || closure.cont.is_some_and(|cont| cont >= closure.params)
+        {
+            return Err(error(0, Problem::Params));
+        }
+        if let Some(parent) = closure.parent {
+            if !program.closures.contains_key(&parent) {
+                return Err(error(0, Problem::Undefined(parent)));
+            }
+        }
+        if let Some(captures) = &closure.captures {
+            let clause = program.handlers.values().any(|handler| {
+                handler.actions.iter().any(|&(_, action)| action == key)
+                    || handler.ret == Some(key)
+                    || handler.finally == Some(key)
+            });
+            let resolves = |&(depth, slot): &(usize, usize)| {
+                closure
+                    .parent
+                    .and_then(|parent| program.origin(parent, depth, slot))
+                    .and_then(|(owner, slot)| program.closures.get(&owner)?.locals.get(slot))
+                    .is_some()
+            };
+            if clause || !captures.iter().all(resolves) {
+                return Err(error(0, Problem::Captures));
+            }
+        }
+        Verifier {
+            program,
+            key,
+            closure,
+            types,
+        }
+        .run()
+        .map_err(|(pc, problem)| error(pc, problem))?;
+    }
+    for (&key, handler) in &program.handlers {
+        for &op in &handler.tail {
+            let undefined = |undefined| VerifyError {
+                closure: key,
+                pc: 0,
+                problem: Problem::Undefined(undefined),
+            };
+            let action = handler.action(op).ok_or(undefined(op))?;
+            let closure = program.closures.get(&action).ok_or(undefined(action))?;
+            let results = program.signatures.get(&op).and_then(|s| s.returns);
+            if !results.is_some_and(|results| tail_resumptive(closure, results)) {
+                return Err(VerifyError {
+                    closure: action,
+                    pc: 0,
+                    problem: Problem::Tail,
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The types of the values on the stack and in the locals before an instruction.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct State {
+    stack: Vec<Type>,
+    locals: Vec<Type>,
+}
+
+impl State {
+    fn pop(&mut self) -> Result<Type, Problem> {
+        self.stack.pop().ok_or(Problem::Underflow)
+    }
+
+    fn pop_as(&mut self, expected: Type) -> Result<Type, Problem> {
+        match self.pop()? {
+            Type::Any => Ok(expected),
+            found if found == expected => Ok(found),
+            found => Err(Problem::Type { expected, found }),
+        }
+    }
+
+    /// Joins the state of another path to the same instruction, returning whether it changed.
+    fn join(&mut self, other: &State) -> Result<bool, Problem> {
+        if self.stack.len() != other.stack.len() {
+            return Err(Problem::Depth {
+                expected: self.stack.len(),
+                found: other.stack.len(),
+            });
+        }
+        let joined = State {
+            stack: join(&self.stack, &other.stack),
+            locals: join(&self.locals, &other.locals),
+        };
+        let changed = joined != *self;
+        *self = joined;
+        Ok(changed)
+    }
+}
+
+fn join(a: &[Type], b: &[Type]) -> Vec<Type> {
+    a.iter().zip(b).map(|(a, b)| a.join(*b)).collect()
+}
+
+struct Verifier<'a> {
+    program: &'a Program,
+    key: SymbolKey,
+    closure: &'a Closure,
+    /// Whether to track the types of values, rather than taking every value to be `Any`.
+    types: bool,
+}
+
+impl Verifier<'_> {
+    fn run(&self) -> Result<(), (usize, Problem)> {
+        let code = &self.closure.code;
+        let mut locals = vec![Type::Unit; self.closure.locals.len()];
+        let params = if self.types {
+            self.closure.params
+        } else {
+            locals.len()
+        };
+        locals[..params].fill(Type::Any);
+        let mut states: Vec<Option<State>> = vec![None; code.len()];
+        let entry = State {
+            stack: Vec::new(),
+            locals,
+        };
+        if code.is_empty() {
+            return Err((0, Problem::FallsOffEnd));
+        }
+        states[0] = Some(entry);
+        let mut pending = vec![0];
+        while let Some(pc) = pending.pop() {
+            let mut state = states[pc]
+                .clone()
+                .expect("pending instructions have a state");
+            let next = self.step(pc, &mut state).map_err(|problem| (pc, problem))?;
+            if !self.types {
+                state.stack.fill(Type::Any);
+                state.locals.fill(Type::Any);
+            }
+            for target in next {
+                let target = match usize::try_from(target) {
+                    Ok(target) if target < code.len() => target,
+                    _ if target == code.len() as i64 => return Err((pc, Problem::FallsOffEnd)),
+                    _ => return Err((pc, Problem::Target(target))),
+                };
+                match &mut states[target] {
+                    Some(existing) => {
+                        if existing.join(&state).map_err(|problem| (target, problem))? {
+                            pending.push(target);
+                        }
+                    }
+                    slot @ None => {
+                        *slot = Some(state.clone());
+                        pending.push(target);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Interprets an instruction, returning the instructions that can run next.
+    fn step(&self, pc: usize, state: &mut State) -> Result<Vec<i64>, Problem> {
+        let program = self.program;
+        let closure = |key: SymbolKey| match program.closures.get(&key) {
+            Some(closure) => Ok(closure),
+            None => Err(Problem::Undefined(key)),
+        };
+        // a closure's environment is the frame of the closure that makes it, which must be its
+        // parent's; one called by name has none, so it can't have a record or read its parent's
+        // frame
+        let nested = |closure: &Closure| match closure.parent {
+            Some(parent) if parent != self.key => Err(Problem::Nesting),
+            _ => Ok(()),
+        };
+        let recorded = |closure: &Closure| closure.captures.as_ref().is_some_and(|c| !c.is_empty());
+        let local = |state: &State, slot: usize| match state.locals.get(slot) {
+            Some(&ty) => Ok(ty),
+            None => Err(Problem::Local(slot)),
+        };
+        let next = pc as i64 + 1;
+        match *self.closure.code.get(pc).ok_or(Problem::FallsOffEnd)? {
+            Opcode::LoadValue(value) => {
+                let ty = match value {
+                    Value::Unit => Type::Unit,
+                    Value::Int(_) => Type::Int,
+                    Value::Cont(key) => {
+                        if !program.signatures.contains_key(&key) {
+                            let named = closure(key)?;
+                            nested(named)?;
+                            if recorded(named) {
+                                return Err(Problem::Captures);
+                            }
+                            if named.parent.is_some() && named.captures.is_none() {
+                                return Err(Problem::Nesting);
+                            }
+                        }
+                        Type::Function
+                    }
+                    Value::String(idx) if idx < program.strings.len() => Type::String,
+                    Value::String(idx) => return Err(Problem::String(idx)),
+                };
+                state.stack.push(ty);
+            }
+            Opcode::LoadLocal(slot) => {
+                let ty = local(state, slot)?;
+                state.stack.push(ty);
+            }
+            Opcode::LoadOuter(depth, slot) => {
+                let outer = program
+                    .origin(self.key, depth, slot)
+                    .and_then(|(owner, slot)| program.closures.get(&owner)?.locals.get(slot));
+                match outer {
+                    // the current frame's locals are known
+                    Some(_) if depth == 0 => {
+                        let ty = local(state, slot)?;
+                        state.stack.push(ty);
+                    }
+                    Some(_) => state.stack.push(Type::Any),
+                    _ if depth == 0 => return Err(Problem::Local(slot)),
+                    _ => return Err(Problem::Outer { depth, slot }),
+                }
+            }
+            Opcode::StoreLocal(slot) => {
+                local(state, slot)?;
+                state.locals[slot] = state.pop()?;
+            }
+            Opcode::Add | Opcode::Eq | Opcode::NotEq => {
+                let b = state.pop()?;
+                let a = state.pop()?;
+                let mismatch = |expected, found| Problem::Type { expected, found };
+                let ty = match (a, b) {
+                    (Type::Any, Type::Any) => Type::Any,
+                    (Type::Int | Type::Any, Type::Int | Type::Any) => Type::Int,
+                    (Type::String | Type::Any, Type::String | Type::Any) => Type::String,
+                    (Type::String, found) => return Err(mismatch(Type::String, found)),
+                    (Type::Int | Type::Any, found) | (found, _) => {
+                        return Err(mismatch(Type::Int, found))
+                    }
+                };
+                // comparisons produce an integer whatever they compare
+                let op = &self.closure.code[pc];
+                state.stack.push(match op {
+                    Opcode::Add => ty,
+                    _ => Type::Int,
+                });
+            }
+            Opcode::Sub
+            | Opcode::Mul
+            | Opcode::Div
+            | Opcode::Rem
+            | Opcode::Gt
+            | Opcode::Ge
+            | Opcode::Lt
+            | Opcode::Le => {
+                state.pop_as(Type::Int)?;
+                state.pop_as(Type::Int)?;
+                state.stack.push(Type::Int);
+            }
+            Opcode::Access(_) => {
+                state.pop()?;
+                state.stack.push(Type::Any);
+            }
+            Opcode::Branch(then, other) => {
+                state.pop_as(Type::Int)?;
+                return Ok(vec![pc as i64 + then as i64, pc as i64 + other as i64]);
+            }
+            Opcode::MakeClosure(key) | Opcode::MakeCont(key) => {
+                let made = closure(key)?;
+                nested(made)?;
+                if made.captures.is_some() && made.parent.is_none() {
+                    return Err(Problem::Captures);
+                }
+                state.stack.push(Type::Function);
+            }
+            Opcode::MakeHandler(key) => {
+                let handler = program.handlers.get(&key).ok_or(Problem::Undefined(key))?;
+                // the clauses' environment is the frame the handler is made in
+                let clauses = handler.actions.iter().map(|&(_, action)| action);
+                for clause in clauses.chain(handler.ret).chain(handler.finally) {
+                    nested(closure(clause)?)?;
+                }
+                state.stack.push(Type::Handler);
+            }
+            Opcode::Handle => {
+                state.pop_as(Type::Function)?;
+                state.pop_as(Type::Function)?;
+                state.pop_as(Type::Handler)?;
+                return Ok(Vec::new());
+            }
+            Opcode::Perform(op, args) => {
+                let signature = program.signatures.get(&op).ok_or(Problem::Undefined(op))?;
+                // everything on the stack is passed, with the continuation last if there's one
+                if signature.params != args {
+                    return Err(Problem::Arity {
+                        expected: signature.params,
+                        found: args,
+                    });
+                }
+                let found = state.stack.len();
+                if !(args..=args + 1).contains(&found) {
+                    return Err(Problem::Arity {
+                        expected: args,
+                        found,
+                    });
+                }
+                if found > args {
+                    state.pop_as(Type::Function)?;
+                }
+                return Ok(Vec::new());
+            }
+            Opcode::Continue => {
+                state.pop_as(Type::Function)?;
+                return Ok(Vec::new());
+            }
+        }
+        Ok(vec![next])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{cache::StringCache, mir::text::assemble, symbol::fmt::SymbolFormatted};
+
+    use super::*;
+
+    fn check(text: &str) -> Result<(), String> {
+        let program = assemble(text, &mut StringCache::new()).expect("assemble");
+        let names = Names::new(&program);
+        verify(&program).map_err(|err| SymbolFormatted(err, &names).to_string())
+    }
+
+    #[test]
+    fn accepts_valid_code() {
+        let text = r#"
+signature ask 1 -> 1
+
+closure main params 1 cont 0
+  locals k s
+  load_value "a"
+  store_local 1
+  load_local 1  ; s
+  load_value "b"
+  add
+  make_cont main#0
+  perform ask 1
+
+closure main#0 in main params 1
+  locals n
+  0: load_local 0
+  1: branch +1 4
+  2: load_outer 1 0
+  3: continue
+  4: load_value 2
+  5: branch 6 6
+  6: load_outer 1 0
+  7: continue
+"#;
+        assert_eq!(Ok(()), check(text));
+    }
+
+    #[test]
+    fn rejects_invalid_code() {
+        let closure = |code: &str| format!("closure main params 1 cont 0\n  locals k s\n{code}");
+        let cases = [
+            ("  add\n", "in main at 0: operand stack underflow"),
+            (
+                "  load_value 1\n  load_value \"s\"\n  sub\n",
+                "in main at 2: expected an integer, found a string",
+            ),
+            (
+                "  load_value 1\n  load_value \"s\"\n  add\n",
+                "in main at 2: expected an integer, found a string",
+            ),
+            (
+                "  load_local 1\n  load_local 0\n  mul\n",
+                "in main at 2: expected an integer, found unit",
+            ),
+            ("  load_local 7\n", "in main at 0: no local 7"),
+            (
+                "  load_outer 1 0\n",
+                "in main at 0: no local 0 1 closures out",
+            ),
+            (
+                "  load_value 1\n  branch +1 +9\n  load_local 0\n  continue\n",
+                "in main at 1: branch target 10 is out of bounds",
+            ),
+            (
+                "  load_value 1\n  store_local 1\n",
+                "in main at 1: falls off the end of the code",
+            ),
+            (
+                "  load_value 1\n  branch 2 3\n  load_value 1\n  load_local 0\n  continue\n",
+                "in main at 3: paths join with 0 and 1 values on the stack",
+            ),
+            ("  make_cont main#9\n", "in main at 0: main#9 isn't defined"),
+            (
+                "  load_value 1\n  continue\n",
+                "in main at 1: expected a function, found an integer",
+            ),
+        ];
+        for (code, expected) in cases {
+            let text = closure(code);
+            match assemble(&text, &mut StringCache::new()) {
+                Ok(program) => {
+                    let names = Names::new(&program);
+                    let err = verify(&program).expect_err(code);
+                    assert_eq!(expected, SymbolFormatted(err, &names).to_string(), "{code}");
+                }
+                // undefined anonymous closures are already caught by the assembler
+                Err(err) => assert!(expected.contains("isn't defined"), "{err}"),
+            }
+        }
+        let text =
+            "signature ask 1 -> 1\nclosure main params 1 cont 0\n  locals k\n  perform ask 1\n";
+        assert_eq!(
+            Err("in main at 0: expected 1 arguments, found 0".to_owned()),
+            check(text)
+        );
+
+        // resuming with the handler's continuation isn't a tail resumption the runtime can skip
+        let text = r#"
+signature ask 0 -> 1
+
+closure main params 1 cont 0
+  locals k
+  load_local 0
+  continue
+
+handler main#0
+  ask main#1 tail
+
+closure main#1 in main params 2 cont 1
+  locals r c
+  load_value 1
+  load_local 1
+  load_local 0
+  continue
+"#;
+        assert_eq!(
+            Err("in main#1 at 0: the action isn't tail-resumptive".to_owned()),
+            check(text)
+        );
+        assert_eq!(Ok(()), check(&text.replace("  load_local 1\n", "")));
+
+        // a record can only copy locals that exist
+        let text = r#"
+closure main params 1 cont 0
+  locals k
+  make_closure main#0
+  load_local 0
+  continue
+
+closure main#0 in main params 1 cont 0
+  captures 0:5
+  locals r
+  load_outer 1 0
+  load_local 0
+  continue
+"#;
+        assert_eq!(
+            Err("in main#0 at 0: invalid captures".to_owned()),
+            check(text)
+        );
+
+        // a closure's environment must be its parent's frame, whether or not it has a record
+        let text = r#"
+closure main params 1 cont 0
+  locals k
+  make_closure other#0
+  load_local 0
+  continue
+
+closure other params 1 cont 0
+  locals k x
+  load_local 0
+  continue
+
+closure other#0 in other params 1 cont 0
+  locals r
+  load_outer 1 1
+  load_local 0
+  continue
+"#;
+        assert_eq!(
+            Err("in main at 0: a closure used outside its parent".to_owned()),
+            check(text)
+        );
+        let named = text.replace("  make_closure other#0\n", "  load_value other#0\n");
+        assert_eq!(
+            Err("in main at 0: a closure used outside its parent".to_owned()),
+            check(&named)
+        );
+        // called by name, even its parent can't give it a frame to read
+        let named = text.replace("  make_closure other#0\n", "").replace(
+            "  locals k x\n",
+            "  locals k x\n  load_value other#0\n  store_local 1\n",
+        );
+        assert_eq!(
+            Err("in other at 0: a closure used outside its parent".to_owned()),
+            check(&named)
+        );
+    }
+}
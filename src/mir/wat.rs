@@ -0,0 +1,573 @@
+//! WebAssembly code generation, for running programs in browsers and other WebAssembly hosts.
+//!
+//! The output is a module in the WebAssembly text format: the runtime in `wat/runtime.wat`, then
+//! the program. It's organized like the [C output](super::c): each closure becomes a function that
+//! runs its body in locals and ends by handing the runtime the value to call next, and the runtime
+//! calls bodies through a table from its trampoline. Continuations and handler frames are ordinary
+//! objects in linear memory, so the module only needs the bulk memory operations that every
+//! current engine supports, rather than the tail call, exception handling or stack switching
+//! proposals, which hosts ship unevenly. Branches within a body dispatch on a block index in a
+//! loop, since MIR's branches aren't structured.
+//!
+//! The module imports three functions from `korou`: `print(ptr, len)` to print a line,
+//! `read_line(ptr, cap) -> len` to read one, and `fail(ptr, len)` to report a runtime error, which
+//! is followed by a trap. It exports its `memory` and a `main` function that runs the entry and
+//! prints the values it finishes with. Objects are never freed, so each run needs a new instance.
+//! Scoped closures and handlers are made in the environment of the body making them, as in the C
+//! output, which saves allocating them on their own.
+//!
+//! [`check`] validates the output's structure without WebAssembly tools.
+
+use std::{collections::HashMap, fmt::Write};
+
+use crate::{builtin::Builtin, symbol::SymbolKey};
+
+use super::{opt::depths, text::Names, Closure, Opcode, Program, Value};
+
+pub mod check;
+
+const RUNTIME: &str = include_str!("wat/runtime.wat");
+
+/// Where static data starts, so that no object is at address 0.
+const DATA_BASE: u32 = 16;
+
+/// The messages the runtime formats errors and values with, by global name.
+const MESSAGES: &[(&str, &str)] = &[
+    ("msg_unit", "()"),
+    ("msg_function", "<function>"),
+    ("msg_closure", "<closure>"),
+    ("msg_continuation", "<continuation>"),
+    ("msg_handler", "<handler>"),
+    ("msg_integer", "expected an integer, found "),
+    ("msg_string", "expected a string, found "),
+    ("msg_handler_expected", "expected a handler, found "),
+    ("msg_zero", "division by zero"),
+    ("msg_unhandled", "unhandled operation "),
+    ("msg_function_named", "function "),
+    ("msg_no_body", " has no body"),
+    ("msg_expected", "expected "),
+    ("msg_arguments", " arguments, found "),
+    (
+        "msg_exited",
+        "continuation invoked after its handler exited",
+    ),
+    ("msg_not_callable", " is not callable"),
+    ("msg_fell_off", "execution fell off the end of "),
+    ("msg_print_arity", "expected 1 arguments, found 0"),
+    ("msg_memory", "out of memory"),
+    ("msg_access", "member access is not supported"),
+];
+
+/// Emits a module whose `main` export runs `entry` and prints the values it finishes with.
+pub fn emit(program: &Program, entry: SymbolKey) -> String {
+    let mut emitter = Emitter::new(program);
+    let mut globals = vec![
+        ("unit", emitter.data.object(&[0; 8])),
+        ("halt", emitter.data.object(&9u64.to_le_bytes())),
+    ];
+    for &(name, message) in MESSAGES {
+        globals.push((name, emitter.data.string(message.as_bytes())));
+    }
+    emitter.literals = program
+        .strings
+        .iter()
+        .map(|s| emitter.data.string(s.as_bytes()))
+        .collect();
+    let mut bodies = String::new();
+    for (&key, closure) in &program.closures {
+        emitter.body(&mut bodies, key, closure);
+    }
+    globals.extend(emitter.tables());
+
+    let mut out = String::from("(module\n");
+    out += RUNTIME;
+    out += "\n  ;; The program\n\n";
+    let end = emitter.data.end().next_multiple_of(8);
+    // leave a page for the runtime to start allocating in
+    let pages = end / 65536 + 2;
+    let _ = writeln!(out, "  (memory (export \"memory\") {pages})");
+    let _ = writeln!(out, "  (global $heap_base i32 (i32.const {end}))");
+    for (name, addr) in globals {
+        let _ = writeln!(out, "  (global ${name} i32 (i32.const {addr}))");
+    }
+    let bodies_len = program.closures.len();
+    let _ = writeln!(out, "  (table {bodies_len} funcref)");
+    out += "  (elem (i32.const 0) func";
+    for idx in 0..bodies_len {
+        let _ = write!(out, " $c{idx}");
+    }
+    out += ")\n";
+    let _ = writeln!(
+        out,
+        "  (data (i32.const {DATA_BASE}) \"{}\")\n",
+        literal(&emitter.data.bytes)
+    );
+    out += &bodies;
+    let _ = writeln!(
+        out,
+        "  (func (export \"main\")\n    (call $run (i32.const {})))\n)",
+        emitter.closures[&entry]
+    );
+    out
+}
+
+/// The static data: objects the program loads and the program's tables.
+#[derive(Default)]
+struct Data {
+    bytes: Vec<u8>,
+}
+
+impl Data {
+    /// Places an object, 8-aligned like the runtime's allocations, and returns its address.
+    fn object(&mut self, bytes: &[u8]) -> u32 {
+        self.bytes.resize(self.bytes.len().next_multiple_of(8), 0);
+        let addr = self.end();
+        self.bytes.extend_from_slice(bytes);
+        addr
+    }
+
+    fn words(&mut self, words: &[u32]) -> u32 {
+        let bytes = words
+            .iter()
+            .flat_map(|w| w.to_le_bytes())
+            .collect::<Vec<_>>();
+        self.object(&bytes)
+    }
+
+    fn string(&mut self, s: &[u8]) -> u32 {
+        let mut bytes = Vec::from(2u32.to_le_bytes());
+        bytes.extend((s.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(s);
+        self.object(&bytes)
+    }
+
+    fn end(&self) -> u32 {
+        DATA_BASE + self.bytes.len() as u32
+    }
+}
+
+struct Emitter<'a> {
+    program: &'a Program,
+    names: Names<'a>,
+    /// The index of each value that can be called, in the closure table. Closures come first, in
+    /// the order of their bodies in the function table, then functions without a body.
+    closures: HashMap<SymbolKey, usize>,
+    callable: Vec<SymbolKey>,
+    handlers: HashMap<SymbolKey, usize>,
+    /// The index of each operation in the operation table.
+    ops: HashMap<SymbolKey, usize>,
+    op_keys: Vec<SymbolKey>,
+    data: Data,
+    /// The address of each string literal.
+    literals: Vec<u32>,
+    /// The addresses of the integers and functions the program loads.
+    ints: HashMap<i64, u32>,
+    functions: HashMap<SymbolKey, u32>,
+}
+
+impl<'a> Emitter<'a> {
+    fn new(program: &'a Program) -> Self {
+        let mut callable = program.closures.keys().copied().collect::<Vec<_>>();
+        let mut op_keys = program.signatures.keys().copied().collect::<Vec<_>>();
+        let code = program.closures.values().flat_map(|closure| &closure.code);
+        for op in code {
+            match *op {
+                Opcode::LoadValue(Value::Cont(key)) if !callable.contains(&key) => {
+                    callable.push(key);
+                }
+                Opcode::Perform(op, _) if !op_keys.contains(&op) => op_keys.push(op),
+                _ => {}
+            }
+        }
+        for &(op, _) in program.handlers.values().flat_map(|h| &h.actions) {
+            if !op_keys.contains(&op) {
+                op_keys.push(op);
+            }
+        }
+        let index = |keys: &[SymbolKey]| {
+            keys.iter()
+                .enumerate()
+                .map(|(idx, &key)| (key, idx))
+                .collect()
+        };
+        Emitter {
+            program,
+            names: Names::new(program),
+            closures: index(&callable),
+            callable,
+            handlers: index(&program.handlers.keys().copied().collect::<Vec<_>>()),
+            ops: index(&op_keys),
+            op_keys,
+            data: Data::default(),
+            literals: Vec::new(),
+            ints: HashMap::new(),
+            functions: HashMap::new(),
+        }
+    }
+
+    /// Places the program's tables, returning the globals the runtime finds them by.
+    fn tables(&mut self) -> Vec<(&'static str, u32)> {
+        let mut rows = Vec::new();
+        for &key in &self.callable {
+            let name = self.data.string(self.names.label(key).as_bytes());
+            rows.extend(match self.program.closures.get(&key) {
+                Some(closure) => [
+                    1,
+                    closure.params as u32,
+                    closure.locals.len() as u32,
+                    name,
+                    self.program.frame_values(closure).len() as u32,
+                ],
+                None => [0, 0, 0, name, 0],
+            });
+        }
+        let closures = self.data.words(&rows);
+
+        let mut rows = Vec::new();
+        for handler in self.program.handlers.values() {
+            let actions = handler
+                .actions
+                .iter()
+                .flat_map(|&(op, action)| {
+                    let tail = handler.tail.contains(&op) as u32;
+                    [self.ops[&op] as u32, self.closures[&action] as u32, tail]
+                })
+                .collect::<Vec<_>>();
+            let clause = |clause: Option<SymbolKey>| {
+                clause.map_or(u32::MAX, |key| self.closures[&key] as u32)
+            };
+            let (ret, finally) = (clause(handler.ret), clause(handler.finally));
+            let actions = self.data.words(&actions);
+            rows.extend([actions, handler.actions.len() as u32, ret, finally]);
+        }
+        let handlers = self.data.words(&rows);
+
+        let mut rows = Vec::new();
+        for &op in &self.op_keys {
+            let name = self.data.string(self.names.label(op).as_bytes());
+            let returns = self.program.signatures.get(&op).and_then(|s| s.returns);
+            let builtin = match self.program.builtins.get(&op) {
+                None => 0,
+                Some(Builtin::Print) => 1,
+                Some(Builtin::ReadLine) => 2,
+            };
+            rows.extend([returns.unwrap_or(0) as u32, builtin, name]);
+        }
+        let ops = self.data.words(&rows);
+        vec![("closures", closures), ("handlers", handlers), ("ops", ops)]
+    }
+
+    fn body(&mut self, out: &mut String, key: SymbolKey, closure: &Closure) {
+        let idx = self.closures[&key];
+        let code = &closure.code;
+        let depths = depths(code);
+        let scoped = self.program.frame_values(closure);
+        // basic blocks start at the entry and at branch targets
+        let mut starts = vec![0];
+        for (pc, op) in code.iter().enumerate() {
+            if let (Opcode::Branch(then, other), Some(_)) = (op, depths[pc]) {
+                starts.extend([target(pc, *then), target(pc, *other)]);
+            }
+        }
+        starts.sort_unstable();
+        starts.dedup();
+        let blocks = starts
+            .iter()
+            .enumerate()
+            .map(|(block, &pc)| (pc, block))
+            .collect::<HashMap<_, _>>();
+        let size = depths.iter().flatten().max().map_or(0, |depth| depth + 1);
+
+        let _ = writeln!(out, "  ;; {}", self.names.label(key));
+        let dispatch = starts.len() > 1;
+        let _ = write!(out, "  (func $c{idx} (type $body) (param $env i32)");
+        if dispatch {
+            out.push_str("\n    (local $pc i32)");
+        }
+        for slot in 0..size {
+            let _ = write!(out, "\n    (local $s{slot} i32)");
+        }
+        let indent = if dispatch { "      " } else { "    " };
+        if dispatch {
+            out.push_str("\n    (loop $top");
+            for block in (0..starts.len()).rev() {
+                let _ = write!(out, "\n      (block $b{block}");
+            }
+            out.push_str("\n        (br_table");
+            for block in 0..starts.len() {
+                let _ = write!(out, " $b{block}");
+            }
+            out.push_str(" (local.get $pc)))");
+        }
+        for (pc, op) in code.iter().enumerate() {
+            if pc > 0 && dispatch && blocks.contains_key(&pc) {
+                out.push(')');
+            }
+            let Some(d) = depths[pc] else {
+                continue;
+            };
+            let s = |slot: usize| format!("(local.get $s{slot})");
+            let binary =
+                |f: &str| format!("(local.set $s{} ({f} {} {}))", d - 2, s(d - 2), s(d - 1));
+            let arith = |op: u32| binary(&format!("call $arith (i32.const {op})"));
+            let equal = |eq: u32| {
+                format!(
+                    "(local.set $s{} (call $equal {} {} (i32.const {eq})))",
+                    d - 2,
+                    s(d - 2),
+                    s(d - 1)
+                )
+            };
+            let args = || {
+                let mut args = String::from("(global.set $argc (i32.const 0))");
+                for slot in 0..d {
+                    let _ = write!(args, "\n{indent}(call $push_arg {})", s(slot));
+                }
+                args
+            };
+            let line = match *op {
+                Opcode::LoadValue(value) => format!("(local.set $s{d} {})", self.value(value)),
+                Opcode::LoadLocal(slot) => format!(
+                    "(local.set $s{d} (i32.load offset={} (local.get $env)))",
+                    12 + 4 * slot
+                ),
+                Opcode::LoadOuter(depth, slot) => format!(
+                    "(local.set $s{d} (call $outer (local.get $env) (i32.const {depth}) (i32.const {slot})))"
+                ),
+                Opcode::StoreLocal(slot) => format!(
+                    "(i32.store offset={} (local.get $env) {})",
+                    12 + 4 * slot,
+                    s(d - 1)
+                ),
+                Opcode::Add => binary("call $add"),
+                Opcode::Sub => arith(0),
+                Opcode::Mul => arith(1),
+                Opcode::Div => arith(2),
+                Opcode::Rem => arith(3),
+                Opcode::Gt => arith(4),
+                Opcode::Ge => arith(5),
+                Opcode::Lt => arith(6),
+                Opcode::Le => arith(7),
+                Opcode::Eq => equal(1),
+                Opcode::NotEq => equal(0),
+                Opcode::Access(_) => "(call $fail (global.get $msg_access))".to_owned(),
+                Opcode::Branch(then, other) => format!(
+                    "(local.set $pc (select (i32.const {}) (i32.const {}) (call $truth {})))\n{indent}(br $top)",
+                    blocks[&target(pc, then)],
+                    blocks[&target(pc, other)],
+                    s(d - 1)
+                ),
+                Opcode::MakeClosure(key) | Opcode::MakeCont(key) => {
+                    let (idx, env) = (self.closures[&key], self.environment(key));
+                    let cont = matches!(op, Opcode::MakeCont(_)) as u8;
+                    match scoped.get(&pc) {
+                        Some(&n) => format!(
+                            "(local.set $s{d} (call $closure_in {} (i32.const {idx}) {env} (i32.const {cont})))",
+                            frame_value(closure, n)
+                        ),
+                        None => format!(
+                            "(local.set $s{d} (call $closure (i32.const {idx}) {env} (i32.const {cont})))"
+                        ),
+                    }
+                }
+                Opcode::MakeHandler(key) => match scoped.get(&pc) {
+                    Some(&n) => format!(
+                        "(local.set $s{d} (call $handler_in {} (i32.const {}) (local.get $env)))",
+                        frame_value(closure, n),
+                        self.handlers[&key]
+                    ),
+                    None => format!(
+                        "(local.set $s{d} (call $handler (i32.const {}) (local.get $env)))",
+                        self.handlers[&key]
+                    ),
+                },
+                Opcode::Handle => format!(
+                    "(call $handle {} {} {})\n{indent}(return)",
+                    s(d - 3),
+                    s(d - 2),
+                    s(d - 1)
+                ),
+                Opcode::Perform(op, arity) => format!(
+                    "{}\n{indent}(call $perform (i32.const {}) (i32.const {arity})) ;; {}\n{indent}(return)",
+                    args(),
+                    self.ops[&op],
+                    self.names.label(op)
+                ),
+                Opcode::Continue => format!("{}\n{indent}(call $continue)\n{indent}(return)", args()),
+            };
+            let _ = write!(out, "\n{indent}{line}");
+        }
+        if dispatch {
+            // the block that starts at the end, if any branch leads there, is left empty
+            let closing = starts.len()
+                - starts
+                    .iter()
+                    .filter(|&&pc| pc > 0 && pc < code.len())
+                    .count();
+            out.push_str(&")".repeat(closing));
+        }
+        let _ = writeln!(out, "\n    (call $fell_off (i32.const {idx})))\n");
+    }
+
+    fn value(&mut self, value: Value) -> String {
+        let addr = match value {
+            Value::Unit => return "(global.get $unit)".to_owned(),
+            Value::Int(v) => *self.ints.entry(v).or_insert_with(|| {
+                let mut bytes = Vec::from(1u64.to_le_bytes());
+                bytes.extend(v.to_le_bytes());
+                self.data.object(&bytes)
+            }),
+            Value::Cont(key) => {
+                let idx = self.closures[&key] as u32;
+                *self
+                    .functions
+                    .entry(key)
+                    .or_insert_with(|| self.data.words(&[3, idx]))
+            }
+            Value::String(idx) => self.literals[idx],
+        };
+        format!("(i32.const {addr})")
+    }
+
+    /// The environment a closure made in the current frame gets.
+    fn environment(&mut self, key: SymbolKey) -> String {
+        let captures = self
+            .program
+            .closures
+            .get(&key)
+            .and_then(|c| c.captures.as_ref());
+        match captures {
+            None => "(local.get $env)".to_owned(),
+            Some(captures) if captures.is_empty() => "(i32.const 0)".to_owned(),
+            Some(captures) => {
+                let pairs = captures
+                    .iter()
+                    .flat_map(|&(depth, slot)| [depth as u32, slot as u32])
+                    .collect::<Vec<_>>();
+                let len = captures.len();
+                format!(
+                    "(call $record (local.get $env) (i32.const {len}) (i32.const {}))",
+                    self.data.words(&pairs)
+                )
+            }
+        }
+    }
+}
+
+/// The address of the space a closure's frame reserves for its `idx`th scoped value.
+fn frame_value(closure: &Closure, idx: usize) -> String {
+    let offset = (12 + 4 * closure.locals.len()).next_multiple_of(8) + 24 * idx;
+    format!("(i32.add (local.get $env) (i32.const {offset}))")
+}
+
+fn target(pc: usize, offset: i32) -> usize {
+    pc.wrapping_add_signed(offset as isize)
+}
+
+/// Writes bytes as the contents of a string in the text format.
+fn literal(bytes: &[u8]) -> String {
+    let mut out = String::new();
+    for &byte in bytes {
+        match byte {
+            b'"' | b'\\' => {
+                out.push('\\');
+                out.push(byte as char);
+            }
+            b' '..=b'~' => out.push(byte as char),
+            _ => out += &format!("\\{byte:02x}"),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use crate::{mir::opt::Passes, Session};
+
+    use super::{check::check, *};
+
+    #[test]
+    fn emits_valid_modules() {
+        let examples = Path::new(env!("CARGO_MANIFEST_DIR")).join("korou-examples");
+        let mut entries = std::fs::read_dir(examples)
+            .expect("examples")
+            .map(|entry| entry.expect("example").path())
+            .collect::<Vec<_>>();
+        entries.sort();
+        let mut emitted = 0;
+        for path in entries {
+            let name = path.file_stem().expect("file name").to_string_lossy();
+            let src = std::fs::read_to_string(&path).expect("read example");
+            let mut session = Session::new();
+            let compilation = session.compile(&name, &src);
+            let program = compilation.program.expect("program");
+            let Some(main) = session
+                .cache()
+                .find("main")
+                .and_then(|main| program.function(main))
+            else {
+                continue;
+            };
+            for level in [0, 2] {
+                let mut program = program.clone();
+                let ds = program.optimize(Passes::level(level));
+                assert!(ds.is_empty(), "{ds:?}");
+                if let Err(err) = check(&emit(&program, main)) {
+                    panic!("{name} at -O{level}: {err}");
+                }
+            }
+            emitted += 1;
+        }
+        assert!(emitted >= 3);
+    }
+
+    #[test]
+    fn rejects_invalid_modules() {
+        let prelude = r#"
+            (memory 1)
+            (global $unit i32 (i32.const 0))
+            (func $add (param i32 i32) (result i32) (i32.add (local.get 0) (local.get 1)))
+            (func $print (param $s i32))
+        "#;
+        let module = |body: &str| format!("(module {prelude} (func $f (result i32) {body}))");
+        let error = |body: &str| check(&module(body)).expect_err(body).to_string();
+        assert_eq!(Ok(()), check(&module("(i32.const 1)")));
+        assert_eq!("in $f: unknown function $nowhere", error("(call $nowhere)"));
+        assert_eq!(
+            "in $f: expected operands [I32, I32], found [I32]",
+            error("(call $add (i32.const 1))")
+        );
+        assert_eq!(
+            "in $f: expected operands [I32], found [I64]",
+            error("(call $print (i64.const 1)) (i32.const 0)")
+        );
+        assert_eq!(
+            "in $f: unknown label $out",
+            error("(br $out (i32.const 1))")
+        );
+        assert_eq!("in $f: unknown local $x", error("(local.get $x)"));
+        assert_eq!(
+            "in $f: global is immutable",
+            error("(global.set $unit (i32.const 0))")
+        );
+        assert_eq!(
+            "in $f: expected [I32] at the end of the block",
+            error("(block $b (br_if $b (i32.const 0)))")
+        );
+        assert_eq!(
+            "in $f: i32.const leaves [I32]",
+            error("(i32.const 1) (i32.const 2)")
+        );
+        assert_eq!(
+            "unclosed parenthesis",
+            check("(module (func $f)").unwrap_err().to_string()
+        );
+    }
+
+    #[test]
+    fn escapes_data() {
+        assert_eq!(r#"a\"b\\c\00\0a\c3\a9"#, literal("a\"b\\c\0\né".as_bytes()));
+    }
+}
@@ -0,0 +1,984 @@
+//! A structural checker for the WebAssembly text the backend emits, for validating it without
+//! WebAssembly tools.
+//!
+//! It accepts the folded subset of the text format the backend and its runtime are written in:
+//! every instruction is an S-expression with its operands nested inside it, and control flow uses
+//! `block`, `loop`, `if`, and branches to labels. Within that subset it checks what a validator
+//! would: names resolve to functions, locals, globals, types and enclosing labels, instructions
+//! get operands of the right number and types, blocks and functions produce their declared
+//! results, and data and table segments fit.
+
+use std::{
+    collections::HashMap,
+    fmt::{self, Display, Formatter},
+};
+
+/// Why a module was rejected.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CheckError {
+    /// The function the problem is in, if any.
+    pub func: Option<String>,
+    pub message: String,
+}
+
+impl Display for CheckError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match &self.func {
+            Some(func) => write!(f, "in {func}: {}", self.message),
+            None => f.write_str(&self.message),
+        }
+    }
+}
+
+impl std::error::Error for CheckError {}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Sexp {
+    Atom(String),
+    Str(Vec<u8>),
+    List(Vec<Sexp>),
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Ty {
+    I32,
+    I64,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+struct FuncType {
+    params: Vec<Ty>,
+    results: Vec<Ty>,
+}
+
+/// The values an instruction leaves, or `None` if control never continues past it.
+type Results = Option<Vec<Ty>>;
+
+/// A function's params, with their names if they have them.
+type Params = Vec<(Option<String>, Ty)>;
+
+type Result<T> = std::result::Result<T, String>;
+
+/// Checks a module in the text format.
+pub fn check(text: &str) -> std::result::Result<(), CheckError> {
+    let module = parse(text).map_err(|message| CheckError {
+        func: None,
+        message,
+    })?;
+    let mut checker = Checker::default();
+    checker.module(&module)
+}
+
+fn parse(text: &str) -> Result<Sexp> {
+    let mut parser = Parser {
+        bytes: text.as_bytes(),
+        pos: 0,
+    };
+    let sexp = parser.sexp()?;
+    parser.skip()?;
+    if parser.pos < parser.bytes.len() {
+        return Err("text after the module".to_owned());
+    }
+    Ok(sexp)
+}
+
+struct Parser<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl Parser<'_> {
+    /// Skips whitespace and comments.
+    fn skip(&mut self) -> Result<()> {
+        loop {
+            match self.bytes.get(self.pos..) {
+                Some([b' ' | b'\t' | b'\n' | b'\r', ..]) => self.pos += 1,
+                Some([b';', b';', ..]) => {
+                    while self.bytes.get(self.pos).is_some_and(|&b| b != b'\n') {
+                        self.pos += 1;
+                    }
+                }
+                Some([b'(', b';', ..]) => {
+                    let end = self.bytes[self.pos..]
+                        .windows(2)
+                        .position(|w| w == b";)")
+                        .ok_or("unterminated block comment")?;
+                    self.pos += end + 2;
+                }
+                _ => return Ok(()),
+            }
+        }
+    }
+
+    fn sexp(&mut self) -> Result<Sexp> {
+        self.skip()?;
+        match self.bytes.get(self.pos) {
+            None => Err("unexpected end of text".to_owned()),
+            Some(b'(') => {
+                self.pos += 1;
+                let mut items = Vec::new();
+                loop {
+                    self.skip()?;
+                    match self.bytes.get(self.pos) {
+                        Some(b')') => {
+                            self.pos += 1;
+                            return Ok(Sexp::List(items));
+                        }
+                        Some(_) => items.push(self.sexp()?),
+                        None => return Err("unclosed parenthesis".to_owned()),
+                    }
+                }
+            }
+            Some(b')') => Err("unexpected `)`".to_owned()),
+            Some(b'"') => self.string(),
+            Some(_) => {
+                let start = self.pos;
+                while self
+                    .bytes
+                    .get(self.pos)
+                    .is_some_and(|&b| !b" \t\n\r()\";".contains(&b))
+                {
+                    self.pos += 1;
+                }
+                let atom = std::str::from_utf8(&self.bytes[start..self.pos])
+                    .map_err(|_| "invalid UTF-8 in atom")?;
+                Ok(Sexp::Atom(atom.to_owned()))
+            }
+        }
+    }
+
+    fn string(&mut self) -> Result<Sexp> {
+        self.pos += 1;
+        let mut out = Vec::new();
+        loop {
+            let byte = *self.bytes.get(self.pos).ok_or("unterminated string")?;
+            self.pos += 1;
+            match byte {
+                b'"' => return Ok(Sexp::Str(out)),
+                b'\\' => {
+                    let escape = *self.bytes.get(self.pos).ok_or("unterminated string")?;
+                    self.pos += 1;
+                    match escape {
+                        b'n' => out.push(b'\n'),
+                        b't' => out.push(b'\t'),
+                        b'r' => out.push(b'\r'),
+                        b'"' | b'\'' | b'\\' => out.push(escape),
+                        _ => {
+                            let digits = self
+                                .bytes
+                                .get(self.pos - 1..self.pos + 1)
+                                .and_then(|d| std::str::from_utf8(d).ok())
+                                .and_then(|d| u8::from_str_radix(d, 16).ok())
+                                .ok_or("invalid string escape")?;
+                            self.pos += 1;
+                            out.push(digits);
+                        }
+                    }
+                }
+                _ => out.push(byte),
+            }
+        }
+    }
+}
+
+fn atom(sexp: &Sexp) -> Option<&str> {
+    match sexp {
+        Sexp::Atom(atom) => Some(atom),
+        _ => None,
+    }
+}
+
+/// The keyword a list starts with.
+fn head(sexp: &Sexp) -> Option<&str> {
+    match sexp {
+        Sexp::List(items) => items.first().and_then(atom),
+        _ => None,
+    }
+}
+
+fn is_name(atom: &str) -> bool {
+    atom.starts_with('$')
+}
+
+fn ty(atom: &str) -> Result<Ty> {
+    match atom {
+        "i32" => Ok(Ty::I32),
+        "i64" => Ok(Ty::I64),
+        other => Err(format!("unsupported value type {other}")),
+    }
+}
+
+/// A namespace of indexed, optionally named entities.
+struct Space<T> {
+    items: Vec<T>,
+    names: HashMap<String, usize>,
+    kind: &'static str,
+}
+
+impl<T> Space<T> {
+    fn new(kind: &'static str) -> Self {
+        Space {
+            items: Vec::new(),
+            names: HashMap::new(),
+            kind,
+        }
+    }
+
+    fn add(&mut self, name: Option<&str>, item: T) -> Result<()> {
+        if let Some(name) = name {
+            if self
+                .names
+                .insert(name.to_owned(), self.items.len())
+                .is_some()
+            {
+                return Err(format!("duplicate {} {name}", self.kind));
+            }
+        }
+        self.items.push(item);
+        Ok(())
+    }
+
+    fn get(&self, reference: &str) -> Result<&T> {
+        let idx = if is_name(reference) {
+            self.names.get(reference).copied()
+        } else {
+            reference.parse().ok()
+        };
+        idx.and_then(|idx| self.items.get(idx))
+            .ok_or_else(|| format!("unknown {} {reference}", self.kind))
+    }
+}
+
+struct Global {
+    ty: Ty,
+    mutable: bool,
+}
+
+struct Checker {
+    types: Space<FuncType>,
+    funcs: Space<FuncType>,
+    globals: Space<Global>,
+    tables: Space<u64>,
+    memories: Space<u64>,
+    exports: Vec<String>,
+}
+
+impl Default for Checker {
+    fn default() -> Self {
+        Checker {
+            types: Space::new("type"),
+            funcs: Space::new("function"),
+            globals: Space::new("global"),
+            tables: Space::new("table"),
+            memories: Space::new("memory"),
+            exports: Vec::new(),
+        }
+    }
+}
+
+/// What a function body can refer to.
+struct Body<'a> {
+    locals: Space<Ty>,
+    results: &'a [Ty],
+    /// The enclosing labels, innermost last, with the types a branch to each carries.
+    labels: Vec<(Option<String>, Vec<Ty>)>,
+}
+
+impl Checker {
+    fn module(&mut self, module: &Sexp) -> std::result::Result<(), CheckError> {
+        let error = |message| CheckError {
+            func: None,
+            message,
+        };
+        let fields = match module {
+            Sexp::List(items) if items.first().and_then(atom) == Some("module") => &items[1..],
+            _ => return Err(error("expected a module".to_owned())),
+        };
+        let fields = match fields.first().and_then(atom) {
+            Some(name) if is_name(name) => &fields[1..],
+            _ => fields,
+        };
+        // declare everything first, since fields can refer to later ones
+        let mut defined = false;
+        for field in fields {
+            match head(field) {
+                Some("import") if defined => {
+                    return Err(error("imports must come before functions".to_owned()))
+                }
+                Some("func") => defined = true,
+                _ => {}
+            }
+            self.declare(field).map_err(error)?;
+        }
+        for field in fields {
+            let Sexp::List(items) = field else {
+                continue;
+            };
+            let func = match head(field) {
+                Some("func") => items.get(1).and_then(atom).filter(|a| is_name(a)),
+                _ => None,
+            };
+            self.define(field).map_err(|message| CheckError {
+                func: func.map(str::to_owned),
+                message,
+            })?;
+        }
+        Ok(())
+    }
+
+    fn declare(&mut self, field: &Sexp) -> Result<()> {
+        let Sexp::List(items) = field else {
+            return Err("expected a module field".to_owned());
+        };
+        let name = items.get(1).and_then(atom).filter(|a| is_name(a));
+        match head(field) {
+            Some("type") => {
+                let func = items
+                    .iter()
+                    .skip(1)
+                    .find(|item| head(item) == Some("func"))
+                    .ok_or("expected a function type")?;
+                let Sexp::List(parts) = func else {
+                    unreachable!()
+                };
+                let signature = self.signature(&parts[1..])?.1;
+                self.types.add(name, signature)
+            }
+            Some("import") => {
+                let [_, Sexp::Str(_), Sexp::Str(_), desc] = &items[..] else {
+                    return Err("expected an import's module, name and description".to_owned());
+                };
+                let Some("func") = head(desc) else {
+                    return Err("only functions can be imported".to_owned());
+                };
+                let Sexp::List(parts) = desc else {
+                    unreachable!()
+                };
+                let name = parts.get(1).and_then(atom).filter(|a| is_name(a));
+                let rest = &parts[1 + name.is_some() as usize..];
+                let signature = self.signature(rest)?.1;
+                self.funcs.add(name, signature)
+            }
+            Some("func") => {
+                let rest = &items[1 + name.is_some() as usize..];
+                let (_, signature) = self.signature(rest)?;
+                self.inline_exports(rest)?;
+                self.funcs.add(name, signature)
+            }
+            Some("global") => {
+                let rest = &items[1 + name.is_some() as usize..];
+                let rest = self.skip_exports(rest)?;
+                let (ty, mutable) = match rest.first() {
+                    Some(Sexp::Atom(t)) => (self::ty(t)?, false),
+                    Some(Sexp::List(parts)) if head(&rest[0]) == Some("mut") => {
+                        (self::ty(parts.get(1).and_then(atom).unwrap_or(""))?, true)
+                    }
+                    _ => return Err("expected a global's type".to_owned()),
+                };
+                self.globals.add(name, Global { ty, mutable })
+            }
+            Some("memory") => {
+                let rest = &items[1 + name.is_some() as usize..];
+                let rest = self.skip_exports(rest)?;
+                let pages = rest
+                    .first()
+                    .and_then(atom)
+                    .and_then(|n| n.parse().ok())
+                    .ok_or("expected a memory's size")?;
+                self.memories.add(name, pages)
+            }
+            Some("table") => {
+                let rest = &items[1 + name.is_some() as usize..];
+                let rest = self.skip_exports(rest)?;
+                let size = rest
+                    .first()
+                    .and_then(atom)
+                    .and_then(|n| n.parse().ok())
+                    .ok_or("expected a table's size")?;
+                if rest.last().and_then(atom) != Some("funcref") {
+                    return Err("expected a table of funcref".to_owned());
+                }
+                self.tables.add(name, size)
+            }
+            Some("export") => {
+                let [_, Sexp::Str(name), _] = &items[..] else {
+                    return Err("expected an export's name and description".to_owned());
+                };
+                self.export(name)
+            }
+            Some("elem" | "data" | "start") => Ok(()),
+            _ => Err("unknown module field".to_owned()),
+        }
+    }
+
+    fn export(&mut self, name: &[u8]) -> Result<()> {
+        let name = String::from_utf8_lossy(name).into_owned();
+        if self.exports.contains(&name) {
+            return Err(format!("duplicate export {name:?}"));
+        }
+        self.exports.push(name);
+        Ok(())
+    }
+
+    fn inline_exports(&mut self, rest: &[Sexp]) -> Result<()> {
+        for item in rest.iter().take_while(|item| head(item) == Some("export")) {
+            match item {
+                Sexp::List(parts) => match &parts[..] {
+                    [_, Sexp::Str(name)] => self.export(name)?,
+                    _ => return Err("expected an export's name".to_owned()),
+                },
+                _ => unreachable!(),
+            }
+        }
+        Ok(())
+    }
+
+    fn skip_exports<'s>(&mut self, rest: &'s [Sexp]) -> Result<&'s [Sexp]> {
+        self.inline_exports(rest)?;
+        let exports = rest
+            .iter()
+            .take_while(|item| head(item) == Some("export"))
+            .count();
+        Ok(&rest[exports..])
+    }
+
+    /// Reads a function's type use, params and results, returning its params and type.
+    fn signature(&self, items: &[Sexp]) -> Result<(Params, FuncType)> {
+        let mut params = Vec::new();
+        let mut results = Vec::new();
+        let mut declared = None;
+        for item in items {
+            let Sexp::List(parts) = item else {
+                break;
+            };
+            match head(item) {
+                Some("export") => {}
+                Some("type") => {
+                    let reference = parts.get(1).and_then(atom).ok_or("expected a type")?;
+                    declared = Some(self.types.get(reference).cloned());
+                }
+                Some("param") => match parts.get(1).and_then(atom) {
+                    Some(name) if is_name(name) => {
+                        let t = ty(parts.get(2).and_then(atom).unwrap_or(""))?;
+                        params.push((Some(name.to_owned()), t));
+                    }
+                    _ => {
+                        for part in &parts[1..] {
+                            params.push((None, ty(atom(part).unwrap_or(""))?));
+                        }
+                    }
+                },
+                Some("result") => {
+                    for part in &parts[1..] {
+                        results.push(ty(atom(part).unwrap_or(""))?);
+                    }
+                }
+                _ => break,
+            }
+        }
+        let signature = FuncType {
+            params: params.iter().map(|&(_, t)| t).collect(),
+            results,
+        };
+        match declared {
+            // types may be declared after their uses, so only check once they're all known
+            Some(Ok(declared)) if params.is_empty() && signature.results.is_empty() => {
+                Ok((Vec::new(), declared))
+            }
+            Some(Ok(declared)) if declared != signature => {
+                Err("function doesn't match its type".to_owned())
+            }
+            _ => Ok((params, signature)),
+        }
+    }
+
+    fn define(&mut self, field: &Sexp) -> Result<()> {
+        let Sexp::List(items) = field else {
+            unreachable!()
+        };
+        let name = items.get(1).and_then(atom).filter(|a| is_name(a));
+        let rest = &items[1 + name.is_some() as usize..];
+        match head(field) {
+            Some("func") => self.func(rest),
+            Some("global") => {
+                let init = rest.last().ok_or("expected a global's initializer")?;
+                let global = self.globals.get(name.unwrap_or(""));
+                let expected = match global {
+                    Ok(global) => global.ty,
+                    Err(_) => self::ty(rest.first().and_then(atom).unwrap_or("i32"))?,
+                };
+                self.constant(init, expected)
+            }
+            Some("export") => {
+                let desc = &items[2];
+                let Sexp::List(parts) = desc else {
+                    return Err("expected an export description".to_owned());
+                };
+                let reference = parts.get(1).and_then(atom).ok_or("expected a reference")?;
+                match head(desc) {
+                    Some("func") => self.funcs.get(reference).map(|_| ()),
+                    Some("memory") => self.memories.get(reference).map(|_| ()),
+                    Some("global") => self.globals.get(reference).map(|_| ()),
+                    Some("table") => self.tables.get(reference).map(|_| ()),
+                    _ => Err("unknown export kind".to_owned()),
+                }
+            }
+            Some("elem") => {
+                let (offset, funcs) = rest.split_first().ok_or("expected an offset")?;
+                let offset = self.constant_value(offset)?;
+                let funcs = match funcs.first().and_then(atom) {
+                    Some("func") => &funcs[1..],
+                    _ => funcs,
+                };
+                for func in funcs {
+                    self.funcs
+                        .get(atom(func).ok_or("expected a function reference")?)?;
+                }
+                let size = *self.tables.get("0")?;
+                if offset + funcs.len() as u64 > size {
+                    return Err("element segment doesn't fit its table".to_owned());
+                }
+                Ok(())
+            }
+            Some("data") => {
+                let (offset, strings) = rest.split_first().ok_or("expected an offset")?;
+                let offset = self.constant_value(offset)?;
+                let mut len = 0;
+                for string in strings {
+                    let Sexp::Str(bytes) = string else {
+                        return Err("expected a data string".to_owned());
+                    };
+                    len += bytes.len() as u64;
+                }
+                let pages = *self.memories.get("0")?;
+                if offset + len > pages * 65536 {
+                    return Err("data segment doesn't fit its memory".to_owned());
+                }
+                Ok(())
+            }
+            Some("start") => {
+                let func = self.funcs.get(rest.first().and_then(atom).unwrap_or(""))?;
+                if *func != FuncType::default() {
+                    return Err("the start function must take and return nothing".to_owned());
+                }
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks a constant expression, which is all that module fields can compute.
+    fn constant(&self, init: &Sexp, expected: Ty) -> Result<()> {
+        let found = match head(init) {
+            Some("i32.const") => Ty::I32,
+            Some("i64.const") => Ty::I64,
+            _ => return Err("expected a constant".to_owned()),
+        };
+        if found != expected {
+            return Err("constant has the wrong type".to_owned());
+        }
+        self.constant_value(init).map(|_| ())
+    }
+
+    fn constant_value(&self, init: &Sexp) -> Result<u64> {
+        let Sexp::List(parts) = init else {
+            return Err("expected a constant".to_owned());
+        };
+        match (head(init), parts.get(1).and_then(atom)) {
+            (Some("i32.const"), Some(n)) => Ok(int(n, 32)? as u32 as u64),
+            (Some("i64.const"), Some(n)) => Ok(int(n, 64)? as u64),
+            _ => Err("expected a constant".to_owned()),
+        }
+    }
+
+    fn func(&self, rest: &[Sexp]) -> Result<()> {
+        let (params, signature) = self.signature(rest)?;
+        let mut body = Body {
+            locals: Space::new("local"),
+            results: &signature.results,
+            labels: Vec::new(),
+        };
+        for (name, t) in params {
+            body.locals.add(name.as_deref(), t)?;
+        }
+        let mut instrs = rest
+            .iter()
+            .skip_while(|item| matches!(head(item), Some("export" | "type" | "param" | "result")))
+            .peekable();
+        while let Some(local) = instrs.next_if(|item| head(item) == Some("local")) {
+            let Sexp::List(parts) = local else {
+                unreachable!()
+            };
+            match parts.get(1).and_then(atom) {
+                Some(name) if is_name(name) => {
+                    let t = ty(parts.get(2).and_then(atom).unwrap_or(""))?;
+                    body.locals.add(Some(name), t)?;
+                }
+                _ => {
+                    for part in &parts[1..] {
+                        body.locals.add(None, ty(atom(part).unwrap_or(""))?)?;
+                    }
+                }
+            }
+        }
+        let instrs = instrs.collect::<Vec<_>>();
+        self.sequence(&instrs, &signature.results, &mut body)
+    }
+
+    /// Checks instructions that together leave `expected`: all but the last leave nothing.
+    fn sequence(&self, instrs: &[&Sexp], expected: &[Ty], body: &mut Body<'_>) -> Result<()> {
+        let mut reachable = true;
+        for (i, instr) in instrs.iter().enumerate() {
+            let results = self.instr(instr, body)?;
+            let Some(results) = results else {
+                reachable = false;
+                continue;
+            };
+            let last = i + 1 == instrs.len();
+            if last && results == expected {
+                return Ok(());
+            }
+            if !results.is_empty() {
+                return Err(format!(
+                    "{} leaves {results:?}",
+                    head(instr).unwrap_or("instruction")
+                ));
+            }
+        }
+        if reachable && !expected.is_empty() {
+            return Err(format!("expected {expected:?} at the end of the block"));
+        }
+        Ok(())
+    }
+
+    /// Checks a folded instruction, returning what it leaves.
+    fn instr(&self, instr: &Sexp, body: &mut Body<'_>) -> Result<Results> {
+        let Sexp::List(parts) = instr else {
+            return Err(format!("expected a folded instruction, found {instr:?}"));
+        };
+        let op = parts
+            .first()
+            .and_then(atom)
+            .ok_or("expected an instruction")?;
+        let mut args = &parts[1..];
+        match op {
+            "block" | "loop" => {
+                let (label, results, rest) = block_type(args)?;
+                let branch = if op == "loop" {
+                    Vec::new()
+                } else {
+                    results.clone()
+                };
+                body.labels.push((label, branch));
+                let instrs = rest.iter().collect::<Vec<_>>();
+                let checked = self.sequence(&instrs, &results, body);
+                body.labels.pop();
+                checked.map(|_| Some(results))
+            }
+            "if" => {
+                let (label, results, rest) = block_type(args)?;
+                let arms = rest
+                    .iter()
+                    .position(|item| head(item) == Some("then"))
+                    .ok_or("expected `then`")?;
+                let (condition, arms) = rest.split_at(arms);
+                self.operands(condition, &[Ty::I32], body)?;
+                body.labels.push((label, results.clone()));
+                let mut checked = Ok(());
+                let mut has_else = false;
+                for arm in arms {
+                    let Sexp::List(items) = arm else {
+                        checked = Err("expected `then` or `else`".to_owned());
+                        break;
+                    };
+                    has_else |= head(arm) == Some("else");
+                    let instrs = items[1..].iter().collect::<Vec<_>>();
+                    checked = checked.and_then(|_| self.sequence(&instrs, &results, body));
+                }
+                body.labels.pop();
+                checked?;
+                if !has_else && !results.is_empty() {
+                    return Err("an `if` with results needs an `else`".to_owned());
+                }
+                Ok(Some(results))
+            }
+            "br" | "br_if" => {
+                let label = args.first().and_then(atom).ok_or("expected a label")?;
+                let carried = body.label(label)?;
+                args = &args[1..];
+                if op == "br" {
+                    self.operands(args, &carried, body)?;
+                    return Ok(None);
+                }
+                let mut expected = carried.clone();
+                expected.push(Ty::I32);
+                self.operands(args, &expected, body)?;
+                Ok(Some(carried))
+            }
+            "br_table" => {
+                let labels = args.iter().take_while(|arg| atom(arg).is_some()).count();
+                if labels == 0 {
+                    return Err("expected labels".to_owned());
+                }
+                for label in &args[..labels] {
+                    if !body.label(atom(label).unwrap_or(""))?.is_empty() {
+                        return Err("branch tables only carry nothing".to_owned());
+                    }
+                }
+                self.operands(&args[labels..], &[Ty::I32], body)?;
+                Ok(None)
+            }
+            "return" => {
+                let results = body.results.to_vec();
+                self.operands(args, &results, body)?;
+                Ok(None)
+            }
+            "unreachable" => {
+                self.operands(args, &[], body)?;
+                Ok(None)
+            }
+            "call" => {
+                let func = args.first().and_then(atom).ok_or("expected a function")?;
+                let signature = self.funcs.get(func)?.clone();
+                self.operands(&args[1..], &signature.params, body)?;
+                Ok(Some(signature.results))
+            }
+            "call_indirect" => {
+                let reference = match args.first() {
+                    Some(item @ Sexp::List(parts)) if head(item) == Some("type") => {
+                        parts.get(1).and_then(atom).ok_or("expected a type")?
+                    }
+                    _ => return Err("expected a type use".to_owned()),
+                };
+                self.tables.get("0")?;
+                let signature = self.types.get(reference)?.clone();
+                let mut expected = signature.params.clone();
+                expected.push(Ty::I32);
+                self.operands(&args[1..], &expected, body)?;
+                Ok(Some(signature.results))
+            }
+            "local.get" | "local.set" | "local.tee" => {
+                let local = args.first().and_then(atom).ok_or("expected a local")?;
+                let t = *body.locals.get(local)?;
+                match op {
+                    "local.get" => {
+                        self.operands(&args[1..], &[], body)?;
+                        Ok(Some(vec![t]))
+                    }
+                    "local.set" => {
+                        self.operands(&args[1..], &[t], body)?;
+                        Ok(Some(Vec::new()))
+                    }
+                    _ => {
+                        self.operands(&args[1..], &[t], body)?;
+                        Ok(Some(vec![t]))
+                    }
+                }
+            }
+            "global.get" | "global.set" => {
+                let global = args.first().and_then(atom).ok_or("expected a global")?;
+                let global = self.globals.get(global)?;
+                if op == "global.get" {
+                    self.operands(&args[1..], &[], body)?;
+                    return Ok(Some(vec![global.ty]));
+                }
+                if !global.mutable {
+                    return Err("global is immutable".to_owned());
+                }
+                self.operands(&args[1..], &[global.ty], body)?;
+                Ok(Some(Vec::new()))
+            }
+            "i32.const" | "i64.const" => {
+                let (t, bits) = if op == "i32.const" {
+                    (Ty::I32, 32)
+                } else {
+                    (Ty::I64, 64)
+                };
+                int(
+                    args.first().and_then(atom).ok_or("expected a number")?,
+                    bits,
+                )?;
+                self.operands(&args[1..], &[], body)?;
+                Ok(Some(vec![t]))
+            }
+            "select" => {
+                let [a, _, _] = args else {
+                    return Err("select takes three operands".to_owned());
+                };
+                let t = match self.instr(a, body)? {
+                    Some(results) if results.len() == 1 => results[0],
+                    Some(_) => return Err("select operand must be one value".to_owned()),
+                    None => Ty::I32,
+                };
+                self.operands(args, &[t, t, Ty::I32], body)?;
+                Ok(Some(vec![t]))
+            }
+            "drop" => {
+                let [operand] = args else {
+                    return Err("drop takes one operand".to_owned());
+                };
+                match self.instr(operand, body)? {
+                    Some(results) if results.len() != 1 => {
+                        Err("drop operand must be one value".to_owned())
+                    }
+                    _ => Ok(Some(Vec::new())),
+                }
+            }
+            "memory.size" | "memory.grow" | "memory.copy" | "memory.fill" => {
+                self.memories.get("0")?;
+                let (params, results): (&[Ty], _) = match op {
+                    "memory.size" => (&[], vec![Ty::I32]),
+                    "memory.grow" => (&[Ty::I32], vec![Ty::I32]),
+                    _ => (&[Ty::I32; 3], Vec::new()),
+                };
+                self.operands(args, params, body)?;
+                Ok(Some(results))
+            }
+            _ => {
+                if let Some((params, result, natural)) = memory_op(op) {
+                    self.memories.get("0")?;
+                    let memargs = args
+                        .iter()
+                        .take_while(|arg| {
+                            atom(arg).is_some_and(|a| {
+                                a.starts_with("offset=") || a.starts_with("align=")
+                            })
+                        })
+                        .count();
+                    for memarg in &args[..memargs] {
+                        let memarg = atom(memarg).unwrap_or("");
+                        let (key, value) = memarg.split_once('=').unwrap_or_default();
+                        let value = int(value, 33)?;
+                        if key == "align" && (value > natural || (value as u64).count_ones() != 1) {
+                            return Err(format!("invalid alignment {memarg}"));
+                        }
+                        if value < 0 || value > u32::MAX as i64 {
+                            return Err(format!("invalid {memarg}"));
+                        }
+                    }
+                    self.operands(&args[memargs..], params, body)?;
+                    return Ok(Some(result.into_iter().collect()));
+                }
+                let (params, result) =
+                    numeric(op).ok_or_else(|| format!("unknown instruction {op}"))?;
+                self.operands(args, &params, body)?;
+                Ok(Some(vec![result]))
+            }
+        }
+    }
+
+    /// Checks the folded operands of an instruction against the types it takes.
+    fn operands(&self, operands: &[Sexp], expected: &[Ty], body: &mut Body<'_>) -> Result<()> {
+        let mut found = Vec::new();
+        let mut reachable = true;
+        for operand in operands {
+            match self.instr(operand, body)? {
+                Some(results) => found.extend(results),
+                None => reachable = false,
+            }
+        }
+        if reachable && found != expected {
+            return Err(format!("expected operands {expected:?}, found {found:?}"));
+        }
+        Ok(())
+    }
+}
+
+impl Body<'_> {
+    fn label(&self, reference: &str) -> Result<Vec<Ty>> {
+        let found = if is_name(reference) {
+            self.labels
+                .iter()
+                .rev()
+                .find(|(name, _)| name.as_deref() == Some(reference))
+        } else {
+            reference
+                .parse::<usize>()
+                .ok()
+                .and_then(|depth| self.labels.iter().rev().nth(depth))
+        };
+        found
+            .map(|(_, types)| types.clone())
+            .ok_or_else(|| format!("unknown label {reference}"))
+    }
+}
+
+/// Reads a block's label and result types, returning the rest of the block.
+fn block_type(args: &[Sexp]) -> Result<(Option<String>, Vec<Ty>, &[Sexp])> {
+    let (label, mut rest) = match args.first().and_then(atom) {
+        Some(name) if is_name(name) => (Some(name.to_owned()), &args[1..]),
+        _ => (None, args),
+    };
+    let mut results = Vec::new();
+    while let Some((item @ Sexp::List(parts), tail)) = rest.split_first() {
+        if head(item) != Some("result") {
+            break;
+        }
+        for part in &parts[1..] {
+            results.push(ty(atom(part).unwrap_or(""))?);
+        }
+        rest = tail;
+    }
+    if let Some(item) = rest.iter().find(|item| atom(item).is_some()) {
+        return Err(format!("unexpected {item:?} in block"));
+    }
+    Ok((label, results, rest))
+}
+
+/// Parses an integer literal that fits in `bits` bits, signed or not.
+fn int(text: &str, bits: u32) -> Result<i64> {
+    let digits = text.replace('_', "");
+    let (negative, digits) = match digits.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, digits.strip_prefix('+').unwrap_or(&digits)),
+    };
+    let magnitude = match digits.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => digits.parse::<u64>(),
+    }
+    .map_err(|_| format!("invalid number {text}"))?;
+    let fits = match (negative, bits) {
+        (false, 64) => true,
+        (true, 64) => magnitude <= 1 << 63,
+        (false, bits) => magnitude < 1 << bits,
+        (true, bits) => magnitude <= 1 << (bits - 1),
+    };
+    if !fits {
+        return Err(format!("number {text} out of range"));
+    }
+    Ok(if negative {
+        (magnitude as i64).wrapping_neg()
+    } else {
+        magnitude as i64
+    })
+}
+
+/// The operand types, result type and natural alignment of a load or store.
+fn memory_op(op: &str) -> Option<(&'static [Ty], Option<Ty>, i64)> {
+    Some(match op {
+        "i32.load" => (&[Ty::I32], Some(Ty::I32), 4),
+        "i64.load" => (&[Ty::I32], Some(Ty::I64), 8),
+        "i32.load8_u" | "i32.load8_s" => (&[Ty::I32], Some(Ty::I32), 1),
+        "i32.store" => (&[Ty::I32, Ty::I32], None, 4),
+        "i64.store" => (&[Ty::I32, Ty::I64], None, 8),
+        "i32.store8" => (&[Ty::I32, Ty::I32], None, 1),
+        _ => return None,
+    })
+}
+
+/// The operand types and result type of a numeric instruction.
+fn numeric(op: &str) -> Option<(Vec<Ty>, Ty)> {
+    let (t, name) = op.split_once('.')?;
+    let t = ty(t).ok()?;
+    Some(match name {
+        "add" | "sub" | "mul" | "div_s" | "div_u" | "rem_s" | "rem_u" | "and" | "or" | "xor"
+        | "shl" | "shr_s" | "shr_u" => (vec![t, t], t),
+        "eq" | "ne" | "lt_s" | "lt_u" | "gt_s" | "gt_u" | "le_s" | "le_u" | "ge_s" | "ge_u" => {
+            (vec![t, t], Ty::I32)
+        }
+        "eqz" => (vec![t], Ty::I32),
+        "wrap_i64" if t == Ty::I32 => (vec![Ty::I64], Ty::I32),
+        "extend_i32_u" | "extend_i32_s" if t == Ty::I64 => (vec![Ty::I32], Ty::I64),
+        _ => return None,
+    })
+}
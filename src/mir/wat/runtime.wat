@@ -0,0 +1,836 @@
+  ;; The Korou WebAssembly runtime, which compiled programs are emitted after, inside the same
+  ;; module. It follows the C runtime: bodies run on a trampoline, each ending by handing the
+  ;; runtime the value to call next, and the only other state is the handler stack.
+  ;;
+  ;; Every value is the address of an object in linear memory, whose first word is its tag:
+  ;;
+  ;;   0 unit                        6 exit: id
+  ;;   1 integer: i64 at 8           7 resumption: k, results, frame count, frames
+  ;;   2 string: length, bytes at 8  8 pending: callee, finally count, argument count,
+  ;;   3 function: key                 (key, env) pairs, arguments
+  ;;   4 closure: key, env, cont,    9 halt
+  ;;     prompt                     10 environment: parent, length, slots
+  ;;   5 handler: key, env          11 finally flag: set
+  ;;
+  ;; A handler frame is six words: id, handler, cc, finally flag, resumed, resumption. Objects are
+  ;; bump-allocated and never freed, so an instance runs one program. Closures and handlers that
+  ;; don't escape the function making them are made in the environment of the body making them,
+  ;; which reserves 24 bytes for each after its slots, from the first 8-aligned offset.
+
+  (import "korou" "print" (func $host_print (param i32 i32)))
+  (import "korou" "read_line" (func $host_read_line (param i32 i32) (result i32)))
+  (import "korou" "fail" (func $host_fail (param i32 i32)))
+
+  (type $body (func (param i32)))
+
+  (global $heap (mut i32) (i32.const 0))
+  (global $frames (mut i32) (i32.const 0))
+  (global $depth (mut i32) (i32.const 0))
+  (global $frames_cap (mut i32) (i32.const 0))
+  (global $next_id (mut i32) (i32.const 1))
+  ;; the value to call next and its arguments, unless a body has been entered
+  (global $callee (mut i32) (i32.const 0))
+  (global $args (mut i32) (i32.const 0))
+  (global $argc (mut i32) (i32.const 0))
+  (global $args_cap (mut i32) (i32.const 0))
+  (global $entered (mut i32) (i32.const 0))
+  (global $code (mut i32) (i32.const 0))
+  (global $env (mut i32) (i32.const 0))
+  ;; the finally blocks collected while leaving frames, as (key, env) pairs
+  (global $fin (mut i32) (i32.const 0))
+  (global $fin_len (mut i32) (i32.const 0))
+  (global $fin_cap (mut i32) (i32.const 0))
+
+  (func $alloc (param $size i32) (result i32)
+    (local $ptr i32)
+    (local $end i32)
+    (local $have i32)
+    (local.set $ptr (global.get $heap))
+    (local.set $end
+      (i32.and (i32.add (i32.add (local.get $ptr) (local.get $size)) (i32.const 7)) (i32.const -8)))
+    (local.set $have (i32.shl (memory.size) (i32.const 16)))
+    (if (i32.gt_u (local.get $end) (local.get $have))
+      (then
+        (if (i32.eq
+              (memory.grow
+                (i32.shr_u
+                  (i32.add (i32.sub (local.get $end) (local.get $have)) (i32.const 65535))
+                  (i32.const 16)))
+              (i32.const -1))
+          (then (call $fail (global.get $msg_memory))))))
+    (global.set $heap (local.get $end))
+    (local.get $ptr))
+
+  ;; Copies an array of `$len` elements of `$size` bytes into a new one with room for `$cap`.
+  (func $regrow (param $ptr i32) (param $len i32) (param $cap i32) (param $size i32) (result i32)
+    (local $new i32)
+    (local.set $new (call $alloc (i32.mul (local.get $cap) (local.get $size))))
+    (memory.copy
+      (local.get $new) (local.get $ptr) (i32.mul (local.get $len) (local.get $size)))
+    (local.get $new))
+
+  (func $fail (param $msg i32)
+    (call $host_fail
+      (i32.add (local.get $msg) (i32.const 8)) (i32.load offset=4 (local.get $msg)))
+    (unreachable))
+
+  (func $fail_value (param $msg i32) (param $v i32)
+    (call $fail (call $concat (local.get $msg) (call $show (local.get $v)))))
+
+  (func $print (param $s i32)
+    (call $host_print (i32.add (local.get $s) (i32.const 8)) (i32.load offset=4 (local.get $s))))
+
+  ;; Values
+
+  (func $string (param $len i32) (result i32)
+    (local $s i32)
+    (local.set $s (call $alloc (i32.add (i32.const 8) (local.get $len))))
+    (i32.store (local.get $s) (i32.const 2))
+    (i32.store offset=4 (local.get $s) (local.get $len))
+    (local.get $s))
+
+  (func $concat (param $a i32) (param $b i32) (result i32)
+    (local $s i32)
+    (local.set $s
+      (call $string (i32.add (i32.load offset=4 (local.get $a)) (i32.load offset=4 (local.get $b)))))
+    (memory.copy
+      (i32.add (local.get $s) (i32.const 8))
+      (i32.add (local.get $a) (i32.const 8))
+      (i32.load offset=4 (local.get $a)))
+    (memory.copy
+      (i32.add (i32.add (local.get $s) (i32.const 8)) (i32.load offset=4 (local.get $a)))
+      (i32.add (local.get $b) (i32.const 8))
+      (i32.load offset=4 (local.get $b)))
+    (local.get $s))
+
+  (func $int (param $v i64) (result i32)
+    (local $obj i32)
+    (local.set $obj (call $alloc (i32.const 16)))
+    (i32.store (local.get $obj) (i32.const 1))
+    (i64.store offset=8 (local.get $obj) (local.get $v))
+    (local.get $obj))
+
+  (func $show_int (param $v i64) (result i32)
+    (local $mag i64)
+    (local $buf i32)
+    (local $pos i32)
+    (local $s i32)
+    (local.set $buf (call $alloc (i32.const 20)))
+    (local.set $pos (i32.const 20))
+    (local.set $mag
+      (select
+        (i64.sub (i64.const 0) (local.get $v))
+        (local.get $v)
+        (i64.lt_s (local.get $v) (i64.const 0))))
+    (loop $digit
+      (local.set $pos (i32.sub (local.get $pos) (i32.const 1)))
+      (i32.store8
+        (i32.add (local.get $buf) (local.get $pos))
+        (i32.add (i32.const 48) (i32.wrap_i64 (i64.rem_u (local.get $mag) (i64.const 10)))))
+      (local.set $mag (i64.div_u (local.get $mag) (i64.const 10)))
+      (br_if $digit (i64.ne (local.get $mag) (i64.const 0))))
+    (if (i64.lt_s (local.get $v) (i64.const 0))
+      (then
+        (local.set $pos (i32.sub (local.get $pos) (i32.const 1)))
+        (i32.store8 (i32.add (local.get $buf) (local.get $pos)) (i32.const 45))))
+    (local.set $s (call $string (i32.sub (i32.const 20) (local.get $pos))))
+    (memory.copy
+      (i32.add (local.get $s) (i32.const 8))
+      (i32.add (local.get $buf) (local.get $pos))
+      (i32.sub (i32.const 20) (local.get $pos)))
+    (local.get $s))
+
+  ;; Converts a value to the string the interpreter displays it as.
+  (func $show (param $v i32) (result i32)
+    (local $tag i32)
+    (local.set $tag (i32.load (local.get $v)))
+    (if (i32.eq (local.get $tag) (i32.const 0)) (then (return (global.get $msg_unit))))
+    (if (i32.eq (local.get $tag) (i32.const 1))
+      (then (return (call $show_int (i64.load offset=8 (local.get $v))))))
+    (if (i32.eq (local.get $tag) (i32.const 2)) (then (return (local.get $v))))
+    (if (i32.eq (local.get $tag) (i32.const 3)) (then (return (global.get $msg_function))))
+    (if (i32.eq (local.get $tag) (i32.const 4))
+      (then
+        (return
+          (select
+            (global.get $msg_continuation)
+            (global.get $msg_closure)
+            (i32.load offset=12 (local.get $v))))))
+    (if (i32.eq (local.get $tag) (i32.const 5)) (then (return (global.get $msg_handler))))
+    (global.get $msg_continuation))
+
+  (func $int_of (param $v i32) (result i64)
+    (if (i32.ne (i32.load (local.get $v)) (i32.const 1))
+      (then (call $fail_value (global.get $msg_integer) (local.get $v))))
+    (i64.load offset=8 (local.get $v)))
+
+  (func $truth (param $v i32) (result i32)
+    (i64.ne (call $int_of (local.get $v)) (i64.const 0)))
+
+  (func $add (param $a i32) (param $b i32) (result i32)
+    (local $ta i32)
+    (local $tb i32)
+    (local.set $ta (i32.load (local.get $a)))
+    (local.set $tb (i32.load (local.get $b)))
+    (if (i32.and (i32.eq (local.get $ta) (i32.const 1)) (i32.eq (local.get $tb) (i32.const 1)))
+      (then
+        (return
+          (call $int
+            (i64.add (i64.load offset=8 (local.get $a)) (i64.load offset=8 (local.get $b)))))))
+    (if (i32.and (i32.eq (local.get $ta) (i32.const 2)) (i32.eq (local.get $tb) (i32.const 2)))
+      (then (return (call $concat (local.get $a) (local.get $b)))))
+    (if (i32.eq (local.get $ta) (i32.const 2))
+      (then (call $fail_value (global.get $msg_string) (local.get $b))))
+    (call $fail_value
+      (global.get $msg_integer)
+      (select (local.get $b) (local.get $a) (i32.eq (local.get $ta) (i32.const 1))))
+    (unreachable))
+
+  ;; Integer operations other than addition: 0 sub, 1 mul, 2 div, 3 rem, 4 gt, 5 ge, 6 lt, 7 le.
+  (func $arith (param $op i32) (param $a i32) (param $b i32) (result i32)
+    (local $x i64)
+    (local $y i64)
+    (local.set $y (call $int_of (local.get $b)))
+    (local.set $x (call $int_of (local.get $a)))
+    (if (i32.and
+          (i32.or (i32.eq (local.get $op) (i32.const 2)) (i32.eq (local.get $op) (i32.const 3)))
+          (i32.or
+            (i64.eqz (local.get $y))
+            (i32.and
+              (i64.eq (local.get $x) (i64.const -9223372036854775808))
+              (i64.eq (local.get $y) (i64.const -1)))))
+      (then (call $fail (global.get $msg_zero))))
+    (block $le
+      (block $lt
+        (block $ge
+          (block $gt
+            (block $rem
+              (block $div
+                (block $mul
+                  (block $sub
+                    (br_table $sub $mul $div $rem $gt $ge $lt $le (local.get $op)))
+                  (return (call $int (i64.sub (local.get $x) (local.get $y)))))
+                (return (call $int (i64.mul (local.get $x) (local.get $y)))))
+              (return (call $int (i64.div_s (local.get $x) (local.get $y)))))
+            (return (call $int (i64.rem_s (local.get $x) (local.get $y)))))
+          (return (call $int (i64.extend_i32_u (i64.gt_s (local.get $x) (local.get $y))))))
+        (return (call $int (i64.extend_i32_u (i64.ge_s (local.get $x) (local.get $y))))))
+      (return (call $int (i64.extend_i32_u (i64.lt_s (local.get $x) (local.get $y))))))
+    (call $int (i64.extend_i32_u (i64.le_s (local.get $x) (local.get $y)))))
+
+  ;; Compares two integers or two strings, producing 1 if their equality is `$eq`.
+  (func $equal (param $a i32) (param $b i32) (param $eq i32) (result i32)
+    (local $ta i32)
+    (local $tb i32)
+    (local $same i32)
+    (local $len i32)
+    (local $i i32)
+    (local.set $ta (i32.load (local.get $a)))
+    (local.set $tb (i32.load (local.get $b)))
+    (if (i32.and (i32.eq (local.get $ta) (i32.const 1)) (i32.eq (local.get $tb) (i32.const 1)))
+      (then
+        (local.set $same
+          (i64.eq (i64.load offset=8 (local.get $a)) (i64.load offset=8 (local.get $b)))))
+      (else
+        (if (i32.and (i32.eq (local.get $ta) (i32.const 2)) (i32.eq (local.get $tb) (i32.const 2)))
+          (then
+            (local.set $len (i32.load offset=4 (local.get $a)))
+            (local.set $same (i32.eq (local.get $len) (i32.load offset=4 (local.get $b))))
+            (block $done
+              (loop $byte
+                (br_if $done
+                  (i32.or (i32.eqz (local.get $same)) (i32.ge_u (local.get $i) (local.get $len))))
+                (local.set $same
+                  (i32.eq
+                    (i32.load8_u offset=8 (i32.add (local.get $a) (local.get $i)))
+                    (i32.load8_u offset=8 (i32.add (local.get $b) (local.get $i)))))
+                (local.set $i (i32.add (local.get $i) (i32.const 1)))
+                (br $byte))))
+          (else
+            (if (i32.eq (local.get $ta) (i32.const 2))
+              (then (call $fail_value (global.get $msg_string) (local.get $b))))
+            (call $fail_value
+              (global.get $msg_integer)
+              (select (local.get $b) (local.get $a) (i32.eq (local.get $ta) (i32.const 1))))))))
+    (call $int (i64.extend_i32_u (i32.eq (local.get $same) (local.get $eq)))))
+
+  (func $env_new (param $parent i32) (param $len i32) (param $scoped i32) (result i32)
+    (local $env i32)
+    (local $i i32)
+    (local.set $env
+      (call $alloc
+        (i32.add
+          (i32.and (i32.add (i32.shl (local.get $len) (i32.const 2)) (i32.const 19)) (i32.const -8))
+          (i32.mul (local.get $scoped) (i32.const 24)))))
+    (i32.store (local.get $env) (i32.const 10))
+    (i32.store offset=4 (local.get $env) (local.get $parent))
+    (i32.store offset=8 (local.get $env) (local.get $len))
+    (block $done
+      (loop $slot
+        (br_if $done (i32.ge_u (local.get $i) (local.get $len)))
+        (i32.store offset=12
+          (i32.add (local.get $env) (i32.shl (local.get $i) (i32.const 2)))
+          (global.get $unit))
+        (local.set $i (i32.add (local.get $i) (i32.const 1)))
+        (br $slot)))
+    (local.get $env))
+
+  ;; Reads a local of the frame `$depth` frames out from `$env`.
+  (func $outer (param $env i32) (param $depth i32) (param $slot i32) (result i32)
+    (block $done
+      (loop $up
+        (br_if $done (i32.eqz (local.get $depth)))
+        (local.set $env (i32.load offset=4 (local.get $env)))
+        (local.set $depth (i32.sub (local.get $depth) (i32.const 1)))
+        (br $up)))
+    (i32.load offset=12 (i32.add (local.get $env) (i32.shl (local.get $slot) (i32.const 2)))))
+
+  ;; Copies the values a converted closure captures, listed as depth and slot pairs, into a record.
+  (func $record (param $env i32) (param $len i32) (param $captures i32) (result i32)
+    (local $record i32)
+    (local $i i32)
+    (local $pair i32)
+    (local.set $record (call $env_new (i32.const 0) (local.get $len) (i32.const 0)))
+    (block $done
+      (loop $capture
+        (br_if $done (i32.ge_u (local.get $i) (local.get $len)))
+        (local.set $pair (i32.add (local.get $captures) (i32.shl (local.get $i) (i32.const 3))))
+        (i32.store offset=12
+          (i32.add (local.get $record) (i32.shl (local.get $i) (i32.const 2)))
+          (call $outer
+            (local.get $env) (i32.load (local.get $pair)) (i32.load offset=4 (local.get $pair))))
+        (local.set $i (i32.add (local.get $i) (i32.const 1)))
+        (br $capture)))
+    (local.get $record))
+
+  (func $frame_at (param $idx i32) (result i32)
+    (i32.add (global.get $frames) (i32.mul (local.get $idx) (i32.const 24))))
+
+  (func $closure (param $key i32) (param $env i32) (param $cont i32) (result i32)
+    (call $closure_in
+      (call $alloc (i32.const 20)) (local.get $key) (local.get $env) (local.get $cont)))
+
+  (func $closure_in
+    (param $obj i32) (param $key i32) (param $env i32) (param $cont i32) (result i32)
+    (i32.store (local.get $obj) (i32.const 4))
+    (i32.store offset=4 (local.get $obj) (local.get $key))
+    (i32.store offset=8 (local.get $obj) (local.get $env))
+    (i32.store offset=12 (local.get $obj) (local.get $cont))
+    (i32.store offset=16
+      (local.get $obj)
+      (select
+        (i32.load (call $frame_at (i32.sub (global.get $depth) (i32.const 1))))
+        (i32.const 0)
+        (local.get $cont)))
+    (local.get $obj))
+
+  (func $handler (param $key i32) (param $env i32) (result i32)
+    (call $handler_in (call $alloc (i32.const 12)) (local.get $key) (local.get $env)))
+
+  (func $handler_in (param $obj i32) (param $key i32) (param $env i32) (result i32)
+    (i32.store (local.get $obj) (i32.const 5))
+    (i32.store offset=4 (local.get $obj) (local.get $key))
+    (i32.store offset=8 (local.get $obj) (local.get $env))
+    (local.get $obj))
+
+  (func $exit (param $id i32) (result i32)
+    (local $obj i32)
+    (local.set $obj (call $alloc (i32.const 8)))
+    (i32.store (local.get $obj) (i32.const 6))
+    (i32.store offset=4 (local.get $obj) (local.get $id))
+    (local.get $obj))
+
+  ;; Program tables: closures are (has body, params, locals, name, scoped values), handlers
+  ;; (actions, action count, return clause or -1, finally or -1), actions (op, closure,
+  ;; tail-resumptive), and operations (returns, built-in, name).
+
+  (func $closure_info (param $key i32) (result i32)
+    (i32.add (global.get $closures) (i32.mul (local.get $key) (i32.const 20))))
+
+  (func $handler_info (param $handler i32) (result i32)
+    (i32.add (global.get $handlers) (i32.shl (i32.load offset=4 (local.get $handler)) (i32.const 4))))
+
+  ;; Control
+
+  (func $push_arg (param $v i32)
+    (if (i32.eq (global.get $argc) (global.get $args_cap))
+      (then
+        (global.set $args_cap
+          (select
+            (i32.shl (global.get $args_cap) (i32.const 1))
+            (i32.const 16)
+            (global.get $args_cap)))
+        (global.set $args
+          (call $regrow
+            (global.get $args) (global.get $argc) (global.get $args_cap) (i32.const 4)))))
+    (i32.store
+      (i32.add (global.get $args) (i32.shl (global.get $argc) (i32.const 2))) (local.get $v))
+    (global.set $argc (i32.add (global.get $argc) (i32.const 1))))
+
+  (func $pop_arg (result i32)
+    (global.set $argc (i32.sub (global.get $argc) (i32.const 1)))
+    (i32.load (i32.add (global.get $args) (i32.shl (global.get $argc) (i32.const 2)))))
+
+  (func $reserve_frames (param $n i32)
+    (block $done
+      (loop $grow
+        (br_if $done
+          (i32.le_u (i32.add (global.get $depth) (local.get $n)) (global.get $frames_cap)))
+        (global.set $frames_cap
+          (select
+            (i32.shl (global.get $frames_cap) (i32.const 1))
+            (i32.const 16)
+            (global.get $frames_cap)))
+        (global.set $frames
+          (call $regrow
+            (global.get $frames) (global.get $depth) (global.get $frames_cap) (i32.const 24)))
+        (br $grow))))
+
+  (func $push_frame
+    (param $id i32) (param $handler i32) (param $cc i32)
+    (param $finalized i32) (param $resumed i32) (param $suspended i32)
+    (local $frame i32)
+    (call $reserve_frames (i32.const 1))
+    (local.set $frame (call $frame_at (global.get $depth)))
+    (i32.store (local.get $frame) (local.get $id))
+    (i32.store offset=4 (local.get $frame) (local.get $handler))
+    (i32.store offset=8 (local.get $frame) (local.get $cc))
+    (i32.store offset=12 (local.get $frame) (local.get $finalized))
+    (i32.store offset=16 (local.get $frame) (local.get $resumed))
+    (i32.store offset=20 (local.get $frame) (local.get $suspended))
+    (global.set $depth (i32.add (global.get $depth) (i32.const 1))))
+
+  ;; Pushes copies of a resumption's frames.
+  (func $push_frames (param $r i32)
+    (local $len i32)
+    (local.set $len (i32.load offset=12 (local.get $r)))
+    (call $reserve_frames (local.get $len))
+    (memory.copy
+      (call $frame_at (global.get $depth))
+      (i32.add (local.get $r) (i32.const 16))
+      (i32.mul (local.get $len) (i32.const 24)))
+    (global.set $depth (i32.add (global.get $depth) (local.get $len))))
+
+  ;; Starts executing a closure with the given environment, taking its arguments.
+  (func $enter (param $key i32) (param $parent i32)
+    (local $info i32)
+    (local $params i32)
+    (local $env i32)
+    (local.set $info (call $closure_info (local.get $key)))
+    (if (i32.eqz (i32.load (local.get $info)))
+      (then
+        (call $fail
+          (call $concat
+            (call $concat (global.get $msg_function_named) (i32.load offset=12 (local.get $info)))
+            (global.get $msg_no_body)))))
+    (local.set $params (i32.load offset=4 (local.get $info)))
+    (if (i32.lt_u (global.get $argc) (local.get $params))
+      (then
+        (call $fail
+          (call $concat
+            (call $concat
+              (call $concat
+                (global.get $msg_expected)
+                (call $show_int (i64.extend_i32_u (local.get $params))))
+              (global.get $msg_arguments))
+            (call $show_int (i64.extend_i32_u (global.get $argc)))))))
+    (local.set $env
+      (call $env_new
+        (local.get $parent)
+        (i32.load offset=8 (local.get $info))
+        (i32.load offset=16 (local.get $info))))
+    (memory.copy
+      (i32.add (local.get $env) (i32.const 12))
+      (global.get $args)
+      (i32.shl (local.get $params) (i32.const 2)))
+    (global.set $argc (i32.const 0))
+    (global.set $code (local.get $key))
+    (global.set $env (local.get $env))
+    (global.set $entered (i32.const 1)))
+
+  ;; Finds the index of the handler frame with the given ID.
+  (func $frame (param $id i32) (result i32)
+    (local $i i32)
+    (local.set $i (global.get $depth))
+    (block $missing
+      (loop $search
+        (br_if $missing (i32.eqz (local.get $i)))
+        (local.set $i (i32.sub (local.get $i) (i32.const 1)))
+        (if (i32.eq (i32.load (call $frame_at (local.get $i))) (local.get $id))
+          (then (return (local.get $i))))
+        (br $search)))
+    (call $fail (global.get $msg_exited))
+    (unreachable))
+
+  ;; Collects the finally blocks to run for a frame leaving the handler stack, innermost first.
+  (func $finalize (param $frame i32)
+    (local $r i32)
+    (local $i i32)
+    (local $handler i32)
+    (local $flag i32)
+    (local $entry i32)
+    (local.set $r (i32.load offset=20 (local.get $frame)))
+    (if (local.get $r)
+      (then
+        (local.set $i (i32.load offset=12 (local.get $r)))
+        (block $done
+          (loop $each
+            (br_if $done (i32.eqz (local.get $i)))
+            (local.set $i (i32.sub (local.get $i) (i32.const 1)))
+            (call $finalize
+              (i32.add
+                (i32.add (local.get $r) (i32.const 16))
+                (i32.mul (local.get $i) (i32.const 24))))
+            (br $each)))))
+    (local.set $handler (i32.load offset=4 (local.get $frame)))
+    (local.set $flag (i32.load offset=12 (local.get $frame)))
+    (if (i32.or (i32.eqz (local.get $handler)) (i32.eqz (local.get $flag))) (then (return)))
+    ;; a resumed frame returns to its handler, whose own frame runs the block when it's left
+    (if (i32.or (i32.load offset=16 (local.get $frame)) (i32.load offset=4 (local.get $flag)))
+      (then (return)))
+    (i32.store offset=4 (local.get $flag) (i32.const 1))
+    (if (i32.eq (global.get $fin_len) (global.get $fin_cap))
+      (then
+        (global.set $fin_cap
+          (select
+            (i32.shl (global.get $fin_cap) (i32.const 1))
+            (i32.const 16)
+            (global.get $fin_cap)))
+        (global.set $fin
+          (call $regrow
+            (global.get $fin) (global.get $fin_len) (global.get $fin_cap) (i32.const 8)))))
+    (local.set $entry (i32.add (global.get $fin) (i32.shl (global.get $fin_len) (i32.const 3))))
+    (i32.store
+      (local.get $entry) (i32.load offset=12 (call $handler_info (local.get $handler))))
+    (i32.store offset=4 (local.get $entry) (i32.load offset=8 (local.get $handler)))
+    (global.set $fin_len (i32.add (global.get $fin_len) (i32.const 1))))
+
+  ;; Removes the handler frames from `$idx` up, then calls `$callee` once their finally blocks
+  ;; have run.
+  (func $leave (param $idx i32) (param $callee i32)
+    (local $n i32)
+    (local $p i32)
+    (local $i i32)
+    (local $to i32)
+    (local $from i32)
+    (global.set $fin_len (i32.const 0))
+    (block $done
+      (loop $pop
+        (br_if $done (i32.le_u (global.get $depth) (local.get $idx)))
+        (global.set $depth (i32.sub (global.get $depth) (i32.const 1)))
+        (call $finalize (call $frame_at (global.get $depth)))
+        (br $pop)))
+    (local.set $n (global.get $fin_len))
+    (if (i32.eqz (local.get $n))
+      (then
+        (global.set $callee (local.get $callee))
+        (return)))
+    (local.set $p
+      (call $alloc
+        (i32.add
+          (i32.add (i32.const 16) (i32.shl (local.get $n) (i32.const 3)))
+          (i32.shl (global.get $argc) (i32.const 2)))))
+    (i32.store (local.get $p) (i32.const 8))
+    (i32.store offset=4 (local.get $p) (local.get $callee))
+    (i32.store offset=8 (local.get $p) (local.get $n))
+    (i32.store offset=12 (local.get $p) (global.get $argc))
+    ;; the innermost block runs first, so it goes last
+    (block $copied
+      (loop $copy
+        (br_if $copied (i32.ge_u (local.get $i) (local.get $n)))
+        (local.set $to
+          (i32.add (i32.add (local.get $p) (i32.const 16)) (i32.shl (local.get $i) (i32.const 3))))
+        (local.set $from
+          (i32.add
+            (global.get $fin)
+            (i32.shl
+              (i32.sub (i32.sub (local.get $n) (i32.const 1)) (local.get $i))
+              (i32.const 3))))
+        (i32.store (local.get $to) (i32.load (local.get $from)))
+        (i32.store offset=4 (local.get $to) (i32.load offset=4 (local.get $from)))
+        (local.set $i (i32.add (local.get $i) (i32.const 1)))
+        (br $copy)))
+    (memory.copy
+      (i32.add (i32.add (local.get $p) (i32.const 16)) (i32.shl (local.get $n) (i32.const 3)))
+      (global.get $args)
+      (i32.shl (global.get $argc) (i32.const 2)))
+    (global.set $callee (local.get $p))
+    (global.set $argc (i32.const 0)))
+
+  ;; Calls `$callee` with the arguments until a body is entered. Returns 1 if the program halted.
+  (func $apply (result i32)
+    (local $callee i32)
+    (local $idx i32)
+    (local $handler i32)
+    (local $ret i32)
+    (local $cc i32)
+    (local $params i32)
+    (local $base i32)
+    (local $n i32)
+    (local $rest i32)
+    (local $last i32)
+    (local $i i32)
+    (loop $next
+      (local.set $callee (global.get $callee))
+      (block $halt
+        (block $then
+          (block $resume
+            (block $exit
+              (block $closure
+                (block $function
+                  (block $bad
+                    (br_table
+                      $bad $bad $bad $function $closure $bad $exit $resume $then $halt $bad
+                      (i32.load (local.get $callee))))
+                  (call $fail
+                    (call $concat (call $show (local.get $callee)) (global.get $msg_not_callable))))
+                (call $enter (i32.load offset=4 (local.get $callee)) (i32.const 0))
+                (return (i32.const 0)))
+              (if (i32.load offset=12 (local.get $callee))
+                (then
+                  ;; leave the frames made since the continuation was
+                  (local.set $idx
+                    (i32.add (call $frame (i32.load offset=16 (local.get $callee))) (i32.const 1)))
+                  (if (i32.lt_u (local.get $idx) (global.get $depth))
+                    (then
+                      (call $leave (local.get $idx) (local.get $callee))
+                      (br $next)))))
+              (call $enter
+                (i32.load offset=4 (local.get $callee)) (i32.load offset=8 (local.get $callee)))
+              (return (i32.const 0)))
+            (local.set $idx (call $frame (i32.load offset=4 (local.get $callee))))
+            (local.set $cc (i32.load offset=8 (call $frame_at (local.get $idx))))
+            (local.set $handler (i32.load offset=4 (call $frame_at (local.get $idx))))
+            (local.set $ret (i32.load offset=8 (call $handler_info (local.get $handler))))
+            (if (i32.ge_s (local.get $ret) (i32.const 0))
+              (then
+                ;; the return clause runs outside the handler, after any finally blocks
+                (local.set $params (i32.load offset=4 (call $closure_info (local.get $ret))))
+                (if (i32.and
+                      (i32.ne (local.get $params) (i32.const 0))
+                      (i32.gt_u (global.get $argc) (i32.sub (local.get $params) (i32.const 1))))
+                  (then (global.set $argc (i32.sub (local.get $params) (i32.const 1)))))
+                (call $push_arg (local.get $cc))
+                (local.set $cc
+                  (call $closure
+                    (local.get $ret) (i32.load offset=8 (local.get $handler)) (i32.const 0)))))
+            (call $leave (local.get $idx) (local.get $cc))
+            (br $next))
+          (if (i32.eq
+                (global.get $argc)
+                (i32.add (i32.load offset=8 (local.get $callee)) (i32.const 1)))
+            (then
+              (local.set $base (global.get $depth))
+              (call $push_frames (local.get $callee))
+              (i32.store offset=8 (call $frame_at (local.get $base)) (call $pop_arg))
+              (i32.store offset=16 (call $frame_at (local.get $base)) (i32.const 1)))
+            (else
+              ;; the handler is done, and the resumed frames take the place of its frame
+              (if (i32.ne (global.get $depth) (i32.const 0))
+                (then
+                  (if (i32.eq
+                        (i32.load offset=20
+                          (call $frame_at (i32.sub (global.get $depth) (i32.const 1))))
+                        (local.get $callee))
+                    (then (global.set $depth (i32.sub (global.get $depth) (i32.const 1)))))))
+              (call $push_frames (local.get $callee))))
+          (global.set $callee (i32.load offset=4 (local.get $callee)))
+          (br $next))
+        (local.set $n (i32.load offset=8 (local.get $callee)))
+        (if (local.get $n)
+          (then
+            (local.set $last
+              (i32.add
+                (i32.add (local.get $callee) (i32.const 16))
+                (i32.shl (i32.sub (local.get $n) (i32.const 1)) (i32.const 3))))
+            (local.set $rest
+              (call $alloc
+                (i32.add
+                  (i32.add
+                    (i32.const 16)
+                    (i32.shl (i32.sub (local.get $n) (i32.const 1)) (i32.const 3)))
+                  (i32.shl (i32.load offset=12 (local.get $callee)) (i32.const 2)))))
+            (i32.store (local.get $rest) (i32.const 8))
+            (i32.store offset=4 (local.get $rest) (i32.load offset=4 (local.get $callee)))
+            (i32.store offset=8 (local.get $rest) (i32.sub (local.get $n) (i32.const 1)))
+            (i32.store offset=12 (local.get $rest) (i32.load offset=12 (local.get $callee)))
+            (memory.copy
+              (i32.add (local.get $rest) (i32.const 16))
+              (i32.add (local.get $callee) (i32.const 16))
+              (i32.shl (i32.sub (local.get $n) (i32.const 1)) (i32.const 3)))
+            (memory.copy
+              (i32.add (local.get $last) (i32.sub (local.get $rest) (local.get $callee)))
+              (i32.add (local.get $last) (i32.const 8))
+              (i32.shl (i32.load offset=12 (local.get $callee)) (i32.const 2)))
+            (global.set $argc (i32.const 0))
+            (call $push_arg (local.get $rest))
+            (call $enter (i32.load (local.get $last)) (i32.load offset=4 (local.get $last)))
+            (return (i32.const 0))))
+        (global.set $callee (i32.load offset=4 (local.get $callee)))
+        (global.set $argc (i32.const 0))
+        (local.set $i (i32.const 0))
+        (block $done
+          (loop $arg
+            (br_if $done (i32.ge_u (local.get $i) (i32.load offset=12 (local.get $callee))))
+            (call $push_arg
+              (i32.load offset=16
+                (i32.add (local.get $callee) (i32.shl (local.get $i) (i32.const 2)))))
+            (local.set $i (i32.add (local.get $i) (i32.const 1)))
+            (br $arg)))
+        (br $next))
+      (if (i32.gt_u (global.get $depth) (i32.const 1))
+        (then
+          (call $leave (i32.const 1) (local.get $callee))
+          (br $next)))
+      (return (i32.const 1)))
+    (unreachable))
+
+  ;; Ends a body by calling the last argument with the ones before it.
+  (func $continue
+    (global.set $callee (call $pop_arg)))
+
+  ;; Ends a body by calling `$body` under a handler, continuing with `$cc`.
+  (func $handle (param $handler i32) (param $body i32) (param $cc i32)
+    (local $flag i32)
+    (local $id i32)
+    (if (i32.ne (i32.load (local.get $handler)) (i32.const 5))
+      (then (call $fail_value (global.get $msg_handler_expected) (local.get $handler))))
+    (if (i32.ge_s (i32.load offset=12 (call $handler_info (local.get $handler))) (i32.const 0))
+      (then
+        (local.set $flag (call $alloc (i32.const 8)))
+        (i32.store (local.get $flag) (i32.const 11))
+        (i32.store offset=4 (local.get $flag) (i32.const 0))))
+    (local.set $id (global.get $next_id))
+    (global.set $next_id (i32.add (global.get $next_id) (i32.const 1)))
+    (call $push_frame
+      (local.get $id) (local.get $handler) (local.get $cc)
+      (local.get $flag) (i32.const 0) (i32.const 0))
+    (global.set $callee (local.get $body))
+    (global.set $argc (i32.const 0))
+    (call $push_arg (call $exit (local.get $id))))
+
+  ;; Handles a built-in operation with the host, leaving its results as the arguments.
+  (func $builtin (param $builtin i32)
+    (local $s i32)
+    (if (i32.eq (local.get $builtin) (i32.const 1))
+      (then
+        (if (i32.eqz (global.get $argc)) (then (call $fail (global.get $msg_print_arity))))
+        (call $print (call $show (i32.load (global.get $args))))
+        (global.set $argc (i32.const 0))
+        (return)))
+    (local.set $s (call $string (i32.const 1024)))
+    (i32.store offset=4
+      (local.get $s)
+      (call $host_read_line (i32.add (local.get $s) (i32.const 8)) (i32.const 1024)))
+    (global.set $argc (i32.const 0))
+    (call $push_arg (local.get $s)))
+
+  ;; Ends a body by performing an operation with `$arity` arguments and maybe a continuation.
+  (func $perform (param $op i32) (param $arity i32)
+    (local $resumes i32)
+    (local $k i32)
+    (local $info i32)
+    (local $idx i32)
+    (local $handler i32)
+    (local $actions i32)
+    (local $action i32)
+    (local $i i32)
+    (local $n i32)
+    (local $r i32)
+    (local $id i32)
+    (local.set $resumes (i32.gt_u (global.get $argc) (local.get $arity)))
+    (local.set $k (global.get $unit))
+    (if (local.get $resumes) (then (local.set $k (call $pop_arg))))
+    (local.set $info (i32.add (global.get $ops) (i32.mul (local.get $op) (i32.const 12))))
+    (local.set $idx (global.get $depth))
+    (block $searched
+      (loop $search
+        (br_if $searched (i32.eqz (local.get $idx)))
+        (local.set $idx (i32.sub (local.get $idx) (i32.const 1)))
+        (local.set $handler (i32.load offset=4 (call $frame_at (local.get $idx))))
+        (if (local.get $handler)
+          (then
+            (local.set $actions (i32.load (call $handler_info (local.get $handler))))
+            (local.set $n (i32.load offset=4 (call $handler_info (local.get $handler))))
+            (local.set $i (i32.const 0))
+            (block $scanned
+              (loop $scan
+                (br_if $scanned (i32.ge_u (local.get $i) (local.get $n)))
+                (local.set $action
+                  (i32.add (local.get $actions) (i32.mul (local.get $i) (i32.const 12))))
+                (br_if $searched (i32.eq (i32.load (local.get $action)) (local.get $op)))
+                (local.set $i (i32.add (local.get $i) (i32.const 1)))
+                (br $scan)))
+            (local.set $action (i32.const 0))))
+        (br $search)))
+    (if (i32.eqz (local.get $action))
+      (then
+        (if (i32.eqz (i32.load offset=4 (local.get $info)))
+          (then
+            (call $fail
+              (call $concat (global.get $msg_unhandled) (i32.load offset=8 (local.get $info))))))
+        (call $builtin (i32.load offset=4 (local.get $info)))
+        (if (i32.eqz (local.get $resumes))
+          (then
+            (call $fail
+              (call $concat (global.get $msg_unhandled) (i32.load offset=8 (local.get $info))))))
+        (global.set $callee (local.get $k))
+        (return)))
+    (if (i32.and (local.get $resumes) (i32.load offset=8 (local.get $action)))
+      (then
+        ;; the action runs on the current stack and resumes by calling `$k` directly
+        (call $push_arg (local.get $k))
+        (call $push_arg (global.get $unit))
+        (call $enter
+          (i32.load offset=4 (local.get $action)) (i32.load offset=8 (local.get $handler)))
+        (return)))
+    (local.set $n (i32.sub (global.get $depth) (local.get $idx)))
+    (local.set $r (call $alloc (i32.add (i32.const 16) (i32.mul (local.get $n) (i32.const 24)))))
+    (i32.store (local.get $r) (i32.const 7))
+    (i32.store offset=4 (local.get $r) (local.get $k))
+    (i32.store offset=8 (local.get $r) (i32.load (local.get $info)))
+    (i32.store offset=12 (local.get $r) (local.get $n))
+    (memory.copy
+      (i32.add (local.get $r) (i32.const 16))
+      (call $frame_at (local.get $idx))
+      (i32.mul (local.get $n) (i32.const 24)))
+    (global.set $depth (local.get $idx))
+    (local.set $id (global.get $next_id))
+    (global.set $next_id (i32.add (global.get $next_id) (i32.const 1)))
+    (call $push_frame
+      (local.get $id) (i32.const 0) (global.get $unit)
+      (i32.const 0) (i32.const 0) (local.get $r))
+    (call $push_arg (select (local.get $r) (global.get $unit) (local.get $resumes)))
+    (call $push_arg (i32.load offset=24 (local.get $r)))
+    (call $enter
+      (i32.load offset=4 (local.get $action)) (i32.load offset=8 (local.get $handler))))
+
+  (func $fell_off (param $key i32)
+    (call $fail
+      (call $concat
+        (global.get $msg_fell_off) (i32.load offset=12 (call $closure_info (local.get $key))))))
+
+  ;; Runs a program from the given function, printing the values it finishes with.
+  (func $run (param $entry i32)
+    (local $i i32)
+    (global.set $heap (global.get $heap_base))
+    (call $push_frame
+      (i32.const 0) (i32.const 0) (global.get $halt) (i32.const 0) (i32.const 0) (i32.const 0))
+    (if (i32.load offset=4 (call $closure_info (local.get $entry)))
+      (then (call $push_arg (global.get $halt))))
+    (call $enter (local.get $entry) (i32.const 0))
+    (block $halted
+      (loop $step
+        (if (i32.eqz (global.get $entered))
+          (then (br_if $halted (call $apply))))
+        (global.set $entered (i32.const 0))
+        (call_indirect (type $body) (global.get $env) (global.get $code))
+        (br $step)))
+    (block $done
+      (loop $result
+        (br_if $done (i32.ge_u (local.get $i) (global.get $argc)))
+        (call $print
+          (call $show
+            (i32.load (i32.add (global.get $args) (i32.shl (local.get $i) (i32.const 2))))))
+        (local.set $i (i32.add (local.get $i) (i32.const 1)))
+        (br $result))))
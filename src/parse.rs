@@ -1,27 +1,44 @@
 //! The parser.
+//!
+//! The parser only records the syntax nodes it recognizes, which are combined with the tokens into
+//! the concrete syntax tree in [`crate::cst`]. The AST is derived from that tree.
 
-use crate::ast::Item;
-use crate::cache::StringCache;
+use crate::cst::{Recorder, SyntaxKind};
 use crate::diagnostic::{Code, Diagnostics};
 use crate::span::Spanned;
 use crate::token::{Token, TokenKind};
 use crate::tokenizer::Tokenizer;
 
-mod atoms;
 mod combinators;
 mod expr;
+pub mod incremental;
 mod item;
 mod paths;
 mod statement;
 mod types;
 
 pub struct Parser<'a> {
-    pub tz: Tokenizer<'a>,
-    pub cache: &'a mut StringCache,
-    pub ds: &'a mut Diagnostics,
+    tz: Tokenizer<'a>,
+    ds: &'a mut Diagnostics,
+    /// Records syntax nodes for the concrete syntax tree.
+    nodes: Recorder,
 }
 
 impl<'a> Parser<'a> {
+    /// Creates a parser reading from a tokenizer and reporting to the given diagnostics.
+    pub fn new(tz: Tokenizer<'a>, ds: &'a mut Diagnostics) -> Self {
+        Parser {
+            tz,
+            ds,
+            nodes: Recorder::default(),
+        }
+    }
+
+    /// Finishes parsing, returning the syntax nodes that were recognized.
+    pub fn into_nodes(self) -> Recorder {
+        self.nodes
+    }
+
     /// Advances to the next token and asserts its kind.
     fn expect(&mut self, kind: TokenKind) -> Spanned<Option<TokenKind>> {
         self.expect_one_of(&[kind])
@@ -54,18 +71,26 @@ impl<'a> Parser<'a> {
         }
     }
 
+    /// Marks the start of a syntax node. Pass the result to [`Parser::finish_node`] when the node is complete.
+    fn start_node(&self) -> usize {
+        self.tz.consumed()
+    }
+
+    /// Records a syntax node covering the tokens since the given start.
+    fn finish_node(&mut self, start: usize, kind: SyntaxKind) {
+        self.nodes.record(start, self.tz.consumed(), kind);
+    }
+
     /// Advances the internal tokenizer, ignoring the next token.
     fn advance(&mut self) {
         self.tz.next();
     }
 
     /// Parse a single file. Might change later.
-    pub fn file(&mut self) -> Vec<Item> {
-        let mut items = Vec::new();
+    pub fn file(&mut self) {
         while *self.tz.peek() != TokenKind::Eof {
-            items.push(self.item());
+            self.item();
         }
-        items
     }
 }
 
@@ -84,9 +109,7 @@ pub(crate) use declare_idents;
 #[cfg(test)]
 mod tests {
     use crate::{
-        cache::{StringCache, StringKey},
-        diagnostic::Diagnostics,
-        token::TokenKind,
+        cache::StringKey, cst::Recorder, diagnostic::Diagnostics, token::TokenKind,
         tokenizer::Tokenizer,
     };
 
@@ -94,16 +117,20 @@ mod tests {
 
     pub fn smoke_template<R>(inputs: &[&str], mut parse_fn: impl FnMut(&mut Parser<'_>) -> R) {
         for input in inputs {
-            let mut cache = StringCache::new();
             let mut ds = Diagnostics::new();
             let tz = Tokenizer::from_parts(StringKey::EMPTY, input);
             let mut parser = Parser {
                 tz,
-                cache: &mut cache,
                 ds: &mut ds,
+                nodes: Recorder::default(),
             };
             let _ = parse_fn(&mut parser);
-            assert_eq!(TokenKind::Eof, *parser.tz.next(), "Failed to parse the entire input: {}", input);
+            assert_eq!(
+                TokenKind::Eof,
+                *parser.tz.next(),
+                "Failed to parse the entire input: {}",
+                input
+            );
             assert!(!parser.ds.has_errors(), "At input: {}", input);
         }
     }
@@ -35,7 +35,7 @@ pub fn comma_sequence<'a, 'b, T>(
                 break;
             }
             values.push(f(this));
-            if this.consume(TokenKind::Comma).is_none(){
+            if this.consume(TokenKind::Comma).is_none() {
                 break;
             }
         }
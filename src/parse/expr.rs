@@ -1,12 +1,22 @@
 use crate::{
-    ast::{Conditional, Expr, TypedIdent},
+    ast::Operator,
+    cst::SyntaxKind,
     diagnostic::Code,
-    span::Spanned,
     token::{Token, TokenKind},
 };
 
 use super::{combinators, Parser};
 
+/// What the parser needs to know about an expression it parsed to parse what follows.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Shape {
+    /// A binary expression, which can't take a block argument.
+    Binary,
+    /// An expression ending in a block, which doesn't need a semicolon to end a statement.
+    Block,
+    Other,
+}
+
 impl<'a> Parser<'a> {
     /// Parses an expression that can be the operand of a binary expression.
     /// This includes:
@@ -14,125 +24,117 @@ impl<'a> Parser<'a> {
     /// - integer literals: 0xFF
     /// - keyword literals
     /// - parenthesized expressions: ( blockbased )
-    pub fn unary_expr(&mut self) -> Expr {
+    pub fn unary_expr(&mut self) -> Shape {
+        let start = self.start_node();
         let token = self.tz.peek();
-        match *token {
+        let kind = match *token {
             TokenKind::RoundL => {
                 // parenthesized expression: (expr)
                 self.advance();
-                let expr = self.block_expr();
+                let shape = self.block_expr();
                 self.expect(TokenKind::RoundR);
-                expr
+                self.finish_node(start, SyntaxKind::ParenExpr);
+                return shape;
             }
             TokenKind::Return => {
                 self.advance();
-                Expr::Return
+                SyntaxKind::ReturnExpr
             }
             TokenKind::CC => {
                 self.advance();
-                Expr::Continue
+                SyntaxKind::ContinueExpr
             }
             TokenKind::Ident => {
                 // qualified identifier: ident::ident
-                let (_, qid) = self.qualified_ident().into_span_value();
-                Expr::Ident(qid)
+                self.qualified_ident();
+                SyntaxKind::PathExpr
             }
             TokenKind::Number | TokenKind::BasePrefixNumber => {
                 // integer literal
-                let (_, int) = self.integer().into_span_value();
-                Expr::Int(int)
+                self.advance();
+                SyntaxKind::IntExpr
+            }
+            TokenKind::String => {
+                self.advance();
+                SyntaxKind::StringExpr
             }
             _ => {
                 self.advance();
-                let err_span = Token::span(&token);
-                self.ds.add(Code::Unexpected, err_span, *token);
-                Expr::Error { err_span }
+                self.ds.add(Code::Unexpected, Token::span(&token), *token);
+                SyntaxKind::ErrorExpr
             }
-        }
+        };
+        self.finish_node(start, kind);
+        Shape::Other
     }
 
     /// Parses a free binary expression. Free binary operators include:
     /// - member access: unary . ident
     /// - function call: unary ( args )
     /// - any unary expression: unary
-    pub fn free_binary_expr(&mut self) -> Expr {
-        let mut expr = self.unary_expr();
+    pub fn free_binary_expr(&mut self) -> Shape {
+        let start = self.start_node();
+        let mut shape = self.unary_expr();
         while let (_, Some(op_token)) = self
             .consume_one_of(&[TokenKind::RoundL, TokenKind::Dot])
             .into_span_value()
         {
-            match op_token {
+            let kind = match op_token {
                 TokenKind::Dot => {
-                    let (_, rhs) = self.ident().into_span_value();
-                    expr = Expr::Member {
-                        recv: Box::new(expr),
-                        member: rhs,
-                    }
+                    self.ident();
+                    SyntaxKind::MemberExpr
                 }
                 TokenKind::RoundL => {
                     let mut arguments_parser =
                         combinators::comma_sequence(Self::block_expr, &[TokenKind::RoundR]);
-                    let args = arguments_parser(self);
+                    arguments_parser(self);
                     self.expect(TokenKind::RoundR);
-                    expr = Expr::Call {
-                        func: Box::new(expr),
-                        args,
-                    }
+                    SyntaxKind::CallExpr
                 }
                 kind => unreachable!("Unknown free operator token {kind:?}"),
-            }
+            };
+            self.finish_node(start, kind);
+            shape = Shape::Other;
         }
-        expr
+        shape
     }
 
     /// Parses a binary expression: a sequence of free binary expressions separated by the same operator.
-    pub fn binary_expr(&mut self) -> Expr {
-        let expr = self.free_binary_expr();
+    pub fn binary_expr(&mut self) -> Shape {
+        let start = self.start_node();
+        let shape = self.free_binary_expr();
         let op_token = *self.tz.peek();
-        let Ok(op) = op_token.try_into() else {
+        if Operator::try_from(op_token).is_err() {
             // no binary operator
-            return expr;
-        };
+            return shape;
+        }
         self.advance();
-        let rhs = self.free_binary_expr();
-        let mut operands = vec![expr, rhs];
+        self.free_binary_expr();
         while self.consume(op_token).is_some() {
-            operands.push(self.free_binary_expr());
-        }
-        Expr::Binary {
-            op,
-            operands,
+            self.free_binary_expr();
         }
+        self.finish_node(start, SyntaxKind::BinaryExpr);
+        Shape::Binary
     }
 
     /// Parses a name and type pair.
-    pub fn name_and_type(&mut self) -> Spanned<Option<TypedIdent>> {
-        let (name_span, name) = self.ident().into_span_value();
-        if self.expect(TokenKind::Colon).is_none() {
-            return Spanned::from_span_value(name_span, None);
+    pub fn name_and_type(&mut self) {
+        let start = self.start_node();
+        self.ident();
+        if self.expect(TokenKind::Colon).is_some() {
+            self.ty();
         }
-        let ty = self.ty();
-        let typed_ident = TypedIdent { name, ty };
-        Spanned::from_span_value(name_span, Some(typed_ident))
+        self.finish_node(start, SyntaxKind::Param);
     }
 
     /// A closure body: args -> stmts.
-    pub fn closure_body(&mut self) -> Expr {
-        let params = if *self.tz.peek2() == TokenKind::Colon {
+    pub fn closure_body(&mut self) {
+        if *self.tz.peek2() == TokenKind::Colon {
             // parameters exist
-            let params =
-                combinators::comma_sequence(Self::name_and_type, &[TokenKind::Arrow])(self)
-                    .into_iter()
-                    .map(|v| v.into_span_value().1)
-                    .collect::<Option<Vec<_>>>()
-                    .unwrap_or_default();
+            combinators::comma_sequence(Self::name_and_type, &[TokenKind::Arrow])(self);
             self.expect(TokenKind::Arrow);
-            params
-        } else {
-            Vec::new()
-        };
-        let stmts = self.block_stmts();
-        Expr::Closure { params, stmts }
+        }
+        self.block_stmts();
     }
 
     /// Block-based expressions include:
@@ -142,99 +144,85 @@ impl<'a> Parser<'a> {
     /// - block-based function call: unary { args -> block }
     /// - handle expression: handle effect, ..., effect { function-or-finally }
     /// - any binary expression: binary
-    pub fn block_expr(&mut self) -> Expr {
+    pub fn block_expr(&mut self) -> Shape {
+        let start = self.start_node();
         let head_tkn = self.tz.peek();
-        match *head_tkn {
+        let (kind, shape) = match *head_tkn {
             TokenKind::If => {
                 // if-then or if-then-else
                 self.advance();
-                let mut cases = Vec::new();
                 loop {
-                    let condition = self.binary_expr();
+                    self.binary_expr();
                     self.expect(TokenKind::CurlyL);
-                    let then_body = self.block_stmts();
+                    self.block_stmts();
                     self.expect(TokenKind::CurlyR);
-                    cases.push(Conditional {
-                        condition,
-                        then_body,
-                    });
                     if self.consume(TokenKind::Else).is_none() {
                         // no else block
-                        break Expr::Conditional {
-                            cases,
-                            final_else: Vec::new(),
-                        };
+                        break;
                     }
                     if self.consume(TokenKind::If).is_none() {
                         // final else block
                         self.expect(TokenKind::CurlyL);
-                        let final_else = self.block_stmts();
+                        self.block_stmts();
                         self.expect(TokenKind::CurlyR);
-                        break Expr::Conditional { cases, final_else };
+                        break;
                     }
                     // otherwise, continue with the next case
                 }
+                (SyntaxKind::ConditionalExpr, Shape::Block)
             }
             TokenKind::Do => {
                 // do expression (immediately invoked nullary closure)
                 // or do-with expression (bind effect handler)
                 self.advance();
                 self.expect(TokenKind::CurlyL);
-                let stmts = self.block_stmts();
+                self.block_stmts();
                 self.expect(TokenKind::CurlyR);
                 if self.consume(TokenKind::With).is_none() {
                     // do-expression
-                    return Expr::Do { stmts };
-                }
-                // do-with expression
-                let handler = self.block_expr();
-                Expr::DoWith {
-                    stmts,
-                    handler: Box::new(handler),
+                    (SyntaxKind::DoExpr, Shape::Block)
+                } else {
+                    // do-with expression, which ends in a block if its handler does
+                    let shape = match self.block_expr() {
+                        Shape::Block => Shape::Block,
+                        Shape::Binary | Shape::Other => Shape::Other,
+                    };
+                    (SyntaxKind::DoWithExpr, shape)
                 }
             }
             TokenKind::CurlyL => {
                 // closure
                 self.advance();
-                let closure = self.closure_body();
+                self.closure_body();
                 self.expect(TokenKind::CurlyR);
-                closure
+                (SyntaxKind::ClosureExpr, Shape::Block)
             }
             TokenKind::Handle => {
                 // handler
                 self.advance();
-                let impl_effects =
-                    combinators::comma_sequence(Self::effect, &[TokenKind::CurlyL])(self);
+                combinators::comma_sequence(Self::effect, &[TokenKind::CurlyL])(self);
                 self.expect(TokenKind::CurlyL);
-                let items = combinators::many(Self::item, &[TokenKind::CurlyR])(self);
+                combinators::many(Self::item, &[TokenKind::CurlyR])(self);
                 self.expect(TokenKind::CurlyR);
-                Expr::Handler {
-                    impl_effects,
-                    items,
-                }
+                (SyntaxKind::HandlerExpr, Shape::Block)
             }
             _ => {
                 // block function call or fallthrough
-                let expr = self.binary_expr();
-                if !matches!(expr, Expr::Binary { .. }) && self.consume(TokenKind::CurlyL).is_some()
-                {
-                    let block_arg = self.closure_body();
+                let shape = self.binary_expr();
+                let block_start = self.start_node();
+                if shape != Shape::Binary && self.consume(TokenKind::CurlyL).is_some() {
+                    self.closure_body();
                     self.expect(TokenKind::CurlyR);
-                    // determine whether we can add this argument to an existing function call
-                    if let Expr::Call { func, mut args } = expr {
-                        args.push(block_arg);
-                        Expr::BlockCall { func, args }
-                    } else {
-                        Expr::BlockCall {
-                            func: Box::new(expr),
-                            args: vec![block_arg],
-                        }
-                    }
+                    self.finish_node(block_start, SyntaxKind::ClosureExpr);
+                    (SyntaxKind::BlockCallExpr, Shape::Block)
                 } else {
-                    expr
+                    // the node was already recorded by the binary expression
+                    return shape;
                 }
             }
-        }
+        };
+        self.finish_node(start, kind);
+        shape
     }
 }
 
@@ -0,0 +1,438 @@
+//! Incremental reparsing.
+//!
+//! A file is kept as a list of top-level items, each with its source span and the diagnostics
+//! produced while parsing it. When an edit falls strictly inside a single item, only that item is
+//! reparsed, and the items after it are reused with their spans shifted.
+
+use std::ops::Range;
+
+use crate::{
+    ast::{Conditional, Effect, Expr, Function, FunctionHeader, Item, Statement, Type, TypedIdent},
+    cache::{StringCache, StringKey},
+    cst::{self, lower::Lowerer},
+    diagnostic::Diagnostics,
+    span::{Span, Spanned},
+    token::TokenKind,
+};
+
+/// A parsed top-level item and its diagnostics.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ParsedItem {
+    pub item: Spanned<Item>,
+    pub ds: Diagnostics,
+}
+
+/// A parsed file that can be edited incrementally.
+#[derive(Clone, Debug)]
+pub struct ParsedFile {
+    file: StringKey,
+    src: String,
+    items: Vec<ParsedItem>,
+}
+
+impl ParsedFile {
+    /// Parses a whole file.
+    pub fn parse(file: StringKey, src: String, cache: &mut StringCache) -> Self {
+        let items = parse_items(file, &src, 0..src.len(), cache);
+        Self { file, src, items }
+    }
+
+    pub fn src(&self) -> &str {
+        &self.src
+    }
+
+    pub fn items(&self) -> &[ParsedItem] {
+        &self.items
+    }
+
+    /// Collects the diagnostics of all items.
+    pub fn diagnostics(&self) -> Diagnostics {
+        let mut ds = Diagnostics::new();
+        for item in &self.items {
+            ds.combine(item.ds.clone());
+        }
+        ds
+    }
+
+    /// Replaces the source in the given range with new text. Returns `true` if only a single item was
+    /// reparsed, or `false` if the whole file was reparsed.
+    ///
+    /// # Panics
+    /// Panics if the range isn't a range of the source on character boundaries.
+    pub fn edit(&mut self, range: Range<usize>, text: &str, cache: &mut StringCache) -> bool {
+        self.src.replace_range(range.clone(), text);
+        let delta = text.len() as isize - range.len() as isize;
+        let idx = self.items.iter().position(|parsed| {
+            let span = Spanned::span(&parsed.item);
+            span.pos < range.start && range.end < span.pos + span.len
+        });
+        if let Some(idx) = idx {
+            let old = Spanned::span(&self.items[idx].item);
+            let region = old.pos..(old.pos + old.len).wrapping_add_signed(delta);
+            if let Some(parsed) = reparse_item(self.file, &self.src, region, cache) {
+                self.items[idx] = parsed;
+                for parsed in &mut self.items[idx + 1..] {
+                    parsed.item.shift(delta);
+                    for d in parsed.ds.iter_mut() {
+                        d.span.shift(delta);
+                    }
+                }
+                return true;
+            }
+        }
+        self.items = parse_items(self.file, &self.src, 0..self.src.len(), cache);
+        false
+    }
+}
+
+/// Parses all items in a range of source.
+fn parse_items(
+    file: StringKey,
+    src: &str,
+    range: Range<usize>,
+    cache: &mut StringCache,
+) -> Vec<ParsedItem> {
+    let mut ds = Diagnostics::new();
+    let mut item_ds = Vec::new();
+    let root = cst::parse_range(file, src, range, &mut ds, |parser| {
+        while *parser.tz.peek() != TokenKind::Eof {
+            parser.item();
+            item_ds.push(parser.ds.split_off(0));
+        }
+    });
+    root.child_nodes()
+        .into_iter()
+        .zip(item_ds)
+        .map(|(node, mut ds)| {
+            let item = Lowerer::new(&root, cache, &mut ds).item(&node);
+            ParsedItem { item, ds }
+        })
+        .collect()
+}
+
+/// Reparses the single item covering the given range. Returns `None` if the range no longer parses as
+/// exactly one well-formed item, in which case a full reparse is needed.
+fn reparse_item(
+    file: StringKey,
+    src: &str,
+    range: Range<usize>,
+    cache: &mut StringCache,
+) -> Option<ParsedItem> {
+    // items that don't end in a delimiter may merge with the next token
+    let last = src[range.clone()].trim_end().chars().last()?;
+    if !matches!(last, '}' | ';') {
+        return None;
+    }
+    let mut items = parse_items(file, src, range.clone(), cache);
+    if items.len() != 1 {
+        return None;
+    }
+    let parsed = items.pop()?;
+    let span = Spanned::span(&parsed.item);
+    // an item with errors could have recovered differently given the rest of the file
+    let full_range = span.pos == range.start && span.pos + span.len == range.end;
+    (full_range && !parsed.ds.has_errors()).then_some(parsed)
+}
+
+/// Moves the source spans contained in a syntax tree.
+trait Shift {
+    fn shift(&mut self, delta: isize);
+}
+
+impl Shift for Span {
+    fn shift(&mut self, delta: isize) {
+        self.pos = self.pos.wrapping_add_signed(delta);
+    }
+}
+
+impl<T: Shift> Shift for Spanned<T> {
+    fn shift(&mut self, delta: isize) {
+        Spanned::span_mut(self).shift(delta);
+        (**self).shift(delta);
+    }
+}
+
+impl<T: Shift> Shift for [T] {
+    fn shift(&mut self, delta: isize) {
+        for v in self {
+            v.shift(delta);
+        }
+    }
+}
+
+impl<T: Shift> Shift for Vec<T> {
+    fn shift(&mut self, delta: isize) {
+        self.as_mut_slice().shift(delta);
+    }
+}
+
+impl<T: Shift> Shift for Option<T> {
+    fn shift(&mut self, delta: isize) {
+        if let Some(v) = self {
+            v.shift(delta);
+        }
+    }
+}
+
+/// Leaves with no spans inside them.
+macro_rules! no_spans {
+    ($($t:ty),*) => {
+        $(impl Shift for $t {
+            fn shift(&mut self, _: isize) {}
+        })*
+    };
+}
+
+no_spans!(crate::ast::Ident, crate::ast::QualifiedIdent);
+
+impl Shift for Type {
+    fn shift(&mut self, delta: isize) {
+        match self {
+            Type::Simple { args, .. } => args.shift(delta),
+            Type::Continuation { args, ret, effects } => {
+                args.shift(delta);
+                ret.shift(delta);
+                effects.shift(delta);
+            }
+            Type::Closure { ret, effects } => {
+                ret.shift(delta);
+                effects.shift(delta);
+            }
+        }
+    }
+}
+
+impl Shift for Effect {
+    fn shift(&mut self, delta: isize) {
+        self.args.shift(delta);
+        self.meta_effects.shift(delta);
+    }
+}
+
+impl Shift for TypedIdent {
+    fn shift(&mut self, delta: isize) {
+        self.name.shift(delta);
+        self.ty.shift(delta);
+    }
+}
+
+impl Shift for FunctionHeader {
+    fn shift(&mut self, delta: isize) {
+        self.name.shift(delta);
+        self.type_params.shift(delta);
+        self.effect_params.shift(delta);
+        self.params.shift(delta);
+        self.effects.shift(delta);
+        self.ret.shift(delta);
+    }
+}
+
+impl Shift for Function {
+    fn shift(&mut self, delta: isize) {
+        self.header.shift(delta);
+        self.body.shift(delta);
+    }
+}
+
+impl Shift for Item {
+    fn shift(&mut self, delta: isize) {
+        match self {
+            Item::Function(function) => function.shift(delta),
+            Item::AbstractFunction(header) => header.shift(delta),
+            Item::Finally { stmts } => stmts.shift(delta),
+            Item::Return { params, ret, body } => {
+                params.shift(delta);
+                ret.shift(delta);
+                body.shift(delta);
+            }
+            Item::Effect {
+                name,
+                type_params,
+                effect_params,
+                body,
+            } => {
+                name.shift(delta);
+                type_params.shift(delta);
+                effect_params.shift(delta);
+                body.shift(delta);
+            }
+            Item::Import { module } => module.shift(delta),
+            Item::Error { err_span } => err_span.shift(delta),
+        }
+    }
+}
+
+impl Shift for Statement {
+    fn shift(&mut self, delta: isize) {
+        match self {
+            Statement::Expr(expr) | Statement::BlockExpr(expr) | Statement::BlockEndExpr(expr) => {
+                expr.shift(delta)
+            }
+            Statement::Let { bindings, init } => {
+                bindings.shift(delta);
+                init.shift(delta);
+            }
+            Statement::Continue { cont, args } => {
+                cont.shift(delta);
+                args.shift(delta);
+            }
+        }
+    }
+}
+
+impl Shift for Conditional {
+    fn shift(&mut self, delta: isize) {
+        self.condition.shift(delta);
+        self.then_body.shift(delta);
+    }
+}
+
+impl Shift for Expr {
+    fn shift(&mut self, delta: isize) {
+        match self {
+            Expr::Ident(path) => path.shift(delta),
+            Expr::Int(_) | Expr::String(_) | Expr::Return | Expr::Continue => {}
+            Expr::Binary { operands, .. } => operands.shift(delta),
+            Expr::Member { recv, member } => {
+                recv.shift(delta);
+                member.shift(delta);
+            }
+            Expr::Call { func, args } | Expr::BlockCall { func, args } => {
+                func.shift(delta);
+                args.shift(delta);
+            }
+            Expr::Closure { params, stmts } => {
+                params.shift(delta);
+                stmts.shift(delta);
+            }
+            Expr::Conditional { cases, final_else } => {
+                cases.shift(delta);
+                final_else.shift(delta);
+            }
+            Expr::Handler {
+                impl_effects,
+                items,
+            } => {
+                impl_effects.shift(delta);
+                items.shift(delta);
+            }
+            Expr::Do { stmts } => stmts.shift(delta),
+            Expr::DoWith {
+                stmts,
+                with_span,
+                handler,
+            } => {
+                stmts.shift(delta);
+                with_span.shift(delta);
+                handler.shift(delta);
+            }
+            Expr::Error { err_span } => err_span.shift(delta),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SRC: &str = r#"
+effect break[R] {
+    fn break(v: R) ->;
+}
+
+fn infinite(code: {}) -> = {
+    code();
+    :infinite code;
+}
+
+fn loop[R](code: {}/break[R]) -> R = {
+    let ret: (R) -> = return;
+    do {
+        :infinite code;
+    } with handle break[R] {
+        fn break(v: R) -> = {
+            :ret v;
+        }
+    }
+}
+
+fn add(x: Int, y: Int) -> Int = {
+    x + y
+}
+"#;
+
+    const SNIPPETS: &[&str] = &[
+        "x",
+        "1 + ",
+        ";",
+        "}",
+        "{",
+        " ",
+        "foo(",
+        ")",
+        "0xZ",
+        "let y: Int = 2;",
+        "",
+    ];
+
+    #[test]
+    fn matches_full_reparse() {
+        let mut cache = StringCache::new();
+        let file = cache.intern("test.ku");
+        let mut parsed = ParsedFile::parse(file, SRC.to_owned(), &mut cache);
+        let mut incremental = 0;
+        // simple linear congruential generator for reproducible edits
+        let mut seed = 0x2545f491u64;
+        let mut rand = |n: usize| {
+            seed = seed
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (seed >> 33) as usize % n
+        };
+        for _ in 0..500 {
+            let len = parsed.src().len();
+            let start = rand(len + 1);
+            let end = (start + rand(4)).min(len);
+            if !parsed.src().is_char_boundary(start) || !parsed.src().is_char_boundary(end) {
+                continue;
+            }
+            let text = SNIPPETS[rand(SNIPPETS.len())];
+            if parsed.edit(start..end, text, &mut cache) {
+                incremental += 1;
+            }
+
+            let full = ParsedFile::parse(file, parsed.src().to_owned(), &mut cache);
+            assert_eq!(
+                full.items(),
+                parsed.items(),
+                "after editing {start}..{end} to {text:?}"
+            );
+            assert_eq!(full.diagnostics(), parsed.diagnostics());
+
+            // keep the source from degrading too far
+            if full.diagnostics().has_errors() && rand(4) == 0 {
+                parsed = ParsedFile::parse(file, SRC.to_owned(), &mut cache);
+            }
+        }
+        assert!(
+            incremental > 50,
+            "only {incremental} edits were incremental"
+        );
+    }
+
+    #[test]
+    fn reuses_items() {
+        let mut cache = StringCache::new();
+        let file = cache.intern("test.ku");
+        let mut parsed = ParsedFile::parse(file, SRC.to_owned(), &mut cache);
+        let before = parsed.items().to_vec();
+        let pos = SRC.find("x + y").unwrap();
+        assert!(parsed.edit(pos..pos + 1, "(x * 2)", &mut cache));
+        assert_eq!(before[..3], parsed.items()[..3]);
+        assert!(!parsed.diagnostics().has_errors());
+
+        // an edit between items reparses everything
+        let pos = SRC.find("\nfn add").unwrap();
+        assert!(!parsed.edit(pos..pos, "\n", &mut cache));
+    }
+}
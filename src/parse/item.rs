@@ -1,125 +1,106 @@
-use crate::{
-    ast::{Function, FunctionHeader, Ident, Item},
-    parse::combinators,
-    token::{Token, TokenKind},
-};
+use crate::{cst::SyntaxKind, parse::combinators, token::TokenKind};
 
 use super::Parser;
 
 impl Parser<'_> {
     /// A top-level or nested item.
-    pub fn item(&mut self) -> Item {
+    pub fn item(&mut self) {
+        let start = self.start_node();
         let head_tkn = self.tz.peek();
-        match *head_tkn {
+        let kind = match *head_tkn {
             TokenKind::Fn => self.function(),
             TokenKind::Finally => {
                 self.advance();
                 self.expect(TokenKind::CurlyL);
-                let stmts = self.block_stmts();
+                self.block_stmts();
                 self.expect(TokenKind::CurlyR);
-                Item::Finally { stmts }
+                SyntaxKind::FinallyItem
+            }
+            TokenKind::Return => {
+                // return clause: return ( nameandtype , ... ) -> type = { block }
+                self.advance();
+                self.expect(TokenKind::RoundL);
+                combinators::comma_sequence(Self::name_and_type, &[TokenKind::RoundR])(self);
+                self.expect(TokenKind::RoundR);
+                self.expect(TokenKind::Arrow);
+                self.fn_return_sequence();
+                self.expect(TokenKind::Equals);
+                self.expect(TokenKind::CurlyL);
+                self.block_stmts();
+                self.expect(TokenKind::CurlyR);
+                SyntaxKind::ReturnItem
             }
             TokenKind::Effect => {
                 self.advance();
-                let (_, name) = self.ident().into_span_value();
-                let (type_params, effect_params) = self.generic_params();
+                self.ident();
+                self.generic_params();
                 self.expect(TokenKind::CurlyL);
-                let body = combinators::many(Self::item, &[TokenKind::CurlyR])(self);
+                combinators::many(Self::item, &[TokenKind::CurlyR])(self);
                 self.expect(TokenKind::CurlyR);
-                Item::Effect {
-                    name,
-                    type_params,
-                    effect_params,
-                    body,
-                }
+                SyntaxKind::EffectItem
             }
             TokenKind::Import => {
                 self.advance();
-                let (_, module) = self.qualified_ident().into_span_value();
+                self.qualified_ident();
                 self.expect(TokenKind::Semi);
-                Item::Import { module }
+                SyntaxKind::ImportItem
             }
             _ => {
                 self.advance();
-                Item::Error {
-                    err_span: Token::span(&head_tkn),
-                }
+                SyntaxKind::ErrorItem
             }
-        }
+        };
+        self.finish_node(start, kind);
     }
 
     /// Parses a function header. A function header must end in either ; or {
     /// fn name [ ident, ..., ident | ident, ..., ident ] ( nameandtype , ... , nameandtype ) / effect, ..., effect -> type
-    pub fn function_header(&mut self) -> FunctionHeader {
+    pub fn function_header(&mut self) {
+        let start = self.start_node();
         self.expect(TokenKind::Fn);
-        let (_, name) = self.ident().into_span_value();
-        let (type_params, effect_params) = self.generic_params();
+        self.ident();
+        self.generic_params();
 
         // parameters
         self.expect(TokenKind::RoundL);
-        let params = combinators::comma_sequence(Self::name_and_type, &[TokenKind::RoundR])(self)
-            .into_iter()
-            .map(|v| v.into_span_value().1)
-            .collect::<Option<Vec<_>>>()
-            .unwrap_or_default();
+        combinators::comma_sequence(Self::name_and_type, &[TokenKind::RoundR])(self);
         self.expect(TokenKind::RoundR);
 
         // effects
-        let effects = if self.consume(TokenKind::Slash).is_some() {
-            combinators::comma_sequence(Self::effect, &[TokenKind::Arrow])(self)
-        } else {
-            Vec::new()
-        };
+        if self.consume(TokenKind::Slash).is_some() {
+            combinators::comma_sequence(Self::effect, &[TokenKind::Arrow])(self);
+        }
 
         self.expect(TokenKind::Arrow);
-        let ret = self.fn_return_sequence();
-
-        FunctionHeader {
-            name,
-            type_params,
-            effect_params,
-            params,
-            effects,
-            ret,
-        }
+        self.fn_return_sequence();
+        self.finish_node(start, SyntaxKind::FunctionHeader);
     }
 
-    /// Parses a function - a function header followed by either ; or = { block }.
-    pub fn function(&mut self) -> Item {
-        let header = self.function_header();
+    /// Parses a function - a function header followed by either ; or = { block }. Returns the kind of
+    /// item it is.
+    pub fn function(&mut self) -> SyntaxKind {
+        self.function_header();
         if self.consume(TokenKind::Semi).is_some() {
-            Item::AbstractFunction(header)
+            SyntaxKind::AbstractFunctionItem
         } else {
             self.expect(TokenKind::Equals);
             self.expect(TokenKind::CurlyL);
-            let body = self.block_stmts();
+            self.block_stmts();
             self.expect(TokenKind::CurlyR);
-            Item::Function(Function { header, body })
+            SyntaxKind::FunctionItem
         }
     }
 
     /// Parses a list of type and effect parameters, including the delimiters.
-    fn generic_params(&mut self) -> (Vec<Ident>, Vec<Ident>) {
+    fn generic_params(&mut self) {
+        let start = self.start_node();
         if self.consume(TokenKind::SquareL).is_some() {
-            let type_params = combinators::comma_sequence(
-                Self::ident,
-                &[TokenKind::SquareR, TokenKind::Pipe],
-            )(self)
-            .into_iter()
-            .map(|v| v.into_span_value().1)
-            .collect::<Vec<_>>();
-            let effect_params = if self.consume(TokenKind::Pipe).is_some() {
-                combinators::comma_sequence(Self::ident, &[TokenKind::SquareR])(self)
-                    .into_iter()
-                    .map(|v| v.into_span_value().1)
-                    .collect::<Vec<_>>()
-            } else {
-                Vec::new()
-            };
+            combinators::comma_sequence(Self::ident, &[TokenKind::SquareR, TokenKind::Pipe])(self);
+            if self.consume(TokenKind::Pipe).is_some() {
+                combinators::comma_sequence(Self::ident, &[TokenKind::SquareR])(self);
+            }
             self.expect(TokenKind::SquareR);
-            (type_params, effect_params)
-        } else {
-            (Vec::new(), Vec::new())
+            self.finish_node(start, SyntaxKind::GenericParams);
         }
     }
 }
@@ -132,6 +113,8 @@ mod tests {
     fn valid_items_smoke() {
         let inputs = [
             "finally {}",
+            "return(x: T) -> T = { x }",
+            "return(x: T, y: U) -> (U, T) = {}",
             "fn foo() ->;",
             "fn foo() -> ();",
             "fn foo() -> () ->;",
@@ -1,6 +1,6 @@
-use crate::{ast::Statement, token::TokenKind, parse::combinators};
+use crate::{cst::SyntaxKind, parse::combinators, token::TokenKind};
 
-use super::Parser;
+use super::{expr::Shape, Parser};
 
 impl Parser<'_> {
     /// Parses a statement. Statements include:
@@ -8,47 +8,43 @@ impl Parser<'_> {
     /// - block expression: blockexpr-end-with-{}
     /// - declaration: let ident: type, ..., ident: type = blockexpr ;
     /// - continuation: : freebinary binary , ... , binary ;
-    pub fn stmt(&mut self) -> Statement {
+    pub fn stmt(&mut self) {
+        let start = self.start_node();
         let head_tkn = self.tz.peek();
-        match *head_tkn {
+        let kind = match *head_tkn {
             TokenKind::Colon => {
                 // continuation statement
                 self.advance();
-                let mut cont_args = combinators::comma_sequence(Self::binary_expr, &[TokenKind::Semi]);
-                let cont = self.free_binary_expr();
-                let args = cont_args(self);
+                self.free_binary_expr();
+                combinators::comma_sequence(Self::binary_expr, &[TokenKind::Semi])(self);
                 self.expect(TokenKind::Semi);
-                Statement::Continue { cont, args }
+                SyntaxKind::ContinueStmt
             }
             TokenKind::Let => {
                 // let statement
                 self.advance();
-                let bindings = combinators::comma_sequence(Self::name_and_type, &[TokenKind::Equals])(self)
-                    .into_iter()
-                    .map(|v| v.into_span_value().1)
-                    .collect::<Option<Vec<_>>>()
-                    .unwrap_or_default();
+                combinators::comma_sequence(Self::name_and_type, &[TokenKind::Equals])(self);
                 self.expect(TokenKind::Equals);
-                let init = self.block_expr();
+                self.block_expr();
                 self.expect(TokenKind::Semi);
-                Statement::Let { bindings, init }
+                SyntaxKind::LetStmt
             }
             _ => {
                 // expression statement
-                let expr = self.block_expr();
-                if expr.is_block_expr() {
+                if self.block_expr() == Shape::Block {
                     self.consume(TokenKind::Semi);
-                    Statement::BlockExpr(expr)
+                    SyntaxKind::BlockExprStmt
                 } else if self.consume(TokenKind::Semi).is_some() {
-                    Statement::Expr(expr)
+                    SyntaxKind::ExprStmt
                 } else {
-                    Statement::BlockEndExpr(expr)
+                    SyntaxKind::BlockEndExprStmt
                 }
             }
-        }
+        };
+        self.finish_node(start, kind);
     }
 
-    pub fn block_stmts(&mut self) -> Vec<Statement> {
-        combinators::many(Parser::stmt, &[TokenKind::CurlyR])(self)
+    pub fn block_stmts(&mut self) {
+        combinators::many(Parser::stmt, &[TokenKind::CurlyR])(self);
     }
-}
\ No newline at end of file
+}
@@ -1,8 +1,4 @@
-use crate::{
-    ast::{Effect, Type},
-    parse::combinators,
-    token::TokenKind,
-};
+use crate::{cst::SyntaxKind, parse::combinators, token::TokenKind};
 
 use super::Parser;
 
@@ -11,137 +7,112 @@ impl Parser<'_> {
     /// Simple: qualident [ type, ..., type ]
     /// Continuation: ( type, ..., type ) / effect, effect -> ... / effect, effect -> ( type, ..., type )
     /// Closure: { type, ..., type } / effect, effect
-    pub fn ty(&mut self) -> Type {
+    pub fn ty(&mut self) {
+        let start = self.start_node();
         let head_tkn = self.tz.peek();
-        match *head_tkn {
+        let kind = match *head_tkn {
             TokenKind::CurlyL => {
                 // closure type
                 self.advance();
-                let ret = combinators::comma_sequence(Self::ty, &[TokenKind::CurlyR])(self);
+                combinators::comma_sequence(Self::ty, &[TokenKind::CurlyR])(self);
                 self.expect(TokenKind::CurlyR);
-                let mut effects = Vec::new();
                 if self.consume(TokenKind::Slash).is_some() {
-                    effects.push(self.effect());
+                    self.effect();
                     while self.consume(TokenKind::Comma).is_some() {
-                        effects.push(self.effect());
+                        self.effect();
                     }
                 }
-                Type::Closure { ret, effects }
+                SyntaxKind::ClosureType
             }
             TokenKind::RoundL => {
                 // continuation type
                 self.advance();
-                let args = combinators::comma_sequence(Self::ty, &[TokenKind::RoundR])(self);
+                combinators::comma_sequence(Self::ty, &[TokenKind::RoundR])(self);
                 self.expect(TokenKind::RoundR);
-                let effects = if self.consume(TokenKind::Slash).is_some() {
-                    combinators::comma_sequence(Self::effect, &[TokenKind::Arrow])(self)
-                } else {
-                    Vec::new()
-                };
+                if self.consume(TokenKind::Slash).is_some() {
+                    combinators::comma_sequence(Self::effect, &[TokenKind::Arrow])(self);
+                }
                 self.expect(TokenKind::Arrow);
-                let ret = self.fn_return_sequence();
-                Type::Continuation { args, ret, effects }
+                self.fn_return_sequence();
+                SyntaxKind::ContinuationType
             }
             _ => {
                 // simple type
-                let (_, ty_name) = self.qualified_ident().into_span_value();
-                let args = if self.consume(TokenKind::SquareL).is_some() {
-                    let args = combinators::comma_sequence(Self::ty, &[TokenKind::SquareR])(self);
+                self.qualified_ident();
+                if self.consume(TokenKind::SquareL).is_some() {
+                    combinators::comma_sequence(Self::ty, &[TokenKind::SquareR])(self);
                     self.expect(TokenKind::SquareR);
-                    args
-                } else {
-                    Vec::new()
-                };
-                Type::Simple {
-                    name: ty_name,
-                    args,
                 }
+                SyntaxKind::SimpleType
             }
-        }
+        };
+        self.finish_node(start, kind);
     }
 
     /// Parses the return types for a continuation type. This may be:
     /// - one type
     /// - multiple types in a parenthesized comma-separated list
     /// - no type at all
+    ///
     /// Because continuation types begin with parentheses, this parse is recursive; e.g. () -> () ->
-    pub fn fn_return_sequence(&mut self) -> Option<Vec<Type>> {
+    pub fn fn_return_sequence(&mut self) {
         let mut arg_lists = Vec::new();
-        let ret_list;
 
         loop {
             let next_tkn = *self.tz.peek();
             if matches!(next_tkn, TokenKind::Ident | TokenKind::CurlyL) {
                 // single return type, no more to parse
-                ret_list = Some(vec![self.ty()]);
+                self.ty();
                 break;
             }
 
+            let start = self.start_node();
             if self.consume(TokenKind::RoundL).is_none() {
                 // no return type, no more to parse
-                ret_list = None;
                 break;
             }
 
             // multiple return types, possibly a single continuation return type - another argument list
-            let args = combinators::comma_sequence(Self::ty, &[TokenKind::RoundR])(self);
+            combinators::comma_sequence(Self::ty, &[TokenKind::RoundR])(self);
             self.expect(TokenKind::RoundR);
             if self.consume(TokenKind::Slash).is_some() {
                 // effects - must be followed by arrow
-                let effects = combinators::comma_sequence(Self::effect, &[TokenKind::Arrow])(self);
+                combinators::comma_sequence(Self::effect, &[TokenKind::Arrow])(self);
                 self.expect(TokenKind::Arrow);
-                arg_lists.push((args, effects));
+                arg_lists.push(start);
             } else if self.consume(TokenKind::Arrow).is_some() {
                 // argument list for another continuation type
-                arg_lists.push((args, Vec::new()));
+                arg_lists.push(start);
             } else {
                 // return type list
-                ret_list = Some(args);
                 break;
             }
         }
 
-        let mut ret_list = ret_list;
-        // if we parsed multiple args lists, the return type is a single continuation type
-        while let Some((args, effects)) = arg_lists.pop() {
-            ret_list = Some(vec![Type::Continuation {
-                args,
-                ret: ret_list,
-                effects,
-            }])
+        // if we parsed multiple args lists, the return type is a single continuation type, which
+        // returns the continuation type of the next list
+        while let Some(start) = arg_lists.pop() {
+            self.finish_node(start, SyntaxKind::ContinuationType);
         }
-        ret_list
     }
 
     /// Parses an effect.
     /// effect ident [ ty, ..., ty ]
-    pub fn effect(&mut self) -> Effect {
-        let mut effect_seq = Vec::new();
+    pub fn effect(&mut self) {
+        let start = self.start_node();
         loop {
-            let (_, name) = self.qualified_ident().into_span_value();
+            self.qualified_ident();
             // generic arguments
-            let args = if self.consume(TokenKind::SquareL).is_some() {
-                let args = combinators::comma_sequence(Self::ty, &[TokenKind::SquareR])(self);
+            if self.consume(TokenKind::SquareL).is_some() {
+                combinators::comma_sequence(Self::ty, &[TokenKind::SquareR])(self);
                 self.expect(TokenKind::SquareR);
-                args
-            } else {
-                Vec::new()
-            };
-            effect_seq.push(Effect {
-                name,
-                args,
-                meta_effects: Vec::new(),
-            });
+            }
             // note: we assume that an effect must begin with an ident token
             if *self.tz.peek() != TokenKind::Ident {
                 break;
             }
         }
-        let mut base_effect = effect_seq
-            .pop()
-            .expect("Effect loop is always run at least once.");
-        base_effect.meta_effects = effect_seq;
-        base_effect
+        self.finish_node(start, SyntaxKind::Effect);
     }
 }
 
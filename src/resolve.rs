@@ -0,0 +1,458 @@
+//! Name resolution.
+//!
+//! Every named item, parameter, and `let` binding is defined in a [`SymbolTable`]. Scopes such as
+//! closures, blocks, and the statements following a `let` are anonymous symbols, so resolving a
+//! name from inside a scope finds the innermost definition.
+
+use std::collections::HashMap;
+
+use crate::{
+    ast::{Expr, FunctionHeader, Ident, Item, Statement, TypedIdent},
+    builtin::Builtin,
+    cache::{StringCache, StringKey},
+    diagnostic::{Code, Diagnostics},
+    span::{Span, Spanned},
+    symbol::{SymbolKey, SymbolTable},
+};
+
+/// The kind of thing a symbol names.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum SymbolKind {
+    Function,
+    Effect,
+    Operation,
+    Parameter,
+    Local,
+}
+
+impl SymbolKind {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Self::Function => "function",
+            Self::Effect => "effect",
+            Self::Operation => "operation",
+            Self::Parameter => "parameter",
+            Self::Local => "local",
+        }
+    }
+}
+
+/// A named definition.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Definition {
+    pub kind: SymbolKind,
+    /// The span of the defining identifier.
+    pub span: Span,
+}
+
+/// The results of resolving names in a file.
+#[derive(Clone, Debug)]
+pub struct Resolution {
+    pub table: SymbolTable,
+    definitions: HashMap<SymbolKey, Definition>,
+    /// The symbols defined by identifiers, keyed by the identifier's span.
+    defined: HashMap<Span, SymbolKey>,
+    /// The symbols referenced by identifiers, keyed by the identifier's span.
+    references: HashMap<Span, SymbolKey>,
+    /// Unqualified names of effect operations, which are usable outside of their effect.
+    operations: HashMap<StringKey, SymbolKey>,
+    /// Built-in operations. These have no definition in the source.
+    builtins: HashMap<SymbolKey, Builtin>,
+}
+
+impl Resolution {
+    /// Gets the definition of a named symbol.
+    pub fn definition(&self, key: SymbolKey) -> Option<Definition> {
+        self.definitions.get(&key).copied()
+    }
+
+    /// Gets the kind of a named symbol, including built-in operations.
+    pub fn kind(&self, key: SymbolKey) -> Option<SymbolKind> {
+        match self.definitions.get(&key) {
+            Some(def) => Some(def.kind),
+            None => self.builtins.get(&key).map(|_| SymbolKind::Operation),
+        }
+    }
+
+    /// Gets the built-in operations the source can refer to.
+    pub fn builtins(&self) -> impl Iterator<Item = (SymbolKey, Builtin)> + '_ {
+        self.builtins.iter().map(|(&key, &builtin)| (key, builtin))
+    }
+
+    /// Gets the symbol defined by the identifier with the given span.
+    pub fn defined_at(&self, span: Span) -> Option<SymbolKey> {
+        self.defined.get(&span).copied()
+    }
+
+    /// Gets the symbol referenced by the identifier with the given span.
+    pub fn reference(&self, span: Span) -> Option<SymbolKey> {
+        self.references.get(&span).copied()
+    }
+
+    /// Gets the symbol referenced or defined by the identifier at the given byte offset.
+    pub fn symbol_at(&self, pos: usize) -> Option<SymbolKey> {
+        let contains = |span: &Span| span.pos <= pos && pos < span.pos + span.len;
+        self.references
+            .iter()
+            .find(|(span, _)| contains(span))
+            .map(|(_, &key)| key)
+            .or_else(|| {
+                self.definitions
+                    .iter()
+                    .find(|(_, def)| contains(&def.span))
+                    .map(|(&key, _)| key)
+            })
+    }
+
+    /// Resolves a path from the given context. Effects resolve to their operation of the same name.
+    fn lookup(&self, path: &[StringKey], context: SymbolKey) -> Option<SymbolKey> {
+        match self.table.resolve(path, context) {
+            Some(key) => {
+                let kind = self.definitions.get(&key).map(|d| d.kind);
+                if kind == Some(SymbolKind::Effect) {
+                    let name = *path.last().expect("paths are non-empty");
+                    Some(self.table.resolve(&[name], key).unwrap_or(key))
+                } else {
+                    Some(key)
+                }
+            }
+            None if path.len() == 1 => self.operations.get(&path[0]).copied(),
+            None => None,
+        }
+    }
+}
+
+/// Resolves all names in the given items.
+pub fn resolve(items: &[Spanned<Item>], cache: &StringCache, ds: &mut Diagnostics) -> Resolution {
+    let mut resolver = Resolver {
+        cache,
+        ds,
+        res: Resolution {
+            table: SymbolTable::new(),
+            definitions: HashMap::new(),
+            defined: HashMap::new(),
+            references: HashMap::new(),
+            operations: HashMap::new(),
+            builtins: HashMap::new(),
+        },
+    };
+    let keys = items
+        .iter()
+        .map(|item| resolver.declare_item(item, SymbolKey::ROOT))
+        .collect::<Vec<_>>();
+    resolver.declare_builtins();
+    for (item, key) in items.iter().zip(keys) {
+        if let (Item::Function(function), Some(key)) = (&**item, key) {
+            resolver.params(&function.header, key);
+            resolver.stmts(&function.body, key);
+        }
+    }
+    resolver.res
+}
+
+struct Resolver<'a> {
+    cache: &'a StringCache,
+    ds: &'a mut Diagnostics,
+    res: Resolution,
+}
+
+impl Resolver<'_> {
+    /// Defines a named symbol, reporting duplicate definitions.
+    fn define(
+        &mut self,
+        name: &Spanned<Ident>,
+        kind: SymbolKind,
+        context: SymbolKey,
+    ) -> Option<SymbolKey> {
+        let Ident::Ident(name_key) = **name else {
+            return None;
+        };
+        let span = Spanned::span(name);
+        let key = self.res.table.define(name_key, context);
+        match key {
+            Some(key) => {
+                self.res.definitions.insert(key, Definition { kind, span });
+                self.res.defined.insert(span, key);
+            }
+            None => self
+                .ds
+                .add(Code::DuplicateDefinition, span, &self.cache[name_key]),
+        }
+        key
+    }
+
+    /// Defines the symbols declared by an item.
+    fn declare_item(&mut self, item: &Item, context: SymbolKey) -> Option<SymbolKey> {
+        match item {
+            Item::Function(function) => {
+                self.define(&function.header.name, SymbolKind::Function, context)
+            }
+            Item::AbstractFunction(header) => {
+                self.define(&header.name, SymbolKind::Function, context)
+            }
+            Item::Effect { name, body, .. } => {
+                let key = self.define(name, SymbolKind::Effect, context)?;
+                for item in body {
+                    let header = match &**item {
+                        Item::Function(function) => &function.header,
+                        Item::AbstractFunction(header) => header,
+                        _ => continue,
+                    };
+                    let op = self.define(&header.name, SymbolKind::Operation, key);
+                    if let (Some(op), Ident::Ident(op_name)) = (op, *header.name) {
+                        self.res.operations.entry(op_name).or_insert(op);
+                    }
+                }
+                Some(key)
+            }
+            Item::Finally { .. }
+            | Item::Return { .. }
+            | Item::Import { .. }
+            | Item::Error { .. } => None,
+        }
+    }
+
+    /// Defines the built-in operations, if the source mentions them and doesn't define their names
+    /// itself. Built-in effects whose names are taken are still usable through their operations.
+    fn declare_builtins(&mut self) {
+        for builtin in Builtin::ALL {
+            let Some(name) = self.cache.find(builtin.name()) else {
+                continue;
+            };
+            let effect = self
+                .cache
+                .find(builtin.effect())
+                .and_then(|effect| {
+                    let key = self.res.table.resolve(&[effect], SymbolKey::ROOT);
+                    let key = key.or_else(|| self.res.table.define(effect, SymbolKey::ROOT))?;
+                    // only use the effect if it's the built-in one
+                    (!self.res.definitions.contains_key(&key)).then_some(key)
+                })
+                .unwrap_or_else(|| self.res.table.define_anonymous(SymbolKey::ROOT));
+            if let Some(op) = self.res.table.define(name, effect) {
+                self.res.builtins.insert(op, builtin);
+                self.res.operations.entry(name).or_insert(op);
+            }
+        }
+    }
+
+    /// Defines function parameters.
+    fn params(&mut self, header: &FunctionHeader, context: SymbolKey) {
+        self.bindings(&header.params, SymbolKind::Parameter, context);
+    }
+
+    fn bindings(&mut self, bindings: &[Spanned<TypedIdent>], kind: SymbolKind, context: SymbolKey) {
+        for binding in bindings {
+            self.define(&binding.name, kind, context);
+        }
+    }
+
+    /// Resolves a sequence of statements in a fresh scope.
+    fn block(&mut self, stmts: &[Spanned<Statement>], context: SymbolKey) {
+        let scope = self.res.table.define_anonymous(context);
+        self.stmts(stmts, scope);
+    }
+
+    fn stmts(&mut self, stmts: &[Spanned<Statement>], mut context: SymbolKey) {
+        for stmt in stmts {
+            match &**stmt {
+                Statement::Expr(expr)
+                | Statement::BlockExpr(expr)
+                | Statement::BlockEndExpr(expr) => self.expr(expr, context),
+                Statement::Let { bindings, init } => {
+                    self.expr(init, context);
+                    // the rest of the statements are nested within the binding's scope
+                    context = self.res.table.define_anonymous(context);
+                    self.bindings(bindings, SymbolKind::Local, context);
+                }
+                Statement::Continue { cont, args } => {
+                    self.expr(cont, context);
+                    for arg in args {
+                        self.expr(arg, context);
+                    }
+                }
+            }
+        }
+    }
+
+    fn expr(&mut self, expr: &Expr, context: SymbolKey) {
+        match expr {
+            Expr::Ident(path) => {
+                let Some(keys) = path.keys() else {
+                    return;
+                };
+                let span = Spanned::span(path);
+                match self.res.lookup(&keys, context) {
+                    Some(key) => {
+                        self.res.references.insert(span, key);
+                    }
+                    None => {
+                        let name = keys
+                            .iter()
+                            .map(|&k| &self.cache[k])
+                            .collect::<Vec<_>>()
+                            .join("::");
+                        self.ds.add(Code::UnresolvedName, span, name);
+                    }
+                }
+            }
+            Expr::Int(_) | Expr::String(_) | Expr::Return | Expr::Continue | Expr::Error { .. } => {
+            }
+            Expr::Binary { operands, .. } => {
+                for operand in operands {
+                    self.expr(operand, context);
+                }
+            }
+            Expr::Member { recv, .. } => self.expr(recv, context),
+            Expr::Call { func, args } | Expr::BlockCall { func, args } => {
+                self.expr(func, context);
+                for arg in args {
+                    self.expr(arg, context);
+                }
+            }
+            Expr::Closure { params, stmts } => {
+                let scope = self.res.table.define_anonymous(context);
+                self.bindings(params, SymbolKind::Parameter, scope);
+                self.stmts(stmts, scope);
+            }
+            Expr::Conditional { cases, final_else } => {
+                for case in cases {
+                    self.expr(&case.condition, context);
+                    self.block(&case.then_body, context);
+                }
+                self.block(final_else, context);
+            }
+            Expr::Handler {
+                impl_effects,
+                items,
+            } => {
+                let effects = impl_effects
+                    .iter()
+                    .filter_map(|effect| self.res.table.resolve(&effect.name.keys()?, context))
+                    .collect::<Vec<_>>();
+                for item in items {
+                    match &**item {
+                        Item::Function(function) => {
+                            let header = &function.header;
+                            if let Ident::Ident(name) = *header.name {
+                                let op = effects.iter().find_map(|&effect| {
+                                    self.res
+                                        .table
+                                        .resolve(&[name], effect)
+                                        .filter(|&op| self.res.table.context(op) == effect)
+                                });
+                                match op {
+                                    Some(op) => {
+                                        self.res.references.insert(Spanned::span(&header.name), op);
+                                    }
+                                    None => self.ds.add(
+                                        Code::UnresolvedName,
+                                        Spanned::span(&header.name),
+                                        &self.cache[name],
+                                    ),
+                                }
+                            }
+                            let scope = self.res.table.define_anonymous(context);
+                            self.params(header, scope);
+                            self.stmts(&function.body, scope);
+                        }
+                        Item::Finally { stmts } => self.block(stmts, context),
+                        Item::Return { params, body, .. } => {
+                            let scope = self.res.table.define_anonymous(context);
+                            self.bindings(params, SymbolKind::Parameter, scope);
+                            self.stmts(body, scope);
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            Expr::Do { stmts } => self.block(stmts, context),
+            Expr::DoWith { stmts, handler, .. } => {
+                self.block(stmts, context);
+                self.expr(handler, context);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{cache::StringKey, cst};
+
+    use super::*;
+
+    fn resolve_src(src: &str) -> (Resolution, Diagnostics) {
+        let mut cache = StringCache::new();
+        let mut ds = Diagnostics::new();
+        let (items, _) = cst::parse(StringKey::EMPTY, src, &mut cache, &mut ds);
+        let res = resolve(&items, &cache, &mut ds);
+        (res, ds)
+    }
+
+    #[test]
+    fn resolves_names() {
+        let src = include_str!("../korou-examples/loop.ku");
+        let (res, ds) = resolve_src(src);
+        assert!(!ds.has_errors(), "{ds:?}");
+
+        // `:infinite code;` refers to the function `infinite`
+        let use_pos = src.find(":infinite").unwrap() + 1;
+        let def_pos = src.find("fn infinite").unwrap() + 3;
+        let key = res.symbol_at(use_pos).expect("infinite");
+        let def = res.definition(key).expect("definition");
+        assert_eq!(SymbolKind::Function, def.kind);
+        assert_eq!(
+            Span {
+                pos: def_pos,
+                len: 8
+            },
+            def.span
+        );
+
+        // `:ret v;` refers to the local `ret`
+        let use_pos = src.find(":ret").unwrap() + 1;
+        let def_pos = src.find("let ret").unwrap() + 4;
+        let def = res.definition(res.symbol_at(use_pos).unwrap()).unwrap();
+        assert_eq!(SymbolKind::Local, def.kind);
+        assert_eq!(def_pos, def.span.pos);
+
+        // the handler's `fn break` refers to the effect operation
+        let use_pos = src.rfind("fn break").unwrap() + 3;
+        let def_pos = src.find("fn break").unwrap() + 3;
+        let def = res.definition(res.symbol_at(use_pos).unwrap()).unwrap();
+        assert_eq!(SymbolKind::Operation, def.kind);
+        assert_eq!(def_pos, def.span.pos);
+    }
+
+    #[test]
+    fn symbol_ranges_are_half_open() {
+        let src = "fn foo(a: Int, b: Int) -> Int = { a+b }";
+        let (res, ds) = resolve_src(src);
+        assert!(!ds.has_errors(), "{ds:?}");
+        let a_pos = src.find("a+b").unwrap();
+        let a = res.definition(res.symbol_at(a_pos).expect("a")).unwrap();
+        assert_eq!(src.find("a:").unwrap(), a.span.pos);
+        // the offsets just past each identifier aren't in it
+        assert_eq!(None, res.symbol_at(a_pos + 1));
+        assert_eq!(None, res.symbol_at(a_pos + 3));
+        assert!(res.symbol_at(a_pos + 2).is_some());
+    }
+
+    #[test]
+    fn reports_errors() {
+        let (_, ds) = resolve_src("fn foo(x: A, x: A) -> = { :bar x; }");
+        let codes = ds.iter().map(|d| d.code).collect::<Vec<_>>();
+        assert_eq!(vec![Code::DuplicateDefinition, Code::UnresolvedName], codes);
+    }
+
+    #[test]
+    fn let_shadows() {
+        let src = "fn foo(x: A) -> = { let x: A = x; :x; }";
+        let (res, ds) = resolve_src(src);
+        assert!(!ds.has_errors());
+        let init = res.symbol_at(src.find("= x").unwrap() + 2).unwrap();
+        let cont = res.symbol_at(src.find(":x").unwrap() + 1).unwrap();
+        assert_eq!(SymbolKind::Parameter, res.definition(init).unwrap().kind);
+        assert_eq!(SymbolKind::Local, res.definition(cont).unwrap().kind);
+    }
+}
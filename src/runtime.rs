@@ -0,0 +1,1721 @@
+//! MIR interpreter.
+//!
+//! Code is in continuation-passing style, so the interpreter has no call stack: every call replaces
+//! the current frame, and pending work lives in continuation closures on the heap. The only other
+//! state is the handler stack. Each `do ... with` pushes a handler frame holding the handler and
+//! the continuation its result goes to.
+//!
+//! Performing an operation moves the frames from the top of the handler stack down to the handling
+//! frame into a resumption, together with the operation's continuation. Frames are never mutated,
+//! so a resumption can be invoked any number of times: each invocation pushes a fresh copy of its
+//! frames. When a resumption is called with a continuation of its own, the handler's result goes
+//! there instead of to the `do ... with`. Either way, the values a handled computation finishes
+//! with pass through the handler's return clause, if it has one.
+//!
+//! While an operation's handler runs, a frame holding the resumption stands in for the captured
+//! frames. A handler's `finally` block runs once, when its frame leaves the stack for good: when the
+//! handled computation finishes, when a continuation escapes past the frame, or when the frame
+//! standing in for it is left because the handler didn't resume. Returning from a resumption to the
+//! handler that called it doesn't leave the scope. When several frames are left at once, their
+//! `finally` blocks run innermost first, before control reaches its destination.
+//!
+//! Actions the optimizer marks as tail-resumptive skip all of this. They run on the current handler
+//! stack and resume by calling the operation's continuation directly, which is what resuming as
+//! their last step would do anyway.
+//!
+//! A closure's environment is the frame it was made in, unless it was converted to take a record
+//! of just the values it reads, which is copied when it's made.
+//!
+//! Values are reference counted, and the machine's heap collects the cycles among closures,
+//! continuations and handlers that reference counting can't free. See [`HeapStats`] for what it
+//! reports.
+//!
+//! A machine can be given [`Limits`] on the resources a program uses, for running untrusted code.
+//!
+//! Operations that the program doesn't handle go to the machine's [`Host`], and built-in ones it
+//! doesn't handle either go to its [`Console`].
+
+use std::{
+    cell::{Cell, RefCell},
+    collections::{HashMap, VecDeque},
+    fmt::{self, Display, Formatter},
+    io::{self, BufRead, Write},
+    rc::Rc,
+};
+
+use crate::{
+    builtin::Builtin,
+    mir::{
+        self,
+        verify::{verify_structure, Problem, VerifyError},
+        Closure, Opcode, Program,
+    },
+    span::Span,
+    symbol::SymbolKey,
+};
+
+pub use self::gc::HeapStats;
+use self::gc::{Charge, Heap};
+pub use self::host::{Continuation, Control, FromValue, Host, HostFunction, IntoValue, IntoValues};
+use self::host::{NativeFunction, NativeHandler};
+pub use self::limits::{Limit, Limits};
+pub use self::trace::{Backtrace, HandlerTrace, TraceFrame};
+
+mod gc;
+pub mod host;
+mod limits;
+mod trace;
+
+/// A runtime value.
+#[derive(Clone, Debug)]
+pub enum Value {
+    Unit,
+    Int(i64),
+    String(Rc<str>),
+    /// A top-level function.
+    Function(SymbolKey),
+    /// A closure or continuation.
+    Closure(Rc<ClosureValue>),
+    Handler(Rc<HandlerValue>),
+    /// Exits the handler frame with the given ID, continuing with the handler's continuation.
+    Exit(u64),
+    Resume(Rc<Resumption>),
+    /// Runs `finally` blocks, then continues.
+    Then(Rc<Pending>),
+    /// Ends the program with its arguments as the result.
+    Halt,
+}
+
+impl Display for Value {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Unit => f.write_str("()"),
+            Value::Int(v) => write!(f, "{v}"),
+            Value::String(s) => f.write_str(s),
+            Value::Function(_) => f.write_str("<function>"),
+            Value::Closure(closure) if closure.prompt.is_some() => f.write_str("<continuation>"),
+            Value::Closure(_) => f.write_str("<closure>"),
+            Value::Handler(_) => f.write_str("<handler>"),
+            Value::Exit(_) | Value::Resume(_) | Value::Then(_) | Value::Halt => {
+                f.write_str("<continuation>")
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ClosureValue {
+    key: SymbolKey,
+    env: Option<Rc<Env>>,
+    /// For continuations, the ID of the handler frame that was innermost when it was made.
+    prompt: Option<u64>,
+    _charge: Charge,
+}
+
+#[derive(Debug)]
+pub struct HandlerValue {
+    key: SymbolKey,
+    env: Rc<Env>,
+    _charge: Charge,
+}
+
+/// The captured part of the handler stack, and the continuation of the operation.
+#[derive(Debug)]
+pub struct Resumption {
+    /// The frames from the handling frame up, innermost last.
+    frames: Vec<HandlerFrame>,
+    /// The operation's continuation, or unit if it never returns.
+    k: Value,
+    /// The number of values the operation returns with.
+    results: usize,
+    /// The operation performed.
+    op: SymbolKey,
+    /// Whether the host handles the operation, rather than the first of `frames`.
+    native: bool,
+    _charge: Charge,
+}
+
+impl Resumption {
+    /// The number of bytes a resumption capturing the given frames takes up.
+    fn size(frames: &[HandlerFrame]) -> usize {
+        size_of::<Resumption>() + size_of_val(frames)
+    }
+}
+
+/// `finally` blocks to run before calling a value.
+#[derive(Clone, Debug)]
+pub struct Pending {
+    /// The blocks, as pairs of the block and its environment, in reverse order.
+    finallies: Vec<(SymbolKey, Rc<Env>)>,
+    callee: Value,
+    args: Vec<Value>,
+    _charge: Charge,
+}
+
+/// The locals of a closure invocation.
+#[derive(Debug)]
+struct Env {
+    parent: Option<Rc<Env>>,
+    slots: RefCell<Vec<Value>>,
+    _charge: Charge,
+}
+
+impl Drop for Env {
+    fn drop(&mut self) {
+        // a deep recursion leaves a chain of continuations, each holding the environment of the next,
+        // so tear it down with a worklist rather than recursively
+        let mut envs = self.parent.take().into_iter().collect::<Vec<_>>();
+        let mut values = std::mem::take(self.slots.get_mut());
+        loop {
+            if let Some(value) = values.pop() {
+                match value {
+                    Value::Closure(closure) => {
+                        if let Ok(mut closure) = Rc::try_unwrap(closure) {
+                            envs.extend(closure.env.take());
+                        }
+                    }
+                    Value::Handler(handler) => {
+                        if let Ok(handler) = Rc::try_unwrap(handler) {
+                            envs.push(handler.env);
+                        }
+                    }
+                    Value::Resume(resumption) => {
+                        if let Ok(resumption) = Rc::try_unwrap(resumption) {
+                            values.push(resumption.k);
+                            for frame in resumption.frames {
+                                values.push(frame.cc);
+                                values.extend(frame.handler.map(Value::Handler));
+                            }
+                        }
+                    }
+                    _ => {}
+                }
+            } else if let Some(env) = envs.pop() {
+                if let Ok(mut env) = Rc::try_unwrap(env) {
+                    envs.extend(env.parent.take());
+                    values.append(env.slots.get_mut());
+                }
+            } else {
+                break;
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct HandlerFrame {
+    id: u64,
+    /// The handler, or `None` for the outermost frame and frames standing in for a resumption.
+    handler: Option<Rc<HandlerValue>>,
+    /// Where the handled computation's result goes.
+    cc: Value,
+    /// If the handler has a `finally` block, whether it has been run. Shared between the copies of
+    /// the frame made by resuming.
+    finalized: Option<Rc<Cell<bool>>>,
+    /// Whether `cc` returns to the handler that resumed this frame rather than leaving the scope.
+    resumed: bool,
+    /// For the frame a handler runs in, the resumption standing in for the captured frames.
+    suspended: Option<Rc<Resumption>>,
+    /// The `with` of the `do ... with` that pushed the frame.
+    span: Span,
+}
+
+impl HandlerFrame {
+    fn new(id: u64, handler: Option<Rc<HandlerValue>>, cc: Value) -> Self {
+        HandlerFrame {
+            id,
+            handler,
+            cc,
+            finalized: None,
+            resumed: false,
+            suspended: None,
+            span: Span::default(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RuntimeError {
+    /// A value was called that isn't a function, closure, or continuation.
+    NotCallable(String),
+    /// A closure was called with too few arguments.
+    Arity {
+        expected: usize,
+        found: usize,
+    },
+    /// An operand had the wrong type.
+    Type {
+        expected: &'static str,
+        found: String,
+    },
+    DivideByZero,
+    /// An operation was performed with no handler for it.
+    Unhandled(SymbolKey),
+    /// A function without a body was called.
+    NoBody(SymbolKey),
+    /// There is no function with the given name to call.
+    Undefined(String),
+    /// A continuation was invoked after the handler it was made under exited.
+    ScopeExited,
+    /// The program exceeded one of the machine's limits.
+    Limit(Limit),
+    /// An instruction is not supported by the interpreter.
+    Unsupported(&'static str),
+    /// Execution ran past the end of a closure's code.
+    FellOffEnd(SymbolKey),
+    /// The console failed.
+    Io(String),
+    /// The program isn't well-formed enough to run.
+    Invalid(VerifyError),
+}
+
+impl Display for RuntimeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            RuntimeError::NotCallable(v) => write!(f, "{v} is not callable"),
+            RuntimeError::Arity { expected, found } => {
+                write!(f, "expected {expected} arguments, found {found}")
+            }
+            RuntimeError::Type { expected, found } => {
+                write!(f, "expected {expected}, found {found}")
+            }
+            RuntimeError::DivideByZero => f.write_str("division by zero"),
+            RuntimeError::Unhandled(op) => write!(f, "unhandled operation {op:?}"),
+            RuntimeError::NoBody(key) => write!(f, "function {key:?} has no body"),
+            RuntimeError::Undefined(name) => write!(f, "no function named {name}"),
+            RuntimeError::ScopeExited => {
+                f.write_str("continuation invoked after its handler exited")
+            }
+            RuntimeError::Limit(limit) => write!(f, "program {limit}"),
+            RuntimeError::Unsupported(what) => write!(f, "{what} is not supported"),
+            RuntimeError::FellOffEnd(key) => write!(f, "execution fell off the end of {key:?}"),
+            RuntimeError::Io(err) => write!(f, "console error: {err}"),
+            RuntimeError::Invalid(err) => write!(f, "invalid program: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for RuntimeError {}
+
+/// An interpreter running a program. Calls never grow the host stack, so a program may run for any
+/// number of steps.
+pub struct Machine<'a> {
+    program: &'a Program,
+    handlers: Vec<HandlerFrame>,
+    next_id: u64,
+    /// The closure being executed.
+    key: SymbolKey,
+    code: &'a [Opcode],
+    env: Rc<Env>,
+    pc: usize,
+    stack: Vec<Value>,
+    /// The number of closures entered so far.
+    calls: u64,
+    /// The program's string literals.
+    strings: Vec<Rc<str>>,
+    console: Box<dyn Console + 'a>,
+    /// The host's functions, by the function they provide the body of.
+    functions: HashMap<SymbolKey, NativeFunction<'a>>,
+    /// The host's effect handlers, by operation.
+    natives: HashMap<SymbolKey, NativeHandler<'a>>,
+    limits: Limits,
+    /// The number of instructions executed so far.
+    steps: u64,
+    heap: Heap,
+}
+
+impl<'a> Machine<'a> {
+    /// Prepares to call a function. The function's continuation is added to the arguments if it has
+    /// one. The program is verified first, apart from the types of its values.
+    pub(crate) fn new(
+        program: &'a Program,
+        function: SymbolKey,
+        mut args: Vec<Value>,
+    ) -> Result<Self, RuntimeError> {
+        verify_structure(program).map_err(RuntimeError::Invalid)?;
+        let closure = program
+            .closures
+            .get(&function)
+            .ok_or(RuntimeError::NoBody(function))?;
+        if closure.parent.is_some() && closure.captures.is_none() {
+            // like any closure called by name, it has no frame to read its parent's locals from
+            return Err(RuntimeError::Invalid(VerifyError {
+                closure: function,
+                pc: 0,
+                problem: Problem::Nesting,
+            }));
+        }
+        if args.len() < closure.params {
+            args.push(Value::Halt);
+        }
+        let mut heap = Heap::new();
+        let mut machine = Machine {
+            program,
+            handlers: vec![HandlerFrame::new(0, None, Value::Halt)],
+            next_id: 1,
+            key: function,
+            code: &[],
+            env: heap.env(None, Vec::new()),
+            pc: 0,
+            stack: Vec::new(),
+            calls: 0,
+            strings: program.strings.iter().map(|s| s.as_str().into()).collect(),
+            console: Box::new(Stdio),
+            functions: HashMap::new(),
+            natives: HashMap::new(),
+            limits: Limits::default(),
+            steps: 0,
+            heap,
+        };
+        machine.enter(function, None, args)?;
+        Ok(machine)
+    }
+
+    /// Sets the console that built-in operations the program doesn't handle use.
+    pub fn with_console(mut self, console: impl Console + 'a) -> Self {
+        self.console = Box::new(console);
+        self
+    }
+
+    /// Limits the resources the program uses from now on. Fuel counts from the start of the program.
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Collects garbage at every allocation if `stress` is set. This is slow, but makes bugs that
+    /// free objects still in use show up right away.
+    pub fn with_gc_stress(mut self, stress: bool) -> Self {
+        self.heap.stress = stress;
+        self
+    }
+
+    /// Provides the host's functions and effect handlers to the program. Those whose names the program
+    /// doesn't declare are ignored.
+    pub fn with_host(mut self, host: Host<'a>) -> Self {
+        let program = self.program;
+        for (name, function) in host.functions {
+            if let Some(key) = program.symbol(&name) {
+                if program.table.context(key) == SymbolKey::ROOT
+                    && program.signatures.contains_key(&key)
+                {
+                    self.functions.insert(key, function);
+                }
+            }
+        }
+        for (op, handler) in host.handlers {
+            if let Some(key) = program.symbol(&op) {
+                self.natives.insert(key, handler);
+            }
+        }
+        self
+    }
+
+    /// Runs the program to completion.
+    pub fn run(&mut self) -> Result<Vec<Value>, RuntimeError> {
+        self.run_for(u64::MAX)
+            .map(|result| result.expect("the program halts before entering u64::MAX closures"))
+    }
+
+    /// Runs the program until it halts, or until it has entered `budget` more closures, in which case
+    /// it can be run further.
+    pub fn run_for(&mut self, budget: u64) -> Result<Option<Vec<Value>>, RuntimeError> {
+        let limit = self.calls.saturating_add(budget);
+        while self.calls < limit {
+            if let Some(values) = self.step()? {
+                return Ok(Some(values));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Executes one instruction. Returns the program's result if it halted.
+    pub fn step(&mut self) -> Result<Option<Vec<Value>>, RuntimeError> {
+        self.steps += 1;
+        if self.limits.fuel.is_some_and(|fuel| self.steps > fuel) {
+            return Err(RuntimeError::Limit(Limit::Fuel));
+        }
+        if self.steps.is_multiple_of(limits::CANCEL_INTERVAL) && self.limits.cancelled() {
+            return Err(RuntimeError::Limit(Limit::Cancelled));
+        }
+        let op = self
+            .code
+            .get(self.pc)
+            .ok_or(RuntimeError::FellOffEnd(self.key))?;
+        self.pc += 1;
+        let halted = match op {
+            Opcode::LoadValue(value) => {
+                let value = match *value {
+                    mir::Value::Unit => Value::Unit,
+                    mir::Value::Int(v) => Value::Int(v),
+                    mir::Value::Cont(key) => Value::Function(key),
+                    mir::Value::String(idx) => Value::String(self.strings[idx].clone()),
+                };
+                self.stack.push(value);
+                None
+            }
+            Opcode::LoadLocal(slot) => {
+                let value = self.env.slots.borrow()[*slot].clone();
+                self.stack.push(value);
+                None
+            }
+            Opcode::LoadOuter(depth, slot) => {
+                let value = self.outer(*depth, *slot);
+                self.stack.push(value);
+                None
+            }
+            Opcode::StoreLocal(slot) => {
+                let value = self.pop();
+                self.env.slots.borrow_mut()[*slot] = value;
+                None
+            }
+            Opcode::Add => {
+                let b = self.pop();
+                let sum = match (self.pop(), b) {
+                    (Value::Int(a), Value::Int(b)) => Value::Int(a.wrapping_add(b)),
+                    (Value::String(a), Value::String(b)) => {
+                        if self
+                            .limits
+                            .memory
+                            .is_some_and(|max| a.len() + b.len() > max)
+                        {
+                            return Err(RuntimeError::Limit(Limit::Memory));
+                        }
+                        Value::String(format!("{a}{b}").into())
+                    }
+                    (Value::String(_), other) => return Err(type_error("a string", &other)),
+                    (Value::Int(_), other) | (other, _) => {
+                        return Err(type_error("an integer", &other))
+                    }
+                };
+                self.stack.push(sum);
+                None
+            }
+            Opcode::Sub => self.arithmetic(|a, b| Ok(a.wrapping_sub(b)))?,
+            Opcode::Mul => self.arithmetic(|a, b| Ok(a.wrapping_mul(b)))?,
+            Opcode::Div => {
+                self.arithmetic(|a, b| a.checked_div(b).ok_or(RuntimeError::DivideByZero))?
+            }
+            Opcode::Rem => {
+                self.arithmetic(|a, b| a.checked_rem(b).ok_or(RuntimeError::DivideByZero))?
+            }
+            Opcode::Eq => self.equality(true)?,
+            Opcode::NotEq => self.equality(false)?,
+            Opcode::Gt => self.arithmetic(|a, b| Ok((a > b) as i64))?,
+            Opcode::Ge => self.arithmetic(|a, b| Ok((a >= b) as i64))?,
+            Opcode::Lt => self.arithmetic(|a, b| Ok((a < b) as i64))?,
+            Opcode::Le => self.arithmetic(|a, b| Ok((a <= b) as i64))?,
+            Opcode::Access(_) => return Err(RuntimeError::Unsupported("member access")),
+            &Opcode::Branch(then, otherwise) => {
+                let condition = self.pop_int()?;
+                let offset = if condition != 0 { then } else { otherwise };
+                self.pc = (self.pc - 1).wrapping_add_signed(offset as isize);
+                None
+            }
+            &Opcode::MakeClosure(key) => {
+                self.push_closure(key, None);
+                None
+            }
+            &Opcode::MakeCont(key) => {
+                let prompt = self.handlers.last().expect("outermost frame").id;
+                self.push_closure(key, Some(prompt));
+                None
+            }
+            &Opcode::MakeHandler(key) => {
+                let handler = HandlerValue {
+                    key,
+                    env: self.env.clone(),
+                    _charge: self.heap.charge(size_of::<HandlerValue>()),
+                };
+                self.stack.push(Value::Handler(Rc::new(handler)));
+                self.heap.allocated();
+                None
+            }
+            Opcode::Handle => {
+                let cc = self.pop();
+                let body = self.pop();
+                let handler = match self.pop() {
+                    Value::Handler(handler) => handler,
+                    other => return Err(type_error("a handler", &other)),
+                };
+                let id = self.next_id;
+                self.next_id += 1;
+                let finally = self.program.handlers[&handler.key].finally;
+                let mut frame = HandlerFrame::new(id, Some(handler), cc);
+                frame.finalized = finally.map(|_| Rc::new(Cell::new(false)));
+                if let Value::Closure(body) = &body {
+                    frame.span = self.closure(body.key)?.span;
+                }
+                self.handlers.push(frame);
+                self.check_depth()?;
+                self.apply(body, vec![Value::Exit(id)])?
+            }
+            &Opcode::Perform(op, arity) => self.perform(op, arity)?,
+            Opcode::Continue => {
+                let callee = self.pop();
+                let args = std::mem::take(&mut self.stack);
+                self.apply(callee, args)?
+            }
+        };
+        Ok(halted)
+    }
+
+    /// Returns statistics about the program's heap so far.
+    pub fn heap_stats(&self) -> HeapStats {
+        self.heap.stats()
+    }
+
+    /// Frees the closures, continuations and handlers that are only kept alive by cycles.
+    pub fn collect_garbage(&mut self) {
+        self.heap.collect();
+    }
+
+    /// Resumes a continuation given to a native handler, once the program has halted, and runs the
+    /// program to completion again.
+    pub fn resume(
+        &mut self,
+        k: &Continuation,
+        mut values: Vec<Value>,
+    ) -> Result<Vec<Value>, RuntimeError> {
+        values.truncate(k.0.results);
+        match self.apply(Value::Resume(k.0.clone()), values)? {
+            Some(values) => Ok(values),
+            None => self.run(),
+        }
+    }
+
+    fn pop(&mut self) -> Value {
+        // the verifier checks that every instruction has its operands on the stack
+        self.stack.pop().expect("operand stack underflow")
+    }
+
+    fn pop_int(&mut self) -> Result<i64, RuntimeError> {
+        match self.pop() {
+            Value::Int(v) => Ok(v),
+            other => Err(type_error("an integer", &other)),
+        }
+    }
+
+    /// Compares two integers or two strings, producing 1 if their equality is `eq`.
+    fn equality(&mut self, eq: bool) -> Result<Option<Vec<Value>>, RuntimeError> {
+        let b = self.pop();
+        let equal = match (self.pop(), b) {
+            (Value::Int(a), Value::Int(b)) => a == b,
+            (Value::String(a), Value::String(b)) => a == b,
+            (Value::String(_), other) => return Err(type_error("a string", &other)),
+            (Value::Int(_), other) | (other, _) => return Err(type_error("an integer", &other)),
+        };
+        self.stack.push(Value::Int((equal == eq) as i64));
+        Ok(None)
+    }
+
+    fn arithmetic(
+        &mut self,
+        op: impl FnOnce(i64, i64) -> Result<i64, RuntimeError>,
+    ) -> Result<Option<Vec<Value>>, RuntimeError> {
+        let b = self.pop_int()?;
+        let a = self.pop_int()?;
+        self.stack.push(Value::Int(op(a, b)?));
+        Ok(None)
+    }
+
+    fn push_closure(&mut self, key: SymbolKey, prompt: Option<u64>) {
+        let captures = self
+            .program
+            .closures
+            .get(&key)
+            .and_then(|closure| closure.captures.as_ref());
+        let env = match captures {
+            None => Some(self.env.clone()),
+            Some(captures) if captures.is_empty() => None,
+            Some(captures) => {
+                let values = captures
+                    .iter()
+                    .map(|&(depth, slot)| self.outer(depth, slot))
+                    .collect();
+                Some(self.heap.env(None, values))
+            }
+        };
+        let closure = ClosureValue {
+            key,
+            env,
+            prompt,
+            _charge: self.heap.charge(size_of::<ClosureValue>()),
+        };
+        self.stack.push(Value::Closure(Rc::new(closure)));
+        self.heap.allocated();
+    }
+
+    /// Reads a local of the frame `depth` frames out from the current one.
+    fn outer(&self, depth: usize, slot: usize) -> Value {
+        let mut env = &self.env;
+        for _ in 0..depth {
+            env = env
+                .parent
+                .as_ref()
+                .expect("closures are nested in their parent");
+        }
+        let value = env.slots.borrow()[slot].clone();
+        value
+    }
+
+    fn closure(&self, key: SymbolKey) -> Result<&'a Closure, RuntimeError> {
+        self.program
+            .closures
+            .get(&key)
+            .ok_or(RuntimeError::NoBody(key))
+    }
+
+    /// Calls a value. Returns the program's result if it halted.
+    fn apply(
+        &mut self,
+        mut callee: Value,
+        mut args: Vec<Value>,
+    ) -> Result<Option<Vec<Value>>, RuntimeError> {
+        loop {
+            match callee {
+                Value::Function(key) if self.functions.contains_key(&key) => {
+                    (callee, args) = self.native(key, args)?;
+                }
+                Value::Function(key) => return self.enter(key, None, args).map(|_| None),
+                Value::Closure(closure) => {
+                    if let Some(prompt) = closure.prompt {
+                        // leave the frames made since the continuation was
+                        let idx = self.frame(prompt)? + 1;
+                        if idx < self.handlers.len() {
+                            (callee, args) = self.leave(idx, Value::Closure(closure), args);
+                            continue;
+                        }
+                    }
+                    return self
+                        .enter(closure.key, closure.env.clone(), args)
+                        .map(|_| None);
+                }
+                Value::Exit(id) => {
+                    let idx = self.frame(id)?;
+                    let frame = &self.handlers[idx];
+                    let mut cc = frame.cc.clone();
+                    let handler = frame.handler.as_ref().expect("exited frame has a handler");
+                    if let Some(ret) = self.program.handlers[&handler.key].ret {
+                        // the return clause runs outside the handler, after any finally blocks
+                        let clause = ClosureValue {
+                            key: ret,
+                            env: Some(handler.env.clone()),
+                            prompt: None,
+                            _charge: self.heap.charge(size_of::<ClosureValue>()),
+                        };
+                        args.truncate(self.closure(ret)?.params - 1);
+                        args.push(cc);
+                        cc = Value::Closure(Rc::new(clause));
+                        self.heap.allocated();
+                    }
+                    (callee, args) = self.leave(idx, cc, args);
+                }
+                Value::Resume(resumption) => {
+                    let mut frames = resumption.frames.clone();
+                    if args.len() == resumption.results + 1 {
+                        frames[0].cc = args.pop().expect("continuation argument");
+                        frames[0].resumed = true;
+                    } else if self
+                        .handlers
+                        .last()
+                        .and_then(|frame| frame.suspended.as_ref())
+                        .is_some_and(|suspended| Rc::ptr_eq(suspended, &resumption))
+                    {
+                        // the handler is done, and the resumed frames take the place of its frame
+                        self.handlers.pop();
+                    }
+                    self.handlers.extend(frames);
+                    self.check_depth()?;
+                    callee = resumption.k.clone();
+                }
+                Value::Then(pending) => {
+                    let mut pending = Rc::unwrap_or_clone(pending);
+                    match pending.finallies.pop() {
+                        Some((key, env)) => {
+                            let then = Value::Then(Rc::new(pending));
+                            self.heap.allocated();
+                            return self.enter(key, Some(env), vec![then]).map(|_| None);
+                        }
+                        None => (callee, args) = (pending.callee, pending.args),
+                    }
+                }
+                Value::Halt if self.handlers.len() > 1 => {
+                    (callee, args) = self.leave(1, Value::Halt, args);
+                }
+                Value::Halt => return Ok(Some(args)),
+                other => return Err(RuntimeError::NotCallable(other.to_string())),
+            }
+        }
+    }
+
+    /// Calls a host function, returning what to call with its results.
+    fn native(
+        &mut self,
+        key: SymbolKey,
+        mut args: Vec<Value>,
+    ) -> Result<(Value, Vec<Value>), RuntimeError> {
+        let signature = self.program.signatures[&key];
+        let expected = signature.params + signature.returns.is_some() as usize;
+        if args.len() < expected {
+            return Err(RuntimeError::Arity {
+                expected,
+                found: args.len(),
+            });
+        }
+        args.truncate(expected);
+        // a function that never returns ends the program
+        let k = match signature.returns {
+            Some(_) => args.pop().expect("continuation argument"),
+            None => Value::Halt,
+        };
+        let function = self.functions.get_mut(&key).expect("host function");
+        Ok((k, function(args)?))
+    }
+
+    /// Starts executing a closure with the given environment.
+    fn enter(
+        &mut self,
+        key: SymbolKey,
+        parent: Option<Rc<Env>>,
+        mut args: Vec<Value>,
+    ) -> Result<(), RuntimeError> {
+        let closure = self.closure(key)?;
+        if args.len() < closure.params {
+            return Err(RuntimeError::Arity {
+                expected: closure.params,
+                found: args.len(),
+            });
+        }
+        args.truncate(closure.params);
+        args.resize(closure.locals.len(), Value::Unit);
+        self.env = self.heap.env(parent, args);
+        self.key = key;
+        self.code = &closure.code;
+        self.pc = 0;
+        self.stack.clear();
+        self.calls += 1;
+        let over = |machine: &Self| {
+            // frames copied back onto the handler stack by resuming count too
+            let live = machine.heap.meter.get() + size_of_val(&machine.handlers[..]);
+            machine.limits.memory.is_some_and(|max| live > max)
+        };
+        if over(self) {
+            // the memory may only be held by cycles
+            self.heap.collect();
+            if over(self) {
+                return Err(RuntimeError::Limit(Limit::Memory));
+            }
+        }
+        Ok(())
+    }
+
+    fn check_depth(&self) -> Result<(), RuntimeError> {
+        match self.limits.handler_depth {
+            Some(max) if self.handlers.len() > max => Err(RuntimeError::Limit(Limit::HandlerDepth)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Finds the index of the handler frame with the given ID.
+    fn frame(&self, id: u64) -> Result<usize, RuntimeError> {
+        self.handlers
+            .iter()
+            .rposition(|frame| frame.id == id)
+            .ok_or(RuntimeError::ScopeExited)
+    }
+
+    /// Removes the handler frames from `idx` up, returning what to call to run their `finally`
+    /// blocks before calling `callee`.
+    fn leave(&mut self, idx: usize, callee: Value, args: Vec<Value>) -> (Value, Vec<Value>) {
+        let mut finallies = Vec::new();
+        for frame in self.handlers.drain(idx..).rev() {
+            finalize(self.program, &frame, &mut finallies);
+        }
+        if finallies.is_empty() {
+            return (callee, args);
+        }
+        finallies.reverse();
+        let size = size_of::<Pending>()
+            + finallies.capacity() * size_of::<(SymbolKey, Rc<Env>)>()
+            + args.capacity() * size_of::<Value>();
+        let pending = Pending {
+            finallies,
+            callee,
+            args,
+            _charge: self.heap.charge(size),
+        };
+        self.heap.allocated();
+        (Value::Then(Rc::new(pending)), Vec::new())
+    }
+
+    fn perform(&mut self, op: SymbolKey, arity: usize) -> Result<Option<Vec<Value>>, RuntimeError> {
+        let k = (self.stack.len() > arity).then(|| self.pop());
+        let mut args = std::mem::take(&mut self.stack);
+        let program = self.program;
+        let results = program.signatures[&op].returns.unwrap_or(0);
+        let found = self
+            .handlers
+            .iter()
+            .enumerate()
+            .rev()
+            .find_map(|(idx, frame)| {
+                let handler = frame.handler.as_ref()?;
+                Some((idx, program.handlers[&handler.key].action(op)?))
+            });
+        let Some((idx, action)) = found else {
+            if self.natives.contains_key(&op) {
+                return self.perform_native(op, k, args, results);
+            }
+            let builtin = program
+                .builtins
+                .get(&op)
+                .ok_or(RuntimeError::Unhandled(op))?;
+            let results = self.builtin(*builtin, args)?;
+            let k = k.ok_or(RuntimeError::Unhandled(op))?;
+            return self.apply(k, results);
+        };
+        let handler = self.handlers[idx]
+            .handler
+            .clone()
+            .expect("handling frame has a handler");
+        if let Some(k) = k
+            .as_ref()
+            .filter(|_| program.handlers[&handler.key].tail.contains(&op))
+        {
+            // the action only ever resumes as its last step, with nothing it does seeing the
+            // handler stack, so it runs on the current stack and resumes by calling `k` directly
+            args.push(k.clone());
+            args.push(Value::Unit);
+            self.enter(action, Some(handler.env.clone()), args)?;
+            return Ok(None);
+        }
+        let frames = self.handlers.split_off(idx);
+        let cc = frames[0].cc.clone();
+        let resumes = k.is_some();
+        let resumption = Rc::new(Resumption {
+            _charge: self.heap.charge(Resumption::size(&frames)),
+            frames,
+            k: k.unwrap_or(Value::Unit),
+            results,
+            op,
+            native: false,
+        });
+        self.heap.allocated();
+        let id = self.next_id;
+        self.next_id += 1;
+        let mut frame = HandlerFrame::new(id, None, Value::Unit);
+        frame.suspended = Some(resumption.clone());
+        self.handlers.push(frame);
+        args.push(if resumes {
+            Value::Resume(resumption)
+        } else {
+            Value::Unit
+        });
+        args.push(cc);
+        self.enter(action, Some(handler.env.clone()), args)?;
+        Ok(None)
+    }
+
+    /// Handles an operation with the host's handler, which gets a continuation capturing every frame.
+    fn perform_native(
+        &mut self,
+        op: SymbolKey,
+        k: Option<Value>,
+        args: Vec<Value>,
+        results: usize,
+    ) -> Result<Option<Vec<Value>>, RuntimeError> {
+        let frames = self.handlers.split_off(1);
+        let resumption = Rc::new(Resumption {
+            _charge: self.heap.charge(Resumption::size(&frames)),
+            frames,
+            k: k.unwrap_or(Value::Unit),
+            results,
+            op,
+            native: true,
+        });
+        self.heap.allocated();
+        let id = self.next_id;
+        self.next_id += 1;
+        let mut frame = HandlerFrame::new(id, None, Value::Unit);
+        frame.suspended = Some(resumption.clone());
+        self.handlers.push(frame);
+        let handler = self.natives.get_mut(&op).expect("native handler");
+        match handler(args, Continuation(resumption.clone()))? {
+            Control::Resume(mut values) => {
+                values.truncate(resumption.results);
+                self.apply(Value::Resume(resumption), values)
+            }
+            Control::Abort(values) => self.apply(Value::Halt, values),
+        }
+    }
+
+    /// Handles a built-in operation with the console.
+    fn builtin(&mut self, builtin: Builtin, args: Vec<Value>) -> Result<Vec<Value>, RuntimeError> {
+        let io_error = |err: io::Error| RuntimeError::Io(err.to_string());
+        match builtin {
+            Builtin::Print => {
+                let text = args.first().ok_or(RuntimeError::Arity {
+                    expected: 1,
+                    found: 0,
+                })?;
+                self.console.print(&text.to_string()).map_err(io_error)?;
+                Ok(Vec::new())
+            }
+            Builtin::ReadLine => {
+                let line = self.console.read_line().map_err(io_error)?;
+                Ok(vec![Value::String(line.unwrap_or_default().into())])
+            }
+        }
+    }
+}
+
+/// Where the built-in `io` operations go when the program doesn't handle them.
+pub trait Console {
+    /// Writes a line.
+    fn print(&mut self, text: &str) -> io::Result<()>;
+
+    /// Reads a line without its line ending, or `None` at the end of input.
+    fn read_line(&mut self) -> io::Result<Option<String>>;
+}
+
+impl<C: Console + ?Sized> Console for &mut C {
+    fn print(&mut self, text: &str) -> io::Result<()> {
+        (**self).print(text)
+    }
+
+    fn read_line(&mut self) -> io::Result<Option<String>> {
+        (**self).read_line()
+    }
+}
+
+/// The process's standard input and output.
+pub struct Stdio;
+
+impl Console for Stdio {
+    fn print(&mut self, text: &str) -> io::Result<()> {
+        writeln!(io::stdout().lock(), "{text}")
+    }
+
+    fn read_line(&mut self) -> io::Result<Option<String>> {
+        let mut line = String::new();
+        if io::stdin().lock().read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let len = line.trim_end_matches(['\n', '\r']).len();
+        line.truncate(len);
+        Ok(Some(line))
+    }
+}
+
+/// An in-memory console, which reads from a list of lines and records what's printed.
+#[derive(Clone, Debug, Default)]
+pub struct Captured {
+    pub input: VecDeque<String>,
+    pub output: Vec<String>,
+}
+
+impl Captured {
+    /// Creates a console that reads the given lines.
+    pub fn new<S: Into<String>>(input: impl IntoIterator<Item = S>) -> Self {
+        Captured {
+            input: input.into_iter().map(Into::into).collect(),
+            output: Vec::new(),
+        }
+    }
+}
+
+impl Console for Captured {
+    fn print(&mut self, text: &str) -> io::Result<()> {
+        self.output.push(text.to_owned());
+        Ok(())
+    }
+
+    fn read_line(&mut self) -> io::Result<Option<String>> {
+        Ok(self.input.pop_front())
+    }
+}
+
+/// Collects the `finally` blocks to run for a frame leaving the handler stack, innermost first.
+fn finalize(program: &Program, frame: &HandlerFrame, finallies: &mut Vec<(SymbolKey, Rc<Env>)>) {
+    if let Some(resumption) = &frame.suspended {
+        for frame in resumption.frames.iter().rev() {
+            finalize(program, frame, finallies);
+        }
+    }
+    let (Some(handler), Some(finalized)) = (&frame.handler, &frame.finalized) else {
+        return;
+    };
+    // a resumed frame returns to its handler, whose own frame runs the block when it's left
+    if !frame.resumed && !finalized.replace(true) {
+        let finally = program.handlers[&handler.key]
+            .finally
+            .expect("handler has a finally block");
+        finallies.push((finally, handler.env.clone()));
+    }
+}
+
+fn type_error(expected: &'static str, found: &Value) -> RuntimeError {
+    RuntimeError::Type {
+        expected,
+        found: found.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{atomic::AtomicBool, Arc};
+
+    use crate::{
+        cache::{StringCache, StringKey},
+        cst,
+        diagnostic::Diagnostics,
+        mir::{lower::lower, text::assemble},
+        resolve::resolve,
+    };
+
+    use super::*;
+
+    /// Calls a function, returning the values it returns with.
+    fn call(
+        program: &Program,
+        function: SymbolKey,
+        args: Vec<Value>,
+    ) -> Result<Vec<Value>, RuntimeError> {
+        Machine::new(program, function, args)?.run()
+    }
+
+    const CHOOSE: &str = r#"
+effect choice {
+    fn choose() -> Int;
+}
+
+fn pow10(n: Int) -> Int = {
+    if n == 0 { 1 } else { 10 * pow10(n - 1) }
+}
+
+fn all() -> Int = {
+    do {
+        let a: Int = choose();
+        let b: Int = choose();
+        pow10((a * 2) + b)
+    } with handle choice {
+        fn choose() -> Int = {
+            let x: Int = return(0);
+            let y: Int = return(1);
+            x + y
+        }
+    }
+}
+
+fn count() -> Int = {
+    do {
+        choose();
+        choose();
+        choose();
+        1
+    } with handle choice {
+        fn choose() -> Int = {
+            return(0) + return(1)
+        }
+    }
+}
+
+fn first() -> Int = {
+    do {
+        let a: Int = choose();
+        pow10(a) + 5
+    } with handle choice {
+        fn choose() -> Int = { :return 1; }
+    }
+}
+
+fn none() -> Int = {
+    do {
+        let a: Int = choose();
+        a + 5
+    } with handle choice {
+        fn choose() -> Int = { 42 }
+    }
+}
+
+fn last() -> Int = {
+    let r: Int = do {
+        choose()
+    } with handle choice {
+        fn choose() -> Int = {
+            let x: Int = return(5);
+            x + 1
+        }
+    };
+    r
+}
+
+fn unhandled() -> Int = {
+    choose()
+}
+"#;
+
+    const RETURN: &str = r#"
+effect choice {
+    fn choose() -> Int;
+}
+
+fn scaled() -> Int = {
+    do {
+        5
+    } with handle choice {
+        return(v: Int) -> Int = { v * 2 }
+    }
+}
+
+fn collect() -> Int = {
+    let sum: Int, count: Int = do {
+        let a: Int = choose();
+        let b: Int = choose();
+        (a * 2) + b
+    } with handle choice {
+        fn choose() -> Int = {
+            let x: Int, n: Int = return(0);
+            let y: Int, m: Int = return(1);
+            :continue x + y, n + m;
+        }
+        return(v: Int) -> (Int, Int) = { :continue v, 1; }
+    };
+    (sum * 10) + count
+}
+"#;
+
+    const IO: &str = r#"
+fn greet() -> () = {
+    let name: String = read_line();
+    print("Hello, " + name + "!");
+    let rest: String = read_line();
+    if rest == "" { print("\tdone"); } else { print(rest); }
+}
+
+fn silenced() -> Int = {
+    do {
+        print("a");
+        print("b");
+        0
+    } with handle io {
+        fn print(s: String) -> () = {
+            let n: Int = return();
+            n + 1
+        }
+    }
+}
+"#;
+
+    /// Compiles a program, returning it along with the named function.
+    pub(super) fn compile(src: &str, function: &str) -> (Program, SymbolKey) {
+        let mut cache = StringCache::new();
+        let mut ds = Diagnostics::new();
+        let (items, _) = cst::parse(StringKey::EMPTY, src, &mut cache, &mut ds);
+        let res = resolve(&items, &cache, &mut ds);
+        let program = lower(&items, res, &Default::default(), &cache, &mut ds);
+        assert!(!ds.has_errors(), "{ds:?}");
+        let function = program.function(cache.intern(function)).expect("function");
+        (program, function)
+    }
+
+    const FINALLY: &str = r#"
+effect note {
+    fn note(d: Int) -> ();
+}
+
+effect abort {
+    fn abort() ->;
+}
+
+effect fail {
+    fn fail() ->;
+}
+
+effect choice {
+    fn choose() -> Int;
+}
+
+fn normal() -> Int = {
+    do {
+        do {
+            note(1);
+        } with handle abort {
+            fn abort() -> = { :continue; }
+            finally { note(2); }
+        };
+        note(3);
+        0
+    } with handle note {
+        fn note(d: Int) -> () = {
+            let r: Int = return();
+            (r * 10) + d
+        }
+    }
+}
+
+fn escaping() -> Int = {
+    let ret: (Int) -> = return;
+    do {
+        note(1);
+        :ret 0;
+    } with handle abort {
+        fn abort() -> = { :continue; }
+        finally { note(2); }
+    }
+}
+
+fn escape() -> Int = {
+    do {
+        let x: Int = escaping();
+        note(3);
+        x
+    } with handle note {
+        fn note(d: Int) -> () = {
+            let r: Int = return();
+            (r * 10) + d
+        }
+    }
+}
+
+fn breaking() -> Int = {
+    let ret: (Int) -> = return;
+    do {
+        note(1);
+        abort();
+    } with handle abort {
+        fn abort() -> = {
+            note(2);
+            :ret 0;
+        }
+        finally { note(3); }
+    }
+}
+
+fn break_out() -> Int = {
+    do {
+        let x: Int = breaking();
+        note(4);
+        x
+    } with handle note {
+        fn note(d: Int) -> () = {
+            let r: Int = return();
+            (r * 10) + d
+        }
+    }
+}
+
+fn discard() -> Int = {
+    do {
+        let x: Int = do {
+            note(1);
+            abort();
+        } with handle abort {
+            fn abort() -> = {
+                note(2);
+                0
+            }
+            finally { note(3); }
+        };
+        note(4);
+        x
+    } with handle note {
+        fn note(d: Int) -> () = {
+            let r: Int = return();
+            (r * 10) + d
+        }
+    }
+}
+
+fn nested() -> Int = {
+    do {
+        do {
+            do {
+                note(1);
+                abort();
+            } with handle fail {
+                fn fail() -> = { :continue; }
+                finally { note(2); }
+            };
+        } with handle abort {
+            fn abort() -> = { :continue; }
+            finally { note(3); }
+        };
+        note(4);
+        0
+    } with handle note {
+        fn note(d: Int) -> () = {
+            let r: Int = return();
+            (r * 10) + d
+        }
+    }
+}
+
+fn resumed() -> Int = {
+    do {
+        do {
+            choose();
+            note(1);
+            1
+        } with handle choice {
+            fn choose() -> Int = { return(0) + return(1) }
+            finally { note(2); }
+        };
+        note(3);
+        0
+    } with handle note {
+        fn note(d: Int) -> () = {
+            let r: Int = return();
+            (r * 10) + d
+        }
+    }
+}
+"#;
+
+    fn run(src: &str, function: &str) -> Result<Vec<i64>, RuntimeError> {
+        let (program, function) = compile(src, function);
+        let values = call(&program, function, Vec::new())?;
+        Ok(values
+            .into_iter()
+            .map(|v| match v {
+                Value::Int(v) => v,
+                other => panic!("expected an integer, found {other}"),
+            })
+            .collect())
+    }
+
+    #[test]
+    fn enumerates_choices() {
+        // each combination of choices contributes a different digit
+        assert_eq!(Ok(vec![1111]), run(CHOOSE, "all"));
+        assert_eq!(Ok(vec![8]), run(CHOOSE, "count"));
+    }
+
+    #[test]
+    fn resumes_once_or_never() {
+        assert_eq!(Ok(vec![15]), run(CHOOSE, "first"));
+        assert_eq!(Ok(vec![42]), run(CHOOSE, "none"));
+        assert_eq!(Ok(vec![6]), run(CHOOSE, "last"));
+    }
+
+    #[test]
+    fn resumes_tail_resumptive_actions_directly() {
+        let src = r#"
+effect reader {
+    fn ask() -> Int;
+}
+
+fn sum(n: Int) -> Int = {
+    if n == 0 { 0 } else { ask() + sum(n - 1) }
+}
+
+fn asked() -> Int = {
+    do {
+        sum(100)
+    } with handle reader {
+        fn ask() -> Int = { return(2) }
+    }
+}
+"#;
+        let (mut program, function) = compile(src, "asked");
+        let mut ids = Vec::new();
+        for optimized in [false, true] {
+            if optimized {
+                crate::mir::opt::tail(&mut program);
+            }
+            let mut machine = Machine::new(&program, function, Vec::new()).expect("machine");
+            let values = machine.run().expect("run");
+            assert!(matches!(values[..], [Value::Int(200)]));
+            ids.push(machine.next_id);
+        }
+        // each `ask` captured a resumption and pushed a frame for the action, until optimized
+        assert_eq!(vec![102, 2], ids);
+    }
+
+    #[test]
+    fn reports_unhandled() {
+        assert!(matches!(
+            run(CHOOSE, "unhandled"),
+            Err(RuntimeError::Unhandled(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_invalid_programs() {
+        let mut cache = StringCache::new();
+        let text = "closure main params 1 cont 0\n  locals k\n  add\n  load_local 0\n  continue\n";
+        let program = assemble(text, &mut cache).expect("assemble");
+        let main = program.symbol("main").expect("main");
+        assert!(matches!(
+            Machine::new(&program, main, Vec::new()).err(),
+            Some(RuntimeError::Invalid(VerifyError {
+                problem: Problem::Underflow,
+                ..
+            }))
+        ));
+        // the types of values are checked as the program runs
+        let text = text.replace("  add\n", "  load_value \"a\"\n  load_value 1\n  add\n");
+        let program = assemble(&text, &mut cache).expect("assemble");
+        let result = call(&program, main, Vec::new());
+        assert!(
+            matches!(result, Err(RuntimeError::Type { .. })),
+            "{result:?}"
+        );
+    }
+
+    #[test]
+    fn breaks_loop() {
+        let src = format!(
+            "{}\nfn seven() -> Int = {{ let x: Int = 3; loop {{ :break x + 4; }} }}",
+            include_str!("../korou-examples/loop.ku")
+        );
+        assert_eq!(Ok(vec![7]), run(&src, "seven"));
+    }
+
+    /// Runs a test on a thread with a small stack, so that recursion in the interpreter would overflow.
+    fn with_small_stack(test: impl FnOnce() + Send + 'static) {
+        std::thread::Builder::new()
+            .stack_size(128 * 1024)
+            .spawn(test)
+            .expect("spawn test thread")
+            .join()
+            .expect("test thread panicked");
+    }
+
+    #[test]
+    fn runs_infinite_in_constant_stack() {
+        with_small_stack(|| {
+            let src = format!(
+                "{}\nfn spin() -> = {{ infinite({{}}); }}",
+                include_str!("../korou-examples/loop.ku")
+            );
+            let (program, function) = compile(&src, "spin");
+            let mut machine = Machine::new(&program, function, Vec::new()).expect("machine");
+            // each iteration enters `code`, the rest of `infinite`, then `infinite` again
+            let iterations = 1_000_000;
+            assert!(matches!(machine.run_for(3 * iterations), Ok(None)));
+            assert_eq!(1, machine.handlers.len());
+            let depth =
+                std::iter::successors(Some(&machine.env), |env| env.parent.as_ref()).count();
+            assert!(depth <= 2, "environment chain of {depth}");
+        });
+    }
+
+    #[test]
+    fn unwinds_deep_recursion() {
+        with_small_stack(|| {
+            let src = "fn sum(n: Int) -> Int = { if n == 0 { 0 } else { n + sum(n - 1) } }";
+            let (program, function) = compile(src, "sum");
+            let values = call(&program, function, vec![Value::Int(100_000)]).expect("sum");
+            assert!(matches!(values[..], [Value::Int(5_000_050_000)]));
+        });
+    }
+
+    /// Runs a function with the given limits.
+    fn run_limited(
+        src: &str,
+        function: &str,
+        args: Vec<Value>,
+        limits: Limits,
+    ) -> Result<Vec<Value>, RuntimeError> {
+        let (program, function) = compile(src, function);
+        let mut machine = Machine::new(&program, function, args)?.with_limits(limits);
+        machine.run()
+    }
+
+    #[test]
+    fn stops_infinite_without_fuel() {
+        let src = format!(
+            "{}\nfn spin() -> = {{ infinite({{}}); }}",
+            include_str!("../korou-examples/loop.ku")
+        );
+        let limits = Limits {
+            fuel: Some(10_000),
+            ..Limits::default()
+        };
+        let result = run_limited(&src, "spin", Vec::new(), limits);
+        assert_eq!(Err(RuntimeError::Limit(Limit::Fuel)), result.map(|_| ()));
+
+        let cancel = Arc::new(AtomicBool::new(true));
+        let limits = Limits {
+            cancel: Some(cancel),
+            ..Limits::default()
+        };
+        let result = run_limited(&src, "spin", Vec::new(), limits);
+        assert_eq!(
+            Err(RuntimeError::Limit(Limit::Cancelled)),
+            result.map(|_| ())
+        );
+    }
+
+    #[test]
+    fn limits_memory() {
+        let src = "fn sum(n: Int) -> Int = { if n == 0 { 0 } else { n + sum(n - 1) } }";
+        let limits = Limits {
+            memory: Some(64 * 1024),
+            ..Limits::default()
+        };
+        let values = run_limited(src, "sum", vec![Value::Int(10)], limits.clone());
+        assert!(matches!(values.as_deref(), Ok([Value::Int(55)])));
+        let result = run_limited(src, "sum", vec![Value::Int(100_000)], limits.clone());
+        assert_eq!(Err(RuntimeError::Limit(Limit::Memory)), result.map(|_| ()));
+
+        let src = "fn grow(s: String) -> String = { grow(s + s) }";
+        let result = run_limited(src, "grow", vec!["x".into_value()], limits);
+        assert_eq!(Err(RuntimeError::Limit(Limit::Memory)), result.map(|_| ()));
+    }
+
+    #[test]
+    fn limits_resumptions() {
+        let src = format!(
+            "{CHOOSE}\nfn tries(n: Int) -> Int = {{ if n == 0 {{ 0 }} else {{ choose() + tries(n - 1) }} }}
+            fn every(n: Int) -> Int = {{
+                do {{ tries(n) }} with handle choice {{ fn choose() -> Int = {{ return(1) + 1 }} }}
+            }}"
+        );
+        let limits = Limits {
+            memory: Some(128 * 1024),
+            ..Limits::default()
+        };
+        let values = run_limited(&src, "every", vec![Value::Int(50)], limits.clone());
+        assert!(matches!(values.as_deref(), Ok([Value::Int(100)])));
+        // resumes 200 times before any returns; the environments alone would stay under the limit
+        let result = run_limited(&src, "every", vec![Value::Int(200)], limits);
+        assert_eq!(Err(RuntimeError::Limit(Limit::Memory)), result.map(|_| ()));
+    }
+
+    #[test]
+    fn limits_handler_depth() {
+        let src = format!(
+            "{CHOOSE}\nfn nest(n: Int) -> Int = {{
+                if n == 0 {{ choose() }} else {{
+                    do {{ nest(n - 1) }} with handle choice {{ fn choose() -> Int = {{ 7 }} }}
+                }}
+            }}"
+        );
+        let limits = Limits {
+            handler_depth: Some(10),
+            ..Limits::default()
+        };
+        let values = run_limited(&src, "nest", vec![Value::Int(5)], limits.clone());
+        assert!(matches!(values.as_deref(), Ok([Value::Int(7)])));
+        let result = run_limited(&src, "nest", vec![Value::Int(20)], limits);
+        assert_eq!(
+            Err(RuntimeError::Limit(Limit::HandlerDepth)),
+            result.map(|_| ())
+        );
+    }
+
+    #[test]
+    fn finally_on_normal_exit() {
+        // noted digits are read most recent first
+        assert_eq!(Ok(vec![321]), run(FINALLY, "normal"));
+    }
+
+    #[test]
+    fn finally_on_escape() {
+        assert_eq!(Ok(vec![321]), run(FINALLY, "escape"));
+        assert_eq!(Ok(vec![4321]), run(FINALLY, "break_out"));
+    }
+
+    #[test]
+    fn finally_on_discarded_continuation() {
+        assert_eq!(Ok(vec![4321]), run(FINALLY, "discard"));
+    }
+
+    #[test]
+    fn finally_runs_innermost_first() {
+        assert_eq!(Ok(vec![4321]), run(FINALLY, "nested"));
+    }
+
+    #[test]
+    fn finally_runs_once_when_resumed_twice() {
+        // both resumptions note 1, then the scope is left once
+        assert_eq!(Ok(vec![3211]), run(FINALLY, "resumed"));
+    }
+
+    #[test]
+    fn return_clause_transforms_result() {
+        assert_eq!(Ok(vec![10]), run(RETURN, "scaled"));
+        // every result of the computation becomes a (sum, count) pair, summing to 0 + 1 + 2 + 3
+        assert_eq!(Ok(vec![64]), run(RETURN, "collect"));
+    }
+
+    #[test]
+    fn prints_and_reads_lines() {
+        let (program, function) = compile(IO, "greet");
+        let mut console = Captured::new(["world"]);
+        let machine = Machine::new(&program, function, Vec::new()).expect("machine");
+        machine.with_console(&mut console).run().expect("greet");
+        assert_eq!(vec!["Hello, world!", "\tdone"], console.output);
+    }
+
+    #[test]
+    fn handles_io_in_program() {
+        let (program, function) = compile(IO, "silenced");
+        let mut console = Captured::default();
+        let machine = Machine::new(&program, function, Vec::new()).expect("machine");
+        let values = machine.with_console(&mut console).run().expect("silenced");
+        assert!(matches!(values[..], [Value::Int(2)]));
+        assert!(console.output.is_empty());
+    }
+
+    #[test]
+    fn collects_cycles() {
+        // each closure is stored in a local of the frame it captures
+        let src = r#"
+fn spin(n: Int) -> Int = {
+    let f: {Int} = { n };
+    if n == 0 { f() } else { spin(n - 1) }
+}
+"#;
+        let (program, function) = compile(src, "spin");
+        let limits = Limits {
+            memory: Some(64 * 1024),
+            ..Limits::default()
+        };
+        let mut machine = Machine::new(&program, function, vec![Value::Int(100_000)])
+            .expect("machine")
+            .with_limits(limits);
+        let values = machine.run().expect("spin");
+        assert!(matches!(values[..], [Value::Int(0)]));
+        let stats = machine.heap_stats();
+        assert!(stats.collected >= 99_000, "{stats:?}");
+        machine.collect_garbage();
+        assert!(machine.heap_stats().live_bytes < 1024);
+    }
+
+    #[test]
+    fn collects_at_every_allocation_under_stress() {
+        let programs = [
+            (CHOOSE, &["all", "count", "first", "none", "last"][..]),
+            (RETURN, &["scaled", "collect"]),
+            (
+                FINALLY,
+                &[
+                    "normal",
+                    "escape",
+                    "break_out",
+                    "discard",
+                    "nested",
+                    "resumed",
+                ],
+            ),
+        ];
+        for (src, functions) in programs {
+            for &function in functions {
+                let (program, key) = compile(src, function);
+                let expected = call(&program, key, Vec::new());
+                let mut machine = Machine::new(&program, key, Vec::new())
+                    .expect("machine")
+                    .with_gc_stress(true);
+                let found = machine.run();
+                assert_eq!(format!("{expected:?}"), format!("{found:?}"), "{function}");
+                // the machine allocates its first environments before it's put under stress
+                let stats = machine.heap_stats();
+                assert!(stats.allocations - stats.collections <= 2, "{stats:?}");
+            }
+        }
+    }
+}
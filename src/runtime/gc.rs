@@ -0,0 +1,357 @@
+//! Cycle collection for the heap objects behind runtime values.
+//!
+//! Values are reference counted, which frees everything except cycles, and programs make cycles
+//! all the time: a closure stored in a local of the frame it captures, a handler holding `return`,
+//! or a continuation captured inside its own resumption. Closures, handlers, resumptions and
+//! pending `finally` blocks never change once made, so each can only refer to objects older than
+//! itself. Environments are the exception, since their slots are assigned, and so every cycle
+//! passes through an environment.
+//!
+//! The heap therefore tracks environments, and a collection finds the ones kept alive only by
+//! cycles by trial deletion. It walks everything reachable from the tracked environments and counts
+//! the references each object gets from the others. An object whose reference count is higher
+//! is also referenced from outside the heap, by the machine or the host, so it's live along with
+//! everything it reaches. The environments left over are garbage, and emptying their slots breaks
+//! the cycles so that reference counting frees them.
+
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    fmt::{self, Display, Formatter},
+    hash::{BuildHasherDefault, Hasher},
+    rc::{Rc, Weak},
+};
+
+use super::{ClosureValue, Env, HandlerValue, Pending, Resumption, Value};
+
+/// The number of bytes of environments allocated before the first collection.
+const INITIAL_THRESHOLD: usize = 1 << 20;
+
+/// The number of environments tracked before the first sweep of those already freed.
+const INITIAL_SWEEP: usize = 1024;
+
+/// Statistics about a machine's heap.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct HeapStats {
+    /// The number of objects allocated: environments, closures, handlers, resumptions, and pending
+    /// `finally` blocks.
+    pub allocations: u64,
+    pub collections: u64,
+    /// The number of environments freed by collections, which reference counting alone would leak.
+    pub collected: u64,
+    /// The number of bytes taken up by live objects.
+    pub live_bytes: usize,
+    /// The most bytes live objects have taken up at once.
+    pub peak_bytes: usize,
+}
+
+impl Display for HeapStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "heap: {} allocations, {} collections, {} environments collected, {} bytes live, {} \
+             bytes at peak",
+            self.allocations, self.collections, self.collected, self.live_bytes, self.peak_bytes
+        )
+    }
+}
+
+pub(super) struct Heap {
+    /// Every environment allocated since the last collection, or that survived it. Most are freed
+    /// by reference counting long before a collection, so the ones that were are swept out whenever
+    /// the list doubles, as each still holds on to the memory of its environment.
+    envs: Vec<Weak<Env>>,
+    /// The number of tracked environments at which to sweep out the freed ones.
+    sweep: usize,
+    /// The number of bytes taken up by live objects, kept up to date as they're dropped.
+    pub(super) meter: Rc<Cell<usize>>,
+    /// The number of bytes of live objects at which to collect next.
+    threshold: usize,
+    /// Whether to collect at every allocation, to shake out bugs that would otherwise be rare.
+    pub(super) stress: bool,
+    stats: HeapStats,
+}
+
+impl Heap {
+    pub(super) fn new() -> Self {
+        Heap {
+            envs: Vec::new(),
+            sweep: INITIAL_SWEEP,
+            meter: Rc::new(Cell::new(0)),
+            threshold: INITIAL_THRESHOLD,
+            stress: false,
+            stats: HeapStats::default(),
+        }
+    }
+
+    /// Allocates an environment.
+    pub(super) fn env(&mut self, parent: Option<Rc<Env>>, slots: Vec<Value>) -> Rc<Env> {
+        let size = std::mem::size_of::<Env>() + slots.capacity() * std::mem::size_of::<Value>();
+        let env = Rc::new(Env {
+            parent,
+            slots: RefCell::new(slots),
+            _charge: self.charge(size),
+        });
+        self.envs.push(Rc::downgrade(&env));
+        if self.envs.len() >= self.sweep {
+            self.sweep_envs();
+        }
+        self.allocated();
+        env
+    }
+
+    /// Counts the bytes of an object as live until the object, and with it the charge, is dropped.
+    pub(super) fn charge(&self, size: usize) -> Charge {
+        self.meter.set(self.meter.get() + size);
+        Charge {
+            size,
+            meter: self.meter.clone(),
+        }
+    }
+
+    /// Records the allocation of an object, collecting if it's time to.
+    pub(super) fn allocated(&mut self) {
+        self.stats.allocations += 1;
+        let live = self.meter.get();
+        self.stats.peak_bytes = self.stats.peak_bytes.max(live);
+        if self.stress || live > self.threshold {
+            self.collect();
+        }
+    }
+
+    fn sweep_envs(&mut self) {
+        self.envs.retain(|env| env.strong_count() > 0);
+        self.sweep = INITIAL_SWEEP.max(2 * self.envs.len());
+    }
+
+    pub(super) fn stats(&self) -> HeapStats {
+        HeapStats {
+            live_bytes: self.meter.get(),
+            ..self.stats
+        }
+    }
+
+    /// Frees the environments that only cycles keep alive.
+    pub(super) fn collect(&mut self) {
+        self.stats.collections += 1;
+        self.sweep_envs();
+        let mut graph = Graph::default();
+        for env in &self.envs {
+            if let Some(env) = env.upgrade() {
+                graph.discover(Object::Env(env));
+            }
+        }
+        let mut children = Vec::new();
+        let mut scanned = 0;
+        while let Some(node) = graph.nodes.get(scanned) {
+            node.object.children(&mut children);
+            for child in children.drain(..) {
+                let child = graph.discover(child);
+                graph.nodes[child].internal += 1;
+                graph.edges.push(child);
+            }
+            graph.nodes[scanned].edges = graph.edges.len();
+            scanned += 1;
+        }
+
+        // the graph holds one reference to each object itself
+        let mut live = (0..graph.nodes.len())
+            .filter(|&idx| {
+                let node = &graph.nodes[idx];
+                node.object.strong_count() - 1 > node.internal
+            })
+            .collect::<Vec<_>>();
+        for &idx in &live {
+            graph.nodes[idx].live = true;
+        }
+        while let Some(idx) = live.pop() {
+            let start = idx.checked_sub(1).map_or(0, |prev| graph.nodes[prev].edges);
+            for &child in &graph.edges[start..graph.nodes[idx].edges] {
+                if !graph.nodes[child].live {
+                    graph.nodes[child].live = true;
+                    live.push(child);
+                }
+            }
+        }
+
+        let mut garbage = Vec::new();
+        for node in &graph.nodes {
+            if let (Object::Env(env), false) = (&node.object, node.live) {
+                garbage.push(std::mem::take(&mut *env.slots.borrow_mut()));
+                self.stats.collected += 1;
+            }
+        }
+        // the cycles are broken, so dropping the graph's references frees the garbage
+        drop(graph);
+        drop(garbage);
+        self.sweep_envs();
+        self.threshold = INITIAL_THRESHOLD.max(2 * self.meter.get());
+    }
+}
+
+/// The bytes an object takes up, counted as live for as long as it is. Objects hold one only to drop
+/// it along with themselves.
+#[derive(Debug)]
+pub(super) struct Charge {
+    size: usize,
+    meter: Rc<Cell<usize>>,
+}
+
+impl Clone for Charge {
+    fn clone(&self) -> Self {
+        self.meter.set(self.meter.get() + self.size);
+        Charge {
+            size: self.size,
+            meter: self.meter.clone(),
+        }
+    }
+}
+
+impl Drop for Charge {
+    fn drop(&mut self) {
+        self.meter.set(self.meter.get() - self.size);
+    }
+}
+
+/// A reference to a heap object, which keeps it alive while a collection looks at it.
+enum Object {
+    Env(Rc<Env>),
+    Closure(Rc<ClosureValue>),
+    Handler(Rc<HandlerValue>),
+    Resumption(Rc<Resumption>),
+    Pending(Rc<Pending>),
+}
+
+impl Object {
+    fn from_value(value: &Value) -> Option<Self> {
+        Some(match value {
+            Value::Closure(closure) => Object::Closure(closure.clone()),
+            Value::Handler(handler) => Object::Handler(handler.clone()),
+            Value::Resume(resumption) => Object::Resumption(resumption.clone()),
+            Value::Then(pending) => Object::Pending(pending.clone()),
+            Value::Unit
+            | Value::Int(_)
+            | Value::String(_)
+            | Value::Function(_)
+            | Value::Exit(_)
+            | Value::Halt => return None,
+        })
+    }
+
+    /// Identifies the object by its address.
+    fn id(&self) -> usize {
+        match self {
+            Object::Env(rc) => Rc::as_ptr(rc) as usize,
+            Object::Closure(rc) => Rc::as_ptr(rc) as usize,
+            Object::Handler(rc) => Rc::as_ptr(rc) as usize,
+            Object::Resumption(rc) => Rc::as_ptr(rc) as usize,
+            Object::Pending(rc) => Rc::as_ptr(rc) as usize,
+        }
+    }
+
+    fn strong_count(&self) -> usize {
+        match self {
+            Object::Env(rc) => Rc::strong_count(rc),
+            Object::Closure(rc) => Rc::strong_count(rc),
+            Object::Handler(rc) => Rc::strong_count(rc),
+            Object::Resumption(rc) => Rc::strong_count(rc),
+            Object::Pending(rc) => Rc::strong_count(rc),
+        }
+    }
+
+    /// Adds the objects this one refers to, once per reference.
+    fn children(&self, children: &mut Vec<Object>) {
+        let values = |children: &mut Vec<Object>, values: &[Value]| {
+            children.extend(values.iter().filter_map(Object::from_value));
+        };
+        match self {
+            Object::Env(env) => {
+                children.extend(env.parent.clone().map(Object::Env));
+                values(children, &env.slots.borrow());
+            }
+            Object::Closure(closure) => children.extend(closure.env.clone().map(Object::Env)),
+            Object::Handler(handler) => children.push(Object::Env(handler.env.clone())),
+            Object::Resumption(resumption) => {
+                values(children, std::slice::from_ref(&resumption.k));
+                for frame in &resumption.frames {
+                    children.extend(frame.handler.clone().map(Object::Handler));
+                    values(children, std::slice::from_ref(&frame.cc));
+                    children.extend(frame.suspended.clone().map(Object::Resumption));
+                }
+            }
+            Object::Pending(pending) => {
+                for (_, env) in &pending.finallies {
+                    children.push(Object::Env(env.clone()));
+                }
+                values(children, std::slice::from_ref(&pending.callee));
+                values(children, &pending.args);
+            }
+        }
+    }
+}
+
+/// The objects reachable from the tracked environments, and the references between them.
+#[derive(Default)]
+struct Graph {
+    /// The index of each object in `nodes`, by address.
+    index: HashMap<usize, usize, BuildHasherDefault<AddressHasher>>,
+    nodes: Vec<Node>,
+    /// The references from each object to others, as indices, in the order of the objects.
+    edges: Vec<usize>,
+}
+
+struct Node {
+    object: Object,
+    /// The number of references to the object from other objects in the graph.
+    internal: usize,
+    /// The end of the object's references in `edges`, which start where the previous object's end.
+    edges: usize,
+    live: bool,
+}
+
+impl Graph {
+    /// Adds an object if it's new, returning its index.
+    fn discover(&mut self, object: Object) -> usize {
+        let next = self.nodes.len();
+        let idx = *self.index.entry(object.id()).or_insert(next);
+        // a reference to an object already in the graph is dropped here, leaving the one it holds
+        if idx == next {
+            self.nodes.push(Node {
+                object,
+                internal: 0,
+                edges: 0,
+                live: false,
+            });
+        }
+        idx
+    }
+}
+
+/// Hashes addresses, which only need mixing to spread them out.
+#[derive(Default)]
+struct AddressHasher(u64);
+
+impl Hasher for AddressHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.write_u8(byte);
+        }
+    }
+
+    fn write_u8(&mut self, byte: u8) {
+        self.write_u64(u64::from(byte));
+    }
+
+    fn write_usize(&mut self, addr: usize) {
+        // objects are at least 8-aligned
+        self.write_u64(addr as u64 >> 3);
+    }
+
+    fn write_u64(&mut self, n: u64) {
+        self.0 = (self.0.rotate_left(5) ^ n).wrapping_mul(0x517c_c1b7_2722_0a95);
+    }
+}
@@ -0,0 +1,399 @@
+//! Native functions and effect handlers provided by the program's host.
+//!
+//! A top-level function declared without a body, such as `fn now() -> Int;`, calls the host function
+//! registered under its name. An operation that no handler in the program handles goes to the
+//! native handler registered under its qualified name, such as `db::get`, before falling back to
+//! the console. A native handler stands outside every handler in the program, so its continuation
+//! captures the whole handler stack: it can resume the operation right away, abort the program, or
+//! keep the [`Continuation`] to resume with [`Machine::resume`](super::Machine::resume) once the
+//! program has halted.
+//!
+//! Host functions are ordinary Rust closures whose parameter and result types convert to and from
+//! runtime values with [`FromValue`] and [`IntoValue`].
+
+use std::{collections::HashMap, rc::Rc};
+
+use super::{type_error, Resumption, RuntimeError, Value};
+
+/// Converts a Rust value to a runtime value.
+pub trait IntoValue {
+    fn into_value(self) -> Value;
+}
+
+impl IntoValue for Value {
+    fn into_value(self) -> Value {
+        self
+    }
+}
+
+impl IntoValue for i64 {
+    fn into_value(self) -> Value {
+        Value::Int(self)
+    }
+}
+
+impl IntoValue for bool {
+    fn into_value(self) -> Value {
+        Value::Int(self as i64)
+    }
+}
+
+impl IntoValue for String {
+    fn into_value(self) -> Value {
+        Value::String(self.into())
+    }
+}
+
+impl IntoValue for &str {
+    fn into_value(self) -> Value {
+        Value::String(self.into())
+    }
+}
+
+impl IntoValue for Rc<str> {
+    fn into_value(self) -> Value {
+        Value::String(self)
+    }
+}
+
+/// Converts a runtime value to a Rust value, failing if it has the wrong type.
+pub trait FromValue: Sized {
+    fn from_value(value: Value) -> Result<Self, RuntimeError>;
+}
+
+impl FromValue for Value {
+    fn from_value(value: Value) -> Result<Self, RuntimeError> {
+        Ok(value)
+    }
+}
+
+impl FromValue for () {
+    fn from_value(value: Value) -> Result<Self, RuntimeError> {
+        match value {
+            Value::Unit => Ok(()),
+            other => Err(type_error("unit", &other)),
+        }
+    }
+}
+
+impl FromValue for i64 {
+    fn from_value(value: Value) -> Result<Self, RuntimeError> {
+        match value {
+            Value::Int(v) => Ok(v),
+            other => Err(type_error("an integer", &other)),
+        }
+    }
+}
+
+impl FromValue for bool {
+    fn from_value(value: Value) -> Result<Self, RuntimeError> {
+        i64::from_value(value).map(|v| v != 0)
+    }
+}
+
+impl FromValue for Rc<str> {
+    fn from_value(value: Value) -> Result<Self, RuntimeError> {
+        match value {
+            Value::String(s) => Ok(s),
+            other => Err(type_error("a string", &other)),
+        }
+    }
+}
+
+impl FromValue for String {
+    fn from_value(value: Value) -> Result<Self, RuntimeError> {
+        Rc::<str>::from_value(value).map(|s| s.to_string())
+    }
+}
+
+/// Converts what a host function returns to the values it returns with.
+pub trait IntoValues {
+    fn into_values(self) -> Result<Vec<Value>, RuntimeError>;
+}
+
+impl IntoValues for () {
+    fn into_values(self) -> Result<Vec<Value>, RuntimeError> {
+        Ok(Vec::new())
+    }
+}
+
+impl<T: IntoValue> IntoValues for T {
+    fn into_values(self) -> Result<Vec<Value>, RuntimeError> {
+        Ok(vec![self.into_value()])
+    }
+}
+
+impl<A: IntoValue, B: IntoValue> IntoValues for (A, B) {
+    fn into_values(self) -> Result<Vec<Value>, RuntimeError> {
+        Ok(vec![self.0.into_value(), self.1.into_value()])
+    }
+}
+
+impl<A: IntoValue, B: IntoValue, C: IntoValue> IntoValues for (A, B, C) {
+    fn into_values(self) -> Result<Vec<Value>, RuntimeError> {
+        Ok(vec![
+            self.0.into_value(),
+            self.1.into_value(),
+            self.2.into_value(),
+        ])
+    }
+}
+
+impl IntoValues for Vec<Value> {
+    fn into_values(self) -> Result<Vec<Value>, RuntimeError> {
+        Ok(self)
+    }
+}
+
+impl<T: IntoValues> IntoValues for Result<T, RuntimeError> {
+    fn into_values(self) -> Result<Vec<Value>, RuntimeError> {
+        self?.into_values()
+    }
+}
+
+/// A Rust closure callable from a program. `Args` is the tuple of its parameter types.
+pub trait HostFunction<Args> {
+    /// The number of parameters.
+    fn arity(&self) -> usize;
+
+    /// Calls the closure with exactly [`arity`](Self::arity) arguments.
+    fn invoke(&mut self, args: Vec<Value>) -> Result<Vec<Value>, RuntimeError>;
+}
+
+macro_rules! host_function {
+    ($($ty:ident $arg:ident),*) => {
+        impl<F, R, $($ty),*> HostFunction<($($ty,)*)> for F
+        where
+            F: FnMut($($ty),*) -> R,
+            R: IntoValues,
+            $($ty: FromValue,)*
+        {
+            fn arity(&self) -> usize {
+                <[&str]>::len(&[$(stringify!($arg)),*])
+            }
+
+            #[allow(unused_mut, unused_variables)]
+            fn invoke(&mut self, args: Vec<Value>) -> Result<Vec<Value>, RuntimeError> {
+                let mut args = args.into_iter();
+                $(let $arg = $ty::from_value(args.next().expect("arity is checked"))?;)*
+                self($($arg),*).into_values()
+            }
+        }
+    };
+}
+
+host_function!();
+host_function!(A a);
+host_function!(A a, B b);
+host_function!(A a, B b, C c);
+host_function!(A a, B b, C c, D d);
+
+/// The continuation of an operation a native handler handles, including every handler frame in the
+/// program at the time. It can be resumed any number of times.
+#[derive(Clone, Debug)]
+pub struct Continuation(pub(super) Rc<Resumption>);
+
+/// What the program does after a native handler returns.
+#[derive(Clone, Debug)]
+pub enum Control {
+    /// Continue after the operation with the given results.
+    Resume(Vec<Value>),
+    /// Halt with the given results, leaving every handler in the program.
+    Abort(Vec<Value>),
+}
+
+pub(super) type NativeFunction<'a> =
+    Box<dyn FnMut(Vec<Value>) -> Result<Vec<Value>, RuntimeError> + 'a>;
+
+pub(super) type NativeHandler<'a> =
+    Box<dyn FnMut(Vec<Value>, Continuation) -> Result<Control, RuntimeError> + 'a>;
+
+/// The native functions and effect handlers to run a program with.
+#[derive(Default)]
+pub struct Host<'a> {
+    pub(super) functions: HashMap<String, NativeFunction<'a>>,
+    pub(super) handlers: HashMap<String, NativeHandler<'a>>,
+}
+
+impl<'a> Host<'a> {
+    /// Creates a host providing nothing.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Provides the body of the top-level function declared with the given name.
+    pub fn function<Args>(
+        &mut self,
+        name: &str,
+        mut function: impl HostFunction<Args> + 'a,
+    ) -> &mut Self {
+        let arity = function.arity();
+        let native = move |args: Vec<Value>| {
+            if args.len() != arity {
+                return Err(RuntimeError::Arity {
+                    expected: arity,
+                    found: args.len(),
+                });
+            }
+            function.invoke(args)
+        };
+        self.functions.insert(name.to_owned(), Box::new(native));
+        self
+    }
+
+    /// Handles the operation with the given qualified name, such as `db::get`, when the program
+    /// doesn't.
+    pub fn handler(
+        &mut self,
+        op: &str,
+        handler: impl FnMut(Vec<Value>, Continuation) -> Result<Control, RuntimeError> + 'a,
+    ) -> &mut Self {
+        self.handlers.insert(op.to_owned(), Box::new(handler));
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use crate::runtime::{tests::compile, Captured, Machine};
+
+    use super::*;
+
+    const HOST: &str = r#"
+effect db {
+    fn get(key: String) -> Int;
+}
+
+effect log {
+    fn log(s: String) -> ();
+}
+
+fn sum3(a: Int, b: Int, c: Int) -> Int;
+fn shout(s: String) -> String;
+
+fn natives() -> Int = {
+    let s: String = shout("hi");
+    if s == "HI!" { sum3(1, 2, 3) * 2 } else { 0 }
+}
+
+fn lookup() -> Int = {
+    get("a") + get("b")
+}
+
+fn guarded() -> Int = {
+    do {
+        let a: Int = get("a");
+        let b: Int = get("missing");
+        a + b
+    } with handle log {
+        fn log(s: String) -> () = { :return; }
+        finally { print("cleanup"); }
+    }
+}
+
+fn logged() -> Int = {
+    do {
+        log("x");
+        get("a")
+    } with handle log {
+        fn log(s: String) -> () = { :return; }
+    }
+}
+"#;
+
+    fn get(args: Vec<Value>, _: Continuation) -> Result<Control, RuntimeError> {
+        let [key] = <[Value; 1]>::try_from(args).expect("one argument");
+        match &*Rc::<str>::from_value(key)? {
+            "a" => Ok(Control::Resume(vec![1.into_value()])),
+            "b" => Ok(Control::Resume(vec![2.into_value()])),
+            _ => Ok(Control::Abort(vec![(-1).into_value()])),
+        }
+    }
+
+    fn ints(values: Vec<Value>) -> Vec<i64> {
+        values
+            .into_iter()
+            .map(|v| i64::from_value(v).expect("integer"))
+            .collect()
+    }
+
+    #[test]
+    fn calls_host_functions() {
+        let (program, function) = compile(HOST, "natives");
+        let mut host = Host::new();
+        host.function("sum3", |a: i64, b: i64, c: i64| a + b + c)
+            .function("shout", |s: String| s.to_uppercase() + "!");
+        let mut machine = Machine::new(&program, function, Vec::new())
+            .expect("machine")
+            .with_host(host);
+        assert_eq!(vec![12], ints(machine.run().expect("natives")));
+
+        let mut machine = Machine::new(&program, function, Vec::new()).expect("machine");
+        assert!(matches!(machine.run(), Err(RuntimeError::NoBody(_))));
+    }
+
+    #[test]
+    fn converts_values() {
+        assert_eq!(Ok(true), bool::from_value(Value::Int(3)));
+        assert_eq!(Ok("x".to_owned()), String::from_value("x".into_value()));
+        assert!(matches!(
+            i64::from_value("x".into_value()),
+            Err(RuntimeError::Type {
+                expected: "an integer",
+                ..
+            })
+        ));
+        let mut add = |a: i64, b: i64| (a + b, a - b);
+        assert_eq!(2, HostFunction::arity(&add));
+        let values = add.invoke(vec![Value::Int(5), Value::Int(3)]).expect("add");
+        assert_eq!(vec![8, 2], ints(values));
+    }
+
+    #[test]
+    fn handles_operations_natively() {
+        let (program, function) = compile(HOST, "lookup");
+        let mut host = Host::new();
+        host.handler("db::get", get);
+        let mut machine = Machine::new(&program, function, Vec::new())
+            .expect("machine")
+            .with_host(host);
+        assert_eq!(vec![3], ints(machine.run().expect("lookup")));
+    }
+
+    #[test]
+    fn aborts_through_finally() {
+        let (program, function) = compile(HOST, "guarded");
+        let mut host = Host::new();
+        host.handler("db::get", get);
+        let mut console = Captured::default();
+        let mut machine = Machine::new(&program, function, Vec::new())
+            .expect("machine")
+            .with_host(host)
+            .with_console(&mut console);
+        assert_eq!(vec![-1], ints(machine.run().expect("guarded")));
+        drop(machine);
+        assert_eq!(vec!["cleanup"], console.output);
+    }
+
+    #[test]
+    fn resumes_kept_continuation() {
+        let (program, function) = compile(HOST, "logged");
+        let kept = RefCell::new(None);
+        let mut host = Host::new();
+        host.handler("db::get", |_, k| {
+            *kept.borrow_mut() = Some(k);
+            Ok(Control::Abort(Vec::new()))
+        });
+        let mut machine = Machine::new(&program, function, Vec::new())
+            .expect("machine")
+            .with_host(host);
+        assert!(machine.run().expect("logged").is_empty());
+        let k = kept.borrow_mut().take().expect("continuation");
+        for v in [10, 20] {
+            let values = machine.resume(&k, vec![Value::Int(v)]).expect("resumed");
+            assert_eq!(vec![v], ints(values));
+        }
+    }
+}
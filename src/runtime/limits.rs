@@ -0,0 +1,59 @@
+//! Resource limits for running untrusted programs.
+//!
+//! A machine stops with [`RuntimeError::Limit`](super::RuntimeError::Limit) when a program exceeds
+//! one of its limits, or soon after it is cancelled.
+
+use std::{
+    fmt::{self, Display, Formatter},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+/// The number of instructions between checks of the cancellation flag.
+pub(super) const CANCEL_INTERVAL: u64 = 1024;
+
+/// The resources a program may use. Each limit is unlimited if `None`.
+#[derive(Clone, Debug, Default)]
+pub struct Limits {
+    /// The number of instructions the program may execute.
+    pub fuel: Option<u64>,
+    /// The number of bytes that live environments, closures, handlers and resumptions may take up,
+    /// counting the handler frames a resumption captures. No single string may be longer than
+    /// this either.
+    pub memory: Option<usize>,
+    /// The number of handler frames that may be on the handler stack at once.
+    pub handler_depth: Option<usize>,
+    /// Stops the program when set.
+    pub cancel: Option<Arc<AtomicBool>>,
+}
+
+impl Limits {
+    /// Whether the cancellation flag is set.
+    pub(super) fn cancelled(&self) -> bool {
+        self.cancel
+            .as_ref()
+            .is_some_and(|cancel| cancel.load(Ordering::Relaxed))
+    }
+}
+
+/// A limit that a program exceeded.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Limit {
+    Fuel,
+    Memory,
+    HandlerDepth,
+    Cancelled,
+}
+
+impl Display for Limit {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Limit::Fuel => f.write_str("ran out of fuel"),
+            Limit::Memory => f.write_str("exceeded the memory limit"),
+            Limit::HandlerDepth => f.write_str("exceeded the handler depth limit"),
+            Limit::Cancelled => f.write_str("cancelled"),
+        }
+    }
+}
@@ -0,0 +1,343 @@
+//! Logical backtraces, and the other views of a running machine that error reports and debuggers
+//! use.
+//!
+//! Code in continuation-passing style has no call stack to walk, but the continuations waiting for
+//! the running code's results form a chain that plays the same role: each closure's continuation
+//! is a parameter of it or of the closure it's nested in, and each continuation's environment holds
+//! the next. Results leaving a `do ... with` go through its handler frame, which holds the
+//! continuation after it. The handler stack is reported alongside, including which `do ... with`
+//! each operation being handled was handled by.
+
+use std::{
+    fmt::{self, Display, Formatter},
+    rc::Rc,
+};
+
+use crate::{mir::Program, span::Span, symbol::SymbolKey};
+
+use super::{Env, Machine, Value};
+
+/// The number of frames shown at each end of a long backtrace.
+const SHOWN: usize = 16;
+
+/// A snapshot of where a machine is and what it will do next.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Backtrace {
+    /// The running code, then the continuations waiting for its results, innermost first.
+    pub frames: Vec<TraceFrame>,
+    /// The handler stack, innermost first.
+    pub handlers: Vec<HandlerTrace>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TraceFrame {
+    /// Code in a function, at the start of the closure it's in: the function's name, or the call
+    /// whose results it receives.
+    Code { function: String, span: Span },
+    /// Results leaving the `do ... with` whose `with` is at the span.
+    Exit { span: Span },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum HandlerTrace {
+    /// The handler of the `do ... with` whose `with` is at the span.
+    Scope { span: Span },
+    /// An operation being handled by the handler of the `do ... with` at the span, or by the host.
+    Handling {
+        op: String,
+        by: Option<Span>,
+        /// Where resuming the operation continues, or `None` if it never returns.
+        resume: Option<TraceFrame>,
+    },
+}
+
+impl Machine<'_> {
+    /// Reconstructs the logical backtrace at the current point, such as where an error occurred.
+    pub fn backtrace(&self) -> Backtrace {
+        let program = self.program;
+        let mut frames = Vec::new();
+        let mut code = Some((self.key, Some(self.env.clone())));
+        while let Some((key, env)) = code.take() {
+            let Some(closure) = program.closures.get(&key) else {
+                break;
+            };
+            frames.push(TraceFrame::Code {
+                function: describe(program, key),
+                span: closure.span,
+            });
+            let mut k = env.and_then(|env| continuation(program, key, env));
+            while let Some(value) = k.take() {
+                match value {
+                    Value::Closure(closure) => code = Some((closure.key, closure.env.clone())),
+                    Value::Exit(id) => {
+                        if let Some(frame) = self.handlers.iter().find(|frame| frame.id == id) {
+                            frames.push(TraceFrame::Exit { span: frame.span });
+                            k = Some(frame.cc.clone());
+                        }
+                    }
+                    Value::Then(pending) => k = Some(pending.callee.clone()),
+                    _ => {}
+                }
+            }
+        }
+        let handlers = self.handlers[1..]
+            .iter()
+            .rev()
+            .map(|frame| match &frame.suspended {
+                Some(resumption) => HandlerTrace::Handling {
+                    op: name(program, resumption.op),
+                    by: (!resumption.native)
+                        .then(|| resumption.frames.first().map(|frame| frame.span))
+                        .flatten(),
+                    resume: match &resumption.k {
+                        Value::Closure(k) => {
+                            program
+                                .closures
+                                .get(&k.key)
+                                .map(|closure| TraceFrame::Code {
+                                    function: describe(program, k.key),
+                                    span: closure.span,
+                                })
+                        }
+                        Value::Exit(id) => resumption
+                            .frames
+                            .iter()
+                            .find(|frame| frame.id == *id)
+                            .map(|frame| TraceFrame::Exit { span: frame.span }),
+                        _ => None,
+                    },
+                },
+                None => HandlerTrace::Scope { span: frame.span },
+            })
+            .collect();
+        Backtrace { frames, handlers }
+    }
+
+    /// The span of the statement the next instruction belongs to, if known.
+    pub fn span(&self) -> Option<Span> {
+        let closure = self.program.closures.get(&self.key)?;
+        closure.spans.get(self.pc).copied()
+    }
+
+    /// The closure being executed and the values of its locals, then those of the closures it's
+    /// nested in, up to one whose environment is a record rather than their frames.
+    pub fn locals(&self) -> Vec<(SymbolKey, Vec<Value>)> {
+        let mut locals = Vec::new();
+        let mut key = Some(self.key);
+        let mut env = Some(&self.env);
+        while let (Some(k), Some(e)) = (key, env) {
+            locals.push((k, e.slots.borrow().clone()));
+            key = self
+                .program
+                .closures
+                .get(&k)
+                .filter(|closure| closure.captures.is_none())
+                .and_then(|closure| closure.parent);
+            env = e.parent.as_ref();
+        }
+        locals
+    }
+}
+
+/// Finds the continuation of a closure, which is a parameter of it or of an enclosing closure,
+/// wherever the closure's environment keeps it.
+fn continuation(program: &Program, key: SymbolKey, env: Rc<Env>) -> Option<Value> {
+    let mut owner = key;
+    let slot = loop {
+        let closure = program.closures.get(&owner)?;
+        if let Some(slot) = closure.cont {
+            break slot;
+        }
+        owner = closure.parent?;
+    };
+    let (depth, slot) = program.locate(key, owner, slot)?;
+    let env = (0..depth).try_fold(env, |env, _| env.parent.clone())?;
+    let value = env.slots.borrow().get(slot).cloned();
+    value
+}
+
+fn name(program: &Program, key: SymbolKey) -> String {
+    program
+        .names
+        .get(&key)
+        .cloned()
+        .unwrap_or_else(|| "<anonymous>".to_owned())
+}
+
+/// Names the code a closure belongs to: its function, or the handler clause it's in.
+fn describe(program: &Program, mut key: SymbolKey) -> String {
+    let mut clause = None;
+    loop {
+        if clause.is_none() {
+            clause = program.handlers.values().find_map(|handler| {
+                let action = handler.actions.iter().find(|&&(_, action)| action == key);
+                match action {
+                    Some(&(op, _)) => Some(format!("handler of {}", name(program, op))),
+                    None if handler.ret == Some(key) => Some("return clause".to_owned()),
+                    None if handler.finally == Some(key) => Some("finally block".to_owned()),
+                    None => None,
+                }
+            });
+        }
+        match program
+            .closures
+            .get(&key)
+            .and_then(|closure| closure.parent)
+        {
+            Some(parent) => key = parent,
+            None => break,
+        }
+    }
+    let function = name(program, key);
+    match clause {
+        Some(clause) => format!("{clause} in {function}"),
+        None => function,
+    }
+}
+
+impl Backtrace {
+    /// Writes the backtrace with line and column numbers in the given source instead of offsets.
+    pub fn render(&self, src: &str) -> String {
+        let location = |span: Span| {
+            let before = &src[..span.pos.min(src.len())];
+            let line = before.matches('\n').count() + 1;
+            let column = before.len() - before.rfind('\n').map_or(0, |idx| idx + 1) + 1;
+            format!("{line}:{column}")
+        };
+        self.write(location)
+    }
+
+    fn write(&self, location: impl Fn(Span) -> String) -> String {
+        let show = |frame: &TraceFrame| match frame {
+            TraceFrame::Code { function, span } => format!("{function} ({})", location(*span)),
+            TraceFrame::Exit { span } => format!("leaving do ... with ({})", location(*span)),
+        };
+        let mut out = String::new();
+        let skipped = self.frames.len().saturating_sub(2 * SHOWN);
+        for (idx, frame) in self.frames.iter().enumerate() {
+            if skipped > 0 && idx == SHOWN {
+                out += &format!("  ... {skipped} more\n");
+            }
+            if skipped > 0 && (SHOWN..SHOWN + skipped).contains(&idx) {
+                continue;
+            }
+            match frame {
+                TraceFrame::Code { .. } => out += &format!("  at {}\n", show(frame)),
+                TraceFrame::Exit { .. } => out += &format!("  {}\n", show(frame)),
+            }
+        }
+        if !self.handlers.is_empty() {
+            out += "handlers:\n";
+        }
+        for handler in &self.handlers {
+            match handler {
+                HandlerTrace::Scope { span } => {
+                    out += &format!("  do ... with ({})\n", location(*span))
+                }
+                HandlerTrace::Handling { op, by, resume } => {
+                    let by = match by {
+                        Some(span) => format!("do ... with ({})", location(*span)),
+                        None => "the host".to_owned(),
+                    };
+                    let resume = match resume {
+                        Some(resume) => format!("resumes at {}", show(resume)),
+                        None => "never resumes".to_owned(),
+                    };
+                    out += &format!("  handling {op} by {by}, {resume}\n");
+                }
+            }
+        }
+        out
+    }
+}
+
+impl Display for Backtrace {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.write(|span| span.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::runtime::{tests::compile, RuntimeError};
+
+    use super::*;
+
+    const SRC: &str = r#"
+effect choice {
+    fn choose() -> Int;
+}
+
+fn check(n: Int) -> Int = {
+    n / 0
+}
+
+fn outer() -> Int = {
+    let a: Int = check(2);
+    a + 1
+}
+
+fn chosen() -> Int = {
+    do {
+        let a: Int = choose();
+        a + 1
+    } with handle choice {
+        fn choose() -> Int = {
+            let x: Int = return(check(1));
+            x
+        }
+    }
+}
+"#;
+
+    fn fail(function: &str) -> Backtrace {
+        let (program, function) = compile(SRC, function);
+        let mut machine = Machine::new(&program, function, Vec::new()).expect("machine");
+        assert_eq!(Err(RuntimeError::DivideByZero), machine.run().map(|_| ()));
+        machine.backtrace()
+    }
+
+    fn functions(trace: &Backtrace) -> Vec<&str> {
+        trace
+            .frames
+            .iter()
+            .map(|frame| match frame {
+                TraceFrame::Code { function, .. } => function.as_str(),
+                TraceFrame::Exit { .. } => "exit",
+            })
+            .collect()
+    }
+
+    #[test]
+    fn follows_continuations() {
+        let trace = fail("outer");
+        assert_eq!(vec!["check", "outer"], functions(&trace));
+        let TraceFrame::Code { span, .. } = trace.frames[1] else {
+            panic!("expected code");
+        };
+        // the continuation receives the results of `check(2)`
+        assert_eq!(&SRC[span.pos..span.pos + span.len], "check");
+        assert!(trace.handlers.is_empty());
+        assert_eq!("  at check (6:4)\n  at outer (11:18)\n", trace.render(SRC));
+    }
+
+    #[test]
+    fn shows_handling_frame() {
+        let trace = fail("chosen");
+        assert_eq!(
+            vec!["check", "handler of choice::choose in chosen"],
+            functions(&trace)
+        );
+        let [HandlerTrace::Handling {
+            op,
+            by: Some(span),
+            resume: Some(TraceFrame::Code { function, .. }),
+        }] = &trace.handlers[..]
+        else {
+            panic!("expected one operation being handled: {trace:?}");
+        };
+        assert_eq!("choice::choose", op);
+        assert_eq!(&SRC[span.pos..span.pos + span.len], "with");
+        assert_eq!("chosen", function);
+    }
+}
@@ -0,0 +1,316 @@
+//! The compiler facade.
+
+use crate::{
+    ast::Item,
+    cache::StringCache,
+    cst::{self, lower::Lowerer, SyntaxNode},
+    debug::Debugger,
+    diagnostic::Diagnostics,
+    escape, json,
+    mir::{bytecode, c, lower::lower, text::disassemble, wat, Program},
+    resolve::resolve,
+    runtime::{Machine, RuntimeError, Value},
+    span::{Span, Spanned},
+    symbol::SymbolKey,
+    token::{Token, TokenKind},
+    tokenizer::Tokenizer,
+};
+
+/// Compiles source files and runs the results. Strings are interned in a cache shared by everything
+/// the session compiles.
+#[derive(Debug, Default)]
+pub struct Session {
+    cache: StringCache,
+}
+
+/// The result of compiling a file.
+#[derive(Debug)]
+pub struct Compilation {
+    pub diagnostics: Diagnostics,
+    /// The lowered program, or `None` if there were errors.
+    pub program: Option<Program>,
+}
+
+/// The concrete syntax tree of a file.
+#[derive(Clone, Debug)]
+pub struct SyntaxTree {
+    /// The name of the file, which the spans in the tree point into.
+    pub filename: String,
+    pub root: SyntaxNode,
+    pub diagnostics: Diagnostics,
+}
+
+/// The forms a parsed file can be written out in.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Syntax {
+    /// The items and diagnostics in their debug representation.
+    Debug,
+    /// The tokens, items, and diagnostics as JSON.
+    Json,
+    /// The concrete syntax tree.
+    Cst,
+}
+
+/// The languages a program can be compiled to.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Target {
+    C,
+    /// WebAssembly text.
+    Wat,
+}
+
+impl Target {
+    /// The file extension of code in the language.
+    pub fn extension(self) -> &'static str {
+        match self {
+            Target::C => "c",
+            Target::Wat => "wat",
+        }
+    }
+}
+
+impl Session {
+    /// Creates a new session.
+    pub fn new() -> Self {
+        Session {
+            cache: StringCache::new(),
+        }
+    }
+
+    /// The session's string cache, for looking up the strings in items and diagnostics.
+    #[cfg(test)]
+    pub(crate) fn cache(&self) -> &StringCache {
+        &self.cache
+    }
+
+    #[cfg(test)]
+    pub(crate) fn cache_mut(&mut self) -> &mut StringCache {
+        &mut self.cache
+    }
+
+    /// Parses a file.
+    pub(crate) fn parse(&mut self, filename: &str, src: &str) -> (Vec<Spanned<Item>>, Diagnostics) {
+        let filename = self.cache.intern(filename);
+        let mut ds = Diagnostics::new();
+        let (items, _) = cst::parse(filename, src, &mut self.cache, &mut ds);
+        (items, ds)
+    }
+
+    /// Splits a file into tokens, ending with [`TokenKind::Eof`].
+    pub fn tokenize(&mut self, filename: &str, src: &str) -> Vec<(TokenKind, Span)> {
+        let filename = self.cache.intern(filename);
+        let tokens = Tokenizer::from_parts(filename, src).into_tokens();
+        tokens
+            .into_iter()
+            .map(|tkn| (*tkn, Token::span(&tkn)))
+            .collect()
+    }
+
+    /// Parses a file into its concrete syntax tree, which holds every token and all whitespace.
+    pub fn syntax_tree(&mut self, filename: &str, src: &str) -> SyntaxTree {
+        let file = self.cache.intern(filename);
+        let mut diagnostics = Diagnostics::new();
+        let (_, root) = cst::parse(file, src, &mut self.cache, &mut diagnostics);
+        SyntaxTree {
+            filename: filename.to_string(),
+            root,
+            diagnostics,
+        }
+    }
+
+    /// Parses a file and writes it out in the given form.
+    pub fn dump(&mut self, filename: &str, src: &str, syntax: Syntax) -> String {
+        let (items, ds) = self.parse(filename, src);
+        match syntax {
+            Syntax::Debug => {
+                let mut out = String::new();
+                for item in items {
+                    out += &format!("Output: {item:?}\n\n");
+                }
+                out + &format!("Diagnostics: {ds:?}\n")
+            }
+            Syntax::Json => {
+                let file = self.cache.intern(filename);
+                let tokens = Tokenizer::from_parts(file, src).into_tokens();
+                let json = json::export(filename, src, &tokens, &items, &ds, &self.cache);
+                format!("{json}\n")
+            }
+            Syntax::Cst => self.syntax_tree(filename, src).root.dump(),
+        }
+    }
+
+    /// Parses a single statement, and writes it and its diagnostics out in their debug
+    /// representation.
+    pub fn dump_statement(&mut self, src: &str) -> String {
+        let file = self.cache.intern("repl.ku");
+        let mut ds = Diagnostics::new();
+        let root = cst::parse_range(file, src, 0..src.len(), &mut ds, |p| p.stmt());
+        let mut lowerer = Lowerer::new(&root, &mut self.cache, &mut ds);
+        let output = root.child_nodes().first().map(|node| lowerer.stmt(node));
+        format!("Output: {output:?}\nDiagnostics: {ds:?}\n")
+    }
+
+    /// Parses, resolves, and lowers a file.
+    pub fn compile(&mut self, filename: &str, src: &str) -> Compilation {
+        let (items, mut diagnostics) = self.parse(filename, src);
+        let res = resolve(&items, &self.cache, &mut diagnostics);
+        let escapes = escape::check(&items, &res, &self.cache, &mut diagnostics);
+        let program = lower(&items, res, &escapes, &self.cache, &mut diagnostics);
+        let program = (!diagnostics.has_errors()).then_some(program);
+        Compilation {
+            diagnostics,
+            program,
+        }
+    }
+
+    /// Prepares to call a top-level function of a program compiled in this session. The function's
+    /// continuation is added to the arguments if it has one.
+    pub fn machine<'a>(
+        &self,
+        program: &'a Program,
+        function: &str,
+        args: Vec<Value>,
+    ) -> Result<Machine<'a>, RuntimeError> {
+        Machine::new(program, self.function(program, function)?, args)
+    }
+
+    /// Calls a top-level function of a program compiled in this session with stdio as the console,
+    /// returning the values it returns with.
+    pub fn run(
+        &self,
+        program: &Program,
+        function: &str,
+        args: Vec<Value>,
+    ) -> Result<Vec<Value>, RuntimeError> {
+        self.machine(program, function, args)?.run()
+    }
+
+    /// Creates a debugger for a machine about to run a program compiled from the given source.
+    pub fn debugger<'a>(
+        &'a self,
+        machine: Machine<'a>,
+        program: &'a Program,
+        src: &'a str,
+    ) -> Debugger<'a> {
+        Debugger::new(machine, program, &self.cache, src)
+    }
+
+    /// Writes a program compiled in this session out as text.
+    pub fn disassemble(&self, program: &Program) -> String {
+        disassemble(program, &self.cache)
+    }
+
+    /// Encodes a program compiled in this session as bytecode.
+    pub fn encode(&self, program: &Program) -> Vec<u8> {
+        bytecode::encode(program, &self.cache)
+    }
+
+    /// Decodes a program from bytecode, verifying it.
+    pub fn decode(&mut self, bytes: &[u8]) -> Result<Program, bytecode::DecodeError> {
+        bytecode::decode(bytes, &mut self.cache)
+    }
+
+    /// Compiles a program to another language, with a top-level function as its entry point.
+    pub fn emit(
+        &self,
+        program: &Program,
+        target: Target,
+        function: &str,
+    ) -> Result<String, RuntimeError> {
+        let entry = self.function(program, function)?;
+        Ok(match target {
+            Target::C => c::emit(program, entry),
+            Target::Wat => wat::emit(program, entry),
+        })
+    }
+
+    /// Finds a top-level function of a program by name.
+    fn function(&self, program: &Program, name: &str) -> Result<SymbolKey, RuntimeError> {
+        self.cache
+            .find(name)
+            .and_then(|name| program.function(name))
+            .ok_or_else(|| RuntimeError::Undefined(name.to_owned()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        cst::SyntaxKind,
+        runtime::{Captured, Host},
+    };
+
+    use super::*;
+
+    const SRC: &str = r#"
+fn answer() -> Int;
+
+fn main() -> Int = {
+    print("asking");
+    answer()
+}
+"#;
+
+    #[test]
+    fn compiles_and_runs() {
+        let mut session = Session::new();
+        let compilation = session.compile("main.ku", SRC);
+        assert!(compilation.diagnostics.is_empty());
+        let program = compilation.program.expect("program");
+
+        let mut host = Host::new();
+        host.function("answer", || 42);
+        let mut console = Captured::default();
+        let mut machine = session
+            .machine(&program, "main", Vec::new())
+            .expect("machine")
+            .with_host(host)
+            .with_console(&mut console);
+        let values = machine.run().expect("main");
+        assert_eq!("42", values[0].to_string());
+        drop(machine);
+        assert_eq!(vec!["asking"], console.output);
+
+        assert!(matches!(
+            session.run(&program, "missing", Vec::new()),
+            Err(RuntimeError::Undefined(_))
+        ));
+    }
+
+    #[test]
+    fn reports_errors() {
+        let mut session = Session::new();
+        let compilation = session.compile("main.ku", "fn main() -> Int = { x }");
+        assert!(compilation.diagnostics.has_errors());
+        assert!(compilation.program.is_none());
+    }
+
+    #[test]
+    fn walks_syntax_tree() {
+        let mut session = Session::new();
+        let tree = session.syntax_tree("main.ku", SRC);
+        assert_eq!("main.ku", tree.filename);
+        assert!(tree.diagnostics.is_empty());
+        assert_eq!(SRC, tree.root.to_string());
+
+        let kinds: Vec<_> = tree
+            .root
+            .descendants()
+            .iter()
+            .map(SyntaxNode::kind)
+            .filter(|kind| kind.is_item())
+            .collect();
+        assert_eq!(
+            vec![SyntaxKind::AbstractFunctionItem, SyntaxKind::FunctionItem],
+            kinds
+        );
+
+        let tokens = tree.root.tokens();
+        let text: String = tokens.iter().map(|token| &*token.text).collect();
+        assert_eq!(SRC, text);
+        for token in &tokens {
+            let span = Spanned::span(token);
+            assert_eq!(&*token.text, &SRC[span.pos..span.pos + span.len]);
+        }
+    }
+}
@@ -57,6 +57,10 @@ impl<T> Spanned<T> {
         this.span
     }
 
+    pub fn span_mut(this: &mut Self) -> &mut Span {
+        &mut this.span
+    }
+
     pub fn map<R, F>(this: Self, f: F) -> Spanned<R>
     where
         F: FnOnce(T) -> R,
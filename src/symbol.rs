@@ -18,11 +18,11 @@ use std::collections::HashMap;
 
 pub mod fmt;
 
-#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[derive(Copy, Clone, Debug, Hash, Eq, PartialEq, Ord, PartialOrd)]
 pub struct SymbolKey(usize);
 
 impl SymbolKey {
-    const ROOT: Self = Self(0);
+    pub const ROOT: Self = Self(0);
 }
 
 /// Represents the root of a symbol (sub)tree.
@@ -53,6 +53,12 @@ pub struct SymbolTable {
     nodes: Vec<Node>,
 }
 
+impl Default for SymbolTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl SymbolTable {
     pub fn new() -> Self {
         Self {
@@ -104,6 +110,38 @@ impl SymbolTable {
         Some(key)
     }
 
+    /// Defines a symbol under the given context that cannot be resolved by name.
+    /// Used for scopes such as closures and `let` bindings.
+    pub fn define_anonymous(&mut self, context: SymbolKey) -> SymbolKey {
+        let key = SymbolKey(self.nodes.len());
+        self.nodes.push(Node::with_context(StringKey::EMPTY, context));
+        key
+    }
+
+    /// Retrieves the context symbol of a given symbol. The root context's context is itself.
+    pub fn context(&self, key: SymbolKey) -> SymbolKey {
+        self.nodes[key.0].context
+    }
+
+    /// Retrieves the string keys of the named symbols on the path from the root to the given symbol.
+    pub fn path(&self, mut key: SymbolKey) -> Vec<StringKey> {
+        let mut path = Vec::new();
+        while key != SymbolKey::ROOT {
+            let node = &self.nodes[key.0];
+            if node.string_key != StringKey::EMPTY {
+                path.push(node.string_key);
+            }
+            key = node.context;
+        }
+        path.reverse();
+        path
+    }
+
+    /// Iterates over the symbols other than the root, in the order they were defined.
+    pub fn keys(&self) -> impl Iterator<Item = SymbolKey> {
+        (1..self.nodes.len()).map(SymbolKey)
+    }
+
     /// Retrieves the string key for a given symbol. Multiple symbols may have the same string key.
     pub fn string_key(&self, key: SymbolKey) -> StringKey {
         self.nodes[key.0].string_key
@@ -1,15 +1,16 @@
 use crate::symbol::SymbolTable;
 use std::fmt::{self, Display, Formatter};
 
-/// Trait for things that can be displayed with the context of a symbol table.
-pub trait SymbolDisplay {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>, symbol_table: &SymbolTable) -> fmt::Result;
+/// Trait for things that can be displayed with the context of a symbol table, or of whatever else
+/// names their symbols.
+pub trait SymbolDisplay<S: ?Sized = SymbolTable> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>, symbols: &S) -> fmt::Result;
 }
 
-pub struct SymbolFormatted<T>(pub T, pub SymbolTable);
+pub struct SymbolFormatted<'a, T, S: ?Sized = SymbolTable>(pub T, pub &'a S);
 
-impl<T: SymbolDisplay> Display for SymbolFormatted<T> {
+impl<T: SymbolDisplay<S>, S: ?Sized> Display for SymbolFormatted<'_, T, S> {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        self.0.fmt(f, &self.1)
+        self.0.fmt(f, self.1)
     }
 }
@@ -10,6 +10,8 @@ pub enum TokenKind {
     // Meta
     Eof,
     Unrecognized,
+    /// Whitespace between tokens. Not produced by the tokenizer.
+    Whitespace,
     // Punctuation
     Arrow,
     Colon,
@@ -53,6 +55,7 @@ pub enum TokenKind {
     // Data-carrying
     Number,
     BasePrefixNumber,
+    String,
     Ident,
 }
 
@@ -147,8 +150,10 @@ impl TokenKind {
             Self::Ident => "<ident>",
             Self::Number => "<number>",
             Self::BasePrefixNumber => "0Z<number>",
+            Self::String => "<string>",
             Self::Eof => "<EOF>",
             Self::Unrecognized => "<?>",
+            Self::Whitespace => "<whitespace>",
         }
     }
 }
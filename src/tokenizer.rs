@@ -1,4 +1,6 @@
 
+use std::ops::Range;
+
 use arraydeque::ArrayDeque;
 
 use crate::cache::StringKey;
@@ -15,30 +17,37 @@ pub struct Tokenizer<'a> {
     base: &'a str,
     src: &'a str,
     lookahead: ArrayDeque<Token, 2>,
+    /// The number of tokens returned by `next`.
+    consumed: usize,
 }
 
 impl<'a> Tokenizer<'a> {
     /// Constructs a tokenizer from its component parts.
     pub fn from_parts(file: StringKey, src: &'a str) -> Self {
+        Self::from_range(file, src, 0..src.len())
+    }
+
+    /// Constructs a tokenizer over a range of the given source. Spans are relative to the whole source,
+    /// and EOF is produced at the end of the range.
+    pub fn from_range(file: StringKey, src: &'a str, range: Range<usize>) -> Self {
+        let base = &src[..range.end];
         Self {
             file,
-            src,
-            base: src,
+            src: &base[range.start..],
+            base,
             lookahead: ArrayDeque::new(),
+            consumed: 0,
         }
     }
 
     /// Eats white space.
     fn consume_ws(&mut self) {
-        self.src = self.src.trim_start();
+        self.src = &self.src[rules::whitespace(self.src)..];
     }
 
     fn next_token(&mut self) -> Token {
         self.consume_ws();
-        let (kind, end) = rules::RULES
-            .iter()
-            .find_map(|&rule| rule(self.src))
-            .unwrap_or_else(|| rules::unrecognized_char(self.src));
+        let (kind, end) = rules::token(self.src);
         let span = Span {
             pos: self.base.len() - self.src.len(),
             len: end,
@@ -67,8 +76,16 @@ impl<'a> Tokenizer<'a> {
     }
 
     /// Gets the next token and advances the tokenizer.
+    #[allow(clippy::should_implement_trait)] // it never runs out of tokens
     pub fn next(&mut self) -> Token {
-        self.lookahead.pop_front().unwrap_or_else(|| self.next_token())
+        let tkn = self.lookahead.pop_front().unwrap_or_else(|| self.next_token());
+        self.consumed += 1;
+        tkn
+    }
+
+    /// Gets the number of tokens returned by [`Tokenizer::next`].
+    pub fn consumed(&self) -> usize {
+        self.consumed
     }
 
     /// Gets the next token, advances the tokenizer, and tests the token's kind against the given kind.
@@ -81,11 +98,16 @@ impl<'a> Tokenizer<'a> {
         }
     }
 
-    /// Gets the source corresponding to the given span.
-    /// # Panics
-    /// This function panics if the span represents an invalid range.
-    pub fn src_for(&self, span: Span) -> &str {
-        &self.base[span.pos..span.pos + span.len]
+    /// Consumes the rest of the input, returning every remaining token up to and including EOF.
+    pub fn into_tokens(mut self) -> Vec<Token> {
+        let mut tokens = Vec::new();
+        loop {
+            let tkn = self.next();
+            tokens.push(tkn);
+            if *tkn == TokenKind::Eof {
+                return tokens;
+            }
+        }
     }
 }
 
@@ -180,6 +202,22 @@ mod tests {
         assert_eq!(expected, tokenizer.next());
     }
 
+    #[test]
+    fn tokenizes_range() {
+        let mut cache = StringCache::new();
+        let file_name = cache.intern("mysource.ku");
+        let mut tokenizer = Tokenizer::from_range(file_name, "foo bar baz", 4..9);
+
+        let expected = Token::from_span_value(Span { pos: 4, len: 3 }, TokenKind::Ident);
+        assert_eq!(expected, tokenizer.next());
+
+        let expected = Token::from_span_value(Span { pos: 8, len: 1 }, TokenKind::Ident);
+        assert_eq!(expected, tokenizer.next());
+
+        let expected = Token::from_span_value(Span { pos: 9, len: 0 }, TokenKind::Eof);
+        assert_eq!(expected, tokenizer.next());
+    }
+
     #[test]
     fn advances2() {
         let mut cache = StringCache::new();
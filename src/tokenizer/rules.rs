@@ -1,77 +1,271 @@
-//! Specific rules for tokenizing subsets of tokens.
-
-use once_cell::sync::Lazy;
-use regex::Regex;
+//! Tokenizing rules. Tokens are recognized by dispatching on their first byte, so each token is
+//! scanned once without trying alternatives.
 
 use crate::token::TokenKind;
 
-static IDENT: Lazy<Regex> =
-    Lazy::new(|| Regex::new("^[a-zA-Z_][a-zA-Z_0-9]*").expect("Ident regex"));
-static BASE_PREFIX_INTEGER: Lazy<Regex> =
-    Lazy::new(|| Regex::new("^0[xcbXCB][0-9a-fA-F]+").expect("Base prefix integer regex"));
-static INTEGER: Lazy<Regex> = Lazy::new(|| Regex::new("^[1-9][0-9]*").expect("Integer regex"));
-
-type Rule = fn(&str) -> Option<(TokenKind, usize)>;
-
-/// All tokenizing rules. When more than one rule applies, the earliest rule is used.
-pub const RULES: &[Rule] = &[
-    eof,
-    width_two_punct,
-    width_one_punct,
-    base_prefix_number,
-    unprefixed_number,
-    keyword,
-    ident,
-];
-
-pub fn width_two_punct(src: &str) -> Option<(TokenKind, usize)> {
-    TokenKind::WIDTH_TWO_PUNCT
-        .iter()
-        .find(|p| src.starts_with(p.as_str()))
-        .map(|p| (*p, p.as_str().len()))
+/// Gets the length of the whitespace at the start of the source.
+pub fn whitespace(src: &str) -> usize {
+    let bytes = src.as_bytes();
+    let mut end = 0;
+    while let Some(&b) = bytes.get(end) {
+        match b {
+            b' ' | b'\t' | b'\n' | b'\r' | 0x0b | 0x0c => end += 1,
+            // non-ASCII whitespace is rare, so defer to the standard library
+            0x80.. => return src.len() - src[end..].trim_start().len(),
+            _ => break,
+        }
+    }
+    end
 }
 
-pub fn width_one_punct(src: &str) -> Option<(TokenKind, usize)> {
-    TokenKind::WIDTH_ONE_PUNCT
-        .iter()
-        .find(|p| src.starts_with(p.as_str()))
-        .map(|p| (*p, p.as_str().len()))
+/// Gets the kind and length of the token at the start of the source, which must not begin with
+/// whitespace. An empty source produces EOF.
+pub fn token(src: &str) -> (TokenKind, usize) {
+    let bytes = src.as_bytes();
+    let Some(&first) = bytes.first() else {
+        return (TokenKind::Eof, 0);
+    };
+    let second = bytes.get(1).copied();
+    let one = |kind| (kind, 1);
+    let one_or_two = |next, long, short| {
+        if second == Some(next) {
+            (long, 2)
+        } else {
+            (short, 1)
+        }
+    };
+    match first {
+        b'-' => one_or_two(b'>', TokenKind::Arrow, TokenKind::Minus),
+        b'=' => one_or_two(b'=', TokenKind::DoubleEquals, TokenKind::Equals),
+        b'>' => one_or_two(b'=', TokenKind::GtEquals, TokenKind::Gt),
+        b'<' => one_or_two(b'=', TokenKind::LtEquals, TokenKind::Lt),
+        b':' => one_or_two(b':', TokenKind::Scope, TokenKind::Colon),
+        b'!' => one_or_two(b'=', TokenKind::ExclaimEquals, TokenKind::Unrecognized),
+        b',' => one(TokenKind::Comma),
+        b'{' => one(TokenKind::CurlyL),
+        b'}' => one(TokenKind::CurlyR),
+        b'.' => one(TokenKind::Dot),
+        b'%' => one(TokenKind::Percent),
+        b'|' => one(TokenKind::Pipe),
+        b'+' => one(TokenKind::Plus),
+        b'(' => one(TokenKind::RoundL),
+        b')' => one(TokenKind::RoundR),
+        b';' => one(TokenKind::Semi),
+        b'/' => one(TokenKind::Slash),
+        b'[' => one(TokenKind::SquareL),
+        b']' => one(TokenKind::SquareR),
+        b'*' => one(TokenKind::Star),
+        b'0' => {
+            // a base prefix must be followed by at least one digit, otherwise this is just 0
+            let digits = count(&bytes[2.min(bytes.len())..], u8::is_ascii_hexdigit);
+            if matches!(second, Some(b'x' | b'c' | b'b' | b'X' | b'C' | b'B')) && digits > 0 {
+                (TokenKind::BasePrefixNumber, 2 + digits)
+            } else {
+                (TokenKind::Number, 1)
+            }
+        }
+        b'1'..=b'9' => (TokenKind::Number, count(bytes, u8::is_ascii_digit)),
+        b'"' => (TokenKind::String, string(bytes)),
+        b'a'..=b'z' | b'A'..=b'Z' | b'_' => match keyword(first, src) {
+            Some(kw) => (kw, kw.as_str().len()),
+            None => (TokenKind::Ident, count(bytes, is_ident_continue)),
+        },
+        0x80.. => {
+            let len = src.chars().next().map_or(1, char::len_utf8);
+            (TokenKind::Unrecognized, len)
+        }
+        _ => one(TokenKind::Unrecognized),
+    }
 }
 
-pub fn keyword(src: &str) -> Option<(TokenKind, usize)> {
-    TokenKind::KEYWORDS
+/// Finds a keyword at the start of the source. Like the rest of the rules, keywords are matched as
+/// prefixes, so `letter` is `let` followed by `ter`.
+fn keyword(first: u8, src: &str) -> Option<TokenKind> {
+    let candidates: &[TokenKind] = match first {
+        b'c' => &[TokenKind::CC],
+        b'd' => &[TokenKind::Do],
+        b'e' => &[TokenKind::Effect, TokenKind::Else],
+        b'f' => &[TokenKind::Finally, TokenKind::Fn],
+        b'h' => &[TokenKind::Handle],
+        b'i' => &[TokenKind::If, TokenKind::Import],
+        b'l' => &[TokenKind::Let],
+        b'r' => &[TokenKind::Return],
+        b'U' => &[TokenKind::Unit],
+        b'w' => &[TokenKind::With],
+        _ => return None,
+    };
+    candidates
         .iter()
-        .find(|p| src.starts_with(p.as_str()))
-        .map(|p| (*p, p.as_str().len()))
+        .copied()
+        .find(|kw| src.starts_with(kw.as_str()))
 }
 
-pub fn ident(src: &str) -> Option<(TokenKind, usize)> {
-    IDENT.find(src).map(|m| (TokenKind::Ident, m.end()))
+/// Gets the length of a string literal, including the quotes. An unterminated literal ends at the end
+/// of the line, and the parser reports it.
+fn string(bytes: &[u8]) -> usize {
+    let mut end = 1;
+    while let Some(&b) = bytes.get(end) {
+        match b {
+            b'"' => return end + 1,
+            b'\n' => return end,
+            // an escape; the parser checks that it's valid
+            b'\\' if bytes.get(end + 1).is_some_and(|&b| b != b'\n') => end += 2,
+            _ => end += 1,
+        }
+    }
+    end
 }
 
-pub fn base_prefix_number(src: &str) -> Option<(TokenKind, usize)> {
-    BASE_PREFIX_INTEGER
-        .find(src)
-        .map(|m| (TokenKind::BasePrefixNumber, m.end()))
+fn is_ident_continue(b: &u8) -> bool {
+    b.is_ascii_alphanumeric() || *b == b'_'
 }
 
-pub fn unprefixed_number(src: &str) -> Option<(TokenKind, usize)> {
-    INTEGER
-        .find(src)
-        .map(|m| m.end())
-        .or_else(|| src.starts_with('0').then_some(1))
-        .map(|e| (TokenKind::Number, e))
+/// Counts the leading bytes matching a predicate.
+fn count(bytes: &[u8], pred: impl Fn(&u8) -> bool) -> usize {
+    bytes.iter().take_while(|b| pred(b)).count()
 }
 
-pub fn eof(src: &str) -> Option<(TokenKind, usize)> {
-    if src.is_empty() {
-        Some((TokenKind::Eof, 0))
-    } else {
-        None
+#[cfg(test)]
+mod tests {
+    use once_cell::sync::Lazy;
+    use regex::Regex;
+
+    use super::*;
+
+    /// The regex-based rules this lexer replaced, kept to check that the output hasn't changed.
+    mod reference {
+        use super::*;
+
+        static IDENT: Lazy<Regex> =
+            Lazy::new(|| Regex::new("^[a-zA-Z_][a-zA-Z_0-9]*").expect("Ident regex"));
+        static BASE_PREFIX_INTEGER: Lazy<Regex> =
+            Lazy::new(|| Regex::new("^0[xcbXCB][0-9a-fA-F]+").expect("Base prefix integer regex"));
+        static INTEGER: Lazy<Regex> =
+            Lazy::new(|| Regex::new("^[1-9][0-9]*").expect("Integer regex"));
+
+        fn prefix(kinds: &[TokenKind], src: &str) -> Option<(TokenKind, usize)> {
+            kinds
+                .iter()
+                .find(|p| src.starts_with(p.as_str()))
+                .map(|p| (*p, p.as_str().len()))
+        }
+
+        pub fn token(src: &str) -> (TokenKind, usize) {
+            if src.is_empty() {
+                return (TokenKind::Eof, 0);
+            }
+            prefix(TokenKind::WIDTH_TWO_PUNCT, src)
+                .or_else(|| prefix(TokenKind::WIDTH_ONE_PUNCT, src))
+                .or_else(|| {
+                    BASE_PREFIX_INTEGER
+                        .find(src)
+                        .map(|m| (TokenKind::BasePrefixNumber, m.end()))
+                })
+                .or_else(|| {
+                    INTEGER
+                        .find(src)
+                        .map(|m| m.end())
+                        .or_else(|| src.starts_with('0').then_some(1))
+                        .map(|e| (TokenKind::Number, e))
+                })
+                .or_else(|| prefix(TokenKind::KEYWORDS, src))
+                .or_else(|| IDENT.find(src).map(|m| (TokenKind::Ident, m.end())))
+                .unwrap_or_else(|| {
+                    let end = src.chars().next().map(char::len_utf8).unwrap_or(0);
+                    (TokenKind::Unrecognized, end)
+                })
+        }
     }
-}
 
-pub fn unrecognized_char(src: &str) -> (TokenKind, usize) {
-    let end = src.chars().next().map(char::len_utf8).unwrap_or(0);
-    (TokenKind::Unrecognized, end)
+    type Lexer = fn(&str) -> (TokenKind, usize);
+
+    fn tokens(src: &str, lexer: Lexer) -> Vec<(usize, TokenKind, usize)> {
+        let mut tokens = Vec::new();
+        let mut rest = src.trim_start();
+        loop {
+            let (kind, len) = lexer(rest);
+            tokens.push((src.len() - rest.len(), kind, len));
+            if kind == TokenKind::Eof {
+                return tokens;
+            }
+            rest = rest[len..].trim_start();
+        }
+    }
+
+    /// Generates a large, plausible source file.
+    fn generate(lines: usize) -> String {
+        let mut src = String::new();
+        for i in 0..lines.div_ceil(14) {
+            src.push_str(&format!(
+                "effect eff_{i}[R] {{\n    fn op_{i}(v: R) ->;\n}}\n\n\
+                 fn func_{i}[T | e](x: T, code: {{}}/e) -> (T) -> = {{\n    \
+                 let y: Int = x.field_{i} + 0x{i:x} * {i} - 0;\n    \
+                 if y >= 10 {{ code(); }} else if y != 0 {{ :return y; }}\n    \
+                 do {{\n        :infinite code;\n    }} with handle eff_{i}[T] {{\n        \
+                 fn op_{i}(v: T) -> = {{ :continue v, a::b::c, letter; }}\n    }}\n}}\n\n"
+            ));
+        }
+        src
+    }
+
+    #[test]
+    fn matches_reference() {
+        let alphabet = [
+            "do", "dog", "letter", "Unit", "Units", "0", "00", "0x", "0xfg", "0b12", "0C", "123",
+            "09", "_a1", "é", "\u{a0}", " ", "\n", "\t", "\u{b}", "-", ">", "=", "!", ":", "\\",
+            "$", "{", "}", "(", ")", ";", ".", "%", "|", "+", "*", "/", "[", "]", ",", "<",
+        ];
+        let mut seed = 0x9e3779b97f4a7c15u64;
+        let mut rand = |n: usize| {
+            seed = seed
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (seed >> 33) as usize % n
+        };
+        for _ in 0..2000 {
+            let src = (0..rand(12))
+                .map(|_| alphabet[rand(alphabet.len())])
+                .collect::<String>();
+            assert_eq!(
+                tokens(&src, reference::token),
+                tokens(&src, token),
+                "{src:?}"
+            );
+        }
+
+        let src = generate(200);
+        assert_eq!(tokens(&src, reference::token), tokens(&src, token));
+        let src = include_str!("../../korou-examples/loop.ku");
+        assert_eq!(tokens(src, reference::token), tokens(src, token));
+    }
+
+    #[test]
+    fn lexes_every_token() {
+        let kinds = [
+            TokenKind::KEYWORDS,
+            TokenKind::WIDTH_TWO_PUNCT,
+            TokenKind::WIDTH_ONE_PUNCT,
+        ];
+        for &kind in kinds.concat().iter() {
+            assert_eq!((kind, kind.as_str().len()), token(kind.as_str()));
+        }
+    }
+
+    #[test]
+    fn string_len() {
+        assert_eq!((TokenKind::String, 2), token(r#""""#));
+        assert_eq!((TokenKind::String, 7), token(r#""a b c" x"#));
+        assert_eq!((TokenKind::String, 6), token(r#""\"\\" x"#));
+        assert_eq!((TokenKind::String, 4), token("\"abc\ndef\""));
+        assert_eq!((TokenKind::String, 3), token(r#""\""#));
+        assert_eq!((TokenKind::String, 4), token("\"é\""));
+    }
+
+    #[test]
+    fn whitespace_len() {
+        assert_eq!(0, whitespace("a "));
+        assert_eq!(3, whitespace(" \t\na"));
+        assert_eq!(4, whitespace(" \u{a0} x"));
+        assert_eq!(0, whitespace("é"));
+    }
 }